@@ -0,0 +1,261 @@
+use core::arch::x86_64::*;
+use once_cell::sync::Lazy;
+
+use super::transformation::*;
+use super::rasterisation::*;
+use super::simd_maths::*;
+
+// screen space ambient occlusion, darkening ambient light in creases and corners by how much of a hemisphere around
+// each pixel is behind what's already in the depth buffer
+// https://learnopengl.com/Advanced-Lighting/SSAO
+//
+// view space depth and occlusion are kept in full screen buffers rather than tiles, as samples reach into neighbouring
+// tiles; their rows are padded either side so spans of 8 can read past the ends of a row, and the padding is left as
+// background, which is negative infinity for depth and -1.0 for occlusion
+
+pub const PADDING: usize = 8;
+const NUM_SAMPLES: usize = 16;
+// the kernel's rotation repeats every 4x4 pixels, which the blur averages over
+const NOISE_SIZE: usize = 4;
+
+// not random, but there's no need for it to be
+fn next_random(seed: &mut u32) -> f32 {
+    *seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+    (*seed >> 8) as f32 / (1 << 24) as f32
+}
+
+// offsets inside a hemisphere around +z, packed closer together near the middle as nearby geometry matters more
+static KERNEL: Lazy<[[f32; 3]; NUM_SAMPLES]> = Lazy::new(|| {
+    let mut seed = 1;
+    let mut kernel = [[0.0; 3]; NUM_SAMPLES];
+    for (i, offset) in kernel.iter_mut().enumerate() {
+        let v = [next_random(&mut seed) * 2.0 - 1.0, next_random(&mut seed) * 2.0 - 1.0, next_random(&mut seed)];
+        let length = (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt().max(f32::MIN_POSITIVE);
+        let t = i as f32 / NUM_SAMPLES as f32;
+        let scale = next_random(&mut seed) * (0.1 + 0.9 * t * t);
+        *offset = v.map(|c| c / length * scale);
+    }
+    kernel
+});
+
+// directions in the xy plane to turn the kernel towards, different for each pixel in a 4x4 block
+static NOISE: Lazy<[[[f32; 2]; NOISE_SIZE]; NOISE_SIZE]> = Lazy::new(|| {
+    let mut seed = 2;
+    [(); NOISE_SIZE].map(|_| [(); NOISE_SIZE].map(|_| {
+        let angle = next_random(&mut seed) * std::f32::consts::TAU;
+        [angle.cos(), angle.sin()]
+    }))
+});
+
+// what's needed to get from depth back to view space and from view space to the screen, for a perspective_rh
+// projection followed by a viewport; view space looks down -z, and w is -z after projection
+#[derive(Clone, Copy)]
+pub struct Projection {
+    x_scale: f32,
+    y_scale: f32,
+    z_scale: f32,
+    z_offset: f32,
+    half_width: f32,
+    half_height: f32
+}
+
+impl Projection {
+    pub fn new(projection: &Transformation, width: usize, height: usize) -> Projection {
+        Projection {
+            x_scale: projection.matrix[0][0],
+            y_scale: projection.matrix[1][1],
+            z_scale: projection.matrix[2][2],
+            z_offset: projection.matrix[3][2],
+            half_width: width as f32 / 2.0,
+            half_height: height as f32 / 2.0
+        }
+    }
+}
+
+// view space depth for a tile of the depth buffer; depth is (z_scale * z + z_offset) / -z, so z is
+// -z_offset / (depth + z_scale), and anything still at the far plane is background
+#[target_feature(enable = "avx,avx2,fma")]
+pub unsafe fn avx2_linearise_tile(
+        depth: &Buffer<f32>, view_zs: &mut [f32], padded_stride: usize, projection: &Projection,
+        xmin: usize, ymin: usize, xmax: usize, ymax: usize) {
+    let one = _mm256_set1_ps(1.0);
+    let background = _mm256_set1_ps(f32::NEG_INFINITY);
+    let z_scale = _mm256_set1_ps(projection.z_scale);
+    let minus_z_offset = _mm256_set1_ps(-projection.z_offset);
+
+    for yp in ymin..ymax {
+        let d_row = depth.buffer.as_ptr().add((yp - depth.top) * depth.stride - depth.left);
+        let v_row = view_zs.as_mut_ptr().add(yp * padded_stride + PADDING);
+        for xp in (xmin..xmax).step_by(8) {
+            let d = _mm256_loadu_ps(d_row.add(xp));
+            let z = _mm256_div_ps(minus_z_offset, _mm256_add_ps(d, z_scale));
+            _mm256_storeu_ps(v_row.add(xp), _mm256_blendv_ps(z, background, _mm256_cmp_ps(d, one, _CMP_GE_OQ)));
+        }
+    }
+}
+
+// view space positions from view space depth, for pixels (x + 0.5) / half_width - 1.0 and 1.0 - (y + 0.5) / half_height
+// across the view volume
+#[target_feature(enable = "avx,avx2,fma")]
+#[inline]
+unsafe fn avx2_view_position(z: __m256, ndc_x: __m256, ndc_y: __m256, projection: &Projection) -> [__m256; 3] {
+    let minus_z = _mm256_sub_ps(_mm256_setzero_ps(), z);
+    [
+        _mm256_mul_ps(minus_z, _mm256_mul_ps(ndc_x, _mm256_set1_ps(1.0 / projection.x_scale))),
+        _mm256_mul_ps(minus_z, _mm256_mul_ps(ndc_y, _mm256_set1_ps(1.0 / projection.y_scale))),
+        z
+    ]
+}
+
+// how much ambient light reaches each pixel of a tile, from 0.0 to 1.0, with -1.0 for background; radius is in view
+// space, so the same units as the scene
+#[target_feature(enable = "avx,avx2,fma")]
+pub unsafe fn avx2_occlusion_tile(
+        view_zs: &[f32], occlusion: &mut [f32], padded_stride: usize, stride: usize, height: usize,
+        projection: &Projection, radius: f32,
+        xmin: usize, ymin: usize, xmax: usize, ymax: usize) {
+    debug_assert!(xmin % 8 == 0 && xmax % 8 == 0 && NOISE_SIZE == 4);
+
+    let zero = _mm256_setzero_ps();
+    let one = _mm256_set1_ps(1.0);
+    let background = _mm256_set1_ps(-1.0);
+    let zero_to_seven = _mm256_set_ps(7.0, 6.0, 5.0, 4.0, 3.0, 2.0, 1.0, 0.0);
+    let inverse_half_width = _mm256_set1_ps(1.0 / projection.half_width);
+    let ndc_y = |yp: usize| _mm256_set1_ps(1.0 - (yp as f32 + 0.5) / projection.half_height);
+
+    let radius = _mm256_set1_ps(radius);
+    // stops flat surfaces occluding themselves through depth precision
+    let bias = _mm256_mul_ps(radius, _mm256_set1_ps(0.05));
+    let kernel = KERNEL.map(|k| k.map(|c| _mm256_mul_ps(_mm256_set1_ps(c), radius)));
+    // each span of 8 covers two of the noise blocks' columns; kept as floats and loaded a row at a time, like the
+    // dither offsets in tone_mapping.rs
+    let noise_rows: [[[f32; 8]; 2]; NOISE_SIZE] = NOISE.map(|row| [0, 1].map(|c| std::array::from_fn(|i| row[i % NOISE_SIZE][c])));
+
+    let max_x = _mm256_set1_ps(stride as f32);
+    let max_y = _mm256_set1_ps(height as f32);
+    let x_scale = _mm256_set1_ps(projection.half_width * projection.x_scale);
+    let y_scale = _mm256_set1_ps(projection.half_height * projection.y_scale);
+    let half_width = _mm256_set1_ps(projection.half_width);
+    let half_height = _mm256_set1_ps(projection.half_height);
+    let i_padded_stride = _mm256_set1_epi32(padded_stride as i32);
+    let i_padding = _mm256_set1_epi32(PADDING as i32);
+    let inverse_num_samples = _mm256_set1_ps(1.0 / NUM_SAMPLES as f32);
+
+    for yp in ymin..ymax {
+        let row = |yp: usize| view_zs.as_ptr().add(yp * padded_stride + PADDING);
+        let (up, centre, down) = (yp.saturating_sub(1), yp, (yp + 1).min(height - 1));
+        let (ndc_up, ndc_centre, ndc_down) = (ndc_y(up), ndc_y(centre), ndc_y(down));
+        let noise_row = &noise_rows[yp % NOISE_SIZE];
+        let noise_x = _mm256_loadu_ps(noise_row[0].as_ptr());
+        let noise_y = _mm256_loadu_ps(noise_row[1].as_ptr());
+        let o_row = occlusion.as_mut_ptr().add(yp * padded_stride + PADDING);
+
+        for xp in (xmin..xmax).step_by(8) {
+            let z = _mm256_loadu_ps(row(centre).add(xp));
+            let is_background = _mm256_cmp_ps(z, _mm256_set1_ps(f32::NEG_INFINITY), _CMP_EQ_OQ);
+            if _mm256_movemask_ps(is_background) == 0xff {
+                _mm256_storeu_ps(o_row.add(xp), background);
+                continue;
+            }
+
+            let x = _mm256_add_ps(_mm256_set1_ps(xp as f32 + 0.5), zero_to_seven);
+            let ndc_x = |x: __m256| _mm256_fmsub_ps(x, inverse_half_width, one);
+            let p = avx2_view_position(z, ndc_x(x), ndc_centre, projection);
+
+            // the normal from whichever neighbour on each axis is closest in depth, so it doesn't bend round edges
+            let neighbour = |z_neighbour: __m256, x: __m256, ndc_y: __m256| (
+                _mm256_andnot_ps(_mm256_set1_ps(-0.0), _mm256_sub_ps(z_neighbour, z)),
+                avx2_view_position(z_neighbour, ndc_x(x), ndc_y, projection));
+            let (left_dz, left) = neighbour(_mm256_loadu_ps(row(centre).add(xp).sub(1)), _mm256_sub_ps(x, one), ndc_centre);
+            let (right_dz, right) = neighbour(_mm256_loadu_ps(row(centre).add(xp + 1)), _mm256_add_ps(x, one), ndc_centre);
+            let (up_dz, above) = neighbour(_mm256_loadu_ps(row(up).add(xp)), x, ndc_up);
+            let (down_dz, below) = neighbour(_mm256_loadu_ps(row(down).add(xp)), x, ndc_down);
+            // the top and bottom rows are their own neighbours
+            let up_dz = if up == centre { _mm256_set1_ps(f32::INFINITY) } else { up_dz };
+            let down_dz = if down == centre { _mm256_set1_ps(f32::INFINITY) } else { down_dz };
+            let use_right = _mm256_cmp_ps(right_dz, left_dz, _CMP_LT_OQ);
+            let use_down = _mm256_cmp_ps(down_dz, up_dz, _CMP_LT_OQ);
+            let across = [0, 1, 2].map(|c| _mm256_blendv_ps(_mm256_sub_ps(p[c], left[c]), _mm256_sub_ps(right[c], p[c]), use_right));
+            let downwards = [0, 1, 2].map(|c| _mm256_blendv_ps(_mm256_sub_ps(p[c], above[c]), _mm256_sub_ps(below[c], p[c]), use_down));
+            // screen y goes down, so this points towards the eye
+            let n = avx2_normalised(avx2_cross(downwards, across));
+
+            // the kernel turned to the normal, by a different amount per pixel
+            let r_dot_n = _mm256_fmadd_ps(noise_x, n[0], _mm256_mul_ps(noise_y, n[1]));
+            let t = avx2_normalised([
+                _mm256_fnmadd_ps(n[0], r_dot_n, noise_x),
+                _mm256_fnmadd_ps(n[1], r_dot_n, noise_y),
+                _mm256_fnmadd_ps(n[2], r_dot_n, zero)]);
+            let b = avx2_cross(n, t);
+
+            let mut occluded = zero;
+            for k in &kernel {
+                let s = [0, 1, 2].map(|c| _mm256_fmadd_ps(t[c], k[0], _mm256_fmadd_ps(b[c], k[1], _mm256_fmadd_ps(n[c], k[2], p[c]))));
+
+                // back onto the screen, to see what's there
+                let inverse_w = _mm256_div_ps(one, _mm256_sub_ps(zero, s[2]));
+                let sx = _mm256_fmadd_ps(_mm256_mul_ps(s[0], inverse_w), x_scale, half_width);
+                let sy = _mm256_fnmadd_ps(_mm256_mul_ps(s[1], inverse_w), y_scale, half_height);
+                let on_screen = _mm256_and_ps(
+                    _mm256_and_ps(_mm256_cmp_ps(sx, zero, _CMP_GE_OQ), _mm256_cmp_ps(sx, max_x, _CMP_LT_OQ)),
+                    _mm256_and_ps(_mm256_cmp_ps(sy, zero, _CMP_GE_OQ), _mm256_cmp_ps(sy, max_y, _CMP_LT_OQ)));
+                let on_screen_i = _mm256_castps_si256(on_screen);
+                let ix = _mm256_and_si256(_mm256_cvttps_epi32(sx), on_screen_i);
+                let iy = _mm256_and_si256(_mm256_cvttps_epi32(sy), on_screen_i);
+                let i = _mm256_add_epi32(_mm256_mullo_epi32(iy, i_padded_stride), _mm256_add_epi32(ix, i_padding));
+                let scene_z = _mm256_mask_i32gather_ps(_mm256_set1_ps(f32::NEG_INFINITY), view_zs.as_ptr(), i, on_screen, 4);
+
+                // occluders much further away than the radius fade out, so silhouettes don't get dark halos
+                let is_occluded = _mm256_cmp_ps(scene_z, _mm256_add_ps(s[2], bias), _CMP_GE_OQ);
+                let distance = _mm256_andnot_ps(_mm256_set1_ps(-0.0), _mm256_sub_ps(p[2], scene_z));
+                let range = _mm256_min_ps(_mm256_div_ps(radius, distance), one);
+                let range = _mm256_mul_ps(_mm256_mul_ps(range, range), _mm256_fnmadd_ps(range, _mm256_set1_ps(2.0), _mm256_set1_ps(3.0)));
+                occluded = _mm256_add_ps(occluded, _mm256_and_ps(is_occluded, range));
+            }
+
+            let visibility = _mm256_fnmadd_ps(occluded, inverse_num_samples, one);
+            _mm256_storeu_ps(o_row.add(xp), _mm256_blendv_ps(visibility, background, is_background));
+        }
+    }
+}
+
+// blurs occlusion over the 4x4 block the kernel's rotations repeat over, ignoring background, and takes the occluded
+// part of the ambient light back out of a tile of the HDR target
+#[target_feature(enable = "avx,avx2,fma")]
+pub unsafe fn avx2_apply_occlusion_tile(
+        occlusion: &[f32], padded_stride: usize, height: usize,
+        hdr: &mut [Buffer<f32>; 3], ambient: &[Buffer<f32>; 3],
+        xmin: usize, ymin: usize, xmax: usize, ymax: usize) {
+    debug_assert!(xmin % 8 == 0 && xmax % 8 == 0);
+
+    let zero = _mm256_setzero_ps();
+    let one = _mm256_set1_ps(1.0);
+    for yp in ymin..ymax {
+        let o_rows: [*const f32; NOISE_SIZE] = std::array::from_fn(|i| {
+            let y = (yp + i).saturating_sub(NOISE_SIZE / 2).min(height - 1);
+            occlusion.as_ptr().add(y * padded_stride + PADDING - NOISE_SIZE / 2)
+        });
+        let h_rows = hdr.each_mut().map(|b| b.buffer.as_mut_ptr().add((yp - b.top) * b.stride - b.left));
+        let a_rows = ambient.each_ref().map(|b| b.buffer.as_ptr().add((yp - b.top) * b.stride - b.left));
+
+        for xp in (xmin..xmax).step_by(8) {
+            let mut total = zero;
+            let mut count = zero;
+            for o_row in o_rows {
+                for i in 0..NOISE_SIZE {
+                    let o = _mm256_loadu_ps(o_row.add(xp + i));
+                    let valid = _mm256_cmp_ps(o, zero, _CMP_GE_OQ);
+                    total = _mm256_add_ps(total, _mm256_and_ps(valid, o));
+                    count = _mm256_add_ps(count, _mm256_and_ps(valid, one));
+                }
+            }
+            let visibility = _mm256_blendv_ps(one, _mm256_div_ps(total, count), _mm256_cmp_ps(count, zero, _CMP_GT_OQ));
+
+            let blocked = _mm256_sub_ps(visibility, one);
+            for c in 0..3 {
+                let h = h_rows[c].add(xp);
+                _mm256_storeu_ps(h, _mm256_fmadd_ps(_mm256_loadu_ps(a_rows[c].add(xp)), blocked, _mm256_loadu_ps(h)));
+            }
+        }
+    }
+}
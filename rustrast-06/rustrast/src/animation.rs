@@ -0,0 +1,252 @@
+use super::transformation::*;
+use super::scene::*;
+
+// keyframe animation of node transformations and the camera, sampled at a time in seconds so that it runs at the
+// same speed whatever the frame rate; tracks work the way glTF's do, so animations can be read straight from files
+
+#[derive(Clone, Copy, PartialEq)]
+#[allow(dead_code)]
+pub enum Interpolation {
+    // each keyframe's value holds until the next
+    Step,
+    // rotations are spherically interpolated
+    Linear,
+    // a cubic Hermite spline through the keyframes, with an in and an out tangent at each
+    CubicSpline
+}
+
+// what happens after the last keyframe
+#[derive(Clone, Copy, PartialEq)]
+pub enum Looping {
+    // stays there
+    Once,
+    // starts again from the beginning
+    Repeat,
+    // goes back to the beginning, then forwards again
+    PingPong
+}
+
+// a value that can be keyframed
+pub trait Keyframe: Copy {
+    fn linear(a: Self, b: Self, t: f32) -> Self;
+    // for cubic splines
+    fn weighted_sum(terms: [(Self, f32); 4]) -> Self;
+}
+
+impl Keyframe for f32 {
+    fn linear(a: Self, b: Self, t: f32) -> Self {
+        a + (b - a) * t
+    }
+
+    fn weighted_sum(terms: [(Self, f32); 4]) -> Self {
+        terms.iter().map(|(v, weight)| v * weight).sum()
+    }
+}
+
+impl Keyframe for [f32; 3] {
+    fn linear(a: Self, b: Self, t: f32) -> Self {
+        [0, 1, 2].map(|c| a[c] + (b[c] - a[c]) * t)
+    }
+
+    fn weighted_sum(terms: [(Self, f32); 4]) -> Self {
+        [0, 1, 2].map(|c| terms.iter().map(|(v, weight)| v[c] * weight).sum())
+    }
+}
+
+impl Keyframe for Quaternion {
+    fn linear(a: Self, b: Self, t: f32) -> Self {
+        a.slerp(b, t)
+    }
+
+    // the spline doesn't keep to unit quaternions, so it's normalised back onto them
+    fn weighted_sum(terms: [(Self, f32); 4]) -> Self {
+        let sum = |component: fn(&Quaternion) -> f32| terms.iter().map(|(q, weight)| component(q) * weight).sum();
+        Quaternion { x: sum(|q| q.x), y: sum(|q| q.y), z: sum(|q| q.z), w: sum(|q| q.w) }.normalised()
+    }
+}
+
+pub struct Track<T> {
+    // in seconds, increasing
+    pub times: Vec<f32>,
+    // one for each time, apart from cubic splines which have three, the in tangent, the value and the out tangent
+    pub values: Vec<T>,
+    pub interpolation: Interpolation
+}
+
+impl<T: Keyframe> Track<T> {
+    fn value(&self, k: usize) -> T {
+        match self.interpolation {
+            Interpolation::CubicSpline => self.values[k * 3 + 1],
+            _ => self.values[k]
+        }
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.times.last().copied().unwrap_or(0.0)
+    }
+
+    // the first and last values hold before and after the keyframes
+    pub fn sample(&self, time: f32) -> T {
+        let last = self.times.len() - 1;
+        if time <= self.times[0] {
+            return self.value(0);
+        }
+        if time >= self.times[last] {
+            return self.value(last);
+        }
+
+        let k = self.times.partition_point(|&t| t <= time) - 1;
+        let dt = self.times[k + 1] - self.times[k];
+        let t = (time - self.times[k]) / dt;
+        match self.interpolation {
+            Interpolation::Step => self.value(k),
+            Interpolation::Linear => T::linear(self.value(k), self.value(k + 1), t),
+            Interpolation::CubicSpline => {
+                // the tangents are per second, so are scaled to the time between the keyframes
+                let (t2, t3) = (t * t, t * t * t);
+                T::weighted_sum([
+                    (self.value(k), 2.0 * t3 - 3.0 * t2 + 1.0),
+                    (self.values[k * 3 + 2], (t3 - 2.0 * t2 + t) * dt),
+                    (self.value(k + 1), -2.0 * t3 + 3.0 * t2),
+                    (self.values[(k + 1) * 3], (t3 - t2) * dt)])
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+#[allow(dead_code)]
+pub enum AnimationTarget {
+    // an index into the scene's nodes
+    Node(usize),
+    // a rig at the origin that the camera is placed relative to, so rotating it orbits the camera around the centre
+    // of the scene and translating it moves the camera
+    Camera
+}
+
+// whichever of a target's translation, rotation and scale are animated; the others stay as they were
+pub struct Channel {
+    pub target: AnimationTarget,
+    pub translation: Option<Track<[f32; 3]>>,
+    pub rotation: Option<Track<Quaternion>>,
+    pub scale: Option<Track<[f32; 3]>>,
+    // one for each of the node's model's morph targets, or none if they aren't animated
+    pub morph_weights: Vec<Track<f32>>
+}
+
+impl Channel {
+    pub fn duration(&self) -> f32 {
+        let durations = [
+            self.translation.as_ref().map(Track::duration),
+            self.rotation.as_ref().map(Track::duration),
+            self.scale.as_ref().map(Track::duration)];
+        let weights = self.morph_weights.iter().map(Track::duration);
+        durations.into_iter().flatten().chain(weights).fold(0.0, f32::max)
+    }
+
+    pub fn animates_transformation(&self) -> bool {
+        self.translation.is_some() || self.rotation.is_some() || self.scale.is_some()
+    }
+
+    // rest is the target's transformation when it isn't being animated
+    pub fn sample(&self, rest: &Transformation, time: f32) -> Transformation {
+        let (translation, rotation, scale) = rest.decomposed();
+        Transformation::from_trs(
+            self.translation.as_ref().map_or(translation, |track| track.sample(time)),
+            self.rotation.as_ref().map_or(rotation, |track| track.sample(time)),
+            self.scale.as_ref().map_or(scale, |track| track.sample(time)))
+    }
+
+    pub fn sample_morph_weights(&self, time: f32) -> Vec<f32> {
+        self.morph_weights.iter().map(|track| track.sample(time)).collect()
+    }
+}
+
+#[allow(dead_code)]
+pub struct Animation {
+    pub name: String,
+    pub channels: Vec<Channel>,
+    pub looping: Looping
+}
+
+impl Animation {
+    // when the last keyframe of any channel is
+    pub fn duration(&self) -> f32 {
+        self.channels.iter().map(Channel::duration).fold(0.0, f32::max)
+    }
+
+    // how far into the animation it is after the given number of seconds
+    pub fn local_time(&self, elapsed: f32) -> f32 {
+        let duration = self.duration();
+        if duration <= 0.0 {
+            return 0.0;
+        }
+        match self.looping {
+            Looping::Once => elapsed.min(duration),
+            Looping::Repeat => elapsed.rem_euclid(duration),
+            Looping::PingPong => {
+                let t = elapsed.rem_euclid(2.0 * duration);
+                if t > duration { 2.0 * duration - t } else { t }
+            }
+        }
+    }
+}
+
+// a whole turn about the y axis every period seconds; it's keyframed in thirds of a turn as interpolating rotations
+// always takes the short way round
+pub fn turntable(target: AnimationTarget, period: f32, looping: Looping) -> Animation {
+    let up = CartesianVector { x: 0.0, y: 1.0, z: 0.0 };
+    let rotation = Track {
+        times: (0..=3).map(|i| period * i as f32 / 3.0).collect(),
+        values: (0..=3).map(|i| Quaternion::from_axis_angle(up, std::f32::consts::TAU * i as f32 / 3.0)).collect(),
+        interpolation: Interpolation::Linear
+    };
+    Animation {
+        name: String::from("turntable"),
+        channels: vec![Channel { target, translation: None, rotation: Some(rotation), scale: None, morph_weights: Vec::new() }],
+        looping
+    }
+}
+
+impl Scene {
+    // every node's transformation relative to its parent, after every animation has run for elapsed seconds; where
+    // more than one animates the same node, the last wins
+    pub fn animated_transformations(&self, elapsed: f32) -> Vec<Transformation> {
+        let mut transformations: Vec<Transformation> = self.nodes.iter().map(|node| node.transformation).collect();
+        for animation in &self.animations {
+            let time = animation.local_time(elapsed);
+            for channel in animation.channels.iter().filter(|channel| channel.animates_transformation()) {
+                if let AnimationTarget::Node(i) = channel.target {
+                    transformations[i] = channel.sample(&self.nodes[i].transformation, time);
+                }
+            }
+        }
+        transformations
+    }
+
+    // every node's morph target weights after elapsed seconds, the same way
+    pub fn animated_morph_weights(&self, elapsed: f32) -> Vec<Vec<f32>> {
+        let mut weights: Vec<Vec<f32>> = self.nodes.iter().map(|node| node.morph_weights.clone()).collect();
+        for animation in &self.animations {
+            let time = animation.local_time(elapsed);
+            for channel in animation.channels.iter().filter(|channel| !channel.morph_weights.is_empty()) {
+                if let AnimationTarget::Node(i) = channel.target {
+                    weights[i] = channel.sample_morph_weights(time);
+                }
+            }
+        }
+        weights
+    }
+
+    // where the camera's rig is after elapsed seconds, which is the identity unless something animates it
+    pub fn camera_rig(&self, elapsed: f32) -> Transformation {
+        let mut rig = Transformation::IDENTITY;
+        for animation in &self.animations {
+            let time = animation.local_time(elapsed);
+            for channel in animation.channels.iter().filter(|channel| channel.target == AnimationTarget::Camera) {
+                rig = channel.sample(&Transformation::IDENTITY, time);
+            }
+        }
+        rig
+    }
+}
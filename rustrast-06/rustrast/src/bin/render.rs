@@ -0,0 +1,15 @@
+use std::{env, path::*, process::*};
+
+use rustrast::*;
+
+// renders each scene file given on the command line to the image it names, without opening a window
+fn main() {
+    let paths: Vec<PathBuf> = env::args_os().skip(1).map(PathBuf::from).collect();
+    if paths.is_empty() {
+        eprintln!("usage: render <scene.toml>...");
+        exit(1);
+    }
+    for path in paths {
+        render_scene_file(&path);
+    }
+}
@@ -0,0 +1,48 @@
+use std::io::*;
+
+// not-suitable-for-production .cube parsing, for colour grading; panics on any error, and only 3D LUTs are supported
+// https://resolve.cafe/developers/luts/
+//
+// the table maps display (sRGB encoded) colours to graded ones, with red changing fastest
+
+pub struct Lut {
+    pub size: usize,
+    pub domain_min: [f32; 3],
+    pub domain_max: [f32; 3],
+    // a plane per channel to suit SIMD gathers, indexed by r + g * size + b * size * size
+    pub table: [Vec<f32>; 3]
+}
+
+pub fn read_cube<R: Read>(mut file: R) -> Lut {
+    let mut text = String::new();
+    file.read_to_string(&mut text).unwrap();
+
+    let mut size = 0;
+    let mut domain_min = [0.0; 3];
+    let mut domain_max = [1.0; 3];
+    let mut table: [Vec<f32>; 3] = Default::default();
+
+    let triple = |tokens: &mut std::str::SplitAsciiWhitespace| [(); 3].map(|_| tokens.next().unwrap().parse::<f32>().unwrap());
+    for line in text.lines() {
+        let mut tokens = line.split_ascii_whitespace();
+        match tokens.next() {
+            None => {}
+            Some(comment) if comment.starts_with('#') => {}
+            Some("LUT_3D_SIZE") => size = tokens.next().unwrap().parse().unwrap(),
+            Some("LUT_1D_SIZE") => panic!("1D LUTs aren't supported"),
+            Some("DOMAIN_MIN") => domain_min = triple(&mut tokens),
+            Some("DOMAIN_MAX") => domain_max = triple(&mut tokens),
+            // TITLE, and anything else that isn't a row of the table
+            Some(keyword) if keyword.starts_with(|c: char| c.is_ascii_alphabetic()) => {}
+            Some(first) => {
+                let mut tokens = std::iter::once(first).chain(tokens);
+                for plane in table.iter_mut() {
+                    plane.push(tokens.next().unwrap().parse().unwrap());
+                }
+            }
+        }
+    }
+
+    assert!(size >= 2 && table[0].len() == size * size * size, "expected a {0}x{0}x{0} table, got {1} entries", size, table[0].len());
+    Lut { size, domain_min, domain_max, table }
+}
@@ -0,0 +1,124 @@
+use core::arch::x86_64::*;
+
+use super::transformation::*;
+use super::lighting::*;
+use super::rasterisation::*;
+use super::texture::*;
+
+// deferred shading, where triangles only write their surface into a G-buffer and each pixel is shaded once, after
+// everything in its tile has been drawn, rather than every time a nearer triangle covers it
+//
+// the G-buffer doesn't keep how texture coordinates change across the screen, so for choosing mip levels they're
+// differenced between neighbouring pixels with the same material instead, which blurs a little along texture seams
+
+// a difference where it's between pixels of the same material, otherwise the other one, otherwise zero
+#[target_feature(enable = "avx,avx2,fma")]
+#[inline]
+unsafe fn avx2_either(first: ([__m256; 2], __m256), second: ([__m256; 2], __m256)) -> [__m256; 2] {
+    let ((d0, same0), (d1, same1)) = (first, second);
+    [0, 1].map(|c| _mm256_blendv_ps(_mm256_and_ps(d1[c], same1), d0[c], same0))
+}
+
+// shades every covered pixel in a tile from the G-buffer, into a Direct or Hdr colour buffer; the G-buffer and depth
+// are laid out the same way, and inverse_camera takes the screen back to world space
+#[target_feature(enable = "avx,avx2,fma")]
+pub unsafe fn avx2_shade_tile(
+        g_buffer: &GBuffer, depth: &Buffer<f32>, colour: &mut ColourBuffer,
+        inverse_camera: &Transformation, materials: &[PreparedMaterial], lighting: &Lighting,
+        xmin: usize, ymin: usize, xmax: usize, ymax: usize) {
+    let (c_top, c_left, c_stride) = colour.layout();
+    let spans = ColourSpans::new(colour).expect("can't shade into a G-buffer");
+    debug_assert!(depth.stride % 8 == 0);
+    debug_assert!(xmin == depth.left && ymin == depth.top);
+
+    let stride = depth.stride;
+    let depths = depth.buffer.as_ptr();
+    let normals = g_buffer.normals.each_ref().map(|b| b.buffer.as_ptr());
+    let uvs = g_buffer.uvs.each_ref().map(|b| b.buffer.as_ptr());
+    let colours = g_buffer.colours.each_ref().map(|b| b.buffer.as_ptr());
+    let ids = g_buffer.materials.buffer.as_ptr();
+
+    let one = _mm256_set1_ps(1.0);
+    let zero = _mm256_setzero_ps();
+    let uncovered = _mm256_set1_epi32(-1);
+    let m = inverse_camera.matrix.map(|column| column.map(|c| _mm256_set1_ps(c)));
+    // pixel centres
+    let half_to_seven_and_a_half = _mm256_set_ps(7.5, 6.5, 5.5, 4.5, 3.5, 2.5, 1.5, 0.5);
+
+    // lanes to take from each other for a step right; the last lane only has the one to its left
+    let forward = (_mm256_setr_epi32(1, 2, 3, 4, 5, 6, 7, 7), _mm256_setr_epi32(0, 1, 2, 3, 4, 5, 6, 6));
+    let backward = (_mm256_setr_epi32(1, 1, 2, 3, 4, 5, 6, 7), _mm256_setr_epi32(0, 0, 1, 2, 3, 4, 5, 6));
+
+    // material ids where something was drawn, -1 where it wasn't
+    let keys_at = |index: usize| {
+        let covered = _mm256_castps_si256(_mm256_cmp_ps(_mm256_loadu_ps(depths.add(index)), one, _CMP_LT_OQ));
+        _mm256_blendv_epi8(uncovered, _mm256_loadu_si256(ids.add(index) as *const __m256i), covered)
+    };
+    let uvs_at = |index: usize| uvs.map(|b| _mm256_loadu_ps(b.add(index)));
+
+    for y in ymin..ymax {
+        let row = (y - ymin) * stride;
+        // rows to difference with for a step down; at the bottom of the tile that's the row above, which only gets the
+        // sign wrong, and that doesn't matter for filtering
+        let below = if y + 1 < ymax { Some(row + stride) } else if y > ymin { Some(row - stride) } else { None };
+        let above = if y > ymin { Some(row - stride) } else { below };
+        let screen_y = _mm256_set1_ps(y as f32 + 0.5);
+
+        for x in (xmin..xmax).step_by(8) {
+            let index = row + x - xmin;
+            let keys = keys_at(index);
+            let covered = _mm256_cmpgt_epi32(keys, uncovered);
+            let mut remaining = _mm256_movemask_ps(_mm256_castsi256_ps(covered));
+            if remaining == 0 {
+                continue;
+            }
+
+            // back to world space from the screen
+            let screen = [_mm256_add_ps(_mm256_set1_ps(x as f32), half_to_seven_and_a_half), screen_y, _mm256_loadu_ps(depths.add(index))];
+            let [wx, wy, wz, ww] = [0, 1, 2, 3].map(|r| _mm256_fmadd_ps(m[0][r], screen[0], _mm256_fmadd_ps(m[1][r], screen[1], _mm256_fmadd_ps(m[2][r], screen[2], m[3][r]))));
+            // which is also the view space depth, as the camera's w is
+            let iw = _mm256_div_ps(one, ww);
+            let position = [wx, wy, wz].map(|c| _mm256_mul_ps(c, iw));
+            let normal = normals.map(|b| _mm256_loadu_ps(b.add(index)));
+            let vertex_colour = colours.map(|b| _mm256_loadu_ps(b.add(index)));
+
+            let uv = uvs_at(index);
+            let horizontal = |(a, b): (__m256i, __m256i)| {
+                let same = _mm256_cmpeq_epi32(_mm256_permutevar8x32_epi32(keys, a), _mm256_permutevar8x32_epi32(keys, b));
+                (uv.map(|c| _mm256_sub_ps(_mm256_permutevar8x32_ps(c, a), _mm256_permutevar8x32_ps(c, b))), _mm256_castsi256_ps(same))
+            };
+            let vertical = |other_row: Option<usize>| match other_row {
+                Some(other_row) => {
+                    let other = other_row + x - xmin;
+                    let other_uv = uvs_at(other);
+                    ([0, 1].map(|c| _mm256_sub_ps(other_uv[c], uv[c])), _mm256_castsi256_ps(_mm256_cmpeq_epi32(keys_at(other), keys)))
+                }
+                None => ([zero; 2], zero)
+            };
+            let dx = avx2_either(horizontal(forward), horizontal(backward));
+            let dy = avx2_either(vertical(below), vertical(above));
+
+            // each material in the span is shaded in turn, masked to its pixels
+            let mut lanes = [0i32; 8];
+            _mm256_storeu_si256(lanes.as_mut_ptr() as *mut __m256i, keys);
+            let c_index = ((y - c_top) * c_stride + x - c_left) as isize;
+            while remaining != 0 {
+                let id = lanes[remaining.trailing_zeros() as usize];
+                let mask = _mm256_cmpeq_epi32(keys, _mm256_set1_epi32(id));
+                remaining &= !_mm256_movemask_ps(_mm256_castsi256_ps(mask));
+                let material = &materials[id as usize];
+
+                let mut base_colour = [0, 1, 2].map(|c| _mm256_mul_ps(_mm256_set1_ps(material.base_colour[c]), vertex_colour[c]));
+                if let Some(texture) = material.base_colour_texture {
+                    // triangles without texture coordinates just have the base colour
+                    let textured = _mm256_cmp_ps(uv[0], uv[0], _CMP_ORD_Q);
+                    let texel = avx2_sample(texture, &material.sampler, uv, dx, dy);
+                    base_colour = [0, 1, 2].map(|c| _mm256_mul_ps(base_colour[c], _mm256_blendv_ps(one, texel[c], textured)));
+                }
+                let (colour, seen) = avx2_fogged(avx2_shade(position, normal, base_colour, material, lighting), iw, position[1], lighting);
+                let ambient = spans.wants_ambient().then(|| avx2_ambient(base_colour, material, lighting).map(|c| _mm256_mul_ps(c, seen)));
+                spans.avx2_store(c_index, mask, colour, ambient);
+            }
+        }
+    }
+}
@@ -0,0 +1,279 @@
+use std::{f32::consts::PI, path::Path};
+use core::arch::x86_64::*;
+use image::DynamicImage;
+
+use super::texture::*;
+use super::transformation::*;
+use super::rasterisation::*;
+
+// not-suitable-for-production environment maps, for the skybox and reflections; panics on any error
+//
+// faces are in the usual +x, -x, +y, -y, +z, -z order, each seen from inside the cube the way OpenGL lays them out; a
+// face's texels are in rows from the top, one face after the other, and each mip level after the one before
+//
+// rougher reflections come from smaller mip levels, which are only box filtered, so are a rough stand-in for blurring
+// the environment by each roughness properly
+
+pub struct Environment {
+    // linear RGB, in a plane per channel to suit SIMD gathers
+    pub texels: [Vec<f32>; 3],
+    // where each level starts in texels and how wide its faces are
+    pub level_offsets: Vec<i32>,
+    pub level_sizes: Vec<i32>
+}
+
+impl Environment {
+    // from the faces of the full size level, all size by size, one after the other
+    pub fn new(size: usize, faces: Vec<[f32; 3]>) -> Environment {
+        assert!(faces.len() == 6 * size * size);
+        let mut environment = Environment { texels: [0, 1, 2].map(|c| faces.iter().map(|t| t[c]).collect()), level_offsets: vec![0], level_sizes: vec![size as i32] };
+
+        let mut size = size;
+        let mut offset = 0;
+        while size > 1 {
+            let next_size = size / 2;
+            for plane in environment.texels.iter_mut() {
+                let level: Vec<f32> = (0..6).flat_map(|face| {
+                    let face_start = offset + face * size * size;
+                    let plane = &plane[..];
+                    (0..next_size * next_size).map(move |i| {
+                        let (x, y) = (i % next_size * 2, i / next_size * 2);
+                        let texel = |dx: usize, dy: usize| plane[face_start + (y + dy) * size + x + dx];
+                        (texel(0, 0) + texel(1, 0) + texel(0, 1) + texel(1, 1)) * 0.25
+                    })
+                }).collect();
+                plane.extend(level);
+            }
+            offset += 6 * size * size;
+            size = next_size;
+            environment.level_offsets.push(offset as i32);
+            environment.level_sizes.push(size as i32);
+        }
+        environment
+    }
+
+    pub fn num_levels(&self) -> usize {
+        self.level_sizes.len()
+    }
+}
+
+// linear RGB from an image file; 8-bit images are taken to be sRGB encoded, and floating point ones linear already
+fn read_image(path: &Path) -> (usize, usize, Vec<[f32; 3]>) {
+    let image = image::open(path).unwrap();
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let texels = match image {
+        DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_) => image.into_rgb32f().pixels().map(|p| p.0).collect(),
+        _ => image.into_rgb8().pixels().map(|p| p.0.map(srgb_to_linear)).collect()
+    };
+    (width, height, texels)
+}
+
+// six square images of the same size
+pub fn read_cube_faces(paths: [&Path; 6]) -> Environment {
+    let faces = paths.map(read_image);
+    let size = faces[0].0;
+    assert!(faces.iter().all(|&(width, height, _)| width == size && height == size), "cube faces must be square and all the same size");
+    Environment::new(size, faces.into_iter().flat_map(|(_, _, texels)| texels).collect())
+}
+
+// an image covering every direction, with longitude across it and latitude down it; -z is in the middle and +y at the
+// top, and it's resampled into faces a quarter of its width so the texels around the middle are about the same size
+pub fn read_equirectangular(path: &Path) -> Environment {
+    let (width, height, texels) = read_image(path);
+    let size = (width / 4).max(1);
+
+    let fetch = |x: i32, y: i32| texels[y.clamp(0, height as i32 - 1) as usize * width + x.rem_euclid(width as i32) as usize];
+    let mut faces = Vec::with_capacity(6 * size * size);
+    for face in 0..6 {
+        for y in 0..size {
+            for x in 0..size {
+                let [dx, dy, dz] = face_direction(face, (x as f32 + 0.5) / size as f32, (y as f32 + 0.5) / size as f32);
+                let u = (0.5 + dx.atan2(-dz) / (2.0 * PI)) * width as f32 - 0.5;
+                let v = (dy / (dx * dx + dy * dy + dz * dz).sqrt()).clamp(-1.0, 1.0).acos() / PI * height as f32 - 0.5;
+                let (x0, y0) = (u.floor(), v.floor());
+                let (tx, ty) = (u - x0, v - y0);
+                let (x0, y0) = (x0 as i32, y0 as i32);
+                faces.push([0, 1, 2].map(|c| {
+                    let top = fetch(x0, y0)[c] + (fetch(x0 + 1, y0)[c] - fetch(x0, y0)[c]) * tx;
+                    let bottom = fetch(x0, y0 + 1)[c] + (fetch(x0 + 1, y0 + 1)[c] - fetch(x0, y0 + 1)[c]) * tx;
+                    top + (bottom - top) * ty
+                }));
+            }
+        }
+    }
+    Environment::new(size, faces)
+}
+
+// the direction through a point on a face, from 0.0 to 1.0 across and down it
+fn face_direction(face: usize, s: f32, t: f32) -> [f32; 3] {
+    let (a, b) = (s * 2.0 - 1.0, t * 2.0 - 1.0);
+    match face {
+        0 => [1.0, -b, -a],
+        1 => [-1.0, -b, a],
+        2 => [a, 1.0, b],
+        3 => [a, -1.0, -b],
+        4 => [a, -b, 1.0],
+        _ => [-a, -b, -1.0]
+    }
+}
+
+// the face a direction points through, and where on it from 0.0 to 1.0 across and down; the inverse of face_direction
+fn face_coordinates(direction: [f32; 3]) -> (usize, f32, f32) {
+    let [x, y, z] = direction;
+    let (ax, ay, az) = (x.abs(), y.abs(), z.abs());
+    let (face, s, t, major) = if ax >= ay && ax >= az {
+        if x >= 0.0 { (0, -z, -y, ax) } else { (1, z, -y, ax) }
+    }
+    else if ay >= az {
+        if y >= 0.0 { (2, x, z, ay) } else { (3, x, -z, ay) }
+    }
+    else if z >= 0.0 { (4, x, -y, az) } else { (5, -x, -y, az) };
+    let major = major.max(f32::MIN_POSITIVE);
+    (face, (s / major + 1.0) * 0.5, (t / major + 1.0) * 0.5)
+}
+
+// texels are clamped to the face rather than continuing onto the next one, so seams can show at small levels
+fn bilinear_level(environment: &Environment, level: usize, face: usize, s: f32, t: f32) -> [f32; 3] {
+    let size = environment.level_sizes[level];
+    let start = environment.level_offsets[level] + face as i32 * size * size;
+    let (u, v) = (s * size as f32 - 0.5, t * size as f32 - 0.5);
+    let (x0, y0) = (u.floor(), v.floor());
+    let (tx, ty) = (u - x0, v - y0);
+    let (x0, y0) = (x0 as i32, y0 as i32);
+    let index = |x: i32, y: i32| (start + y.clamp(0, size - 1) * size + x.clamp(0, size - 1)) as usize;
+    let [tl, tr, bl, br] = [(x0, y0), (x0 + 1, y0), (x0, y0 + 1), (x0 + 1, y0 + 1)].map(|(x, y)| index(x, y));
+    environment.texels.each_ref().map(|plane| {
+        let top = plane[tl] + (plane[tr] - plane[tl]) * tx;
+        let bottom = plane[bl] + (plane[br] - plane[bl]) * tx;
+        top + (bottom - top) * ty
+    })
+}
+
+// linear RGB seen in a direction, which needn't be normalised, blending between the levels either side of lod; the
+// same calculation as the SIMD version
+#[allow(dead_code)]
+pub fn sample_environment(environment: &Environment, direction: [f32; 3], lod: f32) -> [f32; 3] {
+    let (face, s, t) = face_coordinates(direction);
+    let lod = lod.clamp(0.0, (environment.num_levels() - 1) as f32);
+    let level = lod.floor() as usize;
+    let finer = bilinear_level(environment, level, face, s, t);
+    if level + 1 == environment.num_levels() || lod == level as f32 {
+        return finer;
+    }
+    let coarser = bilinear_level(environment, level + 1, face, s, t);
+    let blend = lod - level as f32;
+    [0, 1, 2].map(|c| finer[c] + (coarser[c] - finer[c]) * blend)
+}
+
+#[target_feature(enable = "avx,avx2,fma")]
+#[inline]
+unsafe fn avx2_face_coordinates(direction: [__m256; 3]) -> (__m256i, __m256, __m256) {
+    let sign = _mm256_set1_ps(-0.0);
+    let [x, y, z] = direction;
+    let [ax, ay, az] = direction.map(|c| _mm256_andnot_ps(sign, c));
+    let x_major = _mm256_and_ps(_mm256_cmp_ps(ax, ay, _CMP_GE_OQ), _mm256_cmp_ps(ax, az, _CMP_GE_OQ));
+    let y_major = _mm256_andnot_ps(x_major, _mm256_cmp_ps(ay, az, _CMP_GE_OQ));
+    let negative = |c: __m256| _mm256_and_ps(_mm256_cmp_ps(c, _mm256_setzero_ps(), _CMP_LT_OQ), _mm256_castsi256_ps(_mm256_set1_epi32(1)));
+
+    // the positive face, plus one for the negative one
+    let z_face = _mm256_add_epi32(_mm256_set1_epi32(4), _mm256_castps_si256(negative(z)));
+    let y_face = _mm256_add_epi32(_mm256_set1_epi32(2), _mm256_castps_si256(negative(y)));
+    let x_face = _mm256_castps_si256(negative(x));
+    let face = _mm256_castps_si256(_mm256_blendv_ps(_mm256_blendv_ps(_mm256_castsi256_ps(z_face), _mm256_castsi256_ps(y_face), y_major), _mm256_castsi256_ps(x_face), x_major));
+
+    // flipping signs to match face_coordinates
+    let flip = |c: __m256, by: __m256| _mm256_xor_ps(c, _mm256_and_ps(by, sign));
+    let minus = |c: __m256| _mm256_xor_ps(c, sign);
+    let s = _mm256_blendv_ps(_mm256_blendv_ps(flip(x, z), x, y_major), flip(minus(z), x), x_major);
+    let t = _mm256_blendv_ps(minus(y), flip(z, y), y_major);
+    let major = _mm256_blendv_ps(_mm256_blendv_ps(az, ay, y_major), ax, x_major);
+    let major = _mm256_max_ps(major, _mm256_set1_ps(f32::MIN_POSITIVE));
+
+    let half = _mm256_set1_ps(0.5);
+    let inverse_major = _mm256_div_ps(half, major);
+    (face, _mm256_fmadd_ps(s, inverse_major, half), _mm256_fmadd_ps(t, inverse_major, half))
+}
+
+#[target_feature(enable = "avx,avx2,fma")]
+#[inline]
+unsafe fn avx2_bilinear_level(environment: &Environment, level: usize, face: __m256i, s: __m256, t: __m256) -> [__m256; 3] {
+    let size = environment.level_sizes[level];
+    let size_f = _mm256_set1_ps(size as f32);
+    let half = _mm256_set1_ps(0.5);
+    let start = _mm256_add_epi32(_mm256_set1_epi32(environment.level_offsets[level]), _mm256_mullo_epi32(face, _mm256_set1_epi32(size * size)));
+    let (u, v) = (_mm256_fmsub_ps(s, size_f, half), _mm256_fmsub_ps(t, size_f, half));
+    let (x0, y0) = (_mm256_floor_ps(u), _mm256_floor_ps(v));
+    let (tx, ty) = (_mm256_sub_ps(u, x0), _mm256_sub_ps(v, y0));
+    let (x0, y0) = (_mm256_cvttps_epi32(x0), _mm256_cvttps_epi32(y0));
+
+    let one = _mm256_set1_epi32(1);
+    let last = _mm256_set1_epi32(size - 1);
+    let clamp = |i: __m256i| _mm256_min_epi32(_mm256_max_epi32(i, _mm256_setzero_si256()), last);
+    let (x0, x1, y0, y1) = (clamp(x0), clamp(_mm256_add_epi32(x0, one)), clamp(y0), clamp(_mm256_add_epi32(y0, one)));
+    let rows = [y0, y1].map(|y| _mm256_add_epi32(start, _mm256_mullo_epi32(y, _mm256_set1_epi32(size))));
+    let [tl, tr, bl, br] = [(x0, rows[0]), (x1, rows[0]), (x0, rows[1]), (x1, rows[1])].map(|(x, row)| _mm256_add_epi32(row, x));
+
+    environment.texels.each_ref().map(|plane| {
+        let fetch = |index: __m256i| _mm256_i32gather_ps(plane.as_ptr(), index, 4);
+        let (tl, tr, bl, br) = (fetch(tl), fetch(tr), fetch(bl), fetch(br));
+        let top = _mm256_fmadd_ps(_mm256_sub_ps(tr, tl), tx, tl);
+        let bottom = _mm256_fmadd_ps(_mm256_sub_ps(br, bl), tx, bl);
+        _mm256_fmadd_ps(_mm256_sub_ps(bottom, top), ty, top)
+    })
+}
+
+// linear RGB seen in 8 directions, which needn't be normalised, at the same lod
+#[target_feature(enable = "avx,avx2,fma")]
+#[inline]
+pub unsafe fn avx2_sample_environment(environment: &Environment, direction: [__m256; 3], lod: f32) -> [__m256; 3] {
+    let (face, s, t) = avx2_face_coordinates(direction);
+    let lod = lod.clamp(0.0, (environment.num_levels() - 1) as f32);
+    let level = lod.floor() as usize;
+    let finer = avx2_bilinear_level(environment, level, face, s, t);
+    if level + 1 == environment.num_levels() || lod == level as f32 {
+        return finer;
+    }
+    let coarser = avx2_bilinear_level(environment, level + 1, face, s, t);
+    let blend = _mm256_set1_ps(lod - level as f32);
+    [0, 1, 2].map(|c| _mm256_fmadd_ps(_mm256_sub_ps(coarser[c], finer[c]), blend, finer[c]))
+}
+
+// draws the environment into a tile of a Direct or Hdr colour buffer wherever nothing else was drawn, looking along
+// the ray through each pixel; inverse_camera takes the screen back to world space
+#[target_feature(enable = "avx,avx2,fma")]
+pub unsafe fn avx2_skybox_tile(
+        environment: &Environment, inverse_camera: &Transformation, depth: &Buffer<f32>, colour: &mut ColourBuffer,
+        xmin: usize, ymin: usize, xmax: usize, ymax: usize) {
+    let (c_top, c_left, c_stride) = colour.layout();
+    let spans = ColourSpans::new(colour).expect("can't draw a skybox into a G-buffer");
+    debug_assert!(xmin % 8 == 0 && xmax % 8 == 0);
+
+    let one = _mm256_set1_ps(1.0);
+    let m = inverse_camera.matrix.map(|column| column.map(|c| _mm256_set1_ps(c)));
+    let half_to_seven_and_a_half = _mm256_set_ps(7.5, 6.5, 5.5, 4.5, 3.5, 2.5, 1.5, 0.5);
+    let depths = depth.buffer.as_ptr();
+
+    for y in ymin..ymax {
+        let d_row = (y - depth.top) * depth.stride;
+        let screen_y = _mm256_set1_ps(y as f32 + 0.5);
+        for x in (xmin..xmax).step_by(8) {
+            let uncovered = _mm256_cmp_ps(_mm256_loadu_ps(depths.add(d_row + x - depth.left)), one, _CMP_GE_OQ);
+            if _mm256_movemask_ps(uncovered) == 0 {
+                continue;
+            }
+
+            // from where the pixel's ray crosses the near plane to where it crosses the far one
+            let screen_x = _mm256_add_ps(_mm256_set1_ps(x as f32), half_to_seven_and_a_half);
+            let [near, far] = [0.0, 1.0].map(|z| {
+                let [wx, wy, wz, ww] = [0, 1, 2, 3].map(|r| _mm256_fmadd_ps(m[0][r], screen_x, _mm256_fmadd_ps(m[1][r], screen_y, _mm256_fmadd_ps(m[2][r], _mm256_set1_ps(z), m[3][r]))));
+                let iw = _mm256_div_ps(one, ww);
+                [wx, wy, wz].map(|c| _mm256_mul_ps(c, iw))
+            });
+            let direction = [0, 1, 2].map(|c| _mm256_sub_ps(far[c], near[c]));
+
+            let sky = avx2_sample_environment(environment, direction, 0.0);
+            let c_index = ((y - c_top) * c_stride + x - c_left) as isize;
+            spans.avx2_store(c_index, _mm256_castps_si256(uncovered), sky, None);
+        }
+    }
+}
@@ -0,0 +1,285 @@
+use std::path::*;
+use gltf::{image::Format, mesh::Mode, animation::{Property, util::ReadOutputs}};
+
+use super::simd_vec::*;
+use super::model::*;
+use super::scene::*;
+use super::lighting::*;
+use super::transformation::*;
+use super::animation::*;
+
+// glTF 2.0 loading, from .gltf with embedded or local buffers and images, or .glb; panics on any error
+// https://registry.khronos.org/glTF/specs/2.0/glTF-2.0.html
+//
+// each glTF mesh becomes one model, with its primitives' triangles using the materials the primitives did
+
+fn texture_from_image(image: &gltf::image::Data) -> Texture {
+    let pixels = &image.pixels;
+    // 16 bit channels keep their most significant byte; floats are clamped
+    let texels = match image.format {
+        Format::R8 => pixels.iter().map(|&r| [r, r, r, 255]).collect(),
+        Format::R8G8 => pixels.chunks_exact(2).map(|p| [p[0], p[1], 0, 255]).collect(),
+        Format::R8G8B8 => pixels.chunks_exact(3).map(|p| [p[0], p[1], p[2], 255]).collect(),
+        Format::R8G8B8A8 => pixels.chunks_exact(4).map(|p| [p[0], p[1], p[2], p[3]]).collect(),
+        Format::R16 => pixels.chunks_exact(2).map(|p| [p[1], p[1], p[1], 255]).collect(),
+        Format::R16G16 => pixels.chunks_exact(4).map(|p| [p[1], p[3], 0, 255]).collect(),
+        Format::R16G16B16 => pixels.chunks_exact(6).map(|p| [p[1], p[3], p[5], 255]).collect(),
+        Format::R16G16B16A16 => pixels.chunks_exact(8).map(|p| [p[1], p[3], p[5], p[7]]).collect(),
+        Format::R32G32B32FLOAT | Format::R32G32B32A32FLOAT => {
+            let num_channels = if let Format::R32G32B32FLOAT = image.format { 3 } else { 4 };
+            pixels.chunks_exact(num_channels * 4).map(|p| {
+                let channel = |i: usize| (f32::from_le_bytes(p[i * 4..i * 4 + 4].try_into().unwrap()).clamp(0.0, 1.0) * 255.0) as u8;
+                [channel(0), channel(1), channel(2), if num_channels == 4 { channel(3) } else { 255 }]
+            }).collect()
+        }
+    };
+
+    Texture::new(image.width as usize, image.height as usize, texels)
+}
+
+fn material_from_gltf(material: &gltf::Material) -> Material {
+    let pbr = material.pbr_metallic_roughness();
+    Material {
+        name: material.name().unwrap_or("unnamed").to_string(),
+        base_colour: pbr.base_color_factor(),
+        // textures are shared between materials as images rather than glTF textures, which only add a sampler
+        base_colour_texture: pbr.base_color_texture().map(|info| info.texture().source().index()),
+        normal_texture: material.normal_texture().map(|normal| normal.texture().source().index()),
+        normal_scale: material.normal_texture().map(|normal| normal.scale()).unwrap_or(1.0),
+        metallic: pbr.metallic_factor(),
+        roughness: pbr.roughness_factor(),
+        shading: Shading::CookTorrance
+    }
+}
+
+fn model_from_mesh(mesh: &gltf::Mesh, buffers: &[gltf::buffer::Data], default_material: u32) -> Model {
+    let mut xs = SimdVec::new();
+    let mut ys = SimdVec::new();
+    let mut zs = SimdVec::new();
+    let mut normals: [Vec<f32>; 3] = Default::default();
+    let mut colours: [Vec<f32>; 3] = Default::default();
+    let mut uvs: [Vec<f32>; 2] = Default::default();
+    let mut tangents: [Vec<f32>; 4] = Default::default();
+    let mut joints: [Vec<u32>; 4] = Default::default();
+    let mut joint_weights: [Vec<f32>; 4] = Default::default();
+    // every primitive has the same number of morph targets
+    let mut morph_positions: Vec<[Vec<f32>; 3]> = Vec::new();
+    let mut morph_normals: Vec<[Vec<f32>; 3]> = Vec::new();
+    let mut trianglev0s = SimdVec::new();
+    let mut trianglev1s = SimdVec::new();
+    let mut trianglev2s = SimdVec::new();
+    let mut triangle_materials = SimdVec::new();
+
+    // the optional attributes are only kept if every primitive has them
+    let mut all_have_normals = true;
+    let mut all_have_colours = true;
+    let mut all_have_uvs = true;
+    let mut all_have_tangents = true;
+    let mut all_have_joints = true;
+
+    for primitive in mesh.primitives() {
+        if primitive.mode() != Mode::Triangles {
+            continue;
+        }
+
+        let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+        let first_vertex = xs.len() as u32;
+
+        for [x, y, z] in reader.read_positions().expect("glTF primitive without positions") {
+            xs.push(x);
+            ys.push(y);
+            zs.push(z);
+        }
+        let num_vertices = xs.len() - first_vertex as usize;
+
+        match reader.read_normals() {
+            Some(ns) => ns.for_each(|n| (0..3).for_each(|i| normals[i].push(n[i]))),
+            None => all_have_normals = false
+        }
+        match reader.read_colors(0) {
+            Some(cs) => cs.into_rgb_f32().for_each(|c| (0..3).for_each(|i| colours[i].push(c[i]))),
+            None => all_have_colours = false
+        }
+        match reader.read_tex_coords(0) {
+            Some(ts) => ts.into_f32().for_each(|t| (0..2).for_each(|i| uvs[i].push(t[i]))),
+            None => all_have_uvs = false
+        }
+        match reader.read_tangents() {
+            Some(ts) => ts.for_each(|t| (0..4).for_each(|i| tangents[i].push(t[i]))),
+            None => all_have_tangents = false
+        }
+        // only the first four influences; exporters don't always quite normalise the weights
+        match (reader.read_joints(0), reader.read_weights(0)) {
+            (Some(js), Some(ws)) => {
+                js.into_u16().for_each(|j| (0..4).for_each(|i| joints[i].push(j[i] as u32)));
+                ws.into_f32().for_each(|w| {
+                    let sum: f32 = w.iter().sum();
+                    let w = if sum > 0.0 { w.map(|w| w / sum) } else { [1.0, 0.0, 0.0, 0.0] };
+                    (0..4).for_each(|i| joint_weights[i].push(w[i]));
+                });
+            }
+            _ => all_have_joints = false
+        }
+        // sparse accessors are read as dense, so targets are made sparse again afterwards
+        for (i, (positions, normals, _)) in reader.read_morph_targets().enumerate() {
+            if i == morph_positions.len() {
+                morph_positions.push(Default::default());
+                morph_normals.push(Default::default());
+            }
+            // a target can leave out positions or normals, which then don't move
+            let deltas = |ds: Option<gltf::accessor::Iter<[f32; 3]>>, planes: &mut [Vec<f32>; 3]| match ds {
+                Some(ds) => ds.for_each(|d| (0..3).for_each(|c| planes[c].push(d[c]))),
+                None => planes.iter_mut().for_each(|plane| plane.resize(plane.len() + num_vertices, 0.0))
+            };
+            deltas(positions, &mut morph_positions[i]);
+            deltas(normals, &mut morph_normals[i]);
+        }
+
+        // unindexed primitives use each vertex once, in order
+        let indices: Vec<u32> = match reader.read_indices() {
+            Some(indices) => indices.into_u32().collect(),
+            None => (0..num_vertices as u32).collect()
+        };
+
+        let material = primitive.material().index().map(|i| i as u32).unwrap_or(default_material);
+        for triangle in indices.chunks_exact(3) {
+            trianglev0s.push(first_vertex + triangle[0]);
+            trianglev1s.push(first_vertex + triangle[1]);
+            trianglev2s.push(first_vertex + triangle[2]);
+            triangle_materials.push(material);
+        }
+    }
+
+    let ws = std::iter::repeat(1.0).take(xs.len()).collect();
+    let mut model = Model::new(xs, ys, zs, ws, trianglev0s, trianglev1s, trianglev2s);
+    model.triangle_materials = triangle_materials;
+
+    let to_simd = |vs: &Vec<f32>| vs.iter().copied().collect();
+    if all_have_normals {
+        model.vertex_normal_xs = to_simd(&normals[0]);
+        model.vertex_normal_ys = to_simd(&normals[1]);
+        model.vertex_normal_zs = to_simd(&normals[2]);
+    }
+    if all_have_colours {
+        model.vertex_colour_rs = to_simd(&colours[0]);
+        model.vertex_colour_gs = to_simd(&colours[1]);
+        model.vertex_colour_bs = to_simd(&colours[2]);
+    }
+    if all_have_uvs {
+        model.texture_us = to_simd(&uvs[0]);
+        model.texture_vs = to_simd(&uvs[1]);
+    }
+    // tangents are no use without the normals and texture coordinates they go with
+    if all_have_tangents && all_have_normals && all_have_uvs {
+        model.tangent_xs = to_simd(&tangents[0]);
+        model.tangent_ys = to_simd(&tangents[1]);
+        model.tangent_zs = to_simd(&tangents[2]);
+        model.tangent_ws = to_simd(&tangents[3]);
+    }
+    if all_have_joints {
+        model.joints = joints.each_ref().map(|js| js.iter().copied().collect());
+        model.joint_weights = joint_weights.each_ref().map(to_simd);
+    }
+    // normal deltas are dropped when the normals are, or when none of the targets have any
+    let has_normal_deltas = all_have_normals && morph_normals.iter().flatten().flatten().any(|&d| d != 0.0);
+    model.morph_targets = morph_positions.iter().zip(morph_normals.iter()).map(|(positions, normals)| MorphTarget {
+        vertices: SimdVec::new(),
+        position_deltas: positions.each_ref().map(to_simd),
+        normal_deltas: if has_normal_deltas { normals.each_ref().map(to_simd) } else { [SimdVec::new(), SimdVec::new(), SimdVec::new()] }
+    }.sparsified()).collect();
+
+    model
+}
+
+// glTF has a channel for each of a node's translation, rotation, scale and morph target weights, which are gathered
+// into one for the node
+fn animation_from_gltf(animation: &gltf::Animation, buffers: &[gltf::buffer::Data]) -> Animation {
+    let mut channels: Vec<Channel> = Vec::new();
+    for gltf_channel in animation.channels() {
+        let reader = gltf_channel.reader(|buffer| Some(&buffers[buffer.index()]));
+        let times: Vec<f32> = reader.read_inputs().unwrap().collect();
+        let interpolation = match gltf_channel.sampler().interpolation() {
+            gltf::animation::Interpolation::Step => Interpolation::Step,
+            gltf::animation::Interpolation::Linear => Interpolation::Linear,
+            gltf::animation::Interpolation::CubicSpline => Interpolation::CubicSpline
+        };
+
+        let target = AnimationTarget::Node(gltf_channel.target().node().index());
+        let channel = match channels.iter().position(|c| c.target == target) {
+            Some(i) => &mut channels[i],
+            None => {
+                channels.push(Channel { target, translation: None, rotation: None, scale: None, morph_weights: Vec::new() });
+                channels.last_mut().unwrap()
+            }
+        };
+        match (gltf_channel.target().property(), reader.read_outputs().unwrap()) {
+            (Property::Translation, ReadOutputs::Translations(values)) => channel.translation = Some(Track { times, values: values.collect(), interpolation }),
+            (Property::Rotation, ReadOutputs::Rotations(values)) => {
+                let values = values.into_f32().map(|[x, y, z, w]| Quaternion { x, y, z, w }).collect();
+                channel.rotation = Some(Track { times, values, interpolation });
+            }
+            (Property::Scale, ReadOutputs::Scales(values)) => channel.scale = Some(Track { times, values: values.collect(), interpolation }),
+            // every target's weight for a keyframe together, or their in tangents, then values, then out tangents
+            (Property::MorphTargetWeights, ReadOutputs::MorphTargetWeights(values)) => {
+                let values: Vec<f32> = values.into_f32().collect();
+                let per_keyframe = if interpolation == Interpolation::CubicSpline { 3 } else { 1 };
+                let num_targets = values.len() / (times.len() * per_keyframe).max(1);
+                channel.morph_weights = (0..num_targets).map(|target| Track {
+                    times: times.clone(),
+                    values: values.iter().skip(target).step_by(num_targets).copied().collect(),
+                    interpolation
+                }).collect();
+            }
+            _ => ()
+        }
+    }
+
+    Animation { name: animation.name().unwrap_or("unnamed").to_string(), channels, looping: Looping::Repeat }
+}
+
+pub fn read_gltf(path: &Path) -> Scene {
+    let (document, buffers, images) = gltf::import(path).unwrap();
+
+    let textures = images.iter().map(texture_from_image).collect();
+
+    // primitives without a material use the default one, which goes after all of the file's
+    let mut materials: Vec<Material> = document.materials().map(|m| material_from_gltf(&m)).collect();
+    let default_material = materials.len() as u32;
+    materials.push(Material::default());
+
+    let models = document.meshes().map(|mesh| model_from_mesh(&mesh, &buffers, default_material)).collect();
+
+    // glTF matrices are column major like ours
+    let nodes = document.nodes().map(|node| Node {
+        name: node.name().unwrap_or("unnamed").to_string(),
+        transformation: Transformation::from_columns(node.transform().matrix()),
+        children: node.children().map(|child| child.index()).collect(),
+        model: node.mesh().map(|mesh| mesh.index()),
+        skin: node.skin().map(|skin| skin.index()),
+        morph_weights: match (node.weights(), node.mesh()) {
+            (Some(weights), _) => weights.to_vec(),
+            (None, Some(mesh)) => mesh.weights().map_or_else(|| vec![0.0; mesh.primitives().next().map_or(0, |p| p.morph_targets().count())], |weights| weights.to_vec()),
+            (None, None) => Vec::new()
+        }
+    }).collect();
+
+    // without inverse bind matrices, the joints were bound where they are
+    let skins = document.skins().map(|skin| {
+        let joints: Vec<usize> = skin.joints().map(|joint| joint.index()).collect();
+        let inverse_bind_transformations = match skin.reader(|buffer| Some(&buffers[buffer.index()])).read_inverse_bind_matrices() {
+            Some(matrices) => matrices.map(Transformation::from_columns).collect(),
+            None => vec![Transformation::IDENTITY; joints.len()]
+        };
+        Skin { name: skin.name().unwrap_or("unnamed").to_string(), joints, inverse_bind_transformations }
+    }).collect();
+
+    // use the default scene if there is one, otherwise the first
+    let roots = document.default_scene().or_else(|| document.scenes().next())
+        .map(|scene| scene.nodes().map(|node| node.index()).collect())
+        .unwrap_or_default();
+
+    let animations = document.animations().map(|animation| animation_from_gltf(&animation, &buffers)).collect();
+
+    // KHR_lights_punctual isn't supported
+    Scene { models, materials, textures, nodes, skins, roots, lights: default_lights(), ambient: DEFAULT_AMBIENT, fog: None, environment: None, animations, camera: Camera::default() }
+}
@@ -1,19 +1,59 @@
-use std::{fs::*, path::*, sync::*, cell::*, slice::*, iter, array};
+use std::{fs::*, path::*, sync::*, cell::*, slice::*, mem::take, iter, array, time::Instant, hash::*};
 use windows::Win32::Graphics::Gdi::*;
 use once_cell::sync::Lazy;
 use scoped_threadpool::Pool;
 
 mod time;
 mod simd_vec;
+mod model;
 mod obj;
+mod ply;
+mod stl;
+mod triangulation;
+mod mesh;
+mod primitives;
+mod vertex_cache;
+mod model_cache;
+mod scene;
+mod gltf_file;
 mod transformation;
+mod lighting;
 mod rasterisation;
+mod tone_mapping;
+mod texture;
+mod simd_maths;
+mod ambient_occlusion;
+mod deferred;
+mod cube_file;
+mod post_processing;
+mod environment;
+mod animation;
+mod skinning;
+mod morphing;
+mod simplification;
+mod scene_file;
 
 use time::*;
 use simd_vec::*;
+use model::*;
 use obj::*;
+use model_cache::*;
+use scene::*;
+use gltf_file::*;
 use transformation::*;
+use lighting::*;
 use rasterisation::*;
+use tone_mapping::*;
+use texture::*;
+use ambient_occlusion::*;
+use deferred::*;
+use cube_file::*;
+use post_processing::*;
+use environment::*;
+use animation::*;
+use skinning::*;
+use morphing::*;
+use scene_file::*;
 
 // used by main to ensure the buffer is big enough for whatever SIMD operations we use
 pub const BACK_BUFFER_ALIGNMENT: usize = 8;
@@ -21,59 +61,487 @@ pub const BACK_BUFFER_ALIGNMENT: usize = 8;
 const TILE_WIDTH: usize = 128; // must be a multiple of BACK_BUFFER_ALIGNMENT
 const TILE_HEIGHT: usize = 128;
 
+// .obj, .ply, .stl, or .gltf/.glb for a scene with more than one model
+const MODEL_PATH: &str = "src/DinklageLikenessSculpt.obj";
+
+// time the old regex based .obj parser as well as the parallel one
+const BENCHMARK_OBJ_PARSERS: bool = false;
+// write the loaded and processed model out as .obj or .ply, for looking at in other tools
+const EXPORT_MODEL_PATH: Option<&str> = None;
+// weld duplicate vertices and remove degenerate triangles after loading
+const CLEAN_MODEL: bool = true;
+// how close vertex positions have to be to be welded, relative to the size of the model
+const WELD_TOLERANCE: f32 = 1e-6;
+// vertex normals are generated for models without them, smoothing across edges sharper than this
+const CREASE_ANGLE_DEGREES: f32 = 60.0;
+// reorder the model's triangles and vertices after loading so they're read from memory more sequentially
+const OPTIMISE_MODEL: bool = true;
+// what the average cache miss ratio is reported for; a typical value, and the one the optimisation assumes
+const ACMR_CACHE_SIZE: usize = 32;
+// simplified copies of each model for when it's small on screen, each with LOD_REDUCTION times the triangles of the
+// one before, down to MIN_LOD_TRIANGLES; the simplest one drawn still has a triangle for every LOD_PIXELS_PER_TRIANGLE
+// pixels the model covers
+const GENERATE_LODS: bool = true;
+const LOD_REDUCTION: f32 = 0.25;
+const MIN_LOD_TRIANGLES: u32 = 1000;
+const LOD_PIXELS_PER_TRIANGLE: f32 = 4.0;
+
+// draw into a linear floating point buffer that's tone mapped at the end, rather than straight into the window
+const HDR: bool = true;
+// how HDR colours are brought down to what the window can show, after multiplying by the camera's exposure
+const TONE_MAPPING: ToneMapping = ToneMapping::Aces;
+// ordered dithering when converting HDR colours to 8 bits, which hides banding in smooth gradients
+const DITHER: bool = true;
+
+// how textures are filtered; trilinear blends between mip levels, and more than one sample along the direction a
+// texture is squashed in keeps it sharp at glancing angles
+const TEXTURE_FILTER: Filter = Filter::Trilinear;
+const MAX_ANISOTROPY: u32 = 8;
+
+// screen space ambient occlusion, which needs HDR as it takes ambient light back out of the linear colours; the radius
+// it looks for occluders within is relative to the size of the scene
+const SSAO: bool = true;
+const SSAO_RADIUS: f32 = 0.06;
+
+// draw each tile's triangles into a G-buffer, then shade the pixels that are left once it's finished, rather than
+// shading every pixel of every triangle that passes the depth test
+const DEFERRED: bool = false;
+
+// fog for depth cueing, in the scene's units; neither model files nor glTF have any, so it's set here
+const FOG: Option<Fog> = None;
+
+// an environment to draw behind the scene and reflect in it, from .png, .jpg or .hdr images
+#[allow(dead_code)]
+enum EnvironmentSource {
+    // +x, -x, +y, -y, +z, -z
+    CubeFaces([&'static str; 6]),
+    Equirectangular(&'static str)
+}
+const ENVIRONMENT: Option<EnvironmentSource> = None;
+
+// seconds for the scene to turn around once, on top of any animations it has; or for the camera to orbit it instead
+const TURNTABLE_PERIOD: Option<f32> = Some(20.0);
+const TURNTABLE_CAMERA: bool = false;
+// what animations do after their last keyframe, the turntable and any the scene has; glTF doesn't say
+const ANIMATION_LOOPING: Looping = Looping::Repeat;
+
+// how skinned models follow their joints; dual quaternions keep volume where joints twist, for a little more work
+const SKINNING: Skinning = Skinning::LinearBlend;
+
+// effects on the finished frame, which main can switch on and off while running; bloom needs HDR, and spreads light
+// brighter than the threshold by up to the radius in half resolution pixels
+const FXAA: bool = true;
+const BLOOM: bool = true;
+const BLOOM_THRESHOLD: f32 = 1.0;
+const BLOOM_INTENSITY: f32 = 0.5;
+const BLOOM_RADIUS: usize = 12;
+// how much darker the corners of the window get
+const VIGNETTE: bool = true;
+const VIGNETTE_STRENGTH: f32 = 0.4;
+// a .cube file to colour grade with, if any
+const COLOUR_GRADING_LUT: Option<&str> = None;
+
 // my machine stops showing improvement above 4 threads
 static NUM_BIN_THREADS: usize = 4;
 
-// more hackery to avoid managing memory; these are all initialised based on the loaded model
+// more hackery to avoid managing memory; these are all initialised based on the models in the scene
 struct SceneBuffers {
-    model: Model,
-    rotation: Cell<f32>,
-    xs: RefCell<SimdVec<f32>>,
-    ys: RefCell<SimdVec<f32>>,
-    zs: RefCell<SimdVec<f32>>,
-    iws: RefCell<SimdVec<f32>>,
-    xmins: RefCell<SimdVec<f32>>,
-    ymins: RefCell<SimdVec<f32>>,
-    xmaxs: RefCell<SimdVec<f32>>,
-    ymaxs: RefCell<SimdVec<f32>>,
-    iareas: RefCell<SimdVec<f32>>,
-    intensities: RefCell<SimdVec<u8>>,
+    scene: Scene,
+    // animations are played from here
+    started: Instant,
+    // one for each node, as every model is transformed and binned before any tile is drawn
+    models: RefCell<Vec<ModelBuffers>>,
+    // each model's bounding sphere, for choosing its level of detail
+    model_bounds: Vec<(CartesianCoordinates, f32)>,
+    // across the scene's bounding box as it starts, for scaling the SSAO radius
+    scene_size: f32,
+    depth: RefCell<Vec<f32>>,
+    // linear RGB, laid out in tiles like the depth buffer; only used for HDR
+    hdr: RefCell<[Vec<f32>; 3]>,
+    // the ambient part of hdr, and view space depth and occlusion for the whole screen; only used for SSAO
+    ambient: RefCell<[Vec<f32>; 3]>,
+    view_zs: RefCell<Vec<f32>>,
+    occlusion: RefCell<Vec<f32>>,
+    // laid out in tiles like the depth buffer; only used for deferred shading
+    g_buffer: RefCell<GBufferPlanes>,
+    post_processing: RefCell<PostProcessing>,
+    // half resolution light for bloom, before and after blurring across
+    bloom: RefCell<[Vec<f32>; 3]>,
+    bloom_blurred: RefCell<[Vec<f32>; 3]>,
+    // what FXAA reads from while writing back into the frame
+    fxaa_source: RefCell<Vec<RGBQUAD>>
+}
+
+// a node's model this frame, sized for its most detailed level; empty for nodes without one
+struct ModelBuffers {
+    xs: SimdVec<f32>,
+    ys: SimdVec<f32>,
+    zs: SimdVec<f32>,
+    iws: SimdVec<f32>,
+    xmins: SimdVec<f32>,
+    ymins: SimdVec<f32>,
+    xmaxs: SimdVec<f32>,
+    ymaxs: SimdVec<f32>,
+    iareas: SimdVec<f32>,
+    // world space positions and normals for shading; the inverse ws aren't used
+    world_xs: SimdVec<f32>,
+    world_ys: SimdVec<f32>,
+    world_zs: SimdVec<f32>,
+    world_iws: SimdVec<f32>,
+    normal_xs: SimdVec<f32>,
+    normal_ys: SimdVec<f32>,
+    normal_zs: SimdVec<f32>,
+    tangent_xs: SimdVec<f32>,
+    tangent_ys: SimdVec<f32>,
+    tangent_zs: SimdVec<f32>,
+    // positions and normals after blending in morph targets; only sized for models that have them
+    morphed_xs: SimdVec<f32>,
+    morphed_ys: SimdVec<f32>,
+    morphed_zs: SimdVec<f32>,
+    morphed_normal_xs: SimdVec<f32>,
+    morphed_normal_ys: SimdVec<f32>,
+    morphed_normal_zs: SimdVec<f32>,
     // for each binning thread, each tile has a list of triangles
-    tile_triangles: RefCell<[Vec<Vec<u32>>; NUM_BIN_THREADS]>,
-    depth: RefCell<Vec<f32>>
+    tile_triangles: [Vec<Vec<u32>>; NUM_BIN_THREADS]
+}
+
+#[derive(Default)]
+struct GBufferPlanes {
+    normals: [Vec<f32>; 3],
+    uvs: [Vec<f32>; 2],
+    colours: [Vec<f32>; 3],
+    materials: Vec<u32>
 }
 
 static SCENE: OnceLock<Mutex<SceneBuffers>> = OnceLock::new();
 
+fn load_model(path: &Path) -> Model {
+    if BENCHMARK_OBJ_PARSERS {
+        time(format!("Parsed {} with regexes", path.display()), || read_obj_regex(File::open(path).unwrap()));
+    }
+    let mut model = time(format!("Parsed {}", path.display()), || read_model(path));
+    if CLEAN_MODEL {
+        clean_model(&mut model);
+    }
+    generate_normals(&mut model);
+    if OPTIMISE_MODEL {
+        optimise_model(&mut model);
+    }
+    if GENERATE_LODS {
+        generate_lods(&mut model);
+    }
+    model
+}
+
+fn clean_model(model: &mut Model) {
+    let epsilon = model.size() * WELD_TOLERANCE;
+    let (num_vertices, num_triangles) = time("Cleaned model", || (model.weld_vertices(epsilon), model.remove_degenerate_triangles(epsilon)));
+    println!("Welded {} vertices and removed {} degenerate triangles", num_vertices, num_triangles);
+}
+
+// shading needs vertex normals
+fn generate_normals(model: &mut Model) {
+    if model.vertex_normal_xs.len() == 0 {
+        time("Generated vertex normals", || model.generate_vertex_normals(CREASE_ANGLE_DEGREES.to_radians()));
+    }
+}
+
+// normal mapping needs tangents, which are only worth having if the model uses a normal map; cached models already
+// have their levels of detail, which need them too
+fn generate_tangents(model: &mut Model, materials: &[Material]) {
+    let normal_mapped = (0..model.num_triangles).any(|it| materials[model.triangle_material(it)].normal_texture.is_some());
+    if normal_mapped && model.tangent_xs.len() == 0 && model.texture_us.len() > 0 {
+        time("Generated tangents", || {
+            model.generate_tangents();
+            model.lods.iter_mut().filter(|lod| lod.tangent_xs.len() == 0).for_each(|lod| lod.generate_tangents());
+        });
+    }
+}
+
+// textures are averaged down differently depending on what they're used for, so sRGB colours are averaged as light
+// and normals stay unit length; a texture used for both is treated as a normal map
+fn generate_mips(scene: &mut Scene) {
+    let mut kinds = vec![TextureKind::Linear; scene.textures.len()];
+    for material in &scene.materials {
+        if let Some(i) = material.base_colour_texture {
+            kinds[i] = TextureKind::Srgb;
+        }
+    }
+    for material in &scene.materials {
+        if let Some(i) = material.normal_texture {
+            kinds[i] = TextureKind::Normal;
+        }
+    }
+    time("Generated mip maps", || scene.textures.iter_mut().zip(kinds).for_each(|(texture, kind)| texture.generate_mips(kind)));
+}
+
+// what the scene's materials need from its models and textures, which depends on the materials so isn't cached
+fn prepare_materials(scene: &mut Scene) {
+    for model in scene.models.iter_mut() {
+        generate_tangents(model, &scene.materials);
+    }
+    generate_mips(scene);
+}
+
+fn optimise_model(model: &mut Model) {
+    let acmr_before = model.acmr(ACMR_CACHE_SIZE);
+    time("Optimised model", || model.optimise_vertex_cache());
+    println!("ACMR {:.3} before optimisation, {:.3} after", acmr_before, model.acmr(ACMR_CACHE_SIZE));
+}
+
+fn generate_lods(model: &mut Model) {
+    time("Generated levels of detail", || model.generate_lods(LOD_REDUCTION, MIN_LOD_TRIANGLES));
+    println!("{} levels of detail, with {:?} triangles", model.lods.len(), model.lods.iter().map(|lod| lod.num_triangles).collect::<Vec<_>>());
+    if OPTIMISE_MODEL {
+        time("Optimised levels of detail", || model.lods.iter_mut().for_each(|lod| lod.optimise_vertex_cache()));
+    }
+}
+
+// bump whenever the .obj, .ply or .stl readers or the processing after them produce a different model from the same
+// file, as cached models are only checked against their source's length and modification time; the cache's own version
+// only covers its layout
+const LOADER_VERSION: u32 = 4;
+
+// a hash of everything that changes what load_model does, so that changing any of them invalidates cached models; the
+// standard hasher isn't guaranteed to stay the same between Rust releases, which at worst reloads a model needlessly
+fn load_flags() -> u32 {
+    let mut hasher = DefaultHasher::new();
+    (LOADER_VERSION, CLEAN_MODEL, WELD_TOLERANCE.to_bits(), CREASE_ANGLE_DEGREES.to_bits(), OPTIMISE_MODEL).hash(&mut hasher);
+    (GENERATE_LODS, LOD_REDUCTION.to_bits(), MIN_LOD_TRIANGLES).hash(&mut hasher);
+    hasher.finish() as u32
+}
+
+fn load_scene(path: &Path) -> Scene {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("gltf") | Some("glb") => {
+            let mut scene = time(format!("Loaded {}", path.display()), || read_gltf(path));
+            if CLEAN_MODEL {
+                scene.models.iter_mut().for_each(clean_model);
+            }
+            scene.models.iter_mut().for_each(generate_normals);
+            prepare_materials(&mut scene);
+            if OPTIMISE_MODEL {
+                scene.models.iter_mut().for_each(optimise_model);
+            }
+            if GENERATE_LODS {
+                scene.models.iter_mut().for_each(generate_lods);
+            }
+            scene
+        }
+        // parsing the model is by far the slowest part of starting up, so it's cached after the first run
+        _ => {
+            let model = cached_model(path, load_flags(), load_model);
+            if let Some(export_path) = EXPORT_MODEL_PATH {
+                time(format!("Wrote {}", export_path), || write_model(&model, Path::new(export_path)));
+            }
+            let mut scene = Scene::from_model(model);
+            prepare_materials(&mut scene);
+            scene
+        }
+    }
+}
+
 pub fn init() {
-    let model = read_obj(File::open(Path::new("src/DinklageLikenessSculpt.obj")).unwrap());
-    let num_vertices = model.num_vertices as usize;
-    let num_triangles = model.num_triangles as usize;
-
-    let scene = SceneBuffers {
-        model,
-        rotation: Cell::new(0.0),
-        xs: RefCell::new(iter::repeat(0f32).take(num_vertices).collect()),
-        ys: RefCell::new(iter::repeat(0f32).take(num_vertices).collect()),
-        zs: RefCell::new(iter::repeat(0f32).take(num_vertices).collect()),
-        iws: RefCell::new(iter::repeat(0f32).take(num_vertices).collect()),
-        xmins: RefCell::new(iter::repeat(0f32).take(num_triangles).collect()),
-        ymins: RefCell::new(iter::repeat(0f32).take(num_triangles).collect()),
-        xmaxs: RefCell::new(iter::repeat(0f32).take(num_triangles).collect()),
-        ymaxs: RefCell::new(iter::repeat(0f32).take(num_triangles).collect()),
-        iareas: RefCell::new(iter::repeat(0f32).take(num_triangles).collect()),
-        intensities: RefCell::new(iter::repeat(0u8).take(num_triangles).collect()),
-        tile_triangles: RefCell::new(array::from_fn(|_| Vec::new())),
-        depth: RefCell::new(Vec::new())
+    let mut scene = load_scene(Path::new(MODEL_PATH));
+    scene.fog = FOG;
+    scene.animations.iter_mut().for_each(|animation| animation.looping = ANIMATION_LOOPING);
+    if let Some(period) = TURNTABLE_PERIOD {
+        let target = if TURNTABLE_CAMERA { AnimationTarget::Camera } else { AnimationTarget::Node(scene.add_root_node("turntable")) };
+        scene.animations.push(turntable(target, period, ANIMATION_LOOPING));
+    }
+    scene.environment = ENVIRONMENT.map(|source| match source {
+        EnvironmentSource::CubeFaces(paths) => time("Loaded cube faces", || read_cube_faces(paths.map(Path::new))),
+        EnvironmentSource::Equirectangular(path) => time(format!("Loaded {}", path), || read_equirectangular(Path::new(path)))
+    });
+    let _ = SCENE.set(Mutex::new(new_scene_buffers(scene)));
+}
+
+// draws the scene from a scene file in the window instead; its output settings aren't used
+pub fn init_from_scene_file(path: &Path) {
+    let (scene, _) = load_scene_file(path);
+    let _ = SCENE.set(Mutex::new(new_scene_buffers(scene)));
+}
+
+// renders the scene file's scene at its time and resolution, and saves it where the file says; relative output paths
+// are relative to the scene file like everything else in it
+pub fn render_scene_file(path: &Path) {
+    let (scene, output) = load_scene_file(path);
+    let scene_buffers = new_scene_buffers(scene);
+
+    let (width, height) = (output.width, output.height);
+    // rows are padded the same way main pads the window's, for the SIMD code
+    let stride = width.div_ceil(BACK_BUFFER_ALIGNMENT) * BACK_BUFFER_ALIGNMENT;
+    let mut buffer = vec![RGBQUAD::default(); stride * height];
+    time("Rendered", || draw_frame(&scene_buffers, buffer.as_mut_ptr(), width, height, stride, output.time));
+
+    let image = image::RgbImage::from_fn(width as u32, height as u32, |x, y| {
+        let pixel = buffer[y as usize * stride + x as usize];
+        image::Rgb([pixel.rgbRed, pixel.rgbGreen, pixel.rgbBlue])
+    });
+    let output_path = path.parent().unwrap_or(Path::new("")).join(&output.path);
+    image.save(&output_path).unwrap();
+    println!("Saved {}", output_path.display());
+}
+
+// the scene a scene file describes, and where and how big to render it
+fn load_scene_file(path: &Path) -> (Scene, OutputDescription) {
+    let file = time(format!("Read {}", path.display()), || read_scene_file(path));
+    let directory = path.parent().unwrap_or(Path::new(""));
+
+    let mut scene = match &file.scene {
+        Some(scene_path) => load_scene(&directory.join(scene_path)),
+        None => Scene::empty()
     };
+    scene.camera = file.camera.camera();
+    if let Some(ambient) = file.ambient {
+        scene.ambient = ambient;
+    }
+    if !file.lights.is_empty() {
+        scene.lights = file.lights.iter().map(|light| light.light()).collect();
+    }
+    if let Some(environment) = &file.environment {
+        let environment_path = directory.join(environment);
+        scene.environment = Some(time(format!("Loaded {}", environment_path.display()), || read_equirectangular(&environment_path)));
+    }
+
+    let first_material = scene.materials.len();
+    scene.materials.extend(file.materials.iter().map(|material| material.material()));
+    // models that don't name a material share a default one, added only if there are any
+    let mut default_material = None;
+    for description in &file.models {
+        let material = match &description.material {
+            Some(name) => first_material + file.materials.iter().position(|m| &m.name == name).unwrap_or_else(|| panic!("No material called {}", name)),
+            None => *default_material.get_or_insert_with(|| {
+                scene.materials.push(Material::default());
+                scene.materials.len() - 1
+            })
+        };
+        let (name, model) = match (&description.path, &description.primitive) {
+            (Some(path), None) => {
+                let model = cached_model(&directory.join(path), load_flags(), load_model);
+                (path.file_stem().and_then(|stem| stem.to_str()).unwrap_or("model"), model)
+            }
+            (None, Some(primitive)) => (primitive.name(), primitive.model()),
+            _ => panic!("Models need either a path or a primitive")
+        };
+        scene.add_model(name, model, material, description.transformation());
+    }
 
-    let _ = SCENE.set(Mutex::new(scene));
+    let looping = file.looping.map_or(ANIMATION_LOOPING, |looping| looping.looping());
+    scene.animations.iter_mut().for_each(|animation| animation.looping = looping);
+    if let Some(period) = file.turntable_period {
+        let target = AnimationTarget::Node(scene.add_root_node("turntable"));
+        scene.animations.push(turntable(target, period, looping));
+    }
+    (scene, file.output)
+}
+
+fn new_model_buffers(model: Option<&Model>) -> ModelBuffers {
+    let num_vertices = model.map_or(0, |m| m.num_vertices as usize);
+    let num_triangles = model.map_or(0, |m| m.num_triangles as usize);
+    let num_morphed = model.filter(|m| !m.morph_targets.is_empty()).map_or(0, |m| m.num_vertices as usize);
+    let zeroed = |len: usize| -> SimdVec<f32> { iter::repeat(0f32).take(len).collect() };
+
+    ModelBuffers {
+        xs: zeroed(num_vertices),
+        ys: zeroed(num_vertices),
+        zs: zeroed(num_vertices),
+        iws: zeroed(num_vertices),
+        xmins: zeroed(num_triangles),
+        ymins: zeroed(num_triangles),
+        xmaxs: zeroed(num_triangles),
+        ymaxs: zeroed(num_triangles),
+        iareas: zeroed(num_triangles),
+        world_xs: zeroed(num_vertices),
+        world_ys: zeroed(num_vertices),
+        world_zs: zeroed(num_vertices),
+        world_iws: zeroed(num_vertices),
+        normal_xs: zeroed(num_vertices),
+        normal_ys: zeroed(num_vertices),
+        normal_zs: zeroed(num_vertices),
+        tangent_xs: zeroed(num_vertices),
+        tangent_ys: zeroed(num_vertices),
+        tangent_zs: zeroed(num_vertices),
+        morphed_xs: zeroed(num_morphed),
+        morphed_ys: zeroed(num_morphed),
+        morphed_zs: zeroed(num_morphed),
+        morphed_normal_xs: zeroed(num_morphed),
+        morphed_normal_ys: zeroed(num_morphed),
+        morphed_normal_zs: zeroed(num_morphed),
+        tile_triangles: array::from_fn(|_| Vec::new())
+    }
+}
+
+fn new_scene_buffers(scene: Scene) -> SceneBuffers {
+    let models = scene.nodes.iter().map(|node| new_model_buffers(node.model.map(|i_model| &scene.models[i_model]))).collect();
+    let model_bounds: Vec<_> = scene.models.iter().map(|m| m.bounding_sphere()).collect();
+    let scene_size = scene_size(&scene, &model_bounds);
+
+    SceneBuffers {
+        scene,
+        started: Instant::now(),
+        models: RefCell::new(models),
+        model_bounds,
+        scene_size,
+        depth: RefCell::new(Vec::new()),
+        hdr: RefCell::new(array::from_fn(|_| Vec::new())),
+        ambient: RefCell::new(array::from_fn(|_| Vec::new())),
+        view_zs: RefCell::new(Vec::new()),
+        occlusion: RefCell::new(Vec::new()),
+        g_buffer: RefCell::new(GBufferPlanes::default()),
+        post_processing: RefCell::new(PostProcessing {
+            fxaa: FXAA,
+            bloom: BLOOM,
+            vignette: VIGNETTE,
+            colour_grading: COLOUR_GRADING_LUT.is_some(),
+            lut: COLOUR_GRADING_LUT.map(|path| load_lut(Path::new(path)))
+        }),
+        bloom: RefCell::new(array::from_fn(|_| Vec::new())),
+        bloom_blurred: RefCell::new(array::from_fn(|_| Vec::new())),
+        fxaa_source: RefCell::new(Vec::new())
+    }
 }
 
 fn scene_buffers() -> &'static Mutex<SceneBuffers> {
     SCENE.get().unwrap()
 }
 
+fn load_lut(path: &Path) -> Lut {
+    time(format!("Loaded {}", path.display()), || read_cube(File::open(path).unwrap()))
+}
+
+// post-processing effects that can be switched on and off while running
+#[derive(Clone, Copy, Debug)]
+pub enum PostEffect {
+    Fxaa,
+    Bloom,
+    Vignette,
+    ColourGrading
+}
+
+// switches an effect on or off, returning whether it's now on
+pub fn toggle_post_effect(effect: PostEffect) -> bool {
+    let scene_buffers = scene_buffers().lock().unwrap();
+    let post_processing = &mut *scene_buffers.post_processing.borrow_mut();
+    let enabled = match effect {
+        PostEffect::Fxaa => &mut post_processing.fxaa,
+        PostEffect::Bloom => &mut post_processing.bloom,
+        PostEffect::Vignette => &mut post_processing.vignette,
+        PostEffect::ColourGrading => &mut post_processing.colour_grading
+    };
+    *enabled = !*enabled;
+    *enabled
+}
+
+// colour grades with a LUT from a .cube file from now on
+pub fn load_colour_grading_lut(path: &Path) {
+    let lut = load_lut(path);
+    let scene_buffers = scene_buffers().lock().unwrap();
+    let post_processing = &mut *scene_buffers.post_processing.borrow_mut();
+    post_processing.lut = Some(lut);
+    post_processing.colour_grading = true;
+}
+
 static BIN_WORKERS: Lazy<Mutex<Pool>> = Lazy::new(|| Mutex::new(Pool::new(NUM_BIN_THREADS as u32)));
 
 fn bin_triangles(tile_triangles_out: &mut [Vec<Vec<u32>>; NUM_BIN_THREADS], num_triangles: u32, bounds: [&SimdVec<f32>; 5], num_tiles: usize, num_tiles_x: usize) {
@@ -144,7 +612,9 @@ fn bin_triangles(tile_triangles_out: &mut [Vec<Vec<u32>>; NUM_BIN_THREADS], num_
 
 // enables bypassing safeness checks when multithreading
 struct Tile<'a> {
-    colour: Buffer<'a, RGBQUAD>,
+    colour: ColourBuffer<'a>,
+    // where deferred shading goes once every model has been drawn in the tile
+    output: Option<ColourBuffer<'a>>,
     depth: Buffer<'a, f32>,
     xmin: usize,
     ymin: usize,
@@ -156,7 +626,24 @@ struct Tile<'a> {
 static NUM_DRAW_THREADS: u32 = 4;
 static DRAW_WORKERS: Lazy<Mutex<Pool>> = Lazy::new(|| Mutex::new(Pool::new(NUM_DRAW_THREADS)));
 
-fn draw_tile(tile: &mut Tile, model: &Model, xs: &SimdVec<f32>, ys: &SimdVec<f32>, zs: &SimdVec<f32>, iws: &SimdVec<f32>, bounds: [&SimdVec<f32>; 5], intensities: &SimdVec<u8>, triangles: [&Vec<u32>; NUM_BIN_THREADS]) {
+// a model that's been transformed and binned, ready for the tiles to draw
+#[derive(Clone, Copy)]
+struct DrawnModel<'a> {
+    model: &'a Model,
+    buffers: &'a ModelBuffers,
+    // mirroring flips the bitangent; skinning doesn't keep track of it
+    handedness: f32
+}
+
+fn draw_tile(tile: &mut Tile, drawn: DrawnModel, i_tile: usize, materials: &[PreparedMaterial], lighting: &Lighting) {
+    let DrawnModel { model, buffers, handedness } = drawn;
+    let (xs, ys, zs, iws) = (&buffers.xs, &buffers.ys, &buffers.zs, &buffers.iws);
+    let bounds = [&buffers.xmins, &buffers.ymins, &buffers.xmaxs, &buffers.ymaxs, &buffers.iareas];
+    let world_positions = [&buffers.world_xs, &buffers.world_ys, &buffers.world_zs];
+    let normals = [&buffers.normal_xs, &buffers.normal_ys, &buffers.normal_zs];
+    let tangents = (model.tangent_xs.len() > 0).then_some(([&buffers.tangent_xs, &buffers.tangent_ys, &buffers.tangent_zs], handedness));
+    let triangles: [&Vec<u32>; NUM_BIN_THREADS] = array::from_fn(|i| &buffers.tile_triangles[i][i_tile]);
+
     let tile_xmin = tile.xmin as f32;
     let tile_ymin = tile.ymin as f32;
     let tile_xmax = tile.xmax as f32;
@@ -195,160 +682,537 @@ fn draw_tile(tile: &mut Tile, model: &Model, xs: &SimdVec<f32>, ys: &SimdVec<f32
             let z2 = zs[model.trianglev2s[it] as usize];
             let iw2 = iws[model.trianglev2s[it] as usize];
 
-            let intensity = intensities[it];
-            let colour = RGBQUAD {rgbRed: intensity, rgbGreen: intensity, rgbBlue: intensity, rgbReserved: 0};
-            
-            fill_triangle(&mut tile.colour, &mut tile.depth, xmin, ymin, xmax, ymax, x0, y0, z0, iw0, x1, y1, z1, iw1, x2, y2, z2, iw2, iarea, colour);
+            let vs = [model.trianglev0s[it], model.trianglev1s[it], model.trianglev2s[it]].map(|v| v as usize);
+            let material = &materials[model.triangle_material(it as u32)];
+            let normal_mapped = material.normal_map.is_some() && tangents.is_some();
+            let textured = material.base_colour_texture.is_some() && model.texture_us.len() > 0;
+            let varyings = Varyings {
+                positions: vs.map(|v| world_positions.map(|ps| ps[v])),
+                normals: vs.map(|v| normals.map(|ns| ns[v])),
+                uvs: (textured || normal_mapped).then(|| vs.map(|v| [model.texture_us[v], model.texture_vs[v]])),
+                tangents: tangents.filter(|_| normal_mapped).map(|(ts, handedness)| vs.map(|v| [ts[0][v], ts[1][v], ts[2][v], model.tangent_ws[v] * handedness])),
+                colours: (model.vertex_colour_rs.len() > 0).then(|| vs.map(|v| [model.vertex_colour_rs[v], model.vertex_colour_gs[v], model.vertex_colour_bs[v]]))
+            };
+
+            fill_triangle(&mut tile.colour, &mut tile.depth, xmin, ymin, xmax, ymax, x0, y0, z0, iw0, x1, y1, z1, iw1, x2, y2, z2, iw2, iarea, &varyings, material, lighting);
         }
     }
 }
 
-const ROTATION_STEP: f32 = 0.005;
-const ROTATION_MAX: f32 = std::f32::consts::TAU;
-
 pub fn draw(buffer: *mut RGBQUAD, width: usize, height: usize, stride: usize) {
-    let scene = scene_buffers().lock().unwrap();
-    let model = &scene.model;
-
-    // animate by rotating the model
-    let rotation = scene.rotation.get();
-    let world = Transformation::rotate_y(rotation);
-    scene.rotation.set((rotation + ROTATION_STEP) % ROTATION_MAX);
-    
-    // place the camera above the model's head and look down 30 degrees
-    let eye = CartesianCoordinates {x: 0.0, y: 1.0, z: 2.0};
-    let centre = CartesianCoordinates {x: 0.0, y: 0.0, z: 0.0};
-    let up = CartesianVector {x: 0.0, y: 1.0, z: -0.5};
+    let scene_buffers = scene_buffers().lock().unwrap();
+    let elapsed = scene_buffers.started.elapsed().as_secs_f32();
+    draw_frame(&scene_buffers, buffer, width, height, stride, elapsed);
+}
+
+// the scene as it is elapsed seconds into its animations
+fn draw_frame(scene_buffers: &SceneBuffers, buffer: *mut RGBQUAD, width: usize, height: usize, stride: usize, elapsed: f32) {
+    let scene = &scene_buffers.scene;
+
+    // place the scene's camera relative to its rig
+    let rig = scene.camera_rig(elapsed);
+    let eye = scene.camera.eye.to_homogenous().transformed(&rig).to_cartesian().0;
+    let centre = scene.camera.target.to_homogenous().transformed(&rig).to_cartesian().0;
+    let up = scene.camera.up.transformed(&rig.tl_3x3());
     let view = Transformation::look_at_rh(&eye, &centre, &up);
 
-    // make the canonical view volume big enough to hold the model and a bit more
     let aspect = height as f32 / width as f32;
-    let view_volume_width = 0.4;
+    let view_volume_width = scene.camera.view_width;
     let view_volume_height = view_volume_width * aspect;
-    let near = 2.0;
-    let far = near + 0.5;
+    let near = scene.camera.near;
+    let far = scene.camera.far;
     let projection = Transformation::perspective_rh(view_volume_width, view_volume_height, near, far);
 
     let viewport = Transformation::viewport(0, 0, width, height);
 
-    let t = world.then(&view).then(&projection).then(&viewport);
+    let camera = view.then(&projection).then(&viewport);
+    let pixels_per_unit = near * width as f32 / view_volume_width;
 
-    // one distant light source, coming from top right behind the camera
-    let light_direction = CartesianVector {x: 1.0, y: 1.0, z: 1.0}.normalised();
-    // to transform surface normals
-    let it_world = world.inverted_transposed_tl_3x3().unwrap();
-    let gamma = 2.2;
+    let lighting = Lighting::new(eye, &scene.lights, scene.ambient, scene.fog.as_ref(), scene.environment.as_ref());
+    let sampler = Sampler { filter: TEXTURE_FILTER, max_anisotropy: MAX_ANISOTROPY };
+    let materials: Vec<PreparedMaterial> = scene.materials.iter().enumerate().map(|(i, m)| prepare_material(i as u32, m, &scene.textures, sampler)).collect();
 
-    let num_vertices = model.num_vertices;
-    let num_triangles = model.num_triangles;
+    let depth = &mut *scene_buffers.depth.borrow_mut();
+    time("Cleared depth buffer", || reset_buffer(depth, stride * height, 1.0));
 
-    {
-        let xs_out = &mut *scene.xs.borrow_mut();
-        let ys_out = &mut *scene.ys.borrow_mut();
-        let zs_out = &mut *scene.zs.borrow_mut();
-        let iws_out = &mut *scene.iws.borrow_mut();
-        time(format!("Transformed {} vertices", num_vertices), || {
-            avx2_transformed_to_cartesian(xs_out, ys_out, zs_out, iws_out, model, &t)
-        });
+    let hdr = &mut *scene_buffers.hdr.borrow_mut();
+    if HDR {
+        time("Cleared HDR buffer", || hdr.iter_mut().for_each(|buffer| reset_buffer(buffer, stride * height, 0.0)));
     }
-    let xs = &*scene.xs.borrow();
-    let ys = &*scene.ys.borrow();
-    let zs = &*scene.zs.borrow();
-    let iws = &*scene.iws.borrow();
-
-    time(format!("Lit {} triangles", num_triangles), || {
-        let intensities_out = &mut *scene.intensities.borrow_mut();
-        for i in 0..num_triangles as usize {
-            let surface_normal = model.surface_normal(i as u32).transformed(&it_world).normalised();
-            let diffuse = surface_normal.dot_product(&light_direction).max(0.0) * 0.3;
-            let ambient = 0.05;
-            let intensity = ((diffuse + ambient).min(1.0).powf(1.0 / gamma) * 255.0) as u8;
-            intensities_out[i] = intensity;
-        }
+    let ambient = &mut *scene_buffers.ambient.borrow_mut();
+    if HDR && SSAO {
+        time("Cleared ambient buffer", || ambient.iter_mut().for_each(|buffer| reset_buffer(buffer, stride * height, 0.0)));
+    }
+    // the depth buffer says which pixels were drawn, so the G-buffer doesn't need clearing
+    let g_buffer = &mut *scene_buffers.g_buffer.borrow_mut();
+    let inverse_camera = DEFERRED.then(|| {
+        g_buffer.normals.iter_mut().chain(g_buffer.uvs.iter_mut()).chain(g_buffer.colours.iter_mut()).for_each(|buffer| buffer.resize(stride * height, 0.0));
+        g_buffer.materials.resize(stride * height, 0);
+        camera.inverted().unwrap()
     });
-    let intensities = &*scene.intensities.borrow();
-
-    {
-        let xmins_out = &mut *scene.xmins.borrow_mut();
-        let ymins_out = &mut *scene.ymins.borrow_mut();
-        let xmaxs_out = &mut *scene.xmaxs.borrow_mut();
-        let ymaxs_out = &mut *scene.ymaxs.borrow_mut();
-        let iareas_out = &mut *scene.iareas.borrow_mut();
-        time(format!("Calculated {} bounding boxes", num_triangles), || {
-            calculate_all_bounds(xmins_out, ymins_out,xmaxs_out, ymaxs_out, iareas_out, model, xs, ys, 0.0, 0.0, width as f32, height as f32)
-        });
+
+    // every model is transformed and binned first, so that each tile can draw all of them and then be shaded while
+    // its part of the G-buffer is still in cache
+    let worlds = scene.world_transformations(&scene.animated_transformations(elapsed));
+    let morph_weights = scene.animated_morph_weights(elapsed);
+    let model_buffers = &mut *scene_buffers.models.borrow_mut();
+    let mut drawn = Vec::new();
+    for (i_node, ((node, world), buffers)) in scene.nodes.iter().zip(worlds.iter()).zip(model_buffers.iter_mut()).enumerate() {
+        if let Some(i_model) = node.model {
+            let model = level_of_detail(&scene.models[i_model], scene_buffers.model_bounds[i_model], &world.then(&view), pixels_per_unit);
+            let skin = node.skin.map(|i_skin| &scene.skins[i_skin]).filter(|skin| !skin.joints.is_empty() && model.joints[0].len() > 0);
+            let palette = skin.map(|skin| joint_palette(skin, &worlds, SKINNING));
+            let weights = Some(&morph_weights[i_node][..]).filter(|weights| !model.morph_targets.is_empty() && weights.iter().any(|&w| w != 0.0));
+            prepare_model(buffers, model, world, weights, palette.as_ref(), &camera, width, height, stride);
+            let handedness = if palette.is_some() { 1.0 } else { world.tl_3x3_determinant().signum() };
+            drawn.push((i_node, model, handedness));
+        }
     }
-    let bounds = [&*scene.xmins.borrow(), &*scene.ymins.borrow(), &*scene.xmaxs.borrow(), &*scene.ymaxs.borrow(), &*scene.iareas.borrow()];
+    let models: Vec<DrawnModel> = drawn.into_iter().map(|(i_node, model, handedness)| DrawnModel { model, buffers: &model_buffers[i_node], handedness }).collect();
 
-    let num_tiles_x = (stride + TILE_WIDTH - 1) / TILE_WIDTH;
-    let num_tiles = num_tiles_x * ((height + TILE_HEIGHT - 1) / TILE_HEIGHT);
+    let description = if DEFERRED { "Filled and shaded triangles" } else { "Filled triangles" };
+    time(description, || draw_tiles(&models, &materials, &lighting, inverse_camera.as_ref(), buffer, depth, hdr, ambient, g_buffer, height, stride));
+
+    if let Some(environment) = &scene.environment {
+        let inverse_camera = camera.inverted().unwrap();
+        time("Drew skybox", || draw_skybox(environment, &inverse_camera, buffer, depth, hdr, height, stride));
+    }
+
+    if HDR && SSAO {
+        let view_zs = &mut *scene_buffers.view_zs.borrow_mut();
+        let occlusion = &mut *scene_buffers.occlusion.borrow_mut();
+        let projection = Projection::new(&projection, width, height);
+        let radius = SSAO_RADIUS * scene_buffers.scene_size;
+        time("Applied ambient occlusion", || apply_ambient_occlusion(depth, view_zs, occlusion, hdr, ambient, &projection, radius, height, stride));
+    }
 
-    {
-        let tile_triangles_out = &mut *scene.tile_triangles.borrow_mut();
-        time(format!("Binned {} triangles", num_triangles), || {
-            bin_triangles(tile_triangles_out, num_triangles, bounds, num_tiles, num_tiles_x);
+    let post_processing = &*scene_buffers.post_processing.borrow();
+    if HDR && post_processing.bloom {
+        let bright = &mut *scene_buffers.bloom.borrow_mut();
+        let blurred = &mut *scene_buffers.bloom_blurred.borrow_mut();
+        time("Applied bloom", || apply_bloom(hdr, bright, blurred, height, stride));
+    }
+
+    if HDR {
+        time("Tone mapped", || resolve_hdr(hdr, buffer, scene.camera.exposure, height, stride));
+    }
+
+    let lut = post_processing.lut.as_ref().filter(|_| post_processing.colour_grading);
+    if lut.is_some() || post_processing.vignette {
+        let vignette = post_processing.vignette.then_some(VIGNETTE_STRENGTH);
+        time("Graded colour", || grade(buffer, lut, vignette, width, height, stride));
+    }
+
+    if post_processing.fxaa {
+        let source = &mut *scene_buffers.fxaa_source.borrow_mut();
+        time("Applied FXAA", || apply_fxaa(buffer, source, width, height, stride));
+    }
+}
+
+// the simplest of the model's levels of detail with enough triangles for its size on screen, given how many pixels
+// across a unit is at a distance of one; skinned models are judged by their bind pose
+fn level_of_detail<'a>(model: &'a Model, bounds: (CartesianCoordinates, f32), world_view: &Transformation, pixels_per_unit: f32) -> &'a Model {
+    let (centre, radius) = bounds;
+    let distance = -centre.to_homogenous().transformed(world_view).to_cartesian().0.z;
+    let radius = radius * longest_axis(world_view);
+    if distance <= radius {
+        return model;
+    }
+
+    // about half of a model's triangles face the camera, spread over its silhouette
+    let pixels = radius * pixels_per_unit / distance;
+    let wanted = 2.0 * std::f32::consts::PI * pixels * pixels / LOD_PIXELS_PER_TRIANGLE;
+    iter::once(model).chain(model.lods.iter()).take_while(|lod| lod.num_triangles as f32 >= wanted).last().unwrap_or(model)
+}
+
+// transformations can scale models, so bounding spheres are scaled by their longest axis
+fn longest_axis(transformation: &Transformation) -> f32 {
+    transformation.tl_3x3().iter().map(|c| (c[0] * c[0] + c[1] * c[1] + c[2] * c[2]).sqrt()).fold(0.0, f32::max)
+}
+
+// the diagonal of the box around every node's model's bounding sphere, with the nodes where they are before any
+// animation starts
+fn scene_size(scene: &Scene, model_bounds: &[(CartesianCoordinates, f32)]) -> f32 {
+    let worlds = scene.world_transformations(&scene.animated_transformations(0.0));
+    let mut min = [f32::INFINITY; 3];
+    let mut max = [f32::NEG_INFINITY; 3];
+    for (node, world) in scene.nodes.iter().zip(worlds.iter()) {
+        if let Some(i_model) = node.model {
+            let (centre, radius) = model_bounds[i_model];
+            let centre = centre.to_homogenous().transformed(world).to_cartesian().0;
+            let radius = radius * longest_axis(world);
+            for (c, value) in [centre.x, centre.y, centre.z].into_iter().enumerate() {
+                min[c] = min[c].min(value - radius);
+                max[c] = max[c].max(value + radius);
+            }
+        }
+    }
+    if min[0] > max[0] {
+        return 0.0;
+    }
+    (0..3).map(|c| (max[c] - min[c]) * (max[c] - min[c])).sum::<f32>().sqrt()
+}
+
+// sizes the buffer to len, filled with value; this should only allocate when the window gets bigger
+fn reset_buffer(buffer: &mut Vec<f32>, len: usize, value: f32) {
+    if buffer.len() > len {
+        buffer.truncate(len);
+        buffer.fill(value);
+    }
+    else {
+        buffer.fill(value);
+        if buffer.len() < len {
+            buffer.reserve_exact(len - buffer.len());
+            buffer.extend(iter::repeat(value).take(len - buffer.len()));
+        }
+    }
+}
+
+fn resolve_hdr(hdr: &mut [Vec<f32>; 3], buffer: *mut RGBQUAD, exposure: f32, height: usize, stride: usize) {
+    let mut pool = DRAW_WORKERS.lock().unwrap();
+    pool.scoped(|scope| {
+        let mut hdr = hdr.each_mut().map(|b| &mut b[..]);
+        let mut ymin = 0;
+        while ymin < height {
+            let mut xmin = 0;
+            while xmin < stride {
+                let tile_width = TILE_WIDTH.min(stride - xmin);
+                let len = tile_width * TILE_HEIGHT.min(height - ymin);
+                let tile_hdr = hdr.each_mut().map(|b| {
+                    let (tile, rem) = take(b).split_at_mut(len);
+                    *b = rem;
+                    Buffer { buffer: tile, left: xmin, top: ymin, stride: tile_width }
+                });
+                let mut colour = Buffer {
+                    buffer: unsafe { from_raw_parts_mut(buffer, stride * height) },
+                    left: 0,
+                    top: 0,
+                    stride
+                };
+                let (xmax, ymax) = (xmin + tile_width, (ymin + TILE_HEIGHT).min(height));
+
+                scope.execute(move || unsafe {
+                    avx2_resolve_tile(&tile_hdr, &mut colour, xmin, ymin, xmax, ymax, TONE_MAPPING, exposure, DITHER);
+                });
+
+                xmin += TILE_WIDTH;
+            }
+
+            ymin += TILE_HEIGHT;
+        }
+    });
+}
+
+// the next tile's part of each of the planes, leaving the rest of them
+fn split_tile<'a, T, const N: usize>(planes: &mut [&'a mut [T]; N], tile_len: usize, xmin: usize, ymin: usize, tile_width: usize) -> [Buffer<'a, T>; N] {
+    planes.each_mut().map(|b| {
+        let (tile_plane, rem_plane) = take(b).split_at_mut(tile_len);
+        *b = rem_plane;
+        Buffer { buffer: tile_plane, left: xmin, top: ymin, stride: tile_width }
+    })
+}
+
+// the bounds of each tile, and where it starts in buffers laid out in tiles like the depth buffer
+fn tiles(height: usize, stride: usize) -> impl Iterator<Item = (usize, usize, usize, usize, usize)> {
+    (0..height).step_by(TILE_HEIGHT)
+        .flat_map(move |ymin| (0..stride).step_by(TILE_WIDTH).map(move |xmin| (xmin, ymin)))
+        .scan(0, move |offset, (xmin, ymin)| {
+            let (xmax, ymax) = ((xmin + TILE_WIDTH).min(stride), (ymin + TILE_HEIGHT).min(height));
+            let start = *offset;
+            *offset += (xmax - xmin) * (ymax - ymin);
+            Some((xmin, ymin, xmax, ymax, start))
+        })
+}
+
+// a tile's part of a buffer laid out in tiles, given where it starts
+fn tile_buffer<'a, T>(planes: &mut [T], xmin: usize, ymin: usize, xmax: usize, ymax: usize, start: usize) -> Buffer<'a, T> {
+    let buffer = unsafe { from_raw_parts_mut(planes.as_mut_ptr().add(start), (xmax - xmin) * (ymax - ymin)) };
+    Buffer { buffer, left: xmin, top: ymin, stride: xmax - xmin }
+}
+
+// wherever the depth buffer is still clear
+fn draw_skybox(environment: &Environment, inverse_camera: &Transformation, buffer: *mut RGBQUAD, depth: &mut [f32], hdr: &mut [Vec<f32>; 3], height: usize, stride: usize) {
+    let mut pool = DRAW_WORKERS.lock().unwrap();
+    pool.scoped(|scope| {
+        for (xmin, ymin, xmax, ymax, start) in tiles(height, stride) {
+            let tile_depth = tile_buffer(depth, xmin, ymin, xmax, ymax, start);
+            let mut colour = if HDR {
+                ColourBuffer::Hdr(hdr.each_mut().map(|b| tile_buffer(b, xmin, ymin, xmax, ymax, start)), None)
+            }
+            else {
+                ColourBuffer::Direct(Buffer { buffer: unsafe { from_raw_parts_mut(buffer, stride * height) }, left: 0, top: 0, stride })
+            };
+            scope.execute(move || unsafe {
+                avx2_skybox_tile(environment, inverse_camera, &tile_depth, &mut colour, xmin, ymin, xmax, ymax);
+            });
+        }
+    });
+}
+
+// three passes over the tiles, as each needs its neighbours' results from the pass before
+fn apply_ambient_occlusion(depth: &mut [f32], view_zs: &mut Vec<f32>, occlusion: &mut Vec<f32>, hdr: &mut [Vec<f32>; 3], ambient: &mut [Vec<f32>; 3], projection: &Projection, radius: f32, height: usize, stride: usize) {
+    let padded_stride = stride + 2 * PADDING;
+    reset_buffer(view_zs, padded_stride * height, f32::NEG_INFINITY);
+    reset_buffer(occlusion, padded_stride * height, -1.0);
+
+    let mut pool = DRAW_WORKERS.lock().unwrap();
+    pool.scoped(|scope| {
+        for (xmin, ymin, xmax, ymax, start) in tiles(height, stride) {
+            let tile_depth = tile_buffer(depth, xmin, ymin, xmax, ymax, start);
+            let view_zs = unsafe { from_raw_parts_mut(view_zs.as_mut_ptr(), view_zs.len()) };
+            scope.execute(move || unsafe {
+                avx2_linearise_tile(&tile_depth, view_zs, padded_stride, projection, xmin, ymin, xmax, ymax);
+            });
+        }
+    });
+
+    let view_zs = &view_zs[..];
+    pool.scoped(|scope| {
+        for (xmin, ymin, xmax, ymax, _) in tiles(height, stride) {
+            let occlusion = unsafe { from_raw_parts_mut(occlusion.as_mut_ptr(), occlusion.len()) };
+            scope.execute(move || unsafe {
+                avx2_occlusion_tile(view_zs, occlusion, padded_stride, stride, height, projection, radius, xmin, ymin, xmax, ymax);
+            });
+        }
+    });
+
+    let occlusion = &occlusion[..];
+    pool.scoped(|scope| {
+        for (xmin, ymin, xmax, ymax, start) in tiles(height, stride) {
+            let mut tile_hdr = hdr.each_mut().map(|b| tile_buffer(b, xmin, ymin, xmax, ymax, start));
+            let tile_ambient = ambient.each_mut().map(|b| tile_buffer(b, xmin, ymin, xmax, ymax, start));
+            scope.execute(move || unsafe {
+                avx2_apply_occlusion_tile(occlusion, padded_stride, height, &mut tile_hdr, &tile_ambient, xmin, ymin, xmax, ymax);
+            });
+        }
+    });
+}
+
+// thresholds the HDR target down to half resolution, blurs that across then down, and adds it back on
+fn apply_bloom(hdr: &mut [Vec<f32>; 3], bright: &mut [Vec<f32>; 3], blurred: &mut [Vec<f32>; 3], height: usize, stride: usize) {
+    let (half_stride, half_height) = bloom_layout(stride, height);
+    bright.iter_mut().chain(blurred.iter_mut()).for_each(|buffer| reset_buffer(buffer, half_stride * half_height, 0.0));
+    let weights = &bloom_weights(BLOOM_RADIUS)[..];
+    // each tile's part of the half resolution buffers
+    let half_tiles = || tiles(height, stride).map(|(xmin, ymin, xmax, ymax, _)| (xmin / 2, ymin / 2, xmax / 2, ymax.div_ceil(2)));
+
+    let mut pool = DRAW_WORKERS.lock().unwrap();
+    pool.scoped(|scope| {
+        for (xmin, ymin, xmax, ymax, start) in tiles(height, stride) {
+            let tile_hdr = hdr.each_mut().map(|b| tile_buffer(b, xmin, ymin, xmax, ymax, start));
+            let bright = bright.each_mut().map(|b| unsafe { from_raw_parts_mut(b.as_mut_ptr(), b.len()) });
+            scope.execute(move || unsafe {
+                avx2_bloom_downsample_tile(&tile_hdr, bright, half_stride, BLOOM_THRESHOLD, xmin, ymin, xmax, ymax);
+            });
+        }
+    });
+
+    pool.scoped(|scope| {
+        for (xmin, ymin, xmax, ymax) in half_tiles() {
+            let source = bright.each_ref().map(|b| &b[..]);
+            let blurred = blurred.each_mut().map(|b| unsafe { from_raw_parts_mut(b.as_mut_ptr(), b.len()) });
+            scope.execute(move || unsafe {
+                avx2_bloom_blur_horizontal(source, blurred, half_stride, weights, xmin, ymin, xmax, ymax);
+            });
+        }
+    });
+
+    pool.scoped(|scope| {
+        for (xmin, ymin, xmax, ymax) in half_tiles() {
+            let source = blurred.each_ref().map(|b| &b[..]);
+            let bright = bright.each_mut().map(|b| unsafe { from_raw_parts_mut(b.as_mut_ptr(), b.len()) });
+            scope.execute(move || unsafe {
+                avx2_bloom_blur_vertical(source, bright, half_stride, half_height, weights, xmin, ymin, xmax, ymax);
+            });
+        }
+    });
+
+    let bloom = bright.each_ref().map(|b| &b[..]);
+    pool.scoped(|scope| {
+        for (xmin, ymin, xmax, ymax, start) in tiles(height, stride) {
+            let mut tile_hdr = hdr.each_mut().map(|b| tile_buffer(b, xmin, ymin, xmax, ymax, start));
+            scope.execute(move || unsafe {
+                avx2_bloom_composite_tile(bloom, half_stride, half_height, &mut tile_hdr, BLOOM_INTENSITY, xmin, ymin, xmax, ymax);
+            });
+        }
+    });
+}
+
+fn grade(buffer: *mut RGBQUAD, lut: Option<&Lut>, vignette: Option<f32>, width: usize, height: usize, stride: usize) {
+    let mut pool = DRAW_WORKERS.lock().unwrap();
+    pool.scoped(|scope| {
+        for (xmin, ymin, xmax, ymax, _) in tiles(height, stride) {
+            let mut colour = Buffer { buffer: unsafe { from_raw_parts_mut(buffer, stride * height) }, left: 0, top: 0, stride };
+            scope.execute(move || unsafe {
+                avx2_grade_tile(&mut colour, lut, vignette, width, height, xmin, ymin, xmax, ymax);
+            });
+        }
+    });
+}
+
+fn apply_fxaa(buffer: *mut RGBQUAD, source: &mut Vec<RGBQUAD>, width: usize, height: usize, stride: usize) {
+    source.clear();
+    source.extend_from_slice(unsafe { from_raw_parts(buffer, stride * height) });
+    let source = &source[..];
+
+    let mut pool = DRAW_WORKERS.lock().unwrap();
+    pool.scoped(|scope| {
+        for (xmin, ymin, xmax, ymax, _) in tiles(height, stride) {
+            let mut colour = Buffer { buffer: unsafe { from_raw_parts_mut(buffer, stride * height) }, left: 0, top: 0, stride };
+            scope.execute(move || unsafe {
+                avx2_fxaa_tile(source, &mut colour, width, height, xmin, ymin, xmax, ymax);
+            });
+        }
+    });
+}
+
+// transforms the model and bins its triangles into tiles; morph_weights are only given when some aren't zero, and a
+// skinned model's palette takes it to world space instead of world
+fn prepare_model(buffers: &mut ModelBuffers, model: &Model, world: &Transformation, morph_weights: Option<&[f32]>, palette: Option<&JointPalette>, camera: &Transformation, width: usize, height: usize, stride: usize) {
+    let t = world.then(camera);
+
+    let num_vertices = model.num_vertices;
+    let num_triangles = model.num_triangles;
+    let ModelBuffers {
+        xs, ys, zs, iws, xmins, ymins, xmaxs, ymaxs, iareas,
+        world_xs, world_ys, world_zs, world_iws, normal_xs, normal_ys, normal_zs, tangent_xs, tangent_ys, tangent_zs,
+        morphed_xs, morphed_ys, morphed_zs, morphed_normal_xs, morphed_normal_ys, morphed_normal_zs,
+        tile_triangles
+    } = buffers;
+
+    if let Some(weights) = morph_weights {
+        let positions_out = [&mut *morphed_xs, &mut *morphed_ys, &mut *morphed_zs];
+        let normals_out = (model.vertex_normal_xs.len() > 0).then_some([&mut *morphed_normal_xs, &mut *morphed_normal_ys, &mut *morphed_normal_zs]);
+        time(format!("Morphed {} vertices", num_vertices), || avx2_morphed(positions_out, normals_out, model, weights));
+    }
+    let (positions, model_normals) = if morph_weights.is_some() {
+        ([&*morphed_xs, &*morphed_ys, &*morphed_zs, &model.ws], [&*morphed_normal_xs, &*morphed_normal_ys, &*morphed_normal_zs])
+    }
+    else {
+        ([&model.xs, &model.ys, &model.zs, &model.ws], [&model.vertex_normal_xs, &model.vertex_normal_ys, &model.vertex_normal_zs])
+    };
+
+    // shading is done per pixel in world space
+    if let Some(palette) = palette {
+        let positions_out = [&mut *world_xs, &mut *world_ys, &mut *world_zs, &mut *world_iws];
+        let normals_out = [&mut *normal_xs, &mut *normal_ys, &mut *normal_zs];
+        let tangents_out = (model.tangent_xs.len() > 0).then_some([&mut *tangent_xs, &mut *tangent_ys, &mut *tangent_zs]);
+        time(format!("Skinned {} vertices", num_vertices), || avx2_skinned(positions_out, normals_out, tangents_out, positions, model_normals, model, palette));
+    }
+    else {
+        time(format!("Transformed {} vertices to world space", num_vertices), || {
+            avx2_positions_transformed_to_cartesian(world_xs, world_ys, world_zs, world_iws, positions, num_vertices, world);
+            avx2_transformed_directions(normal_xs, normal_ys, normal_zs, model_normals, num_vertices, &world.inverted_transposed_tl_3x3().unwrap());
+            if model.tangent_xs.len() > 0 {
+                let tangents = [&model.tangent_xs, &model.tangent_ys, &model.tangent_zs];
+                avx2_transformed_directions(tangent_xs, tangent_ys, tangent_zs, tangents, num_vertices, &world.tl_3x3());
+            }
         });
     }
-    let tile_triangles = scene.tile_triangles.borrow();
 
-    let depth = &mut *scene.depth.borrow_mut();
-    time("Cleared depth buffer", ||{
-        if depth.len() > stride * height {
-            depth.truncate(stride * height);
-            depth.fill(1.0);
+    // skinned models are already in world space
+    time(format!("Transformed {} vertices", num_vertices), || {
+        if palette.is_some() {
+            let world_positions = [&*world_xs, &*world_ys, &*world_zs, &*world_iws];
+            avx2_positions_transformed_to_cartesian(xs, ys, zs, iws, world_positions, num_vertices, camera)
         }
         else {
-            depth.fill(1.0);
-            if depth.len() < (stride * height) {
-                depth.reserve_exact((stride * height) - depth.len());
-                depth.extend(iter::repeat(1.0).take((stride * height) - depth.len()));
-            }
+            avx2_positions_transformed_to_cartesian(xs, ys, zs, iws, positions, num_vertices, &t)
         }
     });
 
-    time("Filled triangles", || {
-        let mut pool = DRAW_WORKERS.lock().unwrap();
-        pool.scoped(|scope| {
-            let mut ymin = 0;
-            let mut i_tile = 0;
-            let mut depth = depth.as_mut_slice();
-
-            while ymin < height  {
-                let mut xmin = 0;
-                while xmin < stride {
-                    let (tile_depth, rem_depth) = depth.split_at_mut(TILE_WIDTH.min(stride - xmin) * TILE_HEIGHT.min(height - ymin));
-                    depth = rem_depth;
-                    let mut tile = Tile {
-                        colour: Buffer {
-                            buffer: unsafe { from_raw_parts_mut(buffer, stride * height) },
-                            left: 0,
-                            top: 0,
-                            stride
-                        },
-                        depth: Buffer {
-                            buffer: tile_depth,
-                            left: xmin,
-                            top: ymin,
-                            stride: TILE_WIDTH.min(stride - xmin)
-                        },
-                        xmin,
-                        ymin,
-                        xmax: (xmin + TILE_WIDTH).min(stride),
-                        ymax: (ymin + TILE_HEIGHT).min(height)
-                    };
+    time(format!("Calculated {} bounding boxes", num_triangles), || {
+        calculate_all_bounds(xmins, ymins, xmaxs, ymaxs, iareas, model, xs, ys, 0.0, 0.0, width as f32, height as f32)
+    });
+    let bounds = [&*xmins, &*ymins, &*xmaxs, &*ymaxs, &*iareas];
 
-                    let triangles = array::from_fn(|i| &tile_triangles[i][i_tile]);
+    let num_tiles_x = (stride + TILE_WIDTH - 1) / TILE_WIDTH;
+    let num_tiles = num_tiles_x * ((height + TILE_HEIGHT - 1) / TILE_HEIGHT);
+
+    time(format!("Binned {} triangles", num_triangles), || {
+        bin_triangles(tile_triangles, num_triangles, bounds, num_tiles, num_tiles_x);
+    });
+}
 
-                    scope.execute(move || {
-                        draw_tile(&mut tile, model, xs, ys, zs, iws, bounds, intensities, triangles);
-                    });
+// every model's triangles in each tile in turn, sharing the depth buffer; with deferred shading, the tile is shaded as
+// soon as they're all drawn, while its part of the G-buffer is still in cache
+fn draw_tiles(models: &[DrawnModel], materials: &[PreparedMaterial], lighting: &Lighting, inverse_camera: Option<&Transformation>, buffer: *mut RGBQUAD, depth: &mut [f32], hdr: &mut [Vec<f32>; 3], ambient: &mut [Vec<f32>; 3], g_buffer: &mut GBufferPlanes, height: usize, stride: usize) {
+    let mut pool = DRAW_WORKERS.lock().unwrap();
+    pool.scoped(|scope| {
+        let mut ymin = 0;
+        let mut i_tile = 0;
+        let mut depth = depth;
+        let mut hdr = hdr.each_mut().map(|b| &mut b[..]);
+        let mut ambient = ambient.each_mut().map(|b| &mut b[..]);
+        let mut g_normals = g_buffer.normals.each_mut().map(|b| &mut b[..]);
+        let mut g_uvs = g_buffer.uvs.each_mut().map(|b| &mut b[..]);
+        let mut g_colours = g_buffer.colours.each_mut().map(|b| &mut b[..]);
+        let mut g_materials = [&mut g_buffer.materials[..]];
 
-                    xmin += TILE_WIDTH;
-                    i_tile += 1;
+        while ymin < height  {
+            let mut xmin = 0;
+            while xmin < stride {
+                let tile_len = TILE_WIDTH.min(stride - xmin) * TILE_HEIGHT.min(height - ymin);
+                let (tile_depth, rem_depth) = depth.split_at_mut(tile_len);
+                depth = rem_depth;
+                let tile_width = TILE_WIDTH.min(stride - xmin);
+                let colour = if HDR {
+                    ColourBuffer::Hdr(
+                        split_tile(&mut hdr, tile_len, xmin, ymin, tile_width),
+                        SSAO.then(|| split_tile(&mut ambient, tile_len, xmin, ymin, tile_width)))
+                }
+                else {
+                    ColourBuffer::Direct(Buffer {
+                        buffer: unsafe { from_raw_parts_mut(buffer, stride * height) },
+                        left: 0,
+                        top: 0,
+                        stride
+                    })
+                };
+                let (colour, output) = if DEFERRED {
+                    let [materials] = split_tile(&mut g_materials, tile_len, xmin, ymin, tile_width);
+                    let g_buffer = GBuffer {
+                        normals: split_tile(&mut g_normals, tile_len, xmin, ymin, tile_width),
+                        uvs: split_tile(&mut g_uvs, tile_len, xmin, ymin, tile_width),
+                        colours: split_tile(&mut g_colours, tile_len, xmin, ymin, tile_width),
+                        materials
+                    };
+                    (ColourBuffer::Deferred(g_buffer), Some(colour))
                 }
+                else {
+                    (colour, None)
+                };
+                let mut tile = Tile {
+                    colour,
+                    output,
+                    depth: Buffer {
+                        buffer: tile_depth,
+                        left: xmin,
+                        top: ymin,
+                        stride: tile_width
+                    },
+                    xmin,
+                    ymin,
+                    xmax: (xmin + TILE_WIDTH).min(stride),
+                    ymax: (ymin + TILE_HEIGHT).min(height)
+                };
 
-                ymin += TILE_HEIGHT;
+                scope.execute(move || {
+                    for &model in models {
+                        draw_tile(&mut tile, model, i_tile, materials, lighting);
+                    }
+                    if let (ColourBuffer::Deferred(g_buffer), Some(output), Some(inverse_camera)) = (&tile.colour, &mut tile.output, inverse_camera) {
+                        unsafe {
+                            avx2_shade_tile(g_buffer, &tile.depth, output, inverse_camera, materials, lighting, tile.xmin, tile.ymin, tile.xmax, tile.ymax);
+                        }
+                    }
+                });
+
+                xmin += TILE_WIDTH;
+                i_tile += 1;
             }
-        });
+
+            ymin += TILE_HEIGHT;
+        }
     });
 }
@@ -0,0 +1,551 @@
+use core::arch::x86_64::*;
+
+use super::transformation::*;
+use super::scene::*;
+use super::simd_maths::*;
+use super::texture::*;
+use super::environment::*;
+
+// colours are linear RGB with the light's intensity multiplied in, so can be well above 1.0; they're scaled so that a
+// white diffuse surface facing a light reflects the light's colour, which saves dividing every BRDF by pi
+#[derive(Clone, Copy)]
+pub enum Light {
+    // infinitely far away, shining in one direction
+    Directional { direction: CartesianVector, colour: [f32; 3] },
+    // falls off with the square of the distance, and smoothly to nothing at its range; a range of 0.0 is unlimited
+    Point { position: CartesianCoordinates, colour: [f32; 3], range: f32 },
+    // a point light shining along its direction, fading from full at the inner angle to nothing at the outer angle,
+    // both measured from the direction
+    Spot { position: CartesianCoordinates, direction: CartesianVector, colour: [f32; 3], range: f32, inner_angle: f32, outer_angle: f32 }
+}
+
+// the light that was hard-coded before scenes had lights; coming from top right behind the camera
+pub fn default_lights() -> Vec<Light> {
+    vec![Light::Directional { direction: CartesianVector { x: -1.0, y: -1.0, z: -1.0 }.normalised(), colour: [0.3, 0.3, 0.3] }]
+}
+
+pub const DEFAULT_AMBIENT: [f32; 3] = [0.05, 0.05, 0.05];
+
+// light values that don't change per pixel, worked out once per frame
+#[derive(Clone, Copy)]
+pub struct PreparedLight {
+    // towards the light for directional lights, along the light for spots
+    direction: [f32; 3],
+    position: [f32; 3],
+    colour: [f32; 3],
+    // 0.0 for unlimited
+    inverse_range_squared: f32,
+    // cosine of the outer angle, and how much the cosine changes across the spot's soft edge; 0.0 and 0.0 for no cone
+    cos_outer: f32,
+    inverse_cos_range: f32,
+    kind: LightKind
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum LightKind {
+    Directional,
+    Point,
+    Spot
+}
+
+fn prepare(light: &Light) -> PreparedLight {
+    let xyz = |v: CartesianVector| [v.x, v.y, v.z];
+    let position_xyz = |p: CartesianCoordinates| [p.x, p.y, p.z];
+    let inverse_range_squared = |range: f32| if range > 0.0 { 1.0 / (range * range) } else { 0.0 };
+
+    match *light {
+        Light::Directional { direction, colour } => PreparedLight {
+            direction: xyz(CartesianVector { x: -direction.x, y: -direction.y, z: -direction.z }.normalised()),
+            position: [0.0; 3],
+            colour,
+            inverse_range_squared: 0.0,
+            cos_outer: 0.0,
+            inverse_cos_range: 0.0,
+            kind: LightKind::Directional
+        },
+        Light::Point { position, colour, range } => PreparedLight {
+            direction: [0.0; 3],
+            position: position_xyz(position),
+            colour,
+            inverse_range_squared: inverse_range_squared(range),
+            cos_outer: 0.0,
+            inverse_cos_range: 0.0,
+            kind: LightKind::Point
+        },
+        Light::Spot { position, direction, colour, range, inner_angle, outer_angle } => {
+            let (cos_inner, cos_outer) = (inner_angle.cos(), outer_angle.cos());
+            PreparedLight {
+                direction: xyz(direction.normalised()),
+                position: position_xyz(position),
+                colour,
+                inverse_range_squared: inverse_range_squared(range),
+                cos_outer,
+                inverse_cos_range: 1.0 / (cos_inner - cos_outer).max(1e-4),
+                kind: LightKind::Spot
+            }
+        }
+    }
+}
+
+
+// fog values that don't change per pixel
+#[derive(Clone, Copy)]
+pub struct PreparedFog {
+    colour: [f32; 3],
+    falloff: FogFalloff,
+    density: f32,
+    // how much of the surface is seen is depth * linear_scale + linear_offset for linear fog
+    linear_scale: f32,
+    linear_offset: f32,
+    // the height fog's density at the eye, the falloff, and the eye's height; None without height fog
+    height: Option<(f32, f32, f32)>
+}
+
+fn prepare_fog(fog: &Fog, eye: CartesianCoordinates) -> PreparedFog {
+    let (linear_scale, linear_offset) = match fog.falloff {
+        FogFalloff::Linear { start, end } => {
+            let inverse_range = 1.0 / (end - start).max(1e-6);
+            (-inverse_range, end * inverse_range)
+        }
+        _ => (0.0, 1.0)
+    };
+    let height = (fog.height_density > 0.0).then(|| {
+        (fog.height_density * (-fog.height_falloff * (eye.y - fog.height)).exp(), fog.height_falloff, eye.y)
+    });
+    PreparedFog { colour: fog.colour, falloff: fog.falloff, density: fog.density, linear_scale, linear_offset, height }
+}
+
+// everything shading needs that's the same for every triangle in a frame, in world space
+pub struct Lighting<'a> {
+    pub eye: [f32; 3],
+    pub lights: Vec<PreparedLight>,
+    pub ambient: [f32; 3],
+    pub fog: Option<PreparedFog>,
+    // reflected by everything with a highlight
+    pub environment: Option<&'a Environment>
+}
+
+impl<'a> Lighting<'a> {
+    pub fn new(eye: CartesianCoordinates, lights: &[Light], ambient: [f32; 3], fog: Option<&Fog>, environment: Option<&'a Environment>) -> Lighting<'a> {
+        Lighting {
+            eye: [eye.x, eye.y, eye.z],
+            lights: lights.iter().map(prepare).collect(),
+            ambient,
+            fog: fog.map(|fog| prepare_fog(fog, eye)),
+            environment
+        }
+    }
+}
+
+// the smallest roughness used, as the GGX highlight from a point light becomes infinitely small and bright at 0.0
+const MIN_ROUGHNESS: f32 = 0.05;
+// reflectance at normal incidence of dielectrics, which is about the same for most of them
+const DIELECTRIC_F0: f32 = 0.04;
+
+#[derive(Clone, Copy, PartialEq)]
+enum ShadingKind {
+    Lambert,
+    BlinnPhong,
+    CookTorrance
+}
+
+// a material's parameters in the form the shaders use
+#[derive(Clone, Copy)]
+pub struct PreparedMaterial<'a> {
+    // where it is in the scene's materials, for finding it again from a G-buffer
+    pub id: u32,
+    // linear, multiplied by the texture if there is one
+    pub base_colour: [f32; 3],
+    pub base_colour_texture: Option<&'a Texture>,
+    pub normal_map: Option<&'a Texture>,
+    pub sampler: Sampler,
+    normal_scale: f32,
+    kind: ShadingKind,
+    // for Cook-Torrance; metals have no diffuse reflection, and colour their highlights instead
+    metallic: f32,
+    // the highlight's colour for Blinn-Phong
+    specular: [f32; 3],
+    shininess: f32,
+    // normalisation that keeps the highlight's energy about the same at any shininess
+    shininess_scale: f32,
+    alpha_squared: f32,
+    // from 0.0 for a mirror to 1.0, for how blurry the environment's reflection is
+    reflection_roughness: f32
+}
+
+pub fn prepare_material<'a>(id: u32, material: &Material, textures: &'a [Texture], sampler: Sampler) -> PreparedMaterial<'a> {
+    let unlit = PreparedMaterial {
+        id,
+        base_colour: [material.base_colour[0], material.base_colour[1], material.base_colour[2]],
+        base_colour_texture: material.base_colour_texture.map(|i| &textures[i]),
+        normal_map: material.normal_texture.map(|i| &textures[i]),
+        sampler,
+        normal_scale: material.normal_scale,
+        kind: ShadingKind::Lambert,
+        metallic: 0.0,
+        specular: [0.0; 3],
+        shininess: 1.0,
+        shininess_scale: 0.0,
+        alpha_squared: 1.0,
+        reflection_roughness: 1.0
+    };
+
+    match material.shading {
+        Shading::Lambert => unlit,
+        Shading::BlinnPhong { specular, shininess } => PreparedMaterial {
+            kind: ShadingKind::BlinnPhong,
+            specular,
+            shininess,
+            shininess_scale: (shininess + 8.0) / 8.0,
+            // the Beckmann roughness with about the same highlight
+            reflection_roughness: (2.0 / (shininess + 2.0)).sqrt(),
+            ..unlit
+        },
+        Shading::CookTorrance => {
+            let alpha = material.roughness.clamp(MIN_ROUGHNESS, 1.0).powi(2);
+            PreparedMaterial {
+                kind: ShadingKind::CookTorrance,
+                metallic: material.metallic.clamp(0.0, 1.0),
+                alpha_squared: alpha * alpha,
+                reflection_roughness: material.roughness.clamp(0.0, 1.0),
+                ..unlit
+            }
+        }
+    }
+}
+
+impl PreparedMaterial<'_> {
+    // diffuse reflectance, and the highlight's colour for Blinn-Phong or reflectance at normal incidence for
+    // Cook-Torrance, from the base colour at a point
+    fn reflectances(&self, base_colour: [f32; 3]) -> ([f32; 3], [f32; 3]) {
+        if self.kind == ShadingKind::CookTorrance {
+            (base_colour.map(|c| c * (1.0 - self.metallic)), base_colour.map(|c| DIELECTRIC_F0 + (c - DIELECTRIC_F0) * self.metallic))
+        }
+        else {
+            (base_colour, self.specular)
+        }
+    }
+
+    // without an environment there's nothing for highlights to reflect from every direction, so for Cook-Torrance
+    // ambient light is just added to the specular reflectance so metals don't go black
+    fn ambient_reflectance(&self, diffuse: [f32; 3], specular: [f32; 3], lighting: &Lighting) -> [f32; 3] {
+        if self.kind == ShadingKind::CookTorrance && lighting.environment.is_none() {
+            [0, 1, 2].map(|c| diffuse[c] + specular[c])
+        }
+        else {
+            diffuse
+        }
+    }
+
+    // how much of the environment is reflected, given the specular reflectance; Cook-Torrance reflects more at
+    // grazing angles, though less so the rougher it is
+    fn environment_reflectance(&self, specular: [f32; 3], n_dot_v: f32) -> [f32; 3] {
+        if self.kind == ShadingKind::CookTorrance {
+            let fresnel = (1.0 - n_dot_v).powi(5);
+            specular.map(|f0| f0 + ((1.0 - self.reflection_roughness).max(f0) - f0) * fresnel)
+        }
+        else {
+            specular
+        }
+    }
+}
+
+// the normal from a normal map texel in world space; the interpolated normal and tangent aren't normalised first, to
+// match how MikkTSpace bakes normal maps; the same calculation as the SIMD version
+pub fn normal_mapped(normal: [f32; 3], tangent: [f32; 4], texel: [f32; 4], material: &PreparedMaterial) -> [f32; 3] {
+    let t = [tangent[0], tangent[1], tangent[2]];
+    let b = cross(normal, t).map(|c| c * tangent[3]);
+    let [x, y, z] = [0, 1, 2].map(|c| texel[c] * 2.0 - 1.0);
+    let (x, y) = (x * material.normal_scale, y * material.normal_scale);
+    [0, 1, 2].map(|c| x * t[c] + y * b[c] + z * normal[c])
+}
+
+// the part of shade that comes from ambient light, which ambient occlusion takes back out where it's blocked; the same
+// calculation as the SIMD version
+pub fn ambient(base_colour: [f32; 3], material: &PreparedMaterial, lighting: &Lighting) -> [f32; 3] {
+    let (diffuse, specular) = material.reflectances(base_colour);
+    let ambient_reflectance = material.ambient_reflectance(diffuse, specular, lighting);
+    [0, 1, 2].map(|c| lighting.ambient[c] * ambient_reflectance[c])
+}
+
+// linear RGB leaving a point towards the eye, given the linear base colour there; the same calculation as the SIMD
+// version
+pub fn shade(position: [f32; 3], normal: [f32; 3], base_colour: [f32; 3], material: &PreparedMaterial, lighting: &Lighting) -> [f32; 3] {
+    let n = normalised(normal);
+    let v = normalised([0, 1, 2].map(|c| lighting.eye[c] - position[c]));
+    let n_dot_v = dot(n, v).max(1e-4);
+    let (diffuse, specular) = material.reflectances(base_colour);
+    let mut radiance = ambient(base_colour, material, lighting);
+
+    for light in &lighting.lights {
+        let (l, attenuation) = if light.kind == LightKind::Directional {
+            (light.direction, 1.0)
+        }
+        else {
+            let d = [0, 1, 2].map(|c| light.position[c] - position[c]);
+            let distance_squared = dot(d, d).max(f32::MIN_POSITIVE);
+            let l = d.map(|c| c / distance_squared.sqrt());
+            let window = (1.0 - (distance_squared * light.inverse_range_squared).powi(2)).clamp(0.0, 1.0).powi(2);
+            let mut attenuation = window / distance_squared;
+            if light.kind == LightKind::Spot {
+                let cone = ((-dot(l, light.direction) - light.cos_outer) * light.inverse_cos_range).clamp(0.0, 1.0);
+                attenuation *= cone * cone;
+            }
+            (l, attenuation)
+        };
+
+        let n_dot_l = dot(n, l).max(0.0);
+        let h = normalised([0, 1, 2].map(|c| l[c] + v[c]));
+        let n_dot_h = dot(n, h).max(0.0);
+
+        let highlight = match material.kind {
+            ShadingKind::Lambert => [0.0; 3],
+            ShadingKind::BlinnPhong => specular.map(|s| s * material.shininess_scale * n_dot_h.powf(material.shininess)),
+            ShadingKind::CookTorrance => {
+                let a2 = material.alpha_squared;
+                let d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+                let distribution = a2 / (d * d);
+                // height-correlated Smith, with the BRDF's denominator folded in
+                let visibility = 0.5 / (n_dot_l * (n_dot_v * n_dot_v * (1.0 - a2) + a2).sqrt() + n_dot_v * (n_dot_l * n_dot_l * (1.0 - a2) + a2).sqrt()).max(f32::MIN_POSITIVE);
+                let fresnel = (1.0 - dot(v, h).max(0.0)).powi(5);
+                specular.map(|f0| distribution * visibility * (f0 + (1.0 - f0) * fresnel))
+            }
+        };
+
+        for c in 0..3 {
+            radiance[c] += (diffuse[c] + highlight[c]) * light.colour[c] * n_dot_l * attenuation;
+        }
+    }
+
+    // the environment in the mirror direction, blurrier the rougher the material is
+    if let Some(environment) = lighting.environment.filter(|_| material.kind != ShadingKind::Lambert) {
+        let r = [0, 1, 2].map(|c| 2.0 * n_dot_v * n[c] - v[c]);
+        let reflected = sample_environment(environment, r, material.reflection_roughness * (environment.num_levels() - 1) as f32);
+        let reflectance = material.environment_reflectance(specular, n_dot_v);
+        for c in 0..3 {
+            radiance[c] += reflectance[c] * reflected[c];
+        }
+    }
+
+    radiance
+}
+
+// the height fog's exponent is kept to where e^x is finite
+const MAX_FOG_EXPONENT: f32 = 80.0;
+
+// mixes fog into a linear colour at the given view space depth and world space height, returning that and how much of
+// the surface is still seen, for scaling anything else taken from its colour; height fog is integrated along the view
+// ray, taking its length to be the depth too; the same calculation as the SIMD version
+pub fn fogged(colour: [f32; 3], depth: f32, height: f32, lighting: &Lighting) -> ([f32; 3], f32) {
+    let Some(fog) = &lighting.fog else {
+        return (colour, 1.0);
+    };
+    let depth = depth.max(0.0);
+    let mut seen = match fog.falloff {
+        FogFalloff::Linear { .. } => (depth * fog.linear_scale + fog.linear_offset).clamp(0.0, 1.0),
+        FogFalloff::Exponential => (-fog.density * depth).exp(),
+        FogFalloff::ExponentialSquared => (-(fog.density * depth).powi(2)).exp()
+    };
+    if let Some((eye_density, falloff, eye_height)) = fog.height {
+        // the average density along the ray, relative to the eye's
+        let x = (falloff * (height - eye_height)).clamp(-MAX_FOG_EXPONENT, MAX_FOG_EXPONENT);
+        let spread = if x.abs() > 1e-4 { (1.0 - (-x).exp()) / x } else { 1.0 };
+        seen *= (-eye_density * depth * spread).exp();
+    }
+    ([0, 1, 2].map(|c| fog.colour[c] + (colour[c] - fog.colour[c]) * seen), seen)
+}
+
+#[target_feature(enable = "fma,avx,avx2")]
+#[inline]
+unsafe fn avx2_exp(x: __m256) -> __m256 {
+    avx2_exp2(_mm256_mul_ps(x, _mm256_set1_ps(std::f32::consts::LOG2_E)))
+}
+
+#[target_feature(enable = "fma,avx,avx2")]
+#[inline]
+pub unsafe fn avx2_fogged(colour: [__m256; 3], depth: __m256, height: __m256, lighting: &Lighting) -> ([__m256; 3], __m256) {
+    let one = _mm256_set1_ps(1.0);
+    let Some(fog) = &lighting.fog else {
+        return (colour, one);
+    };
+    let zero = _mm256_setzero_ps();
+    let depth = _mm256_max_ps(depth, zero);
+    let density = _mm256_set1_ps(fog.density);
+    let mut seen = match fog.falloff {
+        FogFalloff::Linear { .. } => {
+            let seen = _mm256_fmadd_ps(depth, _mm256_set1_ps(fog.linear_scale), _mm256_set1_ps(fog.linear_offset));
+            _mm256_min_ps(_mm256_max_ps(seen, zero), one)
+        }
+        FogFalloff::Exponential => avx2_exp(_mm256_sub_ps(zero, _mm256_mul_ps(density, depth))),
+        FogFalloff::ExponentialSquared => {
+            let d = _mm256_mul_ps(density, depth);
+            avx2_exp(_mm256_sub_ps(zero, _mm256_mul_ps(d, d)))
+        }
+    };
+    if let Some((eye_density, falloff, eye_height)) = fog.height {
+        let x = _mm256_mul_ps(_mm256_set1_ps(falloff), _mm256_sub_ps(height, _mm256_set1_ps(eye_height)));
+        let x = _mm256_min_ps(_mm256_max_ps(x, _mm256_set1_ps(-MAX_FOG_EXPONENT)), _mm256_set1_ps(MAX_FOG_EXPONENT));
+        let level = _mm256_cmp_ps(_mm256_andnot_ps(_mm256_set1_ps(-0.0), x), _mm256_set1_ps(1e-4), _CMP_LE_OQ);
+        let spread = _mm256_blendv_ps(_mm256_div_ps(_mm256_sub_ps(one, avx2_exp(_mm256_sub_ps(zero, x))), x), one, level);
+        seen = _mm256_mul_ps(seen, avx2_exp(_mm256_mul_ps(_mm256_set1_ps(-eye_density), _mm256_mul_ps(depth, spread))));
+    }
+    let fog_colour = fog.colour.map(|c| _mm256_set1_ps(c));
+    ([0, 1, 2].map(|c| _mm256_fmadd_ps(_mm256_sub_ps(colour[c], fog_colour[c]), seen, fog_colour[c])), seen)
+}
+
+#[target_feature(enable = "fma,avx,avx2")]
+#[inline]
+pub unsafe fn avx2_normal_mapped(normal: [__m256; 3], tangent: [__m256; 4], texel: [__m256; 4], material: &PreparedMaterial) -> [__m256; 3] {
+    let t = [tangent[0], tangent[1], tangent[2]];
+    let [n_x, n_y, n_z] = normal;
+    let b = [
+        _mm256_fmsub_ps(n_y, t[2], _mm256_mul_ps(n_z, t[1])),
+        _mm256_fmsub_ps(n_z, t[0], _mm256_mul_ps(n_x, t[2])),
+        _mm256_fmsub_ps(n_x, t[1], _mm256_mul_ps(n_y, t[0]))
+    ].map(|c| _mm256_mul_ps(c, tangent[3]));
+
+    let one = _mm256_set1_ps(1.0);
+    let two = _mm256_set1_ps(2.0);
+    let scale = _mm256_set1_ps(material.normal_scale);
+    let x = _mm256_mul_ps(_mm256_fmsub_ps(texel[0], two, one), scale);
+    let y = _mm256_mul_ps(_mm256_fmsub_ps(texel[1], two, one), scale);
+    let z = _mm256_fmsub_ps(texel[2], two, one);
+    [0, 1, 2].map(|c| _mm256_fmadd_ps(x, t[c], _mm256_fmadd_ps(y, b[c], _mm256_mul_ps(z, normal[c]))))
+}
+
+#[target_feature(enable = "fma,avx,avx2")]
+#[inline]
+unsafe fn avx2_reflectances(base_colour: [__m256; 3], material: &PreparedMaterial) -> ([__m256; 3], [__m256; 3]) {
+    if material.kind == ShadingKind::CookTorrance {
+        let metallic = _mm256_set1_ps(material.metallic);
+        let f0 = _mm256_set1_ps(DIELECTRIC_F0);
+        (base_colour.map(|c| _mm256_fnmadd_ps(c, metallic, c)), base_colour.map(|c| _mm256_fmadd_ps(_mm256_sub_ps(c, f0), metallic, f0)))
+    }
+    else {
+        (base_colour, material.specular.map(|c| _mm256_set1_ps(c)))
+    }
+}
+
+#[target_feature(enable = "fma,avx,avx2")]
+#[inline]
+unsafe fn avx2_ambient_radiance(diffuse: [__m256; 3], specular: [__m256; 3], material: &PreparedMaterial, lighting: &Lighting) -> [__m256; 3] {
+    let ambient_reflectance = if material.kind == ShadingKind::CookTorrance && lighting.environment.is_none() {
+        [0, 1, 2].map(|c| _mm256_add_ps(diffuse[c], specular[c]))
+    }
+    else {
+        diffuse
+    };
+    [0, 1, 2].map(|c| _mm256_mul_ps(_mm256_set1_ps(lighting.ambient[c]), ambient_reflectance[c]))
+}
+
+// the part of avx2_shade that comes from ambient light
+#[target_feature(enable = "fma,avx,avx2")]
+#[inline]
+pub unsafe fn avx2_ambient(base_colour: [__m256; 3], material: &PreparedMaterial, lighting: &Lighting) -> [__m256; 3] {
+    let (diffuse, specular) = avx2_reflectances(base_colour, material);
+    avx2_ambient_radiance(diffuse, specular, material, lighting)
+}
+
+// linear RGB leaving 8 points towards the eye, given the linear base colour there; positions and normals are in world
+// space, and normals needn't be normalised as interpolation shortens them anyway
+#[target_feature(enable = "fma,avx,avx2")]
+#[inline]
+pub unsafe fn avx2_shade(position: [__m256; 3], normal: [__m256; 3], base_colour: [__m256; 3], material: &PreparedMaterial, lighting: &Lighting) -> [__m256; 3] {
+    let zero = _mm256_setzero_ps();
+    let one = _mm256_set1_ps(1.0);
+    let tiny = _mm256_set1_ps(f32::MIN_POSITIVE);
+
+    let n = avx2_normalised(normal);
+    let v = avx2_normalised([0, 1, 2].map(|c| _mm256_sub_ps(_mm256_set1_ps(lighting.eye[c]), position[c])));
+    let n_dot_v = _mm256_max_ps(avx2_dot(n, v), _mm256_set1_ps(1e-4));
+
+    let (diffuse, specular) = avx2_reflectances(base_colour, material);
+    let shininess = _mm256_set1_ps(material.shininess);
+    let shininess_scale = _mm256_set1_ps(material.shininess_scale);
+    let a2 = _mm256_set1_ps(material.alpha_squared);
+    let one_minus_a2 = _mm256_set1_ps(1.0 - material.alpha_squared);
+    // the view half of the visibility term doesn't change per light
+    let view_visibility = _mm256_sqrt_ps(_mm256_fmadd_ps(_mm256_mul_ps(n_dot_v, n_dot_v), one_minus_a2, a2));
+
+    let mut radiance = avx2_ambient_radiance(diffuse, specular, material, lighting);
+
+    for light in &lighting.lights {
+        let (l, attenuation) = if light.kind == LightKind::Directional {
+            (light.direction.map(|c| _mm256_set1_ps(c)), one)
+        }
+        else {
+            let d = [0, 1, 2].map(|c| _mm256_sub_ps(_mm256_set1_ps(light.position[c]), position[c]));
+            let distance_squared = _mm256_max_ps(avx2_dot(d, d), tiny);
+            let inverse_distance = _mm256_rsqrt_ps(distance_squared);
+            let l = d.map(|c| _mm256_mul_ps(c, inverse_distance));
+
+            // inverse square, windowed to reach zero at the range
+            let ratio = _mm256_mul_ps(distance_squared, _mm256_set1_ps(light.inverse_range_squared));
+            let window = _mm256_min_ps(_mm256_max_ps(_mm256_fnmadd_ps(ratio, ratio, one), zero), one);
+            let mut attenuation = _mm256_mul_ps(_mm256_mul_ps(window, window), _mm256_rcp_ps(distance_squared));
+
+            if light.kind == LightKind::Spot {
+                let s = light.direction.map(|c| _mm256_set1_ps(c));
+                let cone = _mm256_mul_ps(_mm256_sub_ps(_mm256_sub_ps(zero, avx2_dot(l, s)), _mm256_set1_ps(light.cos_outer)), _mm256_set1_ps(light.inverse_cos_range));
+                let cone = _mm256_min_ps(_mm256_max_ps(cone, zero), one);
+                attenuation = _mm256_mul_ps(attenuation, _mm256_mul_ps(cone, cone));
+            }
+            (l, attenuation)
+        };
+
+        let n_dot_l = _mm256_max_ps(avx2_dot(n, l), zero);
+        let incident = _mm256_mul_ps(n_dot_l, attenuation);
+        let h = avx2_normalised([0, 1, 2].map(|c| _mm256_add_ps(l[c], v[c])));
+        let n_dot_h = _mm256_max_ps(avx2_dot(n, h), zero);
+
+        let reflectance = match material.kind {
+            ShadingKind::Lambert => diffuse,
+            ShadingKind::BlinnPhong => {
+                // pow(n_dot_h, shininess); log2 of zero comes out as -127, which is near enough
+                let highlight = _mm256_mul_ps(avx2_exp2(_mm256_mul_ps(avx2_log2(n_dot_h), shininess)), shininess_scale);
+                [0, 1, 2].map(|c| _mm256_fmadd_ps(specular[c], highlight, diffuse[c]))
+            }
+            ShadingKind::CookTorrance => {
+                let d = _mm256_fmadd_ps(_mm256_mul_ps(n_dot_h, n_dot_h), _mm256_sub_ps(a2, one), one);
+                let distribution = _mm256_div_ps(a2, _mm256_mul_ps(d, d));
+                // height-correlated Smith, with the BRDF's denominator folded in
+                let light_visibility = _mm256_sqrt_ps(_mm256_fmadd_ps(_mm256_mul_ps(n_dot_l, n_dot_l), one_minus_a2, a2));
+                let visibility = _mm256_div_ps(_mm256_set1_ps(0.5), _mm256_max_ps(_mm256_fmadd_ps(n_dot_l, view_visibility, _mm256_mul_ps(n_dot_v, light_visibility)), tiny));
+                let highlight = _mm256_mul_ps(distribution, visibility);
+
+                // Schlick's approximation
+                let f = _mm256_sub_ps(one, _mm256_max_ps(avx2_dot(v, h), zero));
+                let f2 = _mm256_mul_ps(f, f);
+                let fresnel = _mm256_mul_ps(_mm256_mul_ps(f2, f2), f);
+                [0, 1, 2].map(|c| {
+                    let f = _mm256_fmadd_ps(_mm256_sub_ps(one, specular[c]), fresnel, specular[c]);
+                    _mm256_fmadd_ps(f, highlight, diffuse[c])
+                })
+            }
+        };
+
+        for c in 0..3 {
+            radiance[c] = _mm256_fmadd_ps(_mm256_mul_ps(reflectance[c], _mm256_set1_ps(light.colour[c])), incident, radiance[c]);
+        }
+    }
+
+    if let Some(environment) = lighting.environment.filter(|_| material.kind != ShadingKind::Lambert) {
+        let two_n_dot_v = _mm256_add_ps(n_dot_v, n_dot_v);
+        let r = [0, 1, 2].map(|c| _mm256_fmsub_ps(two_n_dot_v, n[c], v[c]));
+        let reflected = avx2_sample_environment(environment, r, material.reflection_roughness * (environment.num_levels() - 1) as f32);
+        let reflectance = if material.kind == ShadingKind::CookTorrance {
+            let f = _mm256_sub_ps(one, n_dot_v);
+            let f2 = _mm256_mul_ps(f, f);
+            let fresnel = _mm256_mul_ps(_mm256_mul_ps(f2, f2), f);
+            specular.map(|f0| _mm256_fmadd_ps(_mm256_sub_ps(_mm256_max_ps(_mm256_set1_ps(1.0 - material.reflection_roughness), f0), f0), fresnel, f0))
+        }
+        else {
+            specular
+        };
+        for c in 0..3 {
+            radiance[c] = _mm256_fmadd_ps(reflectance[c], reflected[c], radiance[c]);
+        }
+    }
+
+    radiance
+}
@@ -98,7 +98,30 @@ static BG: RGBQUAD = RGBQUAD { rgbRed: 0, rgbBlue: 0, rgbGreen: 0, rgbReserved:
 unsafe extern "system" fn window_proc(hwnd: HWND, msg: u32, w_param: WPARAM, l_param: LPARAM) -> LRESULT {
     match msg {
         WM_CREATE => {
-            time(format!("initialised"), || {init()});
+            // a .toml scene file to draw, or a .cube file to colour grade with, can be given on the command line
+            let arg = std::env::args().nth(1);
+            let scene_file = arg.as_deref().filter(|path| path.ends_with(".toml"));
+            time(format!("initialised"), || match scene_file {
+                Some(path) => init_from_scene_file(std::path::Path::new(path)),
+                None => init()
+            });
+            if let Some(path) = arg.as_deref().filter(|path| !path.ends_with(".toml")) {
+                load_colour_grading_lut(std::path::Path::new(path));
+            }
+            LRESULT(0)
+        }
+
+        WM_CHAR => {
+            // keys to switch post-processing effects on and off
+            let effect = match char::from_u32(w_param.0 as u32) {
+                Some('f') => PostEffect::Fxaa,
+                Some('b') => PostEffect::Bloom,
+                Some('v') => PostEffect::Vignette,
+                Some('g') => PostEffect::ColourGrading,
+                _ => return LRESULT(0)
+            };
+            let enabled = toggle_post_effect(effect);
+            println!("{:?} {}", effect, if enabled { "on" } else { "off" });
             LRESULT(0)
         }
 
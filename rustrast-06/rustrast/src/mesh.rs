@@ -0,0 +1,466 @@
+use std::collections::*;
+
+use super::simd_vec::*;
+use super::model::*;
+use super::transformation::*;
+
+// clean-up for meshes from scanners and the like, which often have duplicate vertices and zero-area triangles that
+// cost as much to transform, bound and bin as real ones
+
+// the optional per-vertex attributes have to match as well as the position for vertices to be welded together, so
+// that hard edges and mirrored normal maps survive; unlike positions they don't scale with the model, so have fixed
+// tolerances
+
+// texture coordinates, colours and joint weights are fractions, and this is a small part of a texel of even a 16384
+// wide texture
+const ATTRIBUTE_TOLERANCE: f32 = 1e-5;
+// the cosine of the largest angle between normals or tangents that are the same, about half a degree; it's the
+// direction that matters, and they aren't always normalised
+const DIRECTION_TOLERANCE: f32 = 0.99996;
+
+fn fractional_attributes(model: &Model) -> Vec<&SimdVec<f32>> {
+    [
+        &model.vertex_colour_rs, &model.vertex_colour_gs, &model.vertex_colour_bs,
+        &model.texture_us, &model.texture_vs,
+        // the sign of the bitangent
+        &model.tangent_ws,
+        &model.joint_weights[0], &model.joint_weights[1], &model.joint_weights[2], &model.joint_weights[3]
+    ].into_iter().filter(|a| a.len() > 0).collect()
+}
+
+fn direction_attributes(model: &Model) -> Vec<[&SimdVec<f32>; 3]> {
+    [
+        [&model.vertex_normal_xs, &model.vertex_normal_ys, &model.vertex_normal_zs],
+        [&model.tangent_xs, &model.tangent_ys, &model.tangent_zs]
+    ].into_iter().filter(|a| a[0].len() > 0).collect()
+}
+
+fn same_direction(a: CartesianVector, b: CartesianVector) -> bool {
+    let (magnitude_a, magnitude_b) = (a.magnitude(), b.magnitude());
+    if magnitude_a == 0.0 || magnitude_b == 0.0 {
+        return magnitude_a == magnitude_b;
+    }
+    a.dot_product(&b) >= DIRECTION_TOLERANCE * magnitude_a * magnitude_b
+}
+
+fn cell(p: CartesianCoordinates, epsilon: f32) -> (i64, i64, i64) {
+    ((p.x / epsilon).floor() as i64, (p.y / epsilon).floor() as i64, (p.z / epsilon).floor() as i64)
+}
+
+// what MikkTSpace needs to know about a model, and the tangent it gives each corner of each triangle
+struct TangentGeometry<'a> {
+    model: &'a Model,
+    corner_tangents: Vec<[f32; 4]>
+}
+
+impl TangentGeometry<'_> {
+    fn vertex(&self, face: usize, vert: usize) -> usize {
+        self.model.triangle_vertices(face)[vert] as usize
+    }
+}
+
+impl bevy_mikktspace::Geometry for TangentGeometry<'_> {
+    fn num_faces(&self) -> usize {
+        self.model.num_triangles as usize
+    }
+
+    fn num_vertices_of_face(&self, _face: usize) -> usize {
+        3
+    }
+
+    fn position(&self, face: usize, vert: usize) -> [f32; 3] {
+        let p = self.model.position(self.vertex(face, vert) as u32);
+        [p.x, p.y, p.z]
+    }
+
+    fn normal(&self, face: usize, vert: usize) -> [f32; 3] {
+        let v = self.vertex(face, vert);
+        [self.model.vertex_normal_xs[v], self.model.vertex_normal_ys[v], self.model.vertex_normal_zs[v]]
+    }
+
+    // texture coordinates start at the top, but normal maps have green pointing up
+    fn tex_coord(&self, face: usize, vert: usize) -> [f32; 2] {
+        let v = self.vertex(face, vert);
+        [self.model.texture_us[v], 1.0 - self.model.texture_vs[v]]
+    }
+
+    fn set_tangent_encoded(&mut self, tangent: [f32; 4], face: usize, vert: usize) {
+        self.corner_tangents[face * 3 + vert] = tangent;
+    }
+}
+
+impl Model {
+    fn position(&self, i: u32) -> CartesianCoordinates {
+        self.homogenous_coordinates(i).to_cartesian().0
+    }
+
+    fn triangle_vertices(&self, it: usize) -> [u32; 3] {
+        [self.trianglev0s[it], self.trianglev1s[it], self.trianglev2s[it]]
+    }
+
+    fn set_triangle_vertices(&mut self, it: usize, vs: [u32; 3]) {
+        self.trianglev0s[it] = vs[0];
+        self.trianglev1s[it] = vs[1];
+        self.trianglev2s[it] = vs[2];
+    }
+
+    fn bounding_box(&self) -> (CartesianCoordinates, CartesianCoordinates) {
+        let mut min = self.position(0);
+        let mut max = min;
+        for i in 1..self.num_vertices {
+            let p = self.position(i);
+            min = CartesianCoordinates { x: min.x.min(p.x), y: min.y.min(p.y), z: min.z.min(p.z) };
+            max = CartesianCoordinates { x: max.x.max(p.x), y: max.y.max(p.y), z: max.z.max(p.z) };
+        }
+        (min, max)
+    }
+
+    // length of the bounding box's diagonal, to scale tolerances by
+    pub fn size(&self) -> f32 {
+        if self.num_vertices == 0 {
+            return 0.0;
+        }
+        let (min, max) = self.bounding_box();
+        (max - min).magnitude()
+    }
+
+    // the centre of the bounding box and the distance from it to the furthest vertex; not the smallest sphere, but
+    // close enough to judge how big the model is on screen
+    pub fn bounding_sphere(&self) -> (CartesianCoordinates, f32) {
+        if self.num_vertices == 0 {
+            return (CartesianCoordinates { x: 0.0, y: 0.0, z: 0.0 }, 0.0);
+        }
+        let (min, max) = self.bounding_box();
+        let centre = CartesianCoordinates { x: (min.x + max.x) * 0.5, y: (min.y + max.y) * 0.5, z: (min.z + max.z) * 0.5 };
+        let radius = (0..self.num_vertices).map(|i| (self.position(i) - centre).magnitude()).fold(0.0, f32::max);
+        (centre, radius)
+    }
+
+    // merges vertices closer than epsilon with the same attributes, keeping the first, and returns how many were
+    // removed; triangles can become degenerate so this should be followed by remove_degenerate_triangles
+    pub fn weld_vertices(&mut self, epsilon: f32) -> u32 {
+        let fractions = fractional_attributes(self);
+        let directions = direction_attributes(self);
+        let joints = self.joints.iter().filter(|js| js.len() > 0).collect::<Vec<_>>();
+        // vertices that morph targets would pull apart stay apart
+        let same_deltas = |a: usize, b: usize| self.morph_targets.iter().all(|target| {
+            let (a, b) = (a as u32, b as u32);
+            let (pa, pb, na, nb) = (target.position_delta(a), target.position_delta(b), target.normal_delta(a), target.normal_delta(b));
+            (0..3).all(|c| (pa[c] - pb[c]).abs() <= epsilon && (na[c] - nb[c]).abs() <= ATTRIBUTE_TOLERANCE)
+        });
+        let vector = |vs: &[&SimdVec<f32>; 3], i: usize| CartesianVector { x: vs[0][i], y: vs[1][i], z: vs[2][i] };
+        let same_attributes = |a: usize, b: usize| {
+            fractions.iter().all(|vs| (vs[a] - vs[b]).abs() <= ATTRIBUTE_TOLERANCE)
+                && directions.iter().all(|vs| same_direction(vector(vs, a), vector(vs, b)))
+                && joints.iter().all(|js| js[a] == js[b]) && same_deltas(a, b)
+        };
+
+        // cells are epsilon wide, so a vertex can only be welded to ones in its own or neighbouring cells
+        let mut grid: HashMap<(i64, i64, i64), Vec<u32>> = HashMap::new();
+        let mut representatives = Vec::with_capacity(self.num_vertices as usize);
+        for i in 0..self.num_vertices {
+            let p = self.position(i);
+            let (cx, cy, cz) = cell(p, epsilon);
+
+            let mut representative = None;
+            'search: for dx in -1..=1 {
+                for dy in -1..=1 {
+                    for dz in -1..=1 {
+                        for &j in grid.get(&(cx + dx, cy + dy, cz + dz)).into_iter().flatten() {
+                            if (self.position(j) - p).magnitude() <= epsilon && same_attributes(i as usize, j as usize) {
+                                representative = Some(j);
+                                break 'search;
+                            }
+                        }
+                    }
+                }
+            }
+
+            representatives.push(representative.unwrap_or_else(|| {
+                grid.entry((cx, cy, cz)).or_default().push(i);
+                i
+            }));
+        }
+
+        for it in 0..self.num_triangles as usize {
+            let vs = self.triangle_vertices(it).map(|v| representatives[v as usize]);
+            self.set_triangle_vertices(it, vs);
+        }
+
+        let num_vertices = self.num_vertices;
+        self.remove_unused_vertices();
+        self.calculate_surface_normals();
+        num_vertices - self.num_vertices
+    }
+
+    // keeps the rest in the same order
+    pub fn remove_unused_vertices(&mut self) {
+        let mut used = vec![false; self.num_vertices as usize];
+        for it in 0..self.num_triangles as usize {
+            self.triangle_vertices(it).iter().for_each(|&v| used[v as usize] = true);
+        }
+        let order: Vec<u32> = (0..self.num_vertices).filter(|&i| used[i as usize]).collect();
+        self.reorder_vertices(&order);
+    }
+
+    // removes triangles thinner than epsilon, including ones that use the same vertex more than once, and triangles
+    // using the same vertices as an earlier one with the same winding; returns how many were removed
+    pub fn remove_degenerate_triangles(&mut self, epsilon: f32) -> u32 {
+        let mut seen = HashSet::new();
+        let mut order = Vec::with_capacity(self.num_triangles as usize);
+
+        for it in 0..self.num_triangles as usize {
+            let vs = self.triangle_vertices(it);
+            let [p0, p1, p2] = vs.map(|v| self.position(v));
+
+            // twice the area over the longest edge is the smallest height
+            let twice_area = (p1 - p0).cross_product(&(p2 - p0)).magnitude();
+            let longest_edge = (p1 - p0).magnitude().max((p2 - p1).magnitude()).max((p0 - p2).magnitude());
+            if vs[0] == vs[1] || vs[1] == vs[2] || vs[2] == vs[0] || twice_area <= epsilon * longest_edge {
+                continue;
+            }
+
+            // rotated so the smallest index is first, which keeps the winding
+            let first = (0..3).min_by_key(|&i| vs[i]).unwrap();
+            if !seen.insert([vs[first], vs[(first + 1) % 3], vs[(first + 2) % 3]]) {
+                continue;
+            }
+
+            order.push(it as u32);
+        }
+
+        let num_triangles = self.num_triangles;
+        self.reorder_triangles(&order);
+        num_triangles - self.num_triangles
+    }
+
+    // area-weighted average of the surface normals around each vertex, leaving out triangles that meet the one being
+    // shaded at more than the crease angle; vertices on creases are split so each side gets its own normal
+    pub fn generate_vertex_normals(&mut self, crease_angle: f32) {
+        let num_vertices = self.num_vertices as usize;
+        let cos_crease = crease_angle.cos();
+        let zero = CartesianVector { x: 0.0, y: 0.0, z: 0.0 };
+        let unit_or_zero = |n: CartesianVector| if n.magnitude() > 0.0 { n.normalised() } else { zero };
+
+        // the corners (triangle * 3 + index within it) using each vertex, in one list with offsets to each vertex's
+        let mut offsets = vec![0usize; num_vertices + 1];
+        for it in 0..self.num_triangles as usize {
+            self.triangle_vertices(it).iter().for_each(|&v| offsets[v as usize + 1] += 1);
+        }
+        for i in 0..num_vertices {
+            offsets[i + 1] += offsets[i];
+        }
+        let mut corners = vec![0usize; offsets[num_vertices]];
+        let mut next = offsets.clone();
+        for it in 0..self.num_triangles as usize {
+            for (ic, &v) in self.triangle_vertices(it).iter().enumerate() {
+                corners[next[v as usize]] = it * 3 + ic;
+                next[v as usize] += 1;
+            }
+        }
+
+        let mut normals = vec![zero; num_vertices];
+        // the vertex each added vertex is a copy of
+        let mut copies: Vec<u32> = Vec::new();
+        let mut vertex_normals: Vec<(CartesianVector, u32)> = Vec::new();
+        for v in 0..num_vertices {
+            let around = &corners[offsets[v]..offsets[v + 1]];
+            vertex_normals.clear();
+
+            for &corner in around {
+                let it = corner / 3;
+                let own = unit_or_zero(self.surface_normal(it as u32));
+                let mut sum = zero;
+                for &other in around {
+                    let n = self.surface_normal((other / 3) as u32);
+                    if unit_or_zero(n).dot_product(&own) >= cos_crease {
+                        sum = sum + n;
+                    }
+                }
+                let normal = unit_or_zero(sum);
+
+                // corners with the same normal can share a vertex
+                let vertex = match vertex_normals.iter().find(|(n, _)| n.dot_product(&normal) >= 0.9999 || (n.magnitude() == 0.0 && normal.magnitude() == 0.0)) {
+                    Some(&(_, vertex)) => vertex,
+                    None => {
+                        let vertex = if vertex_normals.is_empty() {
+                            normals[v] = normal;
+                            v as u32
+                        }
+                        else {
+                            copies.push(v as u32);
+                            normals.push(normal);
+                            (num_vertices + copies.len() - 1) as u32
+                        };
+                        vertex_normals.push((normal, vertex));
+                        vertex
+                    }
+                };
+
+                let mut vs = self.triangle_vertices(it);
+                vs[corner % 3] = vertex;
+                self.set_triangle_vertices(it, vs);
+            }
+        }
+
+        self.append_vertex_copies(&copies);
+        self.vertex_normal_xs = normals.iter().map(|n| n.x).collect();
+        self.vertex_normal_ys = normals.iter().map(|n| n.y).collect();
+        self.vertex_normal_zs = normals.iter().map(|n| n.z).collect();
+    }
+
+    // tangents for normal mapping, from the texture coordinates and vertex normals, which the model must have; the
+    // reference MikkTSpace implementation, which is what normal maps are baked with and what glTF asks for, gives each
+    // corner of each triangle a tangent and handedness, and vertices whose corners get different ones are split
+    // http://www.mikktspace.com/
+    pub fn generate_tangents(&mut self) {
+        debug_assert!(self.vertex_normal_xs.len() == self.num_vertices as usize);
+        debug_assert!(self.texture_us.len() == self.num_vertices as usize);
+
+        let mut geometry = TangentGeometry { model: self, corner_tangents: vec![[0.0; 4]; self.num_triangles as usize * 3] };
+        let generated = bevy_mikktspace::generate_tangents(&mut geometry);
+        assert!(generated || self.num_triangles == 0, "couldn't generate tangents");
+        let corner_tangents = geometry.corner_tangents;
+
+        // the tangents each vertex has been used with, and the vertex or copy of it with each
+        let num_vertices = self.num_vertices as usize;
+        let mut vertex_tangents: Vec<Vec<([f32; 4], u32)>> = vec![Vec::new(); num_vertices];
+        let mut copies: Vec<u32> = Vec::new();
+        let mut tangents: Vec<[f32; 4]> = vec![[0.0; 4]; num_vertices];
+
+        for it in 0..self.num_triangles as usize {
+            let mut vs = self.triangle_vertices(it);
+            for (ic, v) in vs.iter_mut().enumerate() {
+                let tangent = corner_tangents[it * 3 + ic];
+                let used = &mut vertex_tangents[*v as usize];
+                *v = match used.iter().find(|(t, _)| *t == tangent) {
+                    Some(&(_, vertex)) => vertex,
+                    None => {
+                        let vertex = if used.is_empty() {
+                            tangents[*v as usize] = tangent;
+                            *v
+                        }
+                        else {
+                            copies.push(*v);
+                            tangents.push(tangent);
+                            (num_vertices + copies.len() - 1) as u32
+                        };
+                        used.push((tangent, vertex));
+                        vertex
+                    }
+                };
+            }
+            self.set_triangle_vertices(it, vs);
+        }
+
+        self.tangent_xs = SimdVec::new();
+        self.tangent_ys = SimdVec::new();
+        self.tangent_zs = SimdVec::new();
+        self.tangent_ws = SimdVec::new();
+        self.append_vertex_copies(&copies);
+        self.tangent_xs = tangents.iter().map(|t| t[0]).collect();
+        self.tangent_ys = tangents.iter().map(|t| t[1]).collect();
+        self.tangent_zs = tangents.iter().map(|t| t[2]).collect();
+        self.tangent_ws = tangents.iter().map(|t| t[3]).collect();
+    }
+
+    // adds copies of the given vertices to the end, including whatever optional attributes they have
+    fn append_vertex_copies(&mut self, sources: &[u32]) {
+        let arrays = [
+            &mut self.xs, &mut self.ys, &mut self.zs, &mut self.ws,
+            &mut self.vertex_normal_xs, &mut self.vertex_normal_ys, &mut self.vertex_normal_zs,
+            &mut self.vertex_colour_rs, &mut self.vertex_colour_gs, &mut self.vertex_colour_bs,
+            &mut self.texture_us, &mut self.texture_vs,
+            &mut self.tangent_xs, &mut self.tangent_ys, &mut self.tangent_zs, &mut self.tangent_ws];
+        let weights = self.joint_weights.iter_mut();
+        for vs in arrays.into_iter().chain(weights).filter(|vs| vs.len() > 0) {
+            for &source in sources {
+                let v = vs[source as usize];
+                vs.push(v);
+            }
+        }
+        for js in self.joints.iter_mut().filter(|js| js.len() > 0) {
+            for &source in sources {
+                let j = js[source as usize];
+                js.push(j);
+            }
+        }
+        for target in self.morph_targets.iter_mut() {
+            target.append_vertex_copies(sources, self.num_vertices);
+        }
+        self.num_vertices += sources.len() as u32;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::primitives::*;
+
+    // a separate copy of every vertex for each triangle using it, like the triangle soup some exporters write
+    fn unwelded(model: &Model) -> Model {
+        let corners: Vec<u32> = (0..model.num_triangles as usize).flat_map(|it| model.triangle_vertices(it)).collect();
+        let gather = |vs: &SimdVec<f32>| -> SimdVec<f32> { corners.iter().map(|&v| vs[v as usize]).collect() };
+        let nt = model.num_triangles;
+        let mut soup = Model::new(gather(&model.xs), gather(&model.ys), gather(&model.zs), gather(&model.ws),
+            (0..nt).map(|it| it * 3).collect(), (0..nt).map(|it| it * 3 + 1).collect(), (0..nt).map(|it| it * 3 + 2).collect());
+        soup.vertex_normal_xs = gather(&model.vertex_normal_xs);
+        soup.vertex_normal_ys = gather(&model.vertex_normal_ys);
+        soup.vertex_normal_zs = gather(&model.vertex_normal_zs);
+        soup.texture_us = gather(&model.texture_us);
+        soup.texture_vs = gather(&model.texture_vs);
+        soup
+    }
+
+    #[test]
+    fn welding_keeps_hard_edges() {
+        let mut model = unwelded(&cuboid(1.0, 2.0, 3.0));
+        let epsilon = model.size() * 1e-6;
+        assert_eq!(model.weld_vertices(epsilon), 36 - 24);
+    }
+
+    #[test]
+    fn attribute_tolerances_dont_depend_on_the_model_size() {
+        // rounding differences weld on a small model
+        let mut model = unwelded(&plane(1.0, 1.0, 1, 1));
+        model.texture_us[0] += 1e-6;
+        model.vertex_normal_xs[1] = 1e-3;
+        model.vertex_normal_ys[2] = 2.0;
+        let epsilon = model.size() * 1e-6;
+        assert_eq!(model.weld_vertices(epsilon), 6 - 4);
+
+        // real differences don't on a big one, where they're well within its position tolerance
+        for change in [|m: &mut Model| (0..3).for_each(|i| m.texture_us[i] += 1e-3),
+                       |m: &mut Model| (0..3).for_each(|i| m.vertex_normal_xs[i] = 0.02)] {
+            let mut model = unwelded(&plane(1e5, 1e5, 1, 1));
+            change(&mut model);
+            let epsilon = model.size() * 1e-6;
+            assert!(epsilon > 0.1);
+            assert_eq!(model.weld_vertices(epsilon), 0);
+        }
+    }
+
+    #[test]
+    fn tangents_follow_the_texture() {
+        // u goes along x, and up in the texture is towards -z, which is normal x tangent
+        let mut model = plane(2.0, 2.0, 2, 2);
+        let num_vertices = model.num_vertices;
+        model.generate_tangents();
+        assert_eq!(model.num_vertices, num_vertices);
+        for v in 0..num_vertices as usize {
+            let t = [model.tangent_xs[v], model.tangent_ys[v], model.tangent_zs[v], model.tangent_ws[v]];
+            assert!(t.iter().zip([1.0, 0.0, 0.0, 1.0]).all(|(a, b)| (a - b).abs() < 1e-6), "tangent {:?}", t);
+        }
+
+        // each face of a cuboid has its own vertices, so none need splitting
+        let mut model = cuboid(1.0, 2.0, 3.0);
+        model.generate_tangents();
+        assert_eq!(model.num_vertices, 24);
+        for v in 0..24 {
+            let n = CartesianVector { x: model.vertex_normal_xs[v], y: model.vertex_normal_ys[v], z: model.vertex_normal_zs[v] };
+            let t = CartesianVector { x: model.tangent_xs[v], y: model.tangent_ys[v], z: model.tangent_zs[v] };
+            assert!(n.dot_product(&t).abs() < 1e-6 && (t.magnitude() - 1.0).abs() < 1e-6);
+            assert_eq!(model.tangent_ws[v].abs(), 1.0);
+        }
+    }
+}
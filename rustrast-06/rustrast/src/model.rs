@@ -0,0 +1,369 @@
+use std::{fs::*, io::*, path::*};
+use safe_transmute::trivial::*;
+
+use super::simd_vec::*;
+use super::transformation::*;
+use super::obj::*;
+use super::ply::*;
+use super::stl::*;
+
+#[derive(Clone)]
+pub struct Model {
+    pub num_vertices: u32,
+    pub xs: SimdVec<f32>,
+    pub ys: SimdVec<f32>,
+    pub zs: SimdVec<f32>,
+    pub ws: SimdVec<f32>,
+    // optional per-vertex attributes; these are empty if the file didn't have them
+    pub vertex_normal_xs: SimdVec<f32>,
+    pub vertex_normal_ys: SimdVec<f32>,
+    pub vertex_normal_zs: SimdVec<f32>,
+    // linear 0.0-1.0, multiplying the material's base colour; OBJ and PLY colours are decoded from sRGB when read
+    pub vertex_colour_rs: SimdVec<f32>,
+    pub vertex_colour_gs: SimdVec<f32>,
+    pub vertex_colour_bs: SimdVec<f32>,
+    // texture coordinates, with 0.0, 0.0 at the top left of the texture
+    pub texture_us: SimdVec<f32>,
+    pub texture_vs: SimdVec<f32>,
+    // for normal mapping, pointing along increasing u; w is the handedness, so the bitangent is w * normal x tangent
+    pub tangent_xs: SimdVec<f32>,
+    pub tangent_ys: SimdVec<f32>,
+    pub tangent_zs: SimdVec<f32>,
+    pub tangent_ws: SimdVec<f32>,
+    // for skinning, up to four joints per vertex, as indices into the skin of whichever node draws the model; unused
+    // influences have a weight of 0.0, and the weights add up to 1.0
+    pub joints: [SimdVec<u32>; 4],
+    pub joint_weights: [SimdVec<f32>; 4],
+    // blend shapes, weighted by whichever node draws the model
+    pub morph_targets: Vec<MorphTarget>,
+    pub num_triangles: u32,
+    pub trianglev0s: SimdVec<u32>,
+    pub trianglev1s: SimdVec<u32>,
+    pub trianglev2s: SimdVec<u32>,
+    pub surface_normal_xs: SimdVec<f32>,
+    pub surface_normal_ys: SimdVec<f32>,
+    pub surface_normal_zs: SimdVec<f32>,
+    // index into the scene's materials; empty if every triangle uses the first
+    pub triangle_materials: SimdVec<u32>,
+    // simplified copies for drawing when the model is small on screen, each with fewer triangles than the one before;
+    // empty unless they've been generated
+    pub lods: Vec<Model>
+}
+
+impl Model {
+    // calculates the surface normals
+    pub fn new(xs: SimdVec<f32>, ys: SimdVec<f32>, zs: SimdVec<f32>, ws: SimdVec<f32>, trianglev0s: SimdVec<u32>, trianglev1s: SimdVec<u32>, trianglev2s: SimdVec<u32>) -> Model {
+        let mut model = Model {
+            num_vertices: xs.len() as u32,
+            xs,
+            ys,
+            zs,
+            ws,
+            vertex_normal_xs: SimdVec::new(),
+            vertex_normal_ys: SimdVec::new(),
+            vertex_normal_zs: SimdVec::new(),
+            vertex_colour_rs: SimdVec::new(),
+            vertex_colour_gs: SimdVec::new(),
+            vertex_colour_bs: SimdVec::new(),
+            texture_us: SimdVec::new(),
+            texture_vs: SimdVec::new(),
+            tangent_xs: SimdVec::new(),
+            tangent_ys: SimdVec::new(),
+            tangent_zs: SimdVec::new(),
+            tangent_ws: SimdVec::new(),
+            joints: [SimdVec::new(), SimdVec::new(), SimdVec::new(), SimdVec::new()],
+            joint_weights: [SimdVec::new(), SimdVec::new(), SimdVec::new(), SimdVec::new()],
+            morph_targets: Vec::new(),
+            num_triangles: trianglev0s.len() as u32,
+            trianglev0s,
+            trianglev1s,
+            trianglev2s,
+            surface_normal_xs: SimdVec::new(),
+            surface_normal_ys: SimdVec::new(),
+            surface_normal_zs: SimdVec::new(),
+            triangle_materials: SimdVec::new(),
+            lods: Vec::new()
+        };
+        model.calculate_surface_normals();
+        model
+    }
+
+    #[allow(dead_code)]
+    pub fn homogenous_coordinates(&self, i: u32) -> HomogenousCoordinates {
+        HomogenousCoordinates { x: self.xs[i as usize], y: self.ys[i as usize], z: self.zs[i as usize], w: self.ws[i as usize] }
+    }
+
+    pub fn surface_normal(&self, it: u32) -> CartesianVector {
+        CartesianVector { x: self.surface_normal_xs[it as usize], y: self.surface_normal_ys[it as usize], z: self.surface_normal_zs[it as usize] }
+    }
+
+    pub fn triangle_material(&self, it: u32) -> usize {
+        if self.triangle_materials.len() == 0 { 0 } else { self.triangle_materials[it as usize] as usize }
+    }
+
+    // not normalised, so the magnitude is twice the triangle's area
+    pub fn calculate_surface_normals(&mut self) {
+        let num_triangles = self.num_triangles as usize;
+        let mut surface_normal_xs = SimdVec::with_capacity(num_triangles);
+        let mut surface_normal_ys = SimdVec::with_capacity(num_triangles);
+        let mut surface_normal_zs = SimdVec::with_capacity(num_triangles);
+
+        for it in 0..num_triangles {
+            let (v0, _) = self.homogenous_coordinates(self.trianglev0s[it]).to_cartesian();
+            let (v1, _) = self.homogenous_coordinates(self.trianglev1s[it]).to_cartesian();
+            let (v2, _) = self.homogenous_coordinates(self.trianglev2s[it]).to_cartesian();
+
+            let edge1 = v1 - v0;
+            let edge2 = v2 - v0;
+
+            let surface_normal = edge1.cross_product(&edge2);
+            surface_normal_xs.push(surface_normal.x);
+            surface_normal_ys.push(surface_normal.y);
+            surface_normal_zs.push(surface_normal.z);
+        }
+
+        self.surface_normal_xs = surface_normal_xs;
+        self.surface_normal_ys = surface_normal_ys;
+        self.surface_normal_zs = surface_normal_zs;
+    }
+
+    // order[i] is the old index of the triangle that should end up at i; triangles not in order are removed
+    pub fn reorder_triangles(&mut self, order: &[u32]) {
+        debug_assert!(order.len() <= self.num_triangles as usize);
+
+        self.num_triangles = order.len() as u32;
+        self.trianglev0s = permuted(&self.trianglev0s, order);
+        self.trianglev1s = permuted(&self.trianglev1s, order);
+        self.trianglev2s = permuted(&self.trianglev2s, order);
+        self.surface_normal_xs = permuted(&self.surface_normal_xs, order);
+        self.surface_normal_ys = permuted(&self.surface_normal_ys, order);
+        self.surface_normal_zs = permuted(&self.surface_normal_zs, order);
+        self.triangle_materials = permuted(&self.triangle_materials, order);
+    }
+
+    // order[i] is the old index of the vertex that should end up at i; vertices not in order are removed, so mustn't
+    // be used by any triangles
+    pub fn reorder_vertices(&mut self, order: &[u32]) {
+        debug_assert!(order.len() <= self.num_vertices as usize);
+
+        let mut remap = vec![u32::MAX; self.num_vertices as usize];
+        for (new, &old) in order.iter().enumerate() {
+            remap[old as usize] = new as u32;
+        }
+
+        self.num_vertices = order.len() as u32;
+        self.xs = permuted(&self.xs, order);
+        self.ys = permuted(&self.ys, order);
+        self.zs = permuted(&self.zs, order);
+        self.ws = permuted(&self.ws, order);
+        self.vertex_normal_xs = permuted(&self.vertex_normal_xs, order);
+        self.vertex_normal_ys = permuted(&self.vertex_normal_ys, order);
+        self.vertex_normal_zs = permuted(&self.vertex_normal_zs, order);
+        self.vertex_colour_rs = permuted(&self.vertex_colour_rs, order);
+        self.vertex_colour_gs = permuted(&self.vertex_colour_gs, order);
+        self.vertex_colour_bs = permuted(&self.vertex_colour_bs, order);
+        self.texture_us = permuted(&self.texture_us, order);
+        self.texture_vs = permuted(&self.texture_vs, order);
+        self.tangent_xs = permuted(&self.tangent_xs, order);
+        self.tangent_ys = permuted(&self.tangent_ys, order);
+        self.tangent_zs = permuted(&self.tangent_zs, order);
+        self.tangent_ws = permuted(&self.tangent_ws, order);
+        self.joints = self.joints.each_ref().map(|js| permuted(js, order));
+        self.joint_weights = self.joint_weights.each_ref().map(|ws| permuted(ws, order));
+        for target in self.morph_targets.iter_mut() {
+            target.reorder_vertices(order, &remap);
+        }
+
+        for it in 0..self.num_triangles as usize {
+            self.trianglev0s[it] = remap[self.trianglev0s[it] as usize];
+            self.trianglev1s[it] = remap[self.trianglev1s[it] as usize];
+            self.trianglev2s[it] = remap[self.trianglev2s[it] as usize];
+        }
+    }
+}
+
+// a blend shape, which moves vertices by up to its deltas as its weight goes from 0.0 to 1.0; tangents aren't morphed
+#[derive(Clone)]
+pub struct MorphTarget {
+    // the vertices that move, in increasing order, so that a target moving a small part of a big model stays small;
+    // empty if every vertex has a delta
+    pub vertices: SimdVec<u32>,
+    pub position_deltas: [SimdVec<f32>; 3],
+    // empty if the target doesn't change the normals
+    pub normal_deltas: [SimdVec<f32>; 3]
+}
+
+impl MorphTarget {
+    pub fn is_sparse(&self) -> bool {
+        self.vertices.len() > 0
+    }
+
+    // where vertex v's deltas are, if it has any
+    fn delta_index(&self, v: u32) -> Option<usize> {
+        if self.is_sparse() { self.vertices[..].binary_search(&v).ok() } else { Some(v as usize) }
+    }
+
+    // zero for vertices the target doesn't move
+    pub fn position_delta(&self, v: u32) -> [f32; 3] {
+        self.delta_index(v).map_or([0.0; 3], |i| self.position_deltas.each_ref().map(|ds| ds[i]))
+    }
+
+    pub fn normal_delta(&self, v: u32) -> [f32; 3] {
+        match self.delta_index(v) {
+            Some(i) if self.normal_deltas[0].len() > 0 => self.normal_deltas.each_ref().map(|ds| ds[i]),
+            _ => [0.0; 3]
+        }
+    }
+
+    // keeps only the vertices that move, if there are few enough of them for it to be worth it
+    pub fn sparsified(self) -> Self {
+        if self.is_sparse() {
+            return self;
+        }
+        let moves = |v: usize| self.position_deltas.iter().chain(self.normal_deltas.iter()).any(|ds| ds.len() > 0 && ds[v] != 0.0);
+        let vertices: Vec<u32> = (0..self.position_deltas[0].len()).filter(|&v| moves(v)).map(|v| v as u32).collect();
+        // gathering costs about as much as blending two or three vertices in full
+        if vertices.len() * 3 > self.position_deltas[0].len() || vertices.is_empty() {
+            return self;
+        }
+        MorphTarget {
+            position_deltas: self.position_deltas.each_ref().map(|ds| permuted(ds, &vertices)),
+            normal_deltas: self.normal_deltas.each_ref().map(|ds| permuted(ds, &vertices)),
+            vertices: vertices.into_iter().collect()
+        }
+    }
+
+    // the same as Model::reorder_vertices, with remap taking old indices to new ones, or u32::MAX for removed vertices
+    fn reorder_vertices(&mut self, order: &[u32], remap: &[u32]) {
+        if !self.is_sparse() {
+            self.position_deltas = self.position_deltas.each_ref().map(|ds| permuted(ds, order));
+            self.normal_deltas = self.normal_deltas.each_ref().map(|ds| permuted(ds, order));
+            return;
+        }
+        let mut moved: Vec<(u32, u32)> = self.vertices[..].iter().enumerate()
+            .filter(|(_, &v)| remap[v as usize] != u32::MAX)
+            .map(|(i, &v)| (remap[v as usize], i as u32))
+            .collect();
+        moved.sort_unstable();
+        let deltas: Vec<u32> = moved.iter().map(|&(_, i)| i).collect();
+        self.vertices = moved.iter().map(|&(v, _)| v).collect();
+        self.position_deltas = self.position_deltas.each_ref().map(|ds| permuted(ds, &deltas));
+        self.normal_deltas = self.normal_deltas.each_ref().map(|ds| permuted(ds, &deltas));
+    }
+
+    // for vertices copied from sources onto the end of the model, which starts at first
+    pub fn append_vertex_copies(&mut self, sources: &[u32], first: u32) {
+        for (i, &source) in sources.iter().enumerate() {
+            let Some(d) = self.delta_index(source) else { continue };
+            for ds in self.position_deltas.iter_mut().chain(self.normal_deltas.iter_mut()).filter(|ds| ds.len() > 0) {
+                let delta = ds[d];
+                ds.push(delta);
+            }
+            if self.is_sparse() {
+                self.vertices.push(first + i as u32);
+            }
+        }
+    }
+}
+
+// leaves missing optional attributes missing
+fn permuted<T>(vs: &SimdVec<T>, order: &[u32]) -> SimdVec<T> where T : TriviallyTransmutable + Copy {
+    if vs.len() == 0 {
+        return SimdVec::new();
+    }
+    order.iter().map(|&i| vs[i as usize]).collect()
+}
+
+enum ModelFormat {
+    Obj,
+    Ply,
+    Stl
+}
+
+fn detect_format(path: &Path, bytes: &[u8]) -> ModelFormat {
+    if let Some(format) = format_from_extension(path) {
+        return format;
+    }
+
+    if bytes.starts_with(b"ply") {
+        ModelFormat::Ply
+    }
+    else if is_binary_stl(bytes) || bytes.starts_with(b"solid") {
+        ModelFormat::Stl
+    }
+    else {
+        ModelFormat::Obj
+    }
+}
+
+fn format_from_extension(path: &Path) -> Option<ModelFormat> {
+    match path.extension().and_then(|e| e.to_str()).map(|e| e.to_ascii_lowercase()).as_deref() {
+        Some("obj") => Some(ModelFormat::Obj),
+        Some("ply") => Some(ModelFormat::Ply),
+        Some("stl") => Some(ModelFormat::Stl),
+        _ => None
+    }
+}
+
+// picks a parser based on the file's extension or, failing that, its first few bytes
+pub fn read_model(path: &Path) -> Model {
+    let mut bytes = Vec::new();
+    File::open(path).unwrap().read_to_end(&mut bytes).unwrap();
+
+    match detect_format(path, &bytes) {
+        ModelFormat::Obj => read_obj(&bytes[..]),
+        ModelFormat::Ply => read_ply(&bytes[..]),
+        ModelFormat::Stl => read_stl(&bytes[..])
+    }
+}
+
+// picks a writer based on the file's extension
+pub fn write_model(model: &Model, path: &Path) {
+    let file = File::create(path).unwrap();
+    match format_from_extension(path) {
+        Some(ModelFormat::Obj) => write_obj(model, file),
+        Some(ModelFormat::Ply) => write_ply(model, file),
+        _ => panic!("can't write models to {}", path.display())
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use super::super::texture::*;
+
+    // a generated model given what else the readers and writers have to carry: vertex colours that are exact in eight
+    // bit sRGB, and materials numbered out of the order they're first used
+    pub fn with_colours_and_materials(mut model: Model) -> Model {
+        let colour = |i: usize, c: usize| srgb_fraction_to_linear(((i * 37 + c * 101) % 256) as f32 / 255.0);
+        model.vertex_colour_rs = (0..model.num_vertices as usize).map(|i| colour(i, 0)).collect();
+        model.vertex_colour_gs = (0..model.num_vertices as usize).map(|i| colour(i, 1)).collect();
+        model.vertex_colour_bs = (0..model.num_vertices as usize).map(|i| colour(i, 2)).collect();
+        model.triangle_materials = (0..model.num_triangles).map(|it| [2, 0, 1][(it / 5 % 3) as usize]).collect();
+        model
+    }
+
+    // every array the same length and every value within epsilon; readers and writers needn't keep vertex attributes
+    // bit for bit, as some are stored in sRGB, eight bits or flipped upside down
+    pub fn assert_same_model(a: &Model, b: &Model, epsilon: f32) {
+        assert_eq!(a.num_vertices, b.num_vertices, "number of vertices");
+        assert_eq!(a.num_triangles, b.num_triangles, "number of triangles");
+        let floats = |m: &Model| [
+            ("xs", m.xs.clone()), ("ys", m.ys.clone()), ("zs", m.zs.clone()), ("ws", m.ws.clone()),
+            ("normal xs", m.vertex_normal_xs.clone()), ("normal ys", m.vertex_normal_ys.clone()), ("normal zs", m.vertex_normal_zs.clone()),
+            ("colour rs", m.vertex_colour_rs.clone()), ("colour gs", m.vertex_colour_gs.clone()), ("colour bs", m.vertex_colour_bs.clone()),
+            ("us", m.texture_us.clone()), ("vs", m.texture_vs.clone())];
+        for ((name, a), (_, b)) in floats(a).iter().zip(floats(b).iter()) {
+            assert_eq!(a.len(), b.len(), "length of {}", name);
+            for i in 0..a.len() {
+                assert!((a[i] - b[i]).abs() <= epsilon, "{}[{}] {} and {}", name, i, a[i], b[i]);
+            }
+        }
+        let indices = |m: &Model| [
+            ("v0s", m.trianglev0s.clone()), ("v1s", m.trianglev1s.clone()), ("v2s", m.trianglev2s.clone()),
+            ("materials", m.triangle_materials.clone())];
+        for ((name, a), (_, b)) in indices(a).iter().zip(indices(b).iter()) {
+            assert_eq!(a[..], b[..], "{}", name);
+        }
+    }
+}
@@ -0,0 +1,373 @@
+use std::{fs::*, io::*, path::*, time::*};
+use safe_transmute::trivial::*;
+
+use super::simd_vec::*;
+use super::model::*;
+
+// a binary copy of a loaded model saved next to the file it came from, so that the model doesn't have to be parsed
+// again unless the file changes; not-suitable-for-production, panics on I/O errors other than a missing cache
+//
+// the layout is a header followed by each of the model's arrays in turn, each starting on an ALIGNMENT byte
+// boundary so they could be memory mapped, then the same again for each of its levels of detail; values are stored in
+// native byte order as this only runs on x86-64 anyway:
+//
+//   0  magic "RRMC"
+//   4  version
+//   8  flags describing how the model was processed after loading
+//  12  number of arrays
+//  16  source file length
+//  24  source file modification time, nanoseconds since the Unix epoch
+//  32  FNV-1a hash of everything after the header
+//  40  number of vertices
+//  44  number of triangles
+//  48  number of levels of detail that follow
+//  52  length of each array, in elements
+
+const MAGIC: [u8; 4] = *b"RRMC";
+// bump whenever the layout or the set of arrays changes; changes to how the model was loaded belong in the flags
+const VERSION: u32 = 7;
+const EXTENSION: &str = "rrmc";
+const ALIGNMENT: usize = 128;
+const HEADER_LENGTH_FIXED: usize = 52;
+const NUM_ARRAYS: usize = 31;
+const HEADER_LENGTH: usize = (HEADER_LENGTH_FIXED + NUM_ARRAYS * 4).div_ceil(ALIGNMENT) * ALIGNMENT;
+
+struct Header {
+    flags: u32,
+    source_length: u64,
+    source_modified: u64,
+    hash: u64,
+    num_vertices: u32,
+    num_triangles: u32,
+    num_lods: u32,
+    array_lengths: [u32; NUM_ARRAYS]
+}
+
+// the order the model's arrays are stored in
+fn arrays(model: &Model) -> [&[u8]; NUM_ARRAYS] {
+    [
+        model.xs.as_bytes(), model.ys.as_bytes(), model.zs.as_bytes(), model.ws.as_bytes(),
+        model.vertex_normal_xs.as_bytes(), model.vertex_normal_ys.as_bytes(), model.vertex_normal_zs.as_bytes(),
+        model.vertex_colour_rs.as_bytes(), model.vertex_colour_gs.as_bytes(), model.vertex_colour_bs.as_bytes(),
+        model.texture_us.as_bytes(), model.texture_vs.as_bytes(),
+        model.tangent_xs.as_bytes(), model.tangent_ys.as_bytes(), model.tangent_zs.as_bytes(), model.tangent_ws.as_bytes(),
+        model.joints[0].as_bytes(), model.joints[1].as_bytes(), model.joints[2].as_bytes(), model.joints[3].as_bytes(),
+        model.joint_weights[0].as_bytes(), model.joint_weights[1].as_bytes(), model.joint_weights[2].as_bytes(), model.joint_weights[3].as_bytes(),
+        model.trianglev0s.as_bytes(), model.trianglev1s.as_bytes(), model.trianglev2s.as_bytes(),
+        model.surface_normal_xs.as_bytes(), model.surface_normal_ys.as_bytes(), model.surface_normal_zs.as_bytes(),
+        model.triangle_materials.as_bytes()
+    ]
+}
+
+fn arrays_mut(model: &mut Model) -> [&mut [u8]; NUM_ARRAYS] {
+    let [j0, j1, j2, j3] = &mut model.joints;
+    let [w0, w1, w2, w3] = &mut model.joint_weights;
+    [
+        model.xs.as_bytes_mut(), model.ys.as_bytes_mut(), model.zs.as_bytes_mut(), model.ws.as_bytes_mut(),
+        model.vertex_normal_xs.as_bytes_mut(), model.vertex_normal_ys.as_bytes_mut(), model.vertex_normal_zs.as_bytes_mut(),
+        model.vertex_colour_rs.as_bytes_mut(), model.vertex_colour_gs.as_bytes_mut(), model.vertex_colour_bs.as_bytes_mut(),
+        model.texture_us.as_bytes_mut(), model.texture_vs.as_bytes_mut(),
+        model.tangent_xs.as_bytes_mut(), model.tangent_ys.as_bytes_mut(), model.tangent_zs.as_bytes_mut(), model.tangent_ws.as_bytes_mut(),
+        j0.as_bytes_mut(), j1.as_bytes_mut(), j2.as_bytes_mut(), j3.as_bytes_mut(),
+        w0.as_bytes_mut(), w1.as_bytes_mut(), w2.as_bytes_mut(), w3.as_bytes_mut(),
+        model.trianglev0s.as_bytes_mut(), model.trianglev1s.as_bytes_mut(), model.trianglev2s.as_bytes_mut(),
+        model.surface_normal_xs.as_bytes_mut(), model.surface_normal_ys.as_bytes_mut(), model.surface_normal_zs.as_bytes_mut(),
+        model.triangle_materials.as_bytes_mut()
+    ]
+}
+
+// all arrays currently hold f32 or u32
+const ELEMENT_SIZE: usize = 4;
+
+fn padding(length: usize) -> usize {
+    (ALIGNMENT - (length % ALIGNMENT)) % ALIGNMENT
+}
+
+// Fowler-Noll-Vo; simple and fast enough to be a small fraction of the time taken to read the file
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Fnv1a(0xcbf29ce484222325)
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 ^= b as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+fn hash(arrays: &[&[u8]]) -> u64 {
+    let mut hash = Fnv1a::new();
+    for array in arrays {
+        hash.update(array);
+        hash.update(&[0u8; ALIGNMENT][..padding(array.len())]);
+    }
+    hash.0
+}
+
+fn cache_path(source: &Path) -> PathBuf {
+    let mut path = source.as_os_str().to_owned();
+    path.push(".");
+    path.push(EXTENSION);
+    PathBuf::from(path)
+}
+
+fn source_key(source: &Path) -> (u64, u64) {
+    let metadata = metadata(source).unwrap();
+    let modified = metadata.modified().unwrap().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0);
+    (metadata.len(), modified)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_ne_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_ne_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+fn read_header<R: Read>(file: &mut R) -> Option<Header> {
+    let mut bytes = [0u8; HEADER_LENGTH];
+    file.read_exact(&mut bytes).ok()?;
+
+    if bytes[0..4] != MAGIC || read_u32(&bytes, 4) != VERSION || read_u32(&bytes, 12) as usize != NUM_ARRAYS {
+        return None;
+    }
+
+    Some(Header {
+        flags: read_u32(&bytes, 8),
+        source_length: read_u64(&bytes, 16),
+        source_modified: read_u64(&bytes, 24),
+        hash: read_u64(&bytes, 32),
+        num_vertices: read_u32(&bytes, 40),
+        num_triangles: read_u32(&bytes, 44),
+        num_lods: read_u32(&bytes, 48),
+        array_lengths: std::array::from_fn(|i| read_u32(&bytes, HEADER_LENGTH_FIXED + i * 4))
+    })
+}
+
+fn write_header<W: Write>(file: &mut W, header: &Header) -> Result<()> {
+    let mut bytes = [0u8; HEADER_LENGTH];
+    bytes[0..4].copy_from_slice(&MAGIC);
+    bytes[4..8].copy_from_slice(&VERSION.to_ne_bytes());
+    bytes[8..12].copy_from_slice(&header.flags.to_ne_bytes());
+    bytes[12..16].copy_from_slice(&(NUM_ARRAYS as u32).to_ne_bytes());
+    bytes[16..24].copy_from_slice(&header.source_length.to_ne_bytes());
+    bytes[24..32].copy_from_slice(&header.source_modified.to_ne_bytes());
+    bytes[32..40].copy_from_slice(&header.hash.to_ne_bytes());
+    bytes[40..44].copy_from_slice(&header.num_vertices.to_ne_bytes());
+    bytes[44..48].copy_from_slice(&header.num_triangles.to_ne_bytes());
+    bytes[48..52].copy_from_slice(&header.num_lods.to_ne_bytes());
+    for (i, length) in header.array_lengths.iter().enumerate() {
+        let offset = HEADER_LENGTH_FIXED + i * 4;
+        bytes[offset..offset + 4].copy_from_slice(&length.to_ne_bytes());
+    }
+    file.write_all(&bytes)
+}
+
+fn zeroed<T>(lengths: &[u32; NUM_ARRAYS], i: usize) -> SimdVec<T> where T : TriviallyTransmutable {
+    SimdVec::zeroed(lengths[i] as usize)
+}
+
+// one model's header and arrays, without its levels of detail; returns None if it's out of date or corrupt
+fn read_cached_model<R: Read>(file: &mut R, flags: u32, source_length: u64, source_modified: u64) -> Option<(Model, u32)> {
+    let header = read_header(file)?;
+    if header.flags != flags || header.source_length != source_length || header.source_modified != source_modified {
+        return None;
+    }
+
+    let lengths = &header.array_lengths;
+    // the SIMD code relies on every vertex and triangle array being the same length; optional ones can be empty
+    let counts_valid = (0..4).all(|i| lengths[i] == header.num_vertices)
+        && (4..24).all(|i| lengths[i] == 0 || lengths[i] == header.num_vertices)
+        && (24..30).all(|i| lengths[i] == header.num_triangles)
+        && (lengths[30] == 0 || lengths[30] == header.num_triangles);
+    if !counts_valid {
+        return None;
+    }
+
+    let mut model = Model {
+        num_vertices: header.num_vertices,
+        xs: zeroed(lengths, 0),
+        ys: zeroed(lengths, 1),
+        zs: zeroed(lengths, 2),
+        ws: zeroed(lengths, 3),
+        vertex_normal_xs: zeroed(lengths, 4),
+        vertex_normal_ys: zeroed(lengths, 5),
+        vertex_normal_zs: zeroed(lengths, 6),
+        vertex_colour_rs: zeroed(lengths, 7),
+        vertex_colour_gs: zeroed(lengths, 8),
+        vertex_colour_bs: zeroed(lengths, 9),
+        texture_us: zeroed(lengths, 10),
+        texture_vs: zeroed(lengths, 11),
+        tangent_xs: zeroed(lengths, 12),
+        tangent_ys: zeroed(lengths, 13),
+        tangent_zs: zeroed(lengths, 14),
+        tangent_ws: zeroed(lengths, 15),
+        joints: [16, 17, 18, 19].map(|i| zeroed(lengths, i)),
+        joint_weights: [20, 21, 22, 23].map(|i| zeroed(lengths, i)),
+        // only glTF models have morph targets, and those aren't cached
+        morph_targets: Vec::new(),
+        num_triangles: header.num_triangles,
+        trianglev0s: zeroed(lengths, 24),
+        trianglev1s: zeroed(lengths, 25),
+        trianglev2s: zeroed(lengths, 26),
+        surface_normal_xs: zeroed(lengths, 27),
+        surface_normal_ys: zeroed(lengths, 28),
+        surface_normal_zs: zeroed(lengths, 29),
+        triangle_materials: zeroed(lengths, 30),
+        lods: Vec::new()
+    };
+
+    // read straight into the model's aligned buffers
+    let mut padding_bytes = [0u8; ALIGNMENT];
+    for array in arrays_mut(&mut model) {
+        file.read_exact(array).ok()?;
+        file.read_exact(&mut padding_bytes[..padding(array.len())]).ok()?;
+    }
+
+    if hash(&arrays(&model)) != header.hash {
+        return None;
+    }
+
+    Some((model, header.num_lods))
+}
+
+// returns None if the cache is out of date or corrupt
+fn read_model_cache(path: &Path, flags: u32, source_length: u64, source_modified: u64) -> Option<Model> {
+    let mut file = BufReader::new(File::open(path).ok()?);
+    let (mut model, num_lods) = read_cached_model(&mut file, flags, source_length, source_modified)?;
+    for _ in 0..num_lods {
+        let (lod, _) = read_cached_model(&mut file, flags, source_length, source_modified)?;
+        model.lods.push(lod);
+    }
+    Some(model)
+}
+
+fn write_cached_model<W: Write>(file: &mut W, model: &Model, flags: u32, source_length: u64, source_modified: u64) -> Result<()> {
+    let arrays = arrays(model);
+    let header = Header {
+        flags,
+        source_length,
+        source_modified,
+        hash: hash(&arrays),
+        num_vertices: model.num_vertices,
+        num_triangles: model.num_triangles,
+        num_lods: model.lods.len() as u32,
+        array_lengths: std::array::from_fn(|i| (arrays[i].len() / ELEMENT_SIZE) as u32)
+    };
+
+    write_header(file, &header)?;
+    for array in arrays {
+        file.write_all(array)?;
+        file.write_all(&[0u8; ALIGNMENT][..padding(array.len())])?;
+    }
+    Ok(())
+}
+
+fn write_model_cache(path: &Path, model: &Model, flags: u32, source_length: u64, source_modified: u64) -> Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    write_cached_model(&mut file, model, flags, source_length, source_modified)?;
+    for lod in &model.lods {
+        write_cached_model(&mut file, lod, flags, source_length, source_modified)?;
+    }
+    file.flush()
+}
+
+// loads the model from the cache next to `source` if it's up to date, otherwise calls `load` and caches the result;
+// `flags` should change whenever `load` would produce a different model from the same file
+pub fn cached_model<F: FnOnce(&Path) -> Model>(source: &Path, flags: u32, load: F) -> Model {
+    let (source_length, source_modified) = source_key(source);
+    let path = cache_path(source);
+
+    if let Some(model) = read_model_cache(&path, flags, source_length, source_modified) {
+        return model;
+    }
+
+    let model = load(source);
+    if let Err(e) = write_model_cache(&path, &model, flags, source_length, source_modified) {
+        // not fatal, it'll just be slow to load next time too
+        println!("Couldn't cache model to {}: {}", path.display(), e);
+    }
+    model
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::*, env::temp_dir};
+    use super::*;
+    use super::super::model::tests::*;
+    use super::super::primitives::*;
+
+    fn test_model() -> Model {
+        let mut model = with_colours_and_materials(uv_sphere(2.0, 32, 16));
+        model.generate_lods(0.25, 100);
+        model
+    }
+
+    // a stand-in source file in the temporary directory, unique to the test, and its cache
+    fn source_path(name: &str) -> PathBuf {
+        let path = temp_dir().join(format!("rustrast_{}_{}.obj", name, std::process::id()));
+        write(&path, b"v 0 0 0\n").unwrap();
+        path
+    }
+
+    fn cached(source: &Path, flags: u32, loads: &Cell<u32>) -> Model {
+        cached_model(source, flags, |_| {
+            loads.set(loads.get() + 1);
+            test_model()
+        })
+    }
+
+    #[test]
+    fn cached_models_read_back() {
+        let source = source_path("cached_models_read_back");
+        let loads = Cell::new(0);
+        let loaded = cached(&source, 1, &loads);
+        let read = cached(&source, 1, &loads);
+        assert_eq!(loads.get(), 1);
+
+        assert_same_model(&loaded, &read, 0.0);
+        assert!(!read.lods.is_empty());
+        assert_eq!(loaded.lods.len(), read.lods.len());
+        for (a, b) in loaded.lods.iter().zip(read.lods.iter()) {
+            assert_same_model(a, b, 0.0);
+        }
+
+        remove_file(cache_path(&source)).unwrap();
+        remove_file(&source).unwrap();
+    }
+
+    #[test]
+    fn cache_misses_when_anything_changes() {
+        let source = source_path("cache_misses_when_anything_changes");
+        let loads = Cell::new(0);
+        cached(&source, 1, &loads);
+
+        cached(&source, 2, &loads);
+        assert_eq!(loads.get(), 2, "different flags");
+        cached(&source, 2, &loads);
+        assert_eq!(loads.get(), 2, "same flags again");
+
+        OpenOptions::new().append(true).open(&source).unwrap().write_all(b"v 1 0 0\n").unwrap();
+        cached(&source, 2, &loads);
+        assert_eq!(loads.get(), 3, "different source length");
+
+        let modified = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        OpenOptions::new().write(true).open(&source).unwrap().set_modified(modified).unwrap();
+        cached(&source, 2, &loads);
+        assert_eq!(loads.get(), 4, "different source modification time");
+
+        let mut bytes = read(cache_path(&source)).unwrap();
+        bytes[HEADER_LENGTH] ^= 1;
+        write(cache_path(&source), bytes).unwrap();
+        cached(&source, 2, &loads);
+        assert_eq!(loads.get(), 5, "hash mismatch");
+
+        remove_file(cache_path(&source)).unwrap();
+        remove_file(&source).unwrap();
+    }
+}
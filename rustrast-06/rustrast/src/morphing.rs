@@ -0,0 +1,94 @@
+use core::arch::x86_64::*;
+
+use super::simd_vec::*;
+use super::model::*;
+
+// morph targets (blend shapes), added onto the model's positions and normals by their weights each frame, before
+// skinning or any transformation; dense targets are all blended in one pass over the vertices, then sparse ones are
+// gathered, added and written back to just the vertices they move
+
+// out is base plus each target's deltas times its weight
+#[target_feature(enable = "fma,avx,avx2")]
+unsafe fn avx2_blended(out: [&mut SimdVec<f32>; 3], base: [&SimdVec<f32>; 3], targets: &[(&MorphTarget, f32)], deltas: fn(&MorphTarget) -> &[SimdVec<f32>; 3], num_vertices: usize) {
+    let dense: Vec<(&[SimdVec<f32>; 3], f32)> = targets.iter().filter(|(target, _)| !target.is_sparse()).map(|&(target, weight)| (deltas(target), weight)).collect();
+
+    for (c, out) in out.into_iter().enumerate() {
+        let base_m256 = base[c].as_m256();
+        let dense_m256: Vec<(&[__m256], __m256)> = dense.iter().map(|&(ds, weight)| (ds[c].as_m256(), _mm256_set1_ps(weight))).collect();
+        let out_m256 = out.as_m256_mut();
+        for i in 0..base_m256.len() {
+            out_m256[i] = dense_m256.iter().fold(base_m256[i], |sum, &(ds, weight)| _mm256_fmadd_ps(weight, ds[i], sum));
+        }
+
+        // do any leftovers sequentially
+        for i in (base_m256.len() * 8)..num_vertices {
+            out[i] = dense.iter().fold(base[c][i], |sum, &(ds, weight)| weight.mul_add(ds[c][i], sum));
+        }
+
+        for &(target, weight) in targets.iter().filter(|(target, _)| target.is_sparse()) {
+            let ds = &deltas(target)[c];
+            let vertices = target.vertices.as_m256i();
+            let w = _mm256_set1_ps(weight);
+            let out = &mut out[..];
+            for (i, &vs) in vertices.iter().enumerate() {
+                let sum = _mm256_fmadd_ps(w, ds.as_m256()[i], _mm256_i32gather_ps(out.as_ptr(), vs, 4));
+                // there's no scatter in AVX2, but a target moves each vertex once so they can be written one by one
+                let mut lanes = [0.0f32; 8];
+                let mut indices = [0u32; 8];
+                _mm256_storeu_ps(lanes.as_mut_ptr(), sum);
+                _mm256_storeu_si256(indices.as_mut_ptr() as *mut __m256i, vs);
+                for (&v, &lane) in indices.iter().zip(lanes.iter()) {
+                    out[v as usize] = lane;
+                }
+            }
+            for i in (vertices.len() * 8)..target.vertices.len() {
+                let v = target.vertices[i] as usize;
+                out[v] = weight.mul_add(ds[i], out[v]);
+            }
+        }
+    }
+}
+
+#[target_feature(enable = "fma,avx,avx2")]
+unsafe fn avx2_normalise(ds: [&mut SimdVec<f32>; 3], num_vertices: usize) {
+    let tiny = _mm256_set1_ps(f32::MIN_POSITIVE);
+    let [xs, ys, zs] = ds;
+    let num_chunks = num_vertices / 8;
+    for ((x, y), z) in xs.as_m256_mut().iter_mut().zip(ys.as_m256_mut().iter_mut()).zip(zs.as_m256_mut().iter_mut()).take(num_chunks) {
+        let length_squared = _mm256_fmadd_ps(*x, *x, _mm256_fmadd_ps(*y, *y, _mm256_mul_ps(*z, *z)));
+        let scale = _mm256_rsqrt_ps(_mm256_max_ps(length_squared, tiny));
+        *x = _mm256_mul_ps(*x, scale);
+        *y = _mm256_mul_ps(*y, scale);
+        *z = _mm256_mul_ps(*z, scale);
+    }
+
+    // do any leftovers sequentially
+    for i in (num_chunks * 8)..num_vertices {
+        let length = (xs[i] * xs[i] + ys[i] * ys[i] + zs[i] * zs[i]).sqrt();
+        if length > 0.0 {
+            xs[i] /= length;
+            ys[i] /= length;
+            zs[i] /= length;
+        }
+    }
+}
+
+// the model's positions and normals with its morph targets blended in, with a weight for each target; normals_out is
+// only given when the model has normals, and they're renormalised if any targets change them
+pub fn avx2_morphed(positions_out: [&mut SimdVec<f32>; 3], normals_out: Option<[&mut SimdVec<f32>; 3]>, model: &Model, weights: &[f32]) {
+    let num_vertices = model.num_vertices as usize;
+    let targets: Vec<(&MorphTarget, f32)> = model.morph_targets.iter().zip(weights.iter().copied()).filter(|&(_, weight)| weight != 0.0).collect();
+
+    unsafe {
+        avx2_blended(positions_out, [&model.xs, &model.ys, &model.zs], &targets, |target| &target.position_deltas, num_vertices);
+        if let Some(normals_out) = normals_out {
+            let normals = [&model.vertex_normal_xs, &model.vertex_normal_ys, &model.vertex_normal_zs];
+            let targets: Vec<(&MorphTarget, f32)> = targets.into_iter().filter(|(target, _)| target.normal_deltas[0].len() > 0).collect();
+            let [nxs_out, nys_out, nzs_out] = normals_out;
+            avx2_blended([&mut *nxs_out, &mut *nys_out, &mut *nzs_out], normals, &targets, |target| &target.normal_deltas, num_vertices);
+            if !targets.is_empty() {
+                avx2_normalise([nxs_out, nys_out, nzs_out], num_vertices);
+            }
+        }
+    }
+}
@@ -1,9 +1,14 @@
 use lazy_static::*;
 use regex::*;
-use std::io::*;
+use std::{collections::HashMap, io::*, mem::take, sync::*};
+use once_cell::sync::Lazy;
+use scoped_threadpool::Pool;
 
 use super::simd_vec::*;
 use super::transformation::*;
+use super::model::*;
+use super::triangulation::*;
+use super::texture::*;
 
 // not-suitable-for-production Wavefront .obj parsing; panics on any error
 // https://en.wikipedia.org/wiki/Wavefront_.obj_file
@@ -23,111 +28,84 @@ impl FaceVertex {
             self.v as u32 - 1
         }
         else {
-            num_vertices + 1 - (self.v.abs() as u32)
+            num_vertices - (self.v.unsigned_abs() as u32)
         }
     }
 }
 
-#[derive(Clone, Copy)]
-struct Triangle {
-    pub v0: u32,
-    pub v1: u32,
-    pub v2: u32
+fn face_from_line<S: AsRef<str>>(line: S, num_vertices: u32) -> Vec<u32> {
+    line.as_ref().split(' ').skip(1).map(FaceVertex::from_face_line_component).map(|v| v.vertex_index(num_vertices)).collect()
 }
 
-impl Triangle {
-    fn from_face_line<S: AsRef<str>>(line: S, num_vertices: u32) -> Vec<Triangle> {
-        let vs: Vec<FaceVertex> = line.as_ref().split(' ').skip(1).map(FaceVertex::from_face_line_component).collect();
-
-        let mut triangles = Vec::new();
-
-        // fan triangulation, so requires convex polygons
-        let v0 = vs[0].vertex_index(num_vertices);
-        for iv1 in 1..(vs.len()-1) {
-            let v1 = vs[iv1].vertex_index(num_vertices);
-            let v2 = vs[iv1 + 1].vertex_index(num_vertices);
-            triangles.push(Triangle {v0, v1, v2});
-        }
-
-        triangles       
-    }
-
-    fn surface_normal(&self, xs: &SimdVec<f32>, ys: &SimdVec<f32>, zs: &SimdVec<f32>, ws: &SimdVec<f32>) -> CartesianVector {
-        let (v0, _) = HomogenousCoordinates{x: xs[self.v0 as usize], y: ys[self.v0 as usize], z: zs[self.v0 as usize], w: ws[self.v0 as usize]}.to_cartesian();
-        let (v1, _) = HomogenousCoordinates{x: xs[self.v1 as usize], y: ys[self.v1 as usize], z: zs[self.v1 as usize], w: ws[self.v1 as usize]}.to_cartesian();
-        let (v2, _) = HomogenousCoordinates{x: xs[self.v2 as usize], y: ys[self.v2 as usize], z: zs[self.v2 as usize], w: ws[self.v2 as usize]}.to_cartesian();
-
-        let edge1 = v1 - v0;
-        let edge2 = v2 - v0;
-
-        edge1.cross_product(&edge2)
-    }
-}
-
-pub struct Model {
-    pub num_vertices: u32,
-    pub xs: SimdVec<f32>,
-    pub ys: SimdVec<f32>,
-    pub zs: SimdVec<f32>,
-    pub ws: SimdVec<f32>,
-    pub num_triangles: u32,
-    pub trianglev0s: SimdVec<u32>,
-    pub trianglev1s: SimdVec<u32>,
-    pub trianglev2s: SimdVec<u32>,
-    pub surface_normal_xs: SimdVec<f32>,
-    pub surface_normal_ys: SimdVec<f32>,
-    pub surface_normal_zs: SimdVec<f32>
+lazy_static! {
+    static ref LINE: Regex = Regex::new(r"(\S+).*").unwrap();
+    static ref VERTEX_LINE: Regex = Regex::new(r"v\s+(\S+)\s+(\S+)\s+(\S+)([^#]*)").unwrap();
 }
 
-impl Model {
-    #[allow(dead_code)]
-    pub fn homogenous_coordinates(&self, i: u32) -> HomogenousCoordinates {
-        HomogenousCoordinates { x: self.xs[i as usize], y: self.ys[i as usize], z: self.zs[i as usize], w: self.ws[i as usize] }
-    }
-
-    #[allow(dead_code)]
-    pub fn surface_normal(&self, it: u32) -> CartesianVector {
-        CartesianVector { x: self.surface_normal_xs[it as usize], y: self.surface_normal_ys[it as usize], z: self.surface_normal_zs[it as usize] }
+// what can follow x, y and z on a vertex line: nothing, w, the r g b that scanners and MeshLab write, or w r g b;
+// colours are meant for display, so are taken to be sRGB, and anything else is ignored as it was before
+fn vertex_extras(extras: &[f32]) -> (f32, Option<[f32; 3]>) {
+    let colour = |rgb: &[f32]| Some([rgb[0], rgb[1], rgb[2]].map(srgb_fraction_to_linear));
+    match extras.len() {
+        0 => (1.0, None),
+        3 => (1.0, colour(extras)),
+        4 => (extras[0], colour(&extras[1..])),
+        _ => (extras[0], None)
     }
 }
 
-lazy_static! {
-    static ref LINE: Regex = Regex::new(r"(\S+).*").unwrap();
-    static ref VERTEX_LINE: Regex = Regex::new(r"v\s+(\S+)\s+(\S+)\s+(\S+)(?:\s+(\S+))?\s*").unwrap();
-}
-
-impl HomogenousCoordinates {
-    fn from_vertex_line<S: AsRef<str>>(line: S) -> HomogenousCoordinates {
-        let captures = VERTEX_LINE.captures(line.as_ref()).unwrap();
-        let x = captures[1].parse::<f32>().unwrap();
-        let y = captures[2].parse::<f32>().unwrap();
-        let z = captures[3].parse::<f32>().unwrap();
-        let w = captures.get(4).map(|m| m.as_str().parse::<f32>().unwrap());
+fn from_vertex_line<S: AsRef<str>>(line: S) -> (HomogenousCoordinates, Option<[f32; 3]>) {
+    let captures = VERTEX_LINE.captures(line.as_ref()).unwrap();
+    let x = captures[1].parse::<f32>().unwrap();
+    let y = captures[2].parse::<f32>().unwrap();
+    let z = captures[3].parse::<f32>().unwrap();
+    let extras: Vec<f32> = captures[4].split_whitespace().map(|e| e.parse::<f32>().unwrap()).collect();
+    let (w, colour) = vertex_extras(&extras);
 
-        HomogenousCoordinates { x, y, z, w: w.unwrap_or(1.0) }
-    }
+    (HomogenousCoordinates { x, y, z, w }, colour)
 }
 
-pub fn read_obj<R: Read>(file: R) -> Model {
+// vertices without a colour in a file that has some are white, so they just have the material's colour
+const NO_VERTEX_COLOUR: [f32; 3] = [1.0; 3];
+
+// the original line-by-line implementation, kept for comparison with the parallel one; it only reads positions,
+// vertex colours and faces, ignoring texture coordinates, normals and materials
+pub fn read_obj_regex<R: Read>(file: R) -> Model {
     let mut xs = SimdVec::new();
     let mut ys = SimdVec::new();
     let mut zs = SimdVec::new();
     let mut ws = SimdVec::new();
+    let mut colours: [SimdVec<f32>; 3] = [SimdVec::new(), SimdVec::new(), SimdVec::new()];
     let mut triangles = Vec::new();
+    let mut num_polygons = 0;
+    let mut num_non_convex = 0;
 
     for line in BufReader::new(file).lines() {
         if let Ok(line) = line {
             if let Some(captures) = LINE.captures(&line) {
                 match &captures[1] {
                     "v" => {
-                        let vertex = HomogenousCoordinates::from_vertex_line(&line);
+                        let (vertex, colour) = from_vertex_line(&line);
+                        if colour.is_some() || colours[0].len() > 0 {
+                            while colours[0].len() < xs.len() {
+                                (0..3).for_each(|c| colours[c].push(NO_VERTEX_COLOUR[c]));
+                            }
+                            let colour = colour.unwrap_or(NO_VERTEX_COLOUR);
+                            (0..3).for_each(|c| colours[c].push(colour[c]));
+                        }
                         xs.push(vertex.x);
                         ys.push(vertex.y);
                         zs.push(vertex.z);
                         ws.push(vertex.w);
                     }
                     "f" => {
-                        triangles.extend(Triangle::from_face_line(&line, xs.len() as u32));
+                        let face = face_from_line(&line, xs.len() as u32);
+                        if face.len() > 3 {
+                            num_polygons += 1;
+                        }
+                        if !triangulate_face(&face, &xs[..], &ys[..], &zs[..], &ws[..], &mut triangles) {
+                            num_non_convex += 1;
+                        }
                     }
                     _ => ()
                 }
@@ -135,20 +113,653 @@ pub fn read_obj<R: Read>(file: R) -> Model {
         }
     }
 
-    let trianglev0s = triangles.iter().map(|t| t.v0).collect();
-    let trianglev1s = triangles.iter().map(|t| t.v1).collect();
-    let trianglev2s = triangles.iter().map(|t| t.v2).collect();
+    report_non_convex(num_non_convex, num_polygons);
+
+    let trianglev0s = triangles.iter().map(|t| t[0]).collect();
+    let trianglev1s = triangles.iter().map(|t| t[1]).collect();
+    let trianglev2s = triangles.iter().map(|t| t[2]).collect();
+
+    let mut model = Model::new(xs, ys, zs, ws, trianglev0s, trianglev1s, trianglev2s);
+    let [colour_rs, colour_gs, colour_bs] = colours;
+    model.vertex_colour_rs = colour_rs;
+    model.vertex_colour_gs = colour_gs;
+    model.vertex_colour_bs = colour_bs;
+    model
+}
+
+// a faster parser that splits the file into chunks on line boundaries and parses them in parallel, in two passes:
+// the first counts the vertices and triangles in each chunk so the second knows where to write them and can resolve
+// relative vertex indices, which count back from the last vertex before the face wherever that is in the file; faces
+// with more than three vertices are set aside and triangulated in a third pass, once every vertex position is known
+
+// my machine has four cores; parsing is CPU bound once the file is in memory
+static NUM_PARSE_THREADS: u32 = 4;
+static PARSE_WORKERS: Lazy<Mutex<Pool>> = Lazy::new(|| Mutex::new(Pool::new(NUM_PARSE_THREADS)));
+
+fn is_space(b: u8) -> bool {
+    b == b' ' || b == b'\t' || b == b'\r'
+}
+
+// iterates over the whitespace separated tokens of a line
+struct Tokens<'a> {
+    line: &'a [u8],
+    position: usize
+}
+
+impl<'a> Tokens<'a> {
+    fn new(line: &'a [u8]) -> Self {
+        Tokens { line, position: 0 }
+    }
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        let line = self.line;
+        while self.position < line.len() && is_space(line[self.position]) {
+            self.position += 1;
+        }
+        if self.position == line.len() || line[self.position] == b'#' {
+            return None;
+        }
+
+        let start = self.position;
+        while self.position < line.len() && !is_space(line[self.position]) {
+            self.position += 1;
+        }
+        Some(&line[start..self.position])
+    }
+}
+
+fn lines(chunk: &[u8]) -> impl Iterator<Item = &[u8]> {
+    chunk.split(|&b| b == b'\n')
+}
+
+fn parse_f32(token: &[u8]) -> f32 {
+    // tokens are ASCII, and the standard library gets the rounding right
+    std::str::from_utf8(token).unwrap().parse::<f32>().unwrap()
+}
+
+// a 1-based index that can also count back from the end of its list, as u32::MAX if it's missing or its list is empty
+fn parse_index(digits: &[u8], token: &[u8], count: u32) -> u32 {
+    let (negative, digits) = match digits.first() {
+        Some(b'-') => (true, &digits[1..]),
+        _ => (false, digits)
+    };
+    if digits.is_empty() || count == 0 {
+        return u32::MAX;
+    }
+
+    let mut index: u32 = 0;
+    for &b in digits {
+        assert!(b.is_ascii_digit(), "invalid vertex index {}", String::from_utf8_lossy(token));
+        index = index * 10 + (b - b'0') as u32;
+    }
+
+    if negative {
+        count - index
+    }
+    else {
+        index - 1
+    }
+}
+
+// the position, texture coordinate and normal indices from v, v/vt, v//vn or v/vt/vn; `counts` are how many of each
+// there are before the face, which relative indices count back from
+fn parse_face_vertex(token: &[u8], counts: [u32; 3]) -> [u32; 3] {
+    let mut parts = token.splitn(3, |&b| b == b'/');
+    counts.map(|count| parse_index(parts.next().unwrap_or(b""), token, count))
+}
+
+#[derive(Clone, Default)]
+struct ChunkCounts {
+    num_vertices: u32,
+    num_triangles: u32,
+    // so the colour arrays are only allocated if the file has any
+    num_coloured_vertices: u32,
+    num_texture_coordinates: u32,
+    num_normals: u32,
+    // the names on each usemtl line, in order
+    materials: Vec<Vec<u8>>
+}
+
+fn count_chunk(chunk: &[u8]) -> ChunkCounts {
+    let mut counts = ChunkCounts::default();
+    for line in lines(chunk) {
+        let mut tokens = Tokens::new(line);
+        match tokens.next() {
+            Some(b"v") => {
+                counts.num_vertices += 1;
+                if tokens.count() >= 6 {
+                    counts.num_coloured_vertices += 1;
+                }
+            }
+            Some(b"vt") => counts.num_texture_coordinates += 1,
+            Some(b"vn") => counts.num_normals += 1,
+            // triangulating a polygon always gives two fewer triangles than vertices
+            Some(b"f") => counts.num_triangles += (tokens.count() as u32).saturating_sub(2),
+            Some(b"usemtl") => counts.materials.push(tokens.next().unwrap_or(b"").to_vec()),
+            _ => ()
+        }
+    }
+    counts
+}
+
+// faces with more than three vertices, to be triangulated once all the vertices have been parsed
+#[derive(Default)]
+struct ChunkPolygons {
+    // index of the first triangle in the chunk and the number of vertices
+    polygons: Vec<(u32, u32)>,
+    // the position, texture coordinate and normal indices of each of their vertices
+    vertices: Vec<[u32; 3]>
+}
+
+struct ChunkOut<'a> {
+    xs: &'a mut [f32],
+    ys: &'a mut [f32],
+    zs: &'a mut [f32],
+    ws: &'a mut [f32],
+    // None if the file has no vertex colours
+    colours: Option<[&'a mut [f32]; 3]>,
+    texture_coordinates: [&'a mut [f32]; 2],
+    normals: [&'a mut [f32]; 3],
+    v0s: &'a mut [u32],
+    v1s: &'a mut [u32],
+    v2s: &'a mut [u32],
+    // the texture coordinate and normal indices of each triangle's vertices, in the same order as v0s, v1s and v2s;
+    // None if the file has no texture coordinates or normals
+    corner_texture_coordinates: Option<[&'a mut [u32]; 3]>,
+    corner_normals: Option<[&'a mut [u32]; 3]>,
+    // None if the file has no usemtl lines
+    materials: Option<&'a mut [u32]>,
+    polygons: &'a mut ChunkPolygons
+}
+
+// where in the file a chunk starts
+#[derive(Clone, Copy, Default)]
+struct ChunkStart {
+    // how many positions, texture coordinates and normals came before it
+    counts: [u32; 3],
+    // the material in use from the last usemtl before it
+    material: u32
+}
+
+fn parse_chunk(chunk: &[u8], start: ChunkStart, material_indices: &HashMap<Vec<u8>, u32>, mut out: ChunkOut) {
+    let mut iv = 0;
+    let mut ivt = 0;
+    let mut ivn = 0;
+    let mut it = 0;
+    let mut material = start.material;
+    let mut face = Vec::new();
+
+    for line in lines(chunk) {
+        let mut tokens = Tokens::new(line);
+        match tokens.next() {
+            Some(b"v") => {
+                out.xs[iv] = parse_f32(tokens.next().unwrap());
+                out.ys[iv] = parse_f32(tokens.next().unwrap());
+                out.zs[iv] = parse_f32(tokens.next().unwrap());
+                // one more than vertex_extras understands is enough to know to ignore them
+                let mut extras = [0.0; 5];
+                let mut num_extras = 0;
+                for token in tokens.take(extras.len()) {
+                    extras[num_extras] = parse_f32(token);
+                    num_extras += 1;
+                }
+                let (w, colour) = vertex_extras(&extras[..num_extras]);
+                out.ws[iv] = w;
+                if let Some(colours) = &mut out.colours {
+                    let colour = colour.unwrap_or(NO_VERTEX_COLOUR);
+                    (0..3).for_each(|c| colours[c][iv] = colour[c]);
+                }
+                iv += 1;
+            }
+            Some(b"vt") => {
+                // w is ignored, and v is left as it is in the file until it's known which vertices it belongs to
+                for texture_coordinate in out.texture_coordinates.iter_mut() {
+                    texture_coordinate[ivt] = tokens.next().map_or(0.0, parse_f32);
+                }
+                ivt += 1;
+            }
+            Some(b"vn") => {
+                for normal in out.normals.iter_mut() {
+                    normal[ivn] = parse_f32(tokens.next().unwrap());
+                }
+                ivn += 1;
+            }
+            Some(b"usemtl") => material = material_indices[tokens.next().unwrap_or(b"")],
+            Some(b"f") => {
+                let [cv, cvt, cvn] = start.counts;
+                let counts = [cv + iv as u32, cvt + ivt as u32, cvn + ivn as u32];
+                face.clear();
+                face.extend(tokens.map(|token| parse_face_vertex(token, counts)));
+
+                if face.len() == 3 {
+                    out.v0s[it] = face[0][0];
+                    out.v1s[it] = face[1][0];
+                    out.v2s[it] = face[2][0];
+                    if let Some(corners) = &mut out.corner_texture_coordinates {
+                        (0..3).for_each(|i| corners[i][it] = face[i][1]);
+                    }
+                    if let Some(corners) = &mut out.corner_normals {
+                        (0..3).for_each(|i| corners[i][it] = face[i][2]);
+                    }
+                }
+                else if face.len() > 3 {
+                    // leaves space for the triangles
+                    out.polygons.polygons.push((it as u32, face.len() as u32));
+                    out.polygons.vertices.extend_from_slice(&face);
+                }
+
+                let num_triangles = face.len().saturating_sub(2);
+                if let Some(materials) = &mut out.materials {
+                    materials[it..it + num_triangles].fill(material);
+                }
+                it += num_triangles;
+            }
+            _ => ()
+        }
+    }
+}
+
+// the triangle arrays a chunk fills in; the corner arrays are empty if the file doesn't need them
+struct ChunkTriangles<'a> {
+    vs: [&'a mut [u32]; 3],
+    texture_coordinates: [&'a mut [u32]; 3],
+    normals: [&'a mut [u32]; 3]
+}
+
+// fills in the triangles left out by `parse_chunk`, returning the number of polygons that weren't convex
+fn triangulate_chunk(polygons: &ChunkPolygons, positions: [&[f32]; 4], out: ChunkTriangles) -> usize {
+    let [xs, ys, zs, ws] = positions;
+    let mut face = Vec::new();
+    let mut triangles = Vec::new();
+    let mut num_non_convex = 0;
+    let mut start = 0;
+
+    for &(first_triangle, num_vertices) in &polygons.polygons {
+        let vertices = &polygons.vertices[start..start + num_vertices as usize];
+        start += num_vertices as usize;
+
+        face.clear();
+        face.extend(vertices.iter().map(|v| v[0]));
+        triangles.clear();
+        if !triangulate_corners(&face, xs, ys, zs, ws, &mut triangles) {
+            num_non_convex += 1;
+        }
+        for (i, t) in triangles.iter().enumerate() {
+            let it = first_triangle as usize + i;
+            for (c, &corner) in t.iter().enumerate() {
+                let [v, vt, vn] = vertices[corner];
+                out.vs[c][it] = v;
+                if !out.texture_coordinates[c].is_empty() {
+                    out.texture_coordinates[c][it] = vt;
+                }
+                if !out.normals[c].is_empty() {
+                    out.normals[c][it] = vn;
+                }
+            }
+        }
+    }
+
+    num_non_convex
+}
+
+// splits at the first line break after each of `num_chunks` roughly equal divisions
+fn split_lines(bytes: &[u8], num_chunks: usize) -> Vec<&[u8]> {
+    let mut chunks = Vec::with_capacity(num_chunks);
+    let mut start = 0;
+    for i in 1..=num_chunks {
+        let mut end = (bytes.len() * i / num_chunks).max(start);
+        while end > 0 && end < bytes.len() && bytes[end - 1] != b'\n' {
+            end += 1;
+        }
+        chunks.push(&bytes[start..end]);
+        start = end;
+    }
+    chunks
+}
+
+// the front `length` elements of what's left of an array, for handing a chunk its part of it
+fn take_front<'a, T>(remaining: &mut &'a mut [T], length: usize) -> &'a mut [T] {
+    let (front, back) = take(remaining).split_at_mut(length);
+    *remaining = back;
+    front
+}
+
+// the same for arrays per triangle that are empty if the file doesn't need them
+fn take_corners<'a>(remaining: &mut &'a mut [u32], num_triangles: usize) -> &'a mut [u32] {
+    let length = if remaining.is_empty() { 0 } else { num_triangles };
+    take_front(remaining, length)
+}
+
+// everything in the file, with the attribute indices of each face vertex; those are empty if the file has none
+struct ParsedObj {
+    positions: [SimdVec<f32>; 4],
+    colours: [SimdVec<f32>; 3],
+    texture_coordinates: [SimdVec<f32>; 2],
+    normals: [SimdVec<f32>; 3],
+    triangles: [SimdVec<u32>; 3],
+    corner_texture_coordinates: [SimdVec<u32>; 3],
+    corner_normals: [SimdVec<u32>; 3],
+    materials: SimdVec<u32>
+}
+
+impl ParsedObj {
+    // OBJ gives each face vertex separate position, texture coordinate and normal indices, whereas a model's vertices
+    // have all three; where they're the same for every face vertex, as in files from write_obj, the attributes are
+    // per vertex already, otherwise each different combination becomes a vertex of its own and positions no face uses
+    // are dropped
+    fn into_model(self) -> Model {
+        let ParsedObj { positions, colours, texture_coordinates, normals, triangles, corner_texture_coordinates, corner_normals, materials } = self;
+        let num_vertices = positions[0].len();
+        let num_triangles = triangles[0].len();
+        let used = |corners: &[SimdVec<u32>; 3]| corners.iter().any(|c| c[..].iter().any(|&i| i != u32::MAX));
+        let has_texture_coordinates = used(&corner_texture_coordinates);
+        let has_normals = used(&corner_normals);
+        let same_indices = |corners: &[SimdVec<u32>; 3], count: usize| count == num_vertices
+            && (0..3).all(|c| corners[c][..] == triangles[c][..]);
+        let per_vertex = (!has_texture_coordinates || same_indices(&corner_texture_coordinates, texture_coordinates[0].len()))
+            && (!has_normals || same_indices(&corner_normals, normals[0].len()));
+
+        let [xs, ys, zs, ws] = positions;
+        let [trianglev0s, trianglev1s, trianglev2s] = triangles;
+        let mut model = if per_vertex {
+            let mut model = Model::new(xs, ys, zs, ws, trianglev0s, trianglev1s, trianglev2s);
+            [model.vertex_colour_rs, model.vertex_colour_gs, model.vertex_colour_bs] = colours;
+            if has_texture_coordinates {
+                let [us, vs] = texture_coordinates;
+                model.texture_us = us;
+                // OBJ texture coordinates start at the bottom left
+                model.texture_vs = vs[..].iter().map(|v| 1.0 - v).collect();
+            }
+            if has_normals {
+                [model.vertex_normal_xs, model.vertex_normal_ys, model.vertex_normal_zs] = normals;
+            }
+            model
+        }
+        else {
+            // done sequentially, as it's a hash lookup per face vertex; missing attributes are zero
+            let triangles = [trianglev0s, trianglev1s, trianglev2s];
+            let mut indices: HashMap<[u32; 3], u32> = HashMap::new();
+            let mut sources: Vec<[u32; 3]> = Vec::new();
+            let mut new_triangles: [SimdVec<u32>; 3] = [SimdVec::new(), SimdVec::new(), SimdVec::new()];
+            for it in 0..num_triangles {
+                for c in 0..3 {
+                    let source = [
+                        triangles[c][it],
+                        if has_texture_coordinates { corner_texture_coordinates[c][it] } else { u32::MAX },
+                        if has_normals { corner_normals[c][it] } else { u32::MAX }];
+                    let index = *indices.entry(source).or_insert_with(|| {
+                        sources.push(source);
+                        sources.len() as u32 - 1
+                    });
+                    new_triangles[c].push(index);
+                }
+            }
+
+            let gather = |values: &SimdVec<f32>, attribute: usize, transform: fn(f32) -> f32| -> SimdVec<f32> {
+                sources.iter().map(|s| if s[attribute] == u32::MAX { 0.0 } else { transform(values[s[attribute] as usize]) }).collect()
+            };
+            let same: fn(f32) -> f32 = |v| v;
+            let [xs, ys, zs, ws] = [&xs, &ys, &zs, &ws].map(|p| gather(p, 0, same));
+            let [v0s, v1s, v2s] = new_triangles;
+            let mut model = Model::new(xs, ys, zs, ws, v0s, v1s, v2s);
+            if colours[0].len() > 0 {
+                [model.vertex_colour_rs, model.vertex_colour_gs, model.vertex_colour_bs] = colours.each_ref().map(|c| gather(c, 0, same));
+            }
+            if has_texture_coordinates {
+                model.texture_us = gather(&texture_coordinates[0], 1, same);
+                model.texture_vs = gather(&texture_coordinates[1], 1, |v| 1.0 - v);
+            }
+            if has_normals {
+                [model.vertex_normal_xs, model.vertex_normal_ys, model.vertex_normal_zs] = normals.each_ref().map(|n| gather(n, 2, same));
+            }
+            model
+        };
+        model.triangle_materials = materials;
+        model
+    }
+}
+
+pub fn read_obj<R: Read>(mut file: R) -> Model {
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes).unwrap();
+
+    let chunks = split_lines(&bytes, NUM_PARSE_THREADS as usize);
+    let mut counts = vec![ChunkCounts::default(); chunks.len()];
+
+    let mut pool = PARSE_WORKERS.lock().unwrap();
+    pool.scoped(|scope| {
+        for (chunk, count) in chunks.iter().zip(counts.iter_mut()) {
+            scope.execute(move || *count = count_chunk(chunk));
+        }
+    });
+
+    // materials are numbered in the order they're first named, and faces before the first usemtl use the first one
+    let mut material_indices: HashMap<Vec<u8>, u32> = HashMap::new();
+    let mut starts = Vec::with_capacity(chunks.len());
+    let mut start = ChunkStart::default();
+    for count in &counts {
+        starts.push(start);
+        for name in &count.materials {
+            let next = material_indices.len() as u32;
+            start.material = *material_indices.entry(name.clone()).or_insert(next);
+        }
+        start.counts[0] += count.num_vertices;
+        start.counts[1] += count.num_texture_coordinates;
+        start.counts[2] += count.num_normals;
+    }
+
+    let [num_vertices, num_texture_coordinates, num_normals] = start.counts.map(|c| c as usize);
+    let num_triangles = counts.iter().map(|c| c.num_triangles as usize).sum();
+    let has_colours = counts.iter().any(|c| c.num_coloured_vertices > 0);
+    let num_corners = |has: bool| if has { num_triangles } else { 0 };
+    let mut parsed = ParsedObj {
+        positions: [0, 1, 2, 3].map(|_| SimdVec::zeroed(num_vertices)),
+        colours: [0, 1, 2].map(|_| SimdVec::zeroed(if has_colours { num_vertices } else { 0 })),
+        texture_coordinates: [0, 1].map(|_| SimdVec::zeroed(num_texture_coordinates)),
+        normals: [0, 1, 2].map(|_| SimdVec::zeroed(num_normals)),
+        triangles: [0, 1, 2].map(|_| SimdVec::zeroed(num_triangles)),
+        corner_texture_coordinates: [0, 1, 2].map(|_| SimdVec::zeroed(num_corners(num_texture_coordinates > 0))),
+        corner_normals: [0, 1, 2].map(|_| SimdVec::zeroed(num_corners(num_normals > 0))),
+        materials: SimdVec::zeroed(num_corners(!material_indices.is_empty()))
+    };
+    let mut polygons: Vec<ChunkPolygons> = chunks.iter().map(|_| ChunkPolygons::default()).collect();
+
+    pool.scoped(|scope| {
+        let mut positions_rem = parsed.positions.each_mut().map(|p| &mut p[..]);
+        let mut colours_rem = parsed.colours.each_mut().map(|c| &mut c[..]);
+        let mut texture_coordinates_rem = parsed.texture_coordinates.each_mut().map(|t| &mut t[..]);
+        let mut normals_rem = parsed.normals.each_mut().map(|n| &mut n[..]);
+        let mut triangles_rem = parsed.triangles.each_mut().map(|t| &mut t[..]);
+        let mut corner_texture_coordinates_rem = parsed.corner_texture_coordinates.each_mut().map(|c| &mut c[..]);
+        let mut corner_normals_rem = parsed.corner_normals.each_mut().map(|c| &mut c[..]);
+        let mut materials_rem = &mut parsed.materials[..];
+        let material_indices = &material_indices;
+
+        for (((chunk, count), chunk_polygons), &start) in chunks.iter().zip(counts.iter()).zip(polygons.iter_mut()).zip(starts.iter()) {
+            let nv = count.num_vertices as usize;
+            let nt = count.num_triangles as usize;
+            let [xs, ys, zs, ws] = positions_rem.each_mut().map(|p| take_front(p, nv));
+            let colours = colours_rem.each_mut().map(|c| take_front(c, if has_colours { nv } else { 0 }));
+            let texture_coordinates = texture_coordinates_rem.each_mut().map(|t| take_front(t, count.num_texture_coordinates as usize));
+            let normals = normals_rem.each_mut().map(|n| take_front(n, count.num_normals as usize));
+            let [v0s, v1s, v2s] = triangles_rem.each_mut().map(|t| take_front(t, nt));
+            let corner_texture_coordinates = corner_texture_coordinates_rem.each_mut().map(|c| take_corners(c, nt));
+            let corner_normals = corner_normals_rem.each_mut().map(|c| take_corners(c, nt));
+            let materials = take_corners(&mut materials_rem, nt);
+
+            let out = ChunkOut {
+                xs, ys, zs, ws,
+                colours: has_colours.then_some(colours),
+                texture_coordinates,
+                normals,
+                v0s, v1s, v2s,
+                corner_texture_coordinates: (num_texture_coordinates > 0).then_some(corner_texture_coordinates),
+                corner_normals: (num_normals > 0).then_some(corner_normals),
+                materials: (!material_indices.is_empty()).then_some(materials),
+                polygons: chunk_polygons
+            };
+            scope.execute(move || parse_chunk(chunk, start, material_indices, out));
+        }
+    });
+
+    let num_polygons: usize = polygons.iter().map(|p| p.polygons.len()).sum();
+    if num_polygons > 0 {
+        let mut num_non_convex = vec![0; chunks.len()];
+        let positions = parsed.positions.each_ref().map(|p| &p[..]);
+
+        pool.scoped(|scope| {
+            let mut triangles_rem = parsed.triangles.each_mut().map(|t| &mut t[..]);
+            let mut corner_texture_coordinates_rem = parsed.corner_texture_coordinates.each_mut().map(|c| &mut c[..]);
+            let mut corner_normals_rem = parsed.corner_normals.each_mut().map(|c| &mut c[..]);
+
+            for ((count, chunk_polygons), chunk_non_convex) in counts.iter().zip(polygons.iter()).zip(num_non_convex.iter_mut()) {
+                let nt = count.num_triangles as usize;
+                let out = ChunkTriangles {
+                    vs: triangles_rem.each_mut().map(|t| take_front(t, nt)),
+                    texture_coordinates: corner_texture_coordinates_rem.each_mut().map(|c| take_corners(c, nt)),
+                    normals: corner_normals_rem.each_mut().map(|c| take_corners(c, nt))
+                };
+                scope.execute(move || *chunk_non_convex = triangulate_chunk(chunk_polygons, positions, out));
+            }
+        });
+
+        report_non_convex(num_non_convex.iter().sum(), num_polygons);
+    }
+
+    parsed.into_model()
+}
+
+// writes positions, with any vertex colours after them, any vertex normals and texture coordinates, and the triangles,
+// switching material with usemtl whenever it changes; there's no .mtl file, so the materials are just named after their
+// index, and they're all named once up front as read_obj numbers them in the order they're first used
+pub fn write_obj<W: Write>(model: &Model, file: W) {
+    let mut file = BufWriter::new(file);
+    let has_normals = model.vertex_normal_xs.len() > 0;
+    let has_texture_coordinates = model.texture_us.len() > 0;
+    let has_colours = model.vertex_colour_rs.len() > 0;
+
+    for i in 0..model.num_vertices as usize {
+        write!(file, "v {} {} {}", model.xs[i], model.ys[i], model.zs[i]).unwrap();
+        if model.ws[i] != 1.0 {
+            write!(file, " {}", model.ws[i]).unwrap();
+        }
+        if has_colours {
+            let colour = [model.vertex_colour_rs[i], model.vertex_colour_gs[i], model.vertex_colour_bs[i]].map(linear_to_srgb_fraction);
+            write!(file, " {} {} {}", colour[0], colour[1], colour[2]).unwrap();
+        }
+        writeln!(file).unwrap();
+    }
+    if has_texture_coordinates {
+        // OBJ texture coordinates start at the bottom left
+        for i in 0..model.num_vertices as usize {
+            writeln!(file, "vt {} {}", model.texture_us[i], 1.0 - model.texture_vs[i]).unwrap();
+        }
+    }
+    if has_normals {
+        for i in 0..model.num_vertices as usize {
+            writeln!(file, "vn {} {} {}", model.vertex_normal_xs[i], model.vertex_normal_ys[i], model.vertex_normal_zs[i]).unwrap();
+        }
+    }
+
+    // every attribute has the same index as the position
+    let face_vertex = |v: u32| -> String {
+        let v = v + 1;
+        match (has_texture_coordinates, has_normals) {
+            (false, false) => format!("{}", v),
+            (true, false) => format!("{}/{}", v, v),
+            (false, true) => format!("{}//{}", v, v),
+            (true, true) => format!("{}/{}/{}", v, v, v)
+        }
+    };
 
-    let mut surface_normal_xs = SimdVec::with_capacity(triangles.len());
-    let mut surface_normal_ys = SimdVec::with_capacity(triangles.len());
-    let mut surface_normal_zs = SimdVec::with_capacity(triangles.len());
+    let mut material = None;
+    if let Some(&last_material) = model.triangle_materials[..].iter().max() {
+        for m in 0..=last_material {
+            writeln!(file, "usemtl material{}", m).unwrap();
+        }
+    }
+    for it in 0..model.num_triangles {
+        if model.triangle_materials.len() > 0 && material != Some(model.triangle_material(it)) {
+            material = Some(model.triangle_material(it));
+            writeln!(file, "usemtl material{}", model.triangle_material(it)).unwrap();
+        }
+        let it = it as usize;
+        writeln!(file, "f {} {} {}", face_vertex(model.trianglev0s[it]), face_vertex(model.trianglev1s[it]), face_vertex(model.trianglev2s[it])).unwrap();
+    }
 
-    for triangle in &triangles {
-        let surface_normal = triangle.surface_normal(&xs, &ys, &zs, &ws);
-        surface_normal_xs.push(surface_normal.x);
-        surface_normal_ys.push(surface_normal.y);
-        surface_normal_zs.push(surface_normal.z);
+    file.flush().unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::model::tests::*;
+    use super::super::primitives::*;
+
+    // long enough to be split into a chunk per parse thread, with w, colours, comments, quads, a non-convex polygon and
+    // relative indices reaching back into earlier chunks
+    const FIXTURE: &str = "\
+# a fixture for the OBJ parsers
+v 0.0 0.0 0.0
+v 1.0 0.0 0.0 1.0
+v 1.0 1.0 0.0 0.5 0.25 0.75
+v 0.0 1.0 0.0 # a comment
+f 1 2 3
+f 1 3 4
+v 0.0 0.0 1.0
+v 1.0 0.0 1.0 1.0 1.0 1.0 1.0
+v 1.0 1.0 1.0
+v 0.0 1.0 1.0
+f -4 -3 -2 -1
+f 1/1 2/2 6/3 5/4
+v 2.0 0.0 0.0
+v 3.0 0.0 0.0
+v 3.0 2.0 0.0
+v 2.5 0.5 0.0
+v 2.0 2.0 0.0
+f -5 -4 -3 -2 -1
+f -9 -10 -6
+f 4//1 3//1 7//1 8//1
+";
+
+    // the regex parser doesn't read texture coordinates, normals or materials, so this only compares positions,
+    // colours and faces; the fixture has no vt, vn or usemtl lines
+    #[test]
+    fn parallel_parser_matches_regex_parser_on_positions_and_colours() {
+        let regex = read_obj_regex(FIXTURE.as_bytes());
+        let parallel = read_obj(FIXTURE.as_bytes());
+        assert_eq!(regex.num_triangles, 12);
+        assert_same_model(&regex, &parallel, 0.0);
+    }
+
+    #[test]
+    fn written_models_read_back() {
+        let model = with_colours_and_materials(torus(1.0, 0.25, 12, 8));
+        let mut bytes = Vec::new();
+        write_obj(&model, &mut bytes);
+        // colours go through sRGB and texture coordinates are flipped, which can each lose a bit
+        assert_same_model(&model, &read_obj(&bytes[..]), 1e-5);
     }
-    
-    Model { num_vertices: xs.len() as u32, xs, ys, zs, ws, num_triangles: triangles.len() as u32, trianglev0s, trianglev1s, trianglev2s, surface_normal_xs, surface_normal_ys, surface_normal_zs }
-}
\ No newline at end of file
+
+    #[test]
+    fn face_vertices_with_different_attributes_become_different_vertices() {
+        let model = read_obj(include_str!("cube.obj").as_bytes());
+        // each corner of the cube is on three faces, with a different normal on each
+        assert_eq!(model.num_vertices, 24);
+        assert_eq!(model.num_triangles, 12);
+        assert_eq!(model.texture_us.len(), 24);
+        assert!(model.triangle_materials[..].iter().all(|&m| m == 0));
+        for it in 0..model.num_triangles as usize {
+            let normal = |v: u32| [&model.vertex_normal_xs, &model.vertex_normal_ys, &model.vertex_normal_zs].map(|n| n[v as usize]);
+            assert_eq!(normal(model.trianglev0s[it]), normal(model.trianglev1s[it]));
+            assert_eq!(normal(model.trianglev0s[it]), normal(model.trianglev2s[it]));
+        }
+        // the first face's first vertex is 2/1/1
+        assert_eq!([model.xs[0], model.ys[0], model.zs[0]], [0.07, -0.07, 0.07]);
+        assert_eq!([model.texture_us[0], model.texture_vs[0]], [1.0, 1.0 - 0.333333]);
+    }
+}
@@ -0,0 +1,406 @@
+use std::io::*;
+
+use super::simd_vec::*;
+use super::model::*;
+use super::triangulation::*;
+use super::texture::*;
+
+// not-suitable-for-production Stanford .ply parsing; panics on any error
+// http://paulbourke.net/dataformats/ply/
+
+#[derive(Clone, Copy)]
+enum Encoding {
+    Ascii,
+    LittleEndian,
+    BigEndian
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ScalarType {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    F32,
+    F64
+}
+
+impl ScalarType {
+    fn from_name(name: &str) -> ScalarType {
+        match name {
+            "char" | "int8" => ScalarType::I8,
+            "uchar" | "uint8" => ScalarType::U8,
+            "short" | "int16" => ScalarType::I16,
+            "ushort" | "uint16" => ScalarType::U16,
+            "int" | "int32" => ScalarType::I32,
+            "uint" | "uint32" => ScalarType::U32,
+            "float" | "float32" => ScalarType::F32,
+            "double" | "float64" => ScalarType::F64,
+            _ => panic!("unknown PLY property type {}", name)
+        }
+    }
+
+    fn size(self) -> usize {
+        match self {
+            ScalarType::I8 | ScalarType::U8 => 1,
+            ScalarType::I16 | ScalarType::U16 => 2,
+            ScalarType::I32 | ScalarType::U32 | ScalarType::F32 => 4,
+            ScalarType::F64 => 8
+        }
+    }
+
+    // colours are normalised to 0.0-1.0 from integer types
+    fn max(self) -> f64 {
+        match self {
+            ScalarType::I8 => i8::MAX as f64,
+            ScalarType::U8 => u8::MAX as f64,
+            ScalarType::I16 => i16::MAX as f64,
+            ScalarType::U16 => u16::MAX as f64,
+            ScalarType::I32 => i32::MAX as f64,
+            ScalarType::U32 => u32::MAX as f64,
+            ScalarType::F32 | ScalarType::F64 => 1.0
+        }
+    }
+}
+
+struct Property {
+    name: String,
+    scalar_type: ScalarType,
+    // the type of the count for list properties
+    list_count_type: Option<ScalarType>
+}
+
+struct Element {
+    name: String,
+    count: usize,
+    properties: Vec<Property>
+}
+
+// reads values one at a time from the body of the file, whatever its encoding
+struct Values<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    encoding: Encoding
+}
+
+impl<'a> Values<'a> {
+    fn next_token(&mut self) -> &'a str {
+        let bytes = self.bytes;
+        while self.position < bytes.len() && bytes[self.position].is_ascii_whitespace() {
+            self.position += 1;
+        }
+        let start = self.position;
+        while self.position < bytes.len() && !bytes[self.position].is_ascii_whitespace() {
+            self.position += 1;
+        }
+        std::str::from_utf8(&bytes[start..self.position]).unwrap()
+    }
+
+    fn next_bytes<const N: usize>(&mut self) -> [u8; N] {
+        let mut b: [u8; N] = self.bytes[self.position..self.position + N].try_into().unwrap();
+        if let Encoding::BigEndian = self.encoding {
+            b.reverse();
+        }
+        self.position += N;
+        b
+    }
+
+    fn next(&mut self, scalar_type: ScalarType) -> f64 {
+        if let Encoding::Ascii = self.encoding {
+            return self.next_token().parse::<f64>().unwrap();
+        }
+
+        // bytes are reversed for big endian so they can always be read as little endian
+        match scalar_type {
+            ScalarType::I8 => i8::from_le_bytes(self.next_bytes()) as f64,
+            ScalarType::U8 => u8::from_le_bytes(self.next_bytes()) as f64,
+            ScalarType::I16 => i16::from_le_bytes(self.next_bytes()) as f64,
+            ScalarType::U16 => u16::from_le_bytes(self.next_bytes()) as f64,
+            ScalarType::I32 => i32::from_le_bytes(self.next_bytes()) as f64,
+            ScalarType::U32 => u32::from_le_bytes(self.next_bytes()) as f64,
+            ScalarType::F32 => f32::from_le_bytes(self.next_bytes()) as f64,
+            ScalarType::F64 => f64::from_le_bytes(self.next_bytes())
+        }
+    }
+
+    fn skip(&mut self, scalar_type: ScalarType) {
+        if let Encoding::Ascii = self.encoding {
+            self.next_token();
+        }
+        else {
+            self.position += scalar_type.size();
+        }
+    }
+}
+
+fn read_header(bytes: &[u8]) -> (Encoding, Vec<Element>, usize) {
+    let mut encoding = None;
+    let mut elements: Vec<Element> = Vec::new();
+    let mut position = 0;
+
+    // the header is always ASCII, one keyword per line
+    loop {
+        let end = position + bytes[position..].iter().position(|&b| b == b'\n').expect("PLY header has no end_header");
+        let line = std::str::from_utf8(&bytes[position..end]).unwrap().trim();
+        position = end + 1;
+
+        let tokens: Vec<&str> = line.split_ascii_whitespace().collect();
+        match tokens.first().copied() {
+            Some("ply") | Some("comment") | Some("obj_info") | None => (),
+            Some("format") => {
+                encoding = Some(match tokens[1] {
+                    "ascii" => Encoding::Ascii,
+                    "binary_little_endian" => Encoding::LittleEndian,
+                    "binary_big_endian" => Encoding::BigEndian,
+                    format => panic!("unknown PLY format {}", format)
+                });
+            }
+            Some("element") => {
+                elements.push(Element { name: tokens[1].to_string(), count: tokens[2].parse().unwrap(), properties: Vec::new() });
+            }
+            Some("property") => {
+                let property = if tokens[1] == "list" {
+                    Property { name: tokens[4].to_string(), scalar_type: ScalarType::from_name(tokens[3]), list_count_type: Some(ScalarType::from_name(tokens[2])) }
+                }
+                else {
+                    Property { name: tokens[2].to_string(), scalar_type: ScalarType::from_name(tokens[1]), list_count_type: None }
+                };
+                elements.last_mut().expect("PLY property before any element").properties.push(property);
+            }
+            Some("end_header") => break,
+            Some(keyword) => panic!("unknown PLY header keyword {}", keyword)
+        }
+    }
+
+    (encoding.expect("PLY header has no format"), elements, position)
+}
+
+fn property_index(element: &Element, names: &[&str]) -> Option<usize> {
+    element.properties.iter().position(|p| names.contains(&p.name.as_str()))
+}
+
+pub fn read_ply<R: Read>(mut file: R) -> Model {
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes).unwrap();
+
+    let (encoding, elements, body_start) = read_header(&bytes);
+    let mut values = Values { bytes: &bytes, position: body_start, encoding };
+
+    let mut xs = SimdVec::new();
+    let mut ys = SimdVec::new();
+    let mut zs = SimdVec::new();
+    let mut ws = SimdVec::new();
+    let mut normals = [SimdVec::new(), SimdVec::new(), SimdVec::new()];
+    let mut colours = [SimdVec::new(), SimdVec::new(), SimdVec::new()];
+    let mut texture_coordinates = [SimdVec::new(), SimdVec::new()];
+    // faces are triangulated at the end, as the vertices could come after them
+    let mut faces = Vec::new();
+    let mut face_lengths = Vec::new();
+    let mut face_materials = Vec::new();
+
+    // elements are stored in the order they're declared, and all have to be read to find the next one
+    let mut row = Vec::new();
+    let mut face = Vec::new();
+    for element in &elements {
+        let position_indices = [property_index(element, &["x"]), property_index(element, &["y"]), property_index(element, &["z"])];
+        let normal_indices = [property_index(element, &["nx"]), property_index(element, &["ny"]), property_index(element, &["nz"])];
+        let colour_indices = [
+            property_index(element, &["red", "r", "diffuse_red"]),
+            property_index(element, &["green", "g", "diffuse_green"]),
+            property_index(element, &["blue", "b", "diffuse_blue"])];
+        let texture_coordinate_indices = [property_index(element, &["u", "s", "texture_u"]), property_index(element, &["v", "t", "texture_v"])];
+        let face_index = property_index(element, &["vertex_indices", "vertex_index"]);
+        let material_index = property_index(element, &["material_index"]);
+        let is_vertex = element.name == "vertex";
+        let is_face = element.name == "face";
+
+        for _ in 0..element.count {
+            row.clear();
+            for (ip, property) in element.properties.iter().enumerate() {
+                match property.list_count_type {
+                    None => row.push(values.next(property.scalar_type)),
+                    Some(count_type) => {
+                        let count = values.next(count_type) as usize;
+                        if is_face && Some(ip) == face_index {
+                            face.clear();
+                            face.extend((0..count).map(|_| values.next(property.scalar_type) as u32));
+                        }
+                        else {
+                            (0..count).for_each(|_| values.skip(property.scalar_type));
+                        }
+                        // lists don't have a single value
+                        row.push(0.0);
+                    }
+                }
+            }
+
+            if is_vertex {
+                let position = position_indices.map(|i| row[i.expect("PLY vertex without x, y and z")] as f32);
+                xs.push(position[0]);
+                ys.push(position[1]);
+                zs.push(position[2]);
+                ws.push(1.0);
+
+                if let [Some(nx), Some(ny), Some(nz)] = normal_indices {
+                    normals[0].push(row[nx] as f32);
+                    normals[1].push(row[ny] as f32);
+                    normals[2].push(row[nz] as f32);
+                }
+
+                // colours from scanners and the like are meant for display, so are taken to be sRGB
+                if let [Some(r), Some(g), Some(b)] = colour_indices {
+                    let max = element.properties[r].scalar_type.max();
+                    colours[0].push(srgb_fraction_to_linear((row[r] / max) as f32));
+                    colours[1].push(srgb_fraction_to_linear((row[g] / max) as f32));
+                    colours[2].push(srgb_fraction_to_linear((row[b] / max) as f32));
+                }
+
+                // PLY texture coordinates start at the bottom left, like OBJ's
+                if let [Some(u), Some(v)] = texture_coordinate_indices {
+                    texture_coordinates[0].push(row[u] as f32);
+                    texture_coordinates[1].push(1.0 - row[v] as f32);
+                }
+            }
+            else if is_face && face.len() >= 3 {
+                faces.extend_from_slice(&face);
+                face_lengths.push(face.len());
+                if let Some(material) = material_index {
+                    face_materials.push(row[material] as u32);
+                }
+            }
+        }
+    }
+
+    let mut triangles = Vec::new();
+    let mut num_non_convex = 0;
+    let mut triangle_materials = SimdVec::new();
+    let mut start = 0;
+    for (iface, &length) in face_lengths.iter().enumerate() {
+        if !triangulate_face(&faces[start..start + length], &xs[..], &ys[..], &zs[..], &ws[..], &mut triangles) {
+            num_non_convex += 1;
+        }
+        start += length;
+        if !face_materials.is_empty() {
+            (0..length - 2).for_each(|_| triangle_materials.push(face_materials[iface]));
+        }
+    }
+    report_non_convex(num_non_convex, face_lengths.iter().filter(|&&length| length > 3).count());
+
+    let trianglev0s = triangles.iter().map(|t| t[0]).collect();
+    let trianglev1s = triangles.iter().map(|t| t[1]).collect();
+    let trianglev2s = triangles.iter().map(|t| t[2]).collect();
+
+    let mut model = Model::new(xs, ys, zs, ws, trianglev0s, trianglev1s, trianglev2s);
+    let [normal_xs, normal_ys, normal_zs] = normals;
+    model.vertex_normal_xs = normal_xs;
+    model.vertex_normal_ys = normal_ys;
+    model.vertex_normal_zs = normal_zs;
+    let [colour_rs, colour_gs, colour_bs] = colours;
+    model.vertex_colour_rs = colour_rs;
+    model.vertex_colour_gs = colour_gs;
+    model.vertex_colour_bs = colour_bs;
+    let [texture_us, texture_vs] = texture_coordinates;
+    model.texture_us = texture_us;
+    model.texture_vs = texture_vs;
+    model.triangle_materials = triangle_materials;
+    model
+}
+
+// binary little endian, with whichever optional vertex attributes the model has and, if it has them, a non-standard
+// material_index property on the faces
+pub fn write_ply<W: Write>(model: &Model, file: W) {
+    let mut file = BufWriter::new(file);
+    let has_normals = model.vertex_normal_xs.len() > 0;
+    let has_colours = model.vertex_colour_rs.len() > 0;
+    let has_texture_coordinates = model.texture_us.len() > 0;
+    let has_materials = model.triangle_materials.len() > 0;
+
+    let mut header = String::from("ply\nformat binary_little_endian 1.0\ncomment written by rustrast\n");
+    header += &format!("element vertex {}\nproperty float x\nproperty float y\nproperty float z\n", model.num_vertices);
+    if has_normals {
+        header += "property float nx\nproperty float ny\nproperty float nz\n";
+    }
+    if has_colours {
+        header += "property uchar red\nproperty uchar green\nproperty uchar blue\n";
+    }
+    if has_texture_coordinates {
+        header += "property float u\nproperty float v\n";
+    }
+    header += &format!("element face {}\nproperty list uchar uint vertex_indices\n", model.num_triangles);
+    if has_materials {
+        header += "property uint material_index\n";
+    }
+    header += "end_header\n";
+    file.write_all(header.as_bytes()).unwrap();
+
+    for i in 0..model.num_vertices {
+        // PLY has no w
+        let (position, _) = model.homogenous_coordinates(i).to_cartesian();
+        let i = i as usize;
+        for v in [position.x, position.y, position.z] {
+            file.write_all(&v.to_le_bytes()).unwrap();
+        }
+        if has_normals {
+            for v in [model.vertex_normal_xs[i], model.vertex_normal_ys[i], model.vertex_normal_zs[i]] {
+                file.write_all(&v.to_le_bytes()).unwrap();
+            }
+        }
+        if has_colours {
+            let colour = [model.vertex_colour_rs[i], model.vertex_colour_gs[i], model.vertex_colour_bs[i]];
+            file.write_all(&colour.map(linear_to_srgb)).unwrap();
+        }
+        if has_texture_coordinates {
+            for v in [model.texture_us[i], 1.0 - model.texture_vs[i]] {
+                file.write_all(&v.to_le_bytes()).unwrap();
+            }
+        }
+    }
+
+    for it in 0..model.num_triangles as usize {
+        file.write_all(&[3]).unwrap();
+        for v in [model.trianglev0s[it], model.trianglev1s[it], model.trianglev2s[it]] {
+            file.write_all(&v.to_le_bytes()).unwrap();
+        }
+        if has_materials {
+            file.write_all(&model.triangle_materials[it].to_le_bytes()).unwrap();
+        }
+    }
+
+    file.flush().unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::model::tests::*;
+    use super::super::primitives::*;
+
+    #[test]
+    fn written_models_read_back() {
+        let model = with_colours_and_materials(uv_sphere(2.0, 16, 8));
+        let mut bytes = Vec::new();
+        write_ply(&model, &mut bytes);
+        // the colours are exact in eight bits, but go through sRGB both ways
+        assert_same_model(&model, &read_ply(&bytes[..]), 1e-6);
+    }
+
+    #[test]
+    fn hand_written_file_reads() {
+        let model = read_ply(include_str!("square.ply").as_bytes());
+        assert_eq!(model.num_vertices, 4);
+        assert_eq!(model.num_triangles, 2);
+        assert_eq!([model.xs[2], model.ys[2], model.zs[2], model.ws[2]], [1.0, 1.0, 0.0, 1.0]);
+        assert_eq!([model.vertex_normal_xs[2], model.vertex_normal_ys[2], model.vertex_normal_zs[2]], [0.0, 0.0, 1.0]);
+        assert_eq!([model.vertex_colour_rs[2], model.vertex_colour_gs[2], model.vertex_colour_bs[2]], [0.0, 0.0, 1.0]);
+        // the file's bottom left is the top left here
+        assert_eq!(model.texture_us[..], [0.0, 1.0, 1.0, 0.0]);
+        assert_eq!(model.texture_vs[..], [1.0, 1.0, 0.0, 0.0]);
+        assert_eq!(model.triangle_materials[..], [2, 2]);
+        let mut corners: Vec<u32> = (0..2).flat_map(|it| [model.trianglev0s[it], model.trianglev1s[it], model.trianglev2s[it]]).collect();
+        corners.sort();
+        corners.dedup();
+        assert_eq!(corners, [0, 1, 2, 3]);
+    }
+}
@@ -0,0 +1,371 @@
+use windows::Win32::Graphics::Gdi::*;
+use core::arch::x86_64::*;
+
+use super::rasterisation::*;
+use super::cube_file::*;
+
+// effects on the finished frame, in the order they run: bloom on the HDR target before tone mapping, then colour grading
+// and vignetting on each tile of the 8-bit output, then FXAA over the whole of it
+//
+// bloom and FXAA reach into neighbouring tiles, so they work on full screen buffers rather than tiles; bloom is blurred
+// at half resolution, in rows padded either side so spans of 8 can read past the ends, and the padding is left black
+
+// what runs, which can be changed between frames
+pub struct PostProcessing {
+    pub fxaa: bool,
+    // needs HDR, as it's what's brighter than white that glows
+    pub bloom: bool,
+    pub vignette: bool,
+    // does nothing without a LUT
+    pub colour_grading: bool,
+    pub lut: Option<Lut>
+}
+
+// the furthest the bloom blur can reach, in half resolution pixels
+pub const BLOOM_PADDING: usize = 16;
+
+// Rec. 709, for linear light
+const LUMINANCE: [f32; 3] = [0.2126, 0.7152, 0.0722];
+// FXAA wants luma from gamma encoded colours, which is what the 8-bit output is
+const LUMA: [f32; 3] = [0.299, 0.587, 0.114];
+
+// FXAA 3.11's PC console version, by Timothy Lottes, which blurs along the direction an edge runs
+// https://developer.download.nvidia.com/assets/gamedev/files/sdk/11/FXAA_WhitePaper.pdf
+const FXAA_SPAN_MAX: f32 = 8.0;
+const FXAA_REDUCE_MUL: f32 = 1.0 / 8.0;
+const FXAA_REDUCE_MIN: f32 = 1.0 / 128.0;
+// spans are left alone unless their neighbourhood's contrast is above both of these
+const FXAA_EDGE_THRESHOLD: f32 = 1.0 / 8.0;
+const FXAA_EDGE_THRESHOLD_MIN: f32 = 1.0 / 16.0;
+
+// the row stride and height of the half resolution bloom buffers
+pub fn bloom_layout(stride: usize, height: usize) -> (usize, usize) {
+    ((stride / 2).div_ceil(8) * 8 + 2 * BLOOM_PADDING, height.div_ceil(2))
+}
+
+// half of a normalised gaussian from the middle out, reaching about zero at radius
+pub fn bloom_weights(radius: usize) -> Vec<f32> {
+    let radius = radius.clamp(1, BLOOM_PADDING);
+    let sigma = radius as f32 / 3.0;
+    let weights: Vec<f32> = (0..=radius).map(|i| (-((i * i) as f32) / (2.0 * sigma * sigma)).exp()).collect();
+    let total = weights[0] + 2.0 * weights[1..].iter().sum::<f32>();
+    weights.iter().map(|w| w / total).collect()
+}
+
+// averages each 2x2 block of a tile of the HDR target into the half resolution bloom buffer, keeping only light above
+// the threshold; tiles start on even rows and columns, so blocks never straddle them
+#[target_feature(enable = "avx,avx2,fma")]
+pub unsafe fn avx2_bloom_downsample_tile(
+        hdr: &[Buffer<f32>; 3], bright: [&mut [f32]; 3], half_stride: usize, threshold: f32,
+        xmin: usize, ymin: usize, xmax: usize, ymax: usize) {
+    debug_assert!(xmin % 16 == 0 && ymin % 2 == 0 && xmax % 8 == 0);
+
+    let zero = _mm256_setzero_ps();
+    let quarter = _mm256_set1_ps(0.25);
+    let threshold = _mm256_set1_ps(threshold);
+    let tiny = _mm256_set1_ps(f32::MIN_POSITIVE);
+    let zero_to_seven = _mm256_setr_epi32(0, 1, 2, 3, 4, 5, 6, 7);
+    let h_buffers = hdr.each_ref().map(|b| b.buffer.as_ptr());
+    let b_buffers = bright.map(|b| b.as_mut_ptr());
+    let stride = hdr[0].stride;
+
+    for y in (ymin..ymax).step_by(2) {
+        // an odd last row just counts twice
+        let rows = [y, (y + 1).min(ymax - 1)].map(|y| (y - ymin) * stride);
+        let b_row = (y / 2 * half_stride + BLOOM_PADDING) as isize;
+
+        for x in (xmin..xmax).step_by(16) {
+            let x_tile = x - xmin;
+            let second_span = x + 8 < xmax;
+            let sums = h_buffers.map(|buffer| {
+                let pairs = rows.map(|row| {
+                    let first = _mm256_loadu_ps(buffer.add(row + x_tile));
+                    let second = if second_span { _mm256_loadu_ps(buffer.add(row + x_tile + 8)) } else { zero };
+                    // horizontal adds interleave the halves, so put them back in order
+                    _mm256_castpd_ps(_mm256_permute4x64_pd(_mm256_castps_pd(_mm256_hadd_ps(first, second)), 0b11_01_10_00))
+                });
+                _mm256_mul_ps(_mm256_add_ps(pairs[0], pairs[1]), quarter)
+            });
+
+            let luminance = _mm256_fmadd_ps(sums[0], _mm256_set1_ps(LUMINANCE[0]), _mm256_fmadd_ps(sums[1], _mm256_set1_ps(LUMINANCE[1]), _mm256_mul_ps(sums[2], _mm256_set1_ps(LUMINANCE[2]))));
+            let scale = _mm256_div_ps(_mm256_max_ps(_mm256_sub_ps(luminance, threshold), zero), _mm256_max_ps(luminance, tiny));
+
+            // only half as many pixels when the tile's width isn't a multiple of 16
+            let mask = _mm256_cmpgt_epi32(_mm256_set1_epi32(((xmax - x) / 2) as i32), zero_to_seven);
+            for (buffer, c) in b_buffers.into_iter().zip(sums) {
+                _mm256_maskstore_ps(buffer.offset(b_row + (x / 2) as isize), mask, _mm256_mul_ps(c, scale));
+            }
+        }
+    }
+}
+
+// blurs rows of the half resolution bloom buffer, between the given half resolution bounds
+#[target_feature(enable = "avx,avx2,fma")]
+pub unsafe fn avx2_bloom_blur_horizontal(
+        source: [&[f32]; 3], dest: [&mut [f32]; 3], half_stride: usize, weights: &[f32],
+        xmin: usize, ymin: usize, xmax: usize, ymax: usize) {
+    let weights: Vec<__m256> = weights.iter().map(|&w| _mm256_set1_ps(w)).collect();
+    let radius = weights.len() as isize - 1;
+    for (source, dest) in source.into_iter().zip(dest) {
+        let (s_buffer, d_buffer) = (source.as_ptr(), dest.as_mut_ptr());
+        for y in ymin..ymax {
+            let row = (y * half_stride + BLOOM_PADDING) as isize;
+            for x in (xmin..xmax).step_by(8) {
+                let centre = s_buffer.offset(row + x as isize);
+                let mut sum = _mm256_mul_ps(_mm256_loadu_ps(centre), weights[0]);
+                for k in 1..=radius {
+                    let pair = _mm256_add_ps(_mm256_loadu_ps(centre.offset(-k)), _mm256_loadu_ps(centre.offset(k)));
+                    sum = _mm256_fmadd_ps(pair, weights[k as usize], sum);
+                }
+                _mm256_storeu_ps(d_buffer.offset(row + x as isize), sum);
+            }
+        }
+    }
+}
+
+// blurs columns of the half resolution bloom buffer; rows past the top and bottom are black like the padding
+#[target_feature(enable = "avx,avx2,fma")]
+pub unsafe fn avx2_bloom_blur_vertical(
+        source: [&[f32]; 3], dest: [&mut [f32]; 3], half_stride: usize, half_height: usize, weights: &[f32],
+        xmin: usize, ymin: usize, xmax: usize, ymax: usize) {
+    let weights: Vec<__m256> = weights.iter().map(|&w| _mm256_set1_ps(w)).collect();
+    for (source, dest) in source.into_iter().zip(dest) {
+        let (s_buffer, d_buffer) = (source.as_ptr(), dest.as_mut_ptr());
+        for y in ymin..ymax {
+            let row = y * half_stride + BLOOM_PADDING;
+            for x in (xmin..xmax).step_by(8) {
+                let mut sum = _mm256_mul_ps(_mm256_loadu_ps(s_buffer.add(row + x)), weights[0]);
+                for (k, &weight) in weights.iter().enumerate().skip(1) {
+                    if y >= k {
+                        sum = _mm256_fmadd_ps(_mm256_loadu_ps(s_buffer.add(row - k * half_stride + x)), weight, sum);
+                    }
+                    if y + k < half_height {
+                        sum = _mm256_fmadd_ps(_mm256_loadu_ps(s_buffer.add(row + k * half_stride + x)), weight, sum);
+                    }
+                }
+                _mm256_storeu_ps(d_buffer.add(row + x), sum);
+            }
+        }
+    }
+}
+
+// adds the blurred bloom back onto a tile of the HDR target, bilinearly scaled up to full resolution
+#[target_feature(enable = "avx,avx2,fma")]
+pub unsafe fn avx2_bloom_composite_tile(
+        bloom: [&[f32]; 3], half_stride: usize, half_height: usize, hdr: &mut [Buffer<f32>; 3], intensity: f32,
+        xmin: usize, ymin: usize, xmax: usize, ymax: usize) {
+    debug_assert!(xmin % 8 == 0 && xmax % 8 == 0);
+
+    let intensity = _mm256_set1_ps(intensity);
+    // full resolution pixel centres fall a quarter of the way between half resolution ones, alternately from the left
+    // and the right; each span of 8 needs 6 half resolution pixels, starting one to the left of its first
+    let left_lanes = _mm256_setr_epi32(0, 1, 1, 2, 2, 3, 3, 4);
+    let right_lanes = _mm256_setr_epi32(1, 2, 2, 3, 3, 4, 4, 5);
+    let x_weights = _mm256_setr_ps(0.25, 0.75, 0.25, 0.75, 0.25, 0.75, 0.25, 0.75);
+    let h_buffers = hdr.each_mut().map(|b| b.buffer.as_mut_ptr());
+    let stride = hdr[0].stride;
+
+    for y in ymin..ymax {
+        let (top, y_weight) = if y % 2 == 0 { (y as isize / 2 - 1, 0.25) } else { (y as isize / 2, 0.75) };
+        let rows = [top, top + 1].map(|r| r.clamp(0, half_height as isize - 1) as usize * half_stride + BLOOM_PADDING);
+        let y_weight = _mm256_set1_ps(y_weight);
+        let h_row = (y - ymin) * stride;
+
+        for x in (xmin..xmax).step_by(8) {
+            for (buffer, source) in h_buffers.into_iter().zip(bloom) {
+                let [above, below] = rows.map(|row| {
+                    let half = _mm256_loadu_ps(source.as_ptr().add(row + x / 2 - 1));
+                    let left = _mm256_permutevar8x32_ps(half, left_lanes);
+                    let right = _mm256_permutevar8x32_ps(half, right_lanes);
+                    _mm256_fmadd_ps(_mm256_sub_ps(left, right), x_weights, right)
+                });
+                let value = _mm256_fmadd_ps(_mm256_sub_ps(above, below), y_weight, below);
+                let h = buffer.add(h_row + x - xmin);
+                _mm256_storeu_ps(h, _mm256_fmadd_ps(value, intensity, _mm256_loadu_ps(h)));
+            }
+        }
+    }
+}
+
+// BGRA in memory, as 0.0-255.0 for each channel
+#[target_feature(enable = "avx,avx2,fma")]
+#[inline]
+unsafe fn avx2_unpack(span: __m256i) -> [__m256; 3] {
+    let byte = _mm256_set1_epi32(0xff);
+    [16, 8, 0].map(|shift| _mm256_cvtepi32_ps(_mm256_and_si256(_mm256_srlv_epi32(span, _mm256_set1_epi32(shift)), byte)))
+}
+
+#[target_feature(enable = "avx,avx2,fma")]
+#[inline]
+unsafe fn avx2_pack(colour: [__m256; 3]) -> __m256i {
+    let [r, g, b] = colour.map(|c| _mm256_cvtps_epi32(_mm256_min_ps(_mm256_max_ps(c, _mm256_setzero_ps()), _mm256_set1_ps(255.0))));
+    _mm256_or_si256(_mm256_slli_epi32(r, 16), _mm256_or_si256(_mm256_slli_epi32(g, 8), b))
+}
+
+// a LUT's graded colour for colours scaled to the LUT's size, by trilinear interpolation
+#[target_feature(enable = "avx,avx2,fma")]
+#[inline]
+unsafe fn avx2_lut(lut: &Lut, coordinates: [__m256; 3]) -> [__m256; 3] {
+    let last = _mm256_set1_ps((lut.size - 2) as f32);
+    let corners = coordinates.map(|c| _mm256_min_ps(_mm256_floor_ps(c), last));
+    let fractions: [__m256; 3] = [0, 1, 2].map(|i| _mm256_sub_ps(coordinates[i], corners[i]));
+    let corners = corners.map(|c| _mm256_cvttps_epi32(c));
+    let steps = [1, lut.size, lut.size * lut.size].map(|s| _mm256_set1_epi32(s as i32));
+    let base = _mm256_add_epi32(corners[0], _mm256_add_epi32(_mm256_mullo_epi32(corners[1], steps[1]), _mm256_mullo_epi32(corners[2], steps[2])));
+
+    lut.table.each_ref().map(|plane| {
+        let fetch = |r: usize, g: usize, b: usize| {
+            let offset = _mm256_set1_epi32((r + g * lut.size + b * lut.size * lut.size) as i32);
+            _mm256_i32gather_ps(plane.as_ptr(), _mm256_add_epi32(base, offset), 4)
+        };
+        let lerp = |a: __m256, b: __m256, t: __m256| _mm256_fmadd_ps(_mm256_sub_ps(b, a), t, a);
+        let [r, g, b] = fractions;
+        let g0 = lerp(lerp(fetch(0, 0, 0), fetch(1, 0, 0), r), lerp(fetch(0, 1, 0), fetch(1, 1, 0), r), g);
+        let g1 = lerp(lerp(fetch(0, 0, 1), fetch(1, 0, 1), r), lerp(fetch(0, 1, 1), fetch(1, 1, 1), r), g);
+        lerp(g0, g1, b)
+    })
+}
+
+// colour grades and vignettes a tile of the 8-bit output in place; the vignette darkens towards the corners of the
+// window, by strength at the very corners, and nothing is done for either when they're None
+#[target_feature(enable = "avx,avx2,fma")]
+pub unsafe fn avx2_grade_tile(
+        colour: &mut Buffer<RGBQUAD>, lut: Option<&Lut>, vignette: Option<f32>, width: usize, height: usize,
+        xmin: usize, ymin: usize, xmax: usize, ymax: usize) {
+    debug_assert!(xmin % 8 == 0 && xmax % 8 == 0);
+    debug_assert!(colour.stride % 8 == 0 && colour.left % 8 == 0);
+
+    let zero = _mm256_setzero_ps();
+    let one = _mm256_set1_ps(1.0);
+    let scale_255 = _mm256_set1_ps(255.0);
+    let inverse_255 = _mm256_set1_ps(1.0 / 255.0);
+    let zero_to_seven = _mm256_setr_ps(0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0);
+    // from 0.0-1.0 in the LUT's domain to 0.0-size - 1
+    let lut_scales = lut.map(|lut| [0, 1, 2].map(|c| {
+        let scale = (lut.size - 1) as f32 / (lut.domain_max[c] - lut.domain_min[c]);
+        (_mm256_set1_ps(scale), _mm256_set1_ps(-lut.domain_min[c] * scale), _mm256_set1_ps((lut.size - 1) as f32))
+    }));
+    // the squared distance from the middle, where the corners are 1.0
+    let (half_width, half_height) = (width as f32 / 2.0, height as f32 / 2.0);
+    let x_scale = _mm256_set1_ps(std::f32::consts::FRAC_1_SQRT_2 / half_width);
+    let strength = vignette.map(|strength| _mm256_set1_ps(strength));
+
+    let c_buffer = colour.buffer.as_mut_ptr() as *mut __m256i;
+    for y in ymin..ymax {
+        let c_row = ((y - colour.top) * colour.stride - colour.left) / 8;
+        let dy = (y as f32 + 0.5 - half_height) * std::f32::consts::FRAC_1_SQRT_2 / half_height;
+        let dy_squared = _mm256_set1_ps(dy * dy);
+
+        for x in (xmin..xmax).step_by(8) {
+            let span = c_buffer.add(c_row + x / 8);
+            let mut c = avx2_unpack(_mm256_loadu_si256(span)).map(|c| _mm256_mul_ps(c, inverse_255));
+
+            if let (Some(lut), Some(scales)) = (lut, lut_scales) {
+                let coordinates = [0, 1, 2].map(|i| {
+                    let (scale, offset, max) = scales[i];
+                    _mm256_min_ps(_mm256_max_ps(_mm256_fmadd_ps(c[i], scale, offset), zero), max)
+                });
+                c = avx2_lut(lut, coordinates);
+            }
+
+            if let Some(strength) = strength {
+                let dx = _mm256_mul_ps(_mm256_sub_ps(_mm256_add_ps(_mm256_set1_ps(x as f32 + 0.5), zero_to_seven), _mm256_set1_ps(half_width)), x_scale);
+                let distance_squared = _mm256_fmadd_ps(dx, dx, dy_squared);
+                let darkening = _mm256_max_ps(_mm256_fnmadd_ps(strength, distance_squared, one), zero);
+                c = c.map(|c| _mm256_mul_ps(c, darkening));
+            }
+
+            _mm256_storeu_si256(span, avx2_pack(c.map(|c| _mm256_mul_ps(c, scale_255))));
+        }
+    }
+}
+
+// FXAA for a tile of the 8-bit output, reading from a copy of the whole frame with the same layout so neighbouring
+// tiles see what was there before; pixels are only read from inside the window
+#[target_feature(enable = "avx,avx2,fma")]
+pub unsafe fn avx2_fxaa_tile(
+        source: &[RGBQUAD], colour: &mut Buffer<RGBQUAD>, width: usize, height: usize,
+        xmin: usize, ymin: usize, xmax: usize, ymax: usize) {
+    debug_assert!(xmin % 8 == 0 && xmax % 8 == 0);
+    debug_assert!(colour.top == 0 && colour.left == 0 && colour.stride % 8 == 0);
+
+    let stride = colour.stride;
+    let s_buffer = source.as_ptr() as *const i32;
+    let c_buffer = colour.buffer.as_mut_ptr() as *mut i32;
+
+    let zero = _mm256_setzero_ps();
+    let half = _mm256_set1_ps(0.5);
+    let luma_weights = LUMA.map(|w| _mm256_set1_ps(w / 255.0));
+    let x_max = _mm256_set1_epi32(width as i32 - 1);
+    let y_max = _mm256_set1_epi32(height as i32 - 1);
+    let stride_i = _mm256_set1_epi32(stride as i32);
+    let zero_to_seven = _mm256_setr_ps(0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0);
+
+    let luma = |c: [__m256; 3]| _mm256_fmadd_ps(c[0], luma_weights[0], _mm256_fmadd_ps(c[1], luma_weights[1], _mm256_mul_ps(c[2], luma_weights[2])));
+    let fetch = |xs: __m256i, ys: __m256i| {
+        let xs = _mm256_min_epi32(_mm256_max_epi32(xs, _mm256_setzero_si256()), x_max);
+        let ys = _mm256_min_epi32(_mm256_max_epi32(ys, _mm256_setzero_si256()), y_max);
+        avx2_unpack(_mm256_i32gather_epi32(s_buffer, _mm256_add_epi32(_mm256_mullo_epi32(ys, stride_i), xs), 4))
+    };
+    // between pixels, where x and y are in pixels and their centres are at 0.5
+    let bilinear = |x: __m256, y: __m256| {
+        let (x, y) = (_mm256_sub_ps(x, half), _mm256_sub_ps(y, half));
+        let (x0, y0) = (_mm256_floor_ps(x), _mm256_floor_ps(y));
+        let (tx, ty) = (_mm256_sub_ps(x, x0), _mm256_sub_ps(y, y0));
+        let (x0, y0) = (_mm256_cvttps_epi32(x0), _mm256_cvttps_epi32(y0));
+        let one = _mm256_set1_epi32(1);
+        let (x1, y1) = (_mm256_add_epi32(x0, one), _mm256_add_epi32(y0, one));
+        let [tl, tr, bl, br] = [(x0, y0), (x1, y0), (x0, y1), (x1, y1)].map(|(x, y)| fetch(x, y));
+        [0, 1, 2].map(|c| {
+            let top = _mm256_fmadd_ps(_mm256_sub_ps(tr[c], tl[c]), tx, tl[c]);
+            let bottom = _mm256_fmadd_ps(_mm256_sub_ps(br[c], bl[c]), tx, bl[c]);
+            _mm256_fmadd_ps(_mm256_sub_ps(bottom, top), ty, top)
+        })
+    };
+
+    for y in ymin..ymax.min(height) {
+        let ys = _mm256_set1_epi32(y as i32);
+        let centre_y = _mm256_set1_ps(y as f32 + 0.5);
+        for x in (xmin..xmax.min(width)).step_by(8) {
+            let xs = _mm256_add_epi32(_mm256_set1_epi32(x as i32), _mm256_setr_epi32(0, 1, 2, 3, 4, 5, 6, 7));
+            let centre_x = _mm256_add_ps(_mm256_set1_ps(x as f32 + 0.5), zero_to_seven);
+
+            let m = luma(avx2_unpack(_mm256_loadu_si256(s_buffer.add(y * stride + x) as *const __m256i)));
+            let minus_one = _mm256_set1_epi32(-1);
+            let plus_one = _mm256_set1_epi32(1);
+            let [nw, ne, sw, se] = [(minus_one, minus_one), (plus_one, minus_one), (minus_one, plus_one), (plus_one, plus_one)]
+                .map(|(dx, dy)| luma(fetch(_mm256_add_epi32(xs, dx), _mm256_add_epi32(ys, dy))));
+
+            // leave anything without much contrast
+            let luma_min = _mm256_min_ps(m, _mm256_min_ps(_mm256_min_ps(nw, ne), _mm256_min_ps(sw, se)));
+            let luma_max = _mm256_max_ps(m, _mm256_max_ps(_mm256_max_ps(nw, ne), _mm256_max_ps(sw, se)));
+            let threshold = _mm256_max_ps(_mm256_set1_ps(FXAA_EDGE_THRESHOLD_MIN), _mm256_mul_ps(luma_max, _mm256_set1_ps(FXAA_EDGE_THRESHOLD)));
+            let edge = _mm256_cmp_ps(_mm256_sub_ps(luma_max, luma_min), threshold, _CMP_GE_OQ);
+            if _mm256_movemask_ps(edge) == 0 {
+                continue;
+            }
+
+            // across the gradient, which is along the edge
+            let dir_x = _mm256_sub_ps(_mm256_add_ps(sw, se), _mm256_add_ps(nw, ne));
+            let dir_y = _mm256_sub_ps(_mm256_add_ps(nw, sw), _mm256_add_ps(ne, se));
+            let reduce = _mm256_max_ps(_mm256_mul_ps(_mm256_add_ps(_mm256_add_ps(nw, ne), _mm256_add_ps(sw, se)), _mm256_set1_ps(0.25 * FXAA_REDUCE_MUL)), _mm256_set1_ps(FXAA_REDUCE_MIN));
+            let abs = |v: __m256| _mm256_andnot_ps(_mm256_set1_ps(-0.0), v);
+            let scale = _mm256_div_ps(_mm256_set1_ps(1.0), _mm256_add_ps(_mm256_min_ps(abs(dir_x), abs(dir_y)), reduce));
+            let span_max = _mm256_set1_ps(FXAA_SPAN_MAX);
+            let [dir_x, dir_y] = [dir_x, dir_y].map(|d| _mm256_min_ps(_mm256_max_ps(_mm256_mul_ps(d, scale), _mm256_sub_ps(zero, span_max)), span_max));
+
+            let along = |t: f32| bilinear(_mm256_fmadd_ps(dir_x, _mm256_set1_ps(t), centre_x), _mm256_fmadd_ps(dir_y, _mm256_set1_ps(t), centre_y));
+            let (a0, a1) = (along(1.0 / 3.0 - 0.5), along(2.0 / 3.0 - 0.5));
+            let rgb_a: [__m256; 3] = [0, 1, 2].map(|c| _mm256_mul_ps(_mm256_add_ps(a0[c], a1[c]), half));
+            let (b0, b1) = (along(-0.5), along(0.5));
+            let rgb_b: [__m256; 3] = [0, 1, 2].map(|c| _mm256_fmadd_ps(_mm256_add_ps(b0[c], b1[c]), _mm256_set1_ps(0.25), _mm256_mul_ps(rgb_a[c], half)));
+
+            // the wider blur is only kept if it didn't pick up anything from across another edge
+            let luma_b = luma(rgb_b);
+            let outside = _mm256_or_ps(_mm256_cmp_ps(luma_b, luma_min, _CMP_LT_OQ), _mm256_cmp_ps(luma_b, luma_max, _CMP_GT_OQ));
+            let result = [0, 1, 2].map(|c| _mm256_blendv_ps(rgb_b[c], rgb_a[c], outside));
+
+            _mm256_maskstore_epi32(c_buffer.add(y * stride + x), _mm256_castps_si256(edge), avx2_pack(result));
+        }
+    }
+}
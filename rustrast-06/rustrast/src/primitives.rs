@@ -0,0 +1,253 @@
+use std::{collections::*, f32::consts::PI};
+
+use super::model::*;
+
+// generated models for testing and demos, all centred on the origin with vertex normals and texture coordinates;
+// triangles wind counterclockwise seen from outside
+
+// accumulates vertices and triangles, then makes a model out of them
+#[derive(Default)]
+struct Builder {
+    positions: Vec<[f32; 3]>,
+    normals: Vec<[f32; 3]>,
+    texture_coordinates: Vec<[f32; 2]>,
+    triangles: Vec<[u32; 3]>
+}
+
+fn normalised(v: [f32; 3]) -> [f32; 3] {
+    let magnitude = (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt();
+    v.map(|c| c / magnitude)
+}
+
+impl Builder {
+    fn vertex(&mut self, position: [f32; 3], normal: [f32; 3], texture_coordinate: [f32; 2]) -> u32 {
+        self.positions.push(position);
+        self.normals.push(normal);
+        self.texture_coordinates.push(texture_coordinate);
+        (self.positions.len() - 1) as u32
+    }
+
+    // a surface parameterised by u and v from 0.0 to 1.0, which are also its texture coordinates; u should go right
+    // and v down when looking at the surface from outside
+    fn grid<F: Fn(f32, f32) -> ([f32; 3], [f32; 3])>(&mut self, segments_u: usize, segments_v: usize, f: F) {
+        let first = self.positions.len() as u32;
+        for j in 0..=segments_v {
+            for i in 0..=segments_u {
+                let (u, v) = (i as f32 / segments_u as f32, j as f32 / segments_v as f32);
+                let (position, normal) = f(u, v);
+                self.vertex(position, normal, [u, v]);
+            }
+        }
+
+        let index = |i: usize, j: usize| first + (j * (segments_u + 1) + i) as u32;
+        for j in 0..segments_v {
+            for i in 0..segments_u {
+                let (a, b, c, d) = (index(i, j), index(i + 1, j), index(i + 1, j + 1), index(i, j + 1));
+                self.triangles.push([a, d, c]);
+                self.triangles.push([a, c, b]);
+            }
+        }
+    }
+
+    // a horizontal disc facing up or down, with its texture mapped straight down onto it
+    fn disc(&mut self, y: f32, radius: f32, segments: usize, up: bool) {
+        let normal = [0.0, if up { 1.0 } else { -1.0 }, 0.0];
+        let centre = self.vertex([0.0, y, 0.0], normal, [0.5, 0.5]);
+        for i in 0..=segments {
+            let theta = i as f32 / segments as f32 * 2.0 * PI;
+            let (sin, cos) = theta.sin_cos();
+            self.vertex([radius * sin, y, radius * cos], normal, [0.5 + 0.5 * sin, 0.5 + 0.5 * cos]);
+        }
+        for i in 0..segments as u32 {
+            let (a, b) = (centre + 1 + i, centre + 2 + i);
+            self.triangles.push(if up { [centre, a, b] } else { [centre, b, a] });
+        }
+    }
+
+    fn into_model(self) -> Model {
+        let xs = self.positions.iter().map(|p| p[0]).collect();
+        let ys = self.positions.iter().map(|p| p[1]).collect();
+        let zs = self.positions.iter().map(|p| p[2]).collect();
+        let ws = self.positions.iter().map(|_| 1.0).collect();
+        let trianglev0s = self.triangles.iter().map(|t| t[0]).collect();
+        let trianglev1s = self.triangles.iter().map(|t| t[1]).collect();
+        let trianglev2s = self.triangles.iter().map(|t| t[2]).collect();
+
+        let mut model = Model::new(xs, ys, zs, ws, trianglev0s, trianglev1s, trianglev2s);
+        model.vertex_normal_xs = self.normals.iter().map(|n| n[0]).collect();
+        model.vertex_normal_ys = self.normals.iter().map(|n| n[1]).collect();
+        model.vertex_normal_zs = self.normals.iter().map(|n| n[2]).collect();
+        model.texture_us = self.texture_coordinates.iter().map(|t| t[0]).collect();
+        model.texture_vs = self.texture_coordinates.iter().map(|t| t[1]).collect();
+
+        // grids have zero-area triangles wherever a whole edge is squashed to a point, like the poles of a sphere
+        let epsilon = model.size() * 1e-6;
+        model.remove_degenerate_triangles(epsilon);
+        model.remove_unused_vertices();
+        model
+    }
+}
+
+// in the xz plane facing up
+pub fn plane(width: f32, depth: f32, segments_x: usize, segments_z: usize) -> Model {
+    let mut builder = Builder::default();
+    builder.grid(segments_x, segments_z, |u, v| ([(u - 0.5) * width, 0.0, (v - 0.5) * depth], [0.0, 1.0, 0.0]));
+    builder.into_model()
+}
+
+// each face has its own vertices and the whole texture
+pub fn cuboid(width: f32, height: f32, depth: f32) -> Model {
+    let (x, y, z) = (width / 2.0, height / 2.0, depth / 2.0);
+    // centre, then the directions of u and v across the face
+    let faces = [
+        ([0.0, 0.0, z], [width, 0.0, 0.0], [0.0, -height, 0.0]),
+        ([0.0, 0.0, -z], [-width, 0.0, 0.0], [0.0, -height, 0.0]),
+        ([x, 0.0, 0.0], [0.0, 0.0, -depth], [0.0, -height, 0.0]),
+        ([-x, 0.0, 0.0], [0.0, 0.0, depth], [0.0, -height, 0.0]),
+        ([0.0, y, 0.0], [width, 0.0, 0.0], [0.0, 0.0, depth]),
+        ([0.0, -y, 0.0], [width, 0.0, 0.0], [0.0, 0.0, -depth])];
+
+    let mut builder = Builder::default();
+    for (centre, right, down) in faces {
+        let normal = normalised(centre);
+        builder.grid(1, 1, |u, v| {
+            let position = [0, 1, 2].map(|i| centre[i] + (u - 0.5) * right[i] + (v - 0.5) * down[i]);
+            (position, normal)
+        });
+    }
+    builder.into_model()
+}
+
+// the texture wraps around once, from the north pole at the top to the south pole at the bottom
+pub fn uv_sphere(radius: f32, segments: usize, rings: usize) -> Model {
+    let mut builder = Builder::default();
+    builder.grid(segments, rings, |u, v| {
+        let (sin_theta, cos_theta) = (u * 2.0 * PI).sin_cos();
+        let (sin_phi, cos_phi) = (v * PI).sin_cos();
+        let normal = [sin_phi * sin_theta, cos_phi, sin_phi * cos_theta];
+        (normal.map(|c| c * radius), normal)
+    });
+    builder.into_model()
+}
+
+// a subdivided icosahedron, so its triangles are all about the same size unlike a UV sphere's; mapped like uv_sphere
+pub fn icosphere(radius: f32, subdivisions: usize) -> Model {
+    let t = (1.0 + 5f32.sqrt()) / 2.0;
+    let mut positions: Vec<[f32; 3]> = [
+        [-1.0, t, 0.0], [1.0, t, 0.0], [-1.0, -t, 0.0], [1.0, -t, 0.0],
+        [0.0, -1.0, t], [0.0, 1.0, t], [0.0, -1.0, -t], [0.0, 1.0, -t],
+        [t, 0.0, -1.0], [t, 0.0, 1.0], [-t, 0.0, -1.0], [-t, 0.0, 1.0]].map(normalised).to_vec();
+    let mut triangles: Vec<[u32; 3]> = vec![
+        [0, 11, 5], [0, 5, 1], [0, 1, 7], [0, 7, 10], [0, 10, 11],
+        [1, 5, 9], [5, 11, 4], [11, 10, 2], [10, 7, 6], [7, 1, 8],
+        [3, 9, 4], [3, 4, 2], [3, 2, 6], [3, 6, 8], [3, 8, 9],
+        [4, 9, 5], [2, 4, 11], [6, 2, 10], [8, 6, 7], [9, 8, 1]];
+
+    for _ in 0..subdivisions {
+        // edges are shared, so their midpoints are too
+        let mut midpoints: HashMap<(u32, u32), u32> = HashMap::new();
+        let mut midpoint = |a: u32, b: u32| *midpoints.entry((a.min(b), a.max(b))).or_insert_with(|| {
+            let (pa, pb) = (positions[a as usize], positions[b as usize]);
+            positions.push(normalised([0, 1, 2].map(|i| (pa[i] + pb[i]) / 2.0)));
+            (positions.len() - 1) as u32
+        });
+
+        triangles = triangles.iter().flat_map(|&[a, b, c]| {
+            let (ab, bc, ca) = (midpoint(a, b), midpoint(b, c), midpoint(c, a));
+            [[a, ab, ca], [b, bc, ab], [c, ca, bc], [ab, bc, ca]]
+        }).collect();
+    }
+
+    let mut builder = Builder::default();
+    for p in &positions {
+        let u = (p[0].atan2(p[2]) / (2.0 * PI)).rem_euclid(1.0);
+        let v = p[1].clamp(-1.0, 1.0).acos() / PI;
+        builder.vertex(p.map(|c| c * radius), *p, [u, v]);
+    }
+
+    // triangles crossing the seam at the front of the sphere, where u wraps from 1.0 to 0.0, need copies of the
+    // vertices on the 0.0 side with u past 1.0 so the texture isn't squashed backwards across them; the poles have no
+    // sensible u at all, so they get a copy for each triangle with u in the middle of the other two vertices
+    let is_pole = |builder: &Builder, i: u32| builder.normals[i as usize][1].abs() > 0.9999;
+    let mut wrapped: HashMap<u32, u32> = HashMap::new();
+    for triangle in &mut triangles {
+        let us = triangle.map(|i| builder.texture_coordinates[i as usize][0]);
+        let others: Vec<f32> = triangle.iter().zip(us).filter(|&(&i, _)| !is_pole(&builder, i)).map(|(_, u)| u).collect();
+        let wraps = others.iter().cloned().fold(f32::MIN, f32::max) - others.iter().cloned().fold(f32::MAX, f32::min) > 0.5;
+
+        for (i, u) in triangle.iter_mut().zip(us) {
+            if !is_pole(&builder, *i) && wraps && u < 0.5 {
+                *i = *wrapped.entry(*i).or_insert_with(|| {
+                    let [u, v] = builder.texture_coordinates[*i as usize];
+                    builder.vertex(builder.positions[*i as usize], builder.normals[*i as usize], [u + 1.0, v])
+                });
+            }
+        }
+        for i in triangle.iter_mut() {
+            if !is_pole(&builder, *i) {
+                continue;
+            }
+            let u = others.iter().map(|&u| if wraps && u < 0.5 { u + 1.0 } else { u }).sum::<f32>() / others.len() as f32;
+            let v = builder.texture_coordinates[*i as usize][1];
+            *i = builder.vertex(builder.positions[*i as usize], builder.normals[*i as usize], [u, v]);
+        }
+    }
+
+    builder.triangles = triangles;
+    builder.into_model()
+}
+
+// capped, with the texture wrapped around the side once and mapped straight down onto each cap
+pub fn cylinder(radius: f32, height: f32, segments: usize) -> Model {
+    let mut builder = Builder::default();
+    builder.grid(segments, 1, |u, v| {
+        let (sin, cos) = (u * 2.0 * PI).sin_cos();
+        ([radius * sin, height * (0.5 - v), radius * cos], [sin, 0.0, cos])
+    });
+    builder.disc(height / 2.0, radius, segments, true);
+    builder.disc(-height / 2.0, radius, segments, false);
+    builder.into_model()
+}
+
+// pointing up, with the texture wrapped around the side once and mapped straight down onto the base
+pub fn cone(radius: f32, height: f32, segments: usize) -> Model {
+    let mut builder = Builder::default();
+    // the apex has a vertex for each segment so that each gets the normal of its side
+    builder.grid(segments, 1, |u, v| {
+        let (sin, cos) = (u * 2.0 * PI).sin_cos();
+        ([radius * v * sin, height * (0.5 - v), radius * v * cos], normalised([height * sin, radius, height * cos]))
+    });
+    builder.disc(-height / 2.0, radius, segments, false);
+    builder.into_model()
+}
+
+// around the y axis; u goes around the ring and v around the tube, starting from the top
+pub fn torus(ring_radius: f32, tube_radius: f32, ring_segments: usize, tube_segments: usize) -> Model {
+    let mut builder = Builder::default();
+    builder.grid(ring_segments, tube_segments, |u, v| {
+        let (sin_theta, cos_theta) = (u * 2.0 * PI).sin_cos();
+        let (sin_psi, cos_psi) = (PI / 2.0 - v * 2.0 * PI).sin_cos();
+        let distance = ring_radius + tube_radius * cos_psi;
+        ([distance * sin_theta, tube_radius * sin_psi, distance * cos_theta], [cos_psi * sin_theta, sin_psi, cos_psi * cos_theta])
+    });
+    builder.into_model()
+}
+
+// heights is rows of columns, with rows going along z and columns along x, and stretched over width and depth
+pub fn heightfield(width: f32, depth: f32, heights: &[f32], columns: usize, rows: usize) -> Model {
+    assert!(columns >= 2 && rows >= 2 && heights.len() == columns * rows, "heightfield must be at least 2x2");
+    let height = |i: usize, j: usize| heights[j.min(rows - 1) * columns + i.min(columns - 1)];
+    let (dx, dz) = (width / (columns - 1) as f32, depth / (rows - 1) as f32);
+
+    let mut builder = Builder::default();
+    builder.grid(columns - 1, rows - 1, |u, v| {
+        let i = (u * (columns - 1) as f32).round() as usize;
+        let j = (v * (rows - 1) as f32).round() as usize;
+        // central differences, or one-sided at the edges
+        let (i0, i1, j0, j1) = (i.saturating_sub(1), i + 1, j.saturating_sub(1), j + 1);
+        let slope_x = (height(i1, j) - height(i0, j)) / ((i1.min(columns - 1) - i0) as f32 * dx);
+        let slope_z = (height(i, j1) - height(i, j0)) / ((j1.min(rows - 1) - j0) as f32 * dz);
+        ([(u - 0.5) * width, height(i, j), (v - 0.5) * depth], normalised([-slope_x, 1.0, -slope_z]))
+    });
+    builder.into_model()
+}
@@ -1,11 +1,14 @@
 use windows::Win32::Graphics::Gdi::*;
 use core::arch::x86_64::*;
-use std::sync::*;
+use std::{sync::*, ptr::*};
 use once_cell::sync::Lazy;
 use scoped_threadpool::Pool;
 
 use super::simd_vec::*;
-use super::obj::*;
+use super::model::*;
+use super::lighting::*;
+use super::tone_mapping::*;
+use super::texture::*;
 
 #[derive(Clone, Copy)]
 pub struct Bounds {
@@ -33,6 +36,105 @@ impl <T> Buffer<'_, T> where T : Copy {
     }
 }
 
+// where triangles' colours go
+pub enum ColourBuffer<'a> {
+    // sRGB encoded straight into the output, clipping anything brighter than 1.0
+    Direct(Buffer<'a, RGBQUAD>),
+    // linear light to be tone mapped once everything is drawn, with a buffer per channel to suit SIMD; optionally with
+    // the ambient part of it on its own, for ambient occlusion to take back out
+    Hdr([Buffer<'a, f32>; 3], Option<[Buffer<'a, f32>; 3]>),
+    // or what's needed to work them out once everything is drawn, for deferred shading
+    Deferred(GBuffer<'a>)
+}
+
+impl ColourBuffer<'_> {
+    // top, left and stride; the buffers in each variant are all laid out the same way, so the first stands in for the
+    // others
+    pub fn layout(&self) -> (usize, usize, usize) {
+        match self {
+            ColourBuffer::Direct(colour) => (colour.top, colour.left, colour.stride),
+            ColourBuffer::Hdr(hdr, _) => (hdr[0].top, hdr[0].left, hdr[0].stride),
+            ColourBuffer::Deferred(g_buffer) => (g_buffer.materials.top, g_buffer.materials.left, g_buffer.materials.stride)
+        }
+    }
+}
+
+// the nearest triangle's surface at each pixel, laid out like the depth buffer; pixels no triangle covered are left as
+// they were, as the depth buffer says which those are
+pub struct GBuffer<'a> {
+    // world space, after normal mapping
+    pub normals: [Buffer<'a, f32>; 3],
+    // NaN where the triangle has none
+    pub uvs: [Buffer<'a, f32>; 2],
+    // vertex colours, white where the model has none
+    pub colours: [Buffer<'a, f32>; 3],
+    // indices into the scene's materials
+    pub materials: Buffer<'a, u32>
+}
+
+// raw pointers to wherever spans of shaded colour go in a Direct or Hdr colour buffer, indexed from its top left
+#[derive(Clone, Copy)]
+pub struct ColourSpans {
+    direct: *mut i32,
+    hdr: Option<[*mut f32; 3]>,
+    ambient: Option<[*mut f32; 3]>
+}
+
+impl ColourSpans {
+    // None for a G-buffer, which isn't written to a span at a time
+    pub fn new(colour: &mut ColourBuffer) -> Option<Self> {
+        match colour {
+            ColourBuffer::Direct(colour) => Some(ColourSpans { direct: colour.buffer.as_mut_ptr() as *mut i32, hdr: None, ambient: None }),
+            ColourBuffer::Hdr(hdr, ambient) => Some(ColourSpans {
+                direct: null_mut(),
+                hdr: Some(hdr.each_mut().map(|b| b.buffer.as_mut_ptr())),
+                ambient: ambient.as_mut().map(|ambient| ambient.each_mut().map(|b| b.buffer.as_mut_ptr()))
+            }),
+            ColourBuffer::Deferred(_) => None
+        }
+    }
+
+    // whether the ambient part of the colour is kept separately, so needs working out
+    pub fn wants_ambient(&self) -> bool {
+        self.ambient.is_some()
+    }
+
+    #[target_feature(enable = "avx,avx2,fma")]
+    #[inline]
+    pub unsafe fn avx2_store(&self, index: isize, mask: __m256i, colour: [__m256; 3], ambient: Option<[__m256; 3]>) {
+        match self.hdr {
+            Some(hdr_buffers) => {
+                for (buffer, c) in hdr_buffers.into_iter().zip(colour) {
+                    _mm256_maskstore_ps(buffer.offset(index), mask, c);
+                }
+                if let (Some(ambient_buffers), Some(ambient)) = (self.ambient, ambient) {
+                    for (buffer, c) in ambient_buffers.into_iter().zip(ambient) {
+                        _mm256_maskstore_ps(buffer.offset(index), mask, c);
+                    }
+                }
+            }
+            None => {
+                let [r, g, b] = colour.map(|c| _mm256_cvtps_epi32(avx2_srgb_encode(c)));
+                // BGRA in memory
+                let span = _mm256_or_si256(_mm256_slli_epi32(r, 16), _mm256_or_si256(_mm256_slli_epi32(g, 8), b));
+                _mm256_maskstore_epi32(self.direct.offset(index), mask, span);
+            }
+        }
+    }
+}
+
+// what's interpolated across a triangle for the fragment stage, at each of its vertices, in world space
+pub struct Varyings {
+    pub positions: [[f32; 3]; 3],
+    pub normals: [[f32; 3]; 3],
+    // None if the model doesn't have them or the material has no textures
+    pub uvs: Option<[[f32; 2]; 3]>,
+    // for normal mapping; None if the model doesn't have them or the material has no normal map
+    pub tangents: Option<[[f32; 4]; 3]>,
+    // linear, multiplying the base colour; None if the model doesn't have them
+    pub colours: Option<[[f32; 3]; 3]>
+}
+
 fn min3(a: f32, b: f32, c: f32) -> f32 {
     a.min(b).min(c)
 }
@@ -56,6 +158,18 @@ fn calculate_bounds(xmin: f32, ymin: f32, xmax: f32, ymax: f32, x0: f32, y0: f32
     }
 }
 
+// uv = a / b, where a is the sum of w * iw * uv and b the sum of w * iw, and both change by a fixed amount per pixel;
+// so uv changes by (da - uv * db) / b, and these are da and db for a step right and a step down, for picking mip levels
+fn uv_gradients(uvs: [[f32; 2]; 3], iws: [f32; 3], xs: [f32; 3], ys: [f32; 3], iarea: f32) -> [([f32; 2], f32); 2] {
+    let x_steps = [ys[2] - ys[1], ys[0] - ys[2], ys[1] - ys[0]];
+    let y_steps = [xs[1] - xs[2], xs[2] - xs[0], xs[0] - xs[1]];
+    [x_steps, y_steps].map(|steps| {
+        let steps = [0, 1, 2].map(|i| steps[i] * iarea * iws[i]);
+        let da = [0, 1].map(|c| steps[0] * uvs[0][c] + steps[1] * uvs[1][c] + steps[2] * uvs[2][c]);
+        (da, steps[0] + steps[1] + steps[2])
+    })
+}
+
 fn is_top_or_left(x0: f32, y0: f32, x1: f32, y1: f32) -> bool {
     // top                   left (assuming counterclockwise, inverted y axis)
     (y0 == y1 && x0 > x1) || (y1 < y0)
@@ -63,13 +177,13 @@ fn is_top_or_left(x0: f32, y0: f32, x1: f32, y1: f32) -> bool {
 
 #[allow(dead_code)]
 fn simple_fill_triangle(
-        colour: &mut Buffer<RGBQUAD>, depth: &mut Buffer<f32>,
+        colour: &mut ColourBuffer, depth: &mut Buffer<f32>,
         xmin: f32, ymin: f32, xmax: f32, ymax: f32,
         x0: f32, y0: f32, z0: f32, iw0: f32,
         x1: f32, y1: f32, z1: f32, iw1: f32,
         x2: f32, y2: f32, z2: f32, iw2: f32,
         iarea: f32,
-        fill_colour: RGBQUAD) {
+        varyings: &Varyings, material: &PreparedMaterial, lighting: &Lighting) {
     // cull backwards-facing triangles
     if iarea <= 0.0 {
         return;
@@ -80,6 +194,8 @@ fn simple_fill_triangle(
     let tl1 = is_top_or_left(x2, y2, x0, y0);
     let tl2 = is_top_or_left(x0, y0, x1, y1);
 
+    let gradients = varyings.uvs.map(|uvs| uv_gradients(uvs, [iw0, iw1, iw2], [x0, x1, x2], [y0, y1, y2], iarea));
+
     // barycentric coordinates of the first pixel on the first row of the bounding box
     let mut row_w0 = edge_function(x1, y1, x2, y2, xmin + 0.5, ymin + 0.5) * iarea;
     let mut row_w1 = edge_function(x2, y2, x0, y0, xmin + 0.5, ymin + 0.5) * iarea;
@@ -107,7 +223,62 @@ fn simple_fill_triangle(
 
                 // this near test isn't really enough, we really need to clip geometry against the near plane
                 if z >= 0.0 && z < depth.get(xp, yp) {
-                    colour.set(xp, yp, fill_colour);
+                    let interpolate = |vs: [[f32; 3]; 3]| [0, 1, 2].map(|i| vs[0][i] * p_w0 + vs[1][i] * p_w1 + vs[2][i] * p_w2);
+                    let texture_coordinates = varyings.uvs.zip(gradients).map(|(uvs, gradients)| {
+                        let uv = [0, 1].map(|i| uvs[0][i] * p_w0 + uvs[1][i] * p_w1 + uvs[2][i] * p_w2);
+                        let [dx, dy] = gradients.map(|(da, db)| [0, 1].map(|i| (da[i] - uv[i] * db) * t));
+                        (uv, dx, dy)
+                    });
+
+                    let mut normal = interpolate(varyings.normals);
+                    if let (Some((uv, dx, dy)), Some(tangents), Some(normal_map)) = (texture_coordinates, varyings.tangents, material.normal_map) {
+                        let tangent = [0, 1, 2, 3].map(|i| tangents[0][i] * p_w0 + tangents[1][i] * p_w1 + tangents[2][i] * p_w2);
+                        normal = normal_mapped(normal, tangent, sample(normal_map, &material.sampler, uv, dx, dy), material);
+                    }
+                    // the shaded colour with any fog, the base colour it came from, and how much fog left of it; t is the
+                    // interpolated w, which is the view space depth
+                    let vertex_colour = varyings.colours.map_or([1.0; 3], interpolate);
+                    let shade_pixel = || {
+                        let mut base_colour = [0, 1, 2].map(|i| material.base_colour[i] * vertex_colour[i]);
+                        if let (Some((uv, dx, dy)), Some(texture)) = (texture_coordinates, material.base_colour_texture) {
+                            let texel = sample(texture, &material.sampler, uv, dx, dy);
+                            base_colour = [0, 1, 2].map(|i| base_colour[i] * texel[i]);
+                        }
+                        let position = interpolate(varyings.positions);
+                        let (colour, seen) = fogged(shade(position, normal, base_colour, material, lighting), t, position[1], lighting);
+                        (colour, base_colour, seen)
+                    };
+                    match colour {
+                        ColourBuffer::Direct(colour) => {
+                            let c = shade_pixel().0.map(srgb_encode);
+                            colour.set(xp, yp, RGBQUAD { rgbRed: c[0], rgbGreen: c[1], rgbBlue: c[2], rgbReserved: 0 });
+                        }
+                        ColourBuffer::Hdr(hdr, ambient_buffers) => {
+                            let (c, base_colour, seen) = shade_pixel();
+                            for (buffer, c) in hdr.iter_mut().zip(c) {
+                                buffer.set(xp, yp, c);
+                            }
+                            if let Some(ambient_buffers) = ambient_buffers {
+                                for (buffer, c) in ambient_buffers.iter_mut().zip(ambient(base_colour, material, lighting)) {
+                                    buffer.set(xp, yp, c * seen);
+                                }
+                            }
+                        }
+                        // deferred shading only needs the surface
+                        ColourBuffer::Deferred(g_buffer) => {
+                            for (buffer, n) in g_buffer.normals.iter_mut().zip(normal) {
+                                buffer.set(xp, yp, n);
+                            }
+                            let uv = texture_coordinates.map_or([f32::NAN; 2], |(uv, _, _)| uv);
+                            for (buffer, c) in g_buffer.uvs.iter_mut().zip(uv) {
+                                buffer.set(xp, yp, c);
+                            }
+                            for (buffer, c) in g_buffer.colours.iter_mut().zip(vertex_colour) {
+                                buffer.set(xp, yp, c);
+                            }
+                            g_buffer.materials.set(xp, yp, material.id);
+                        }
+                    }
                     depth.set(xp, yp, z);
                 }
             }
@@ -220,7 +391,9 @@ pub fn calculate_all_bounds(
             let ymaxs_out_chunks = ymaxs_out.as_m256_mut().chunks_exact_mut(chunk_size);
             let iareas_out_chunks = iareas_out.as_m256_mut().chunks_exact_mut(chunk_size);
 
-            for (xmins_out_chunk, (ymins_out_chunk, (xmaxs_out_chunk, (ymaxs_out_chunk, iareas_chunk)))) in xmins_out_chunks.zip(ymins_out_chunks.zip(xmaxs_out_chunks.zip(ymaxs_out_chunks.zip(iareas_out_chunks)))) {
+            // the output buffers can be bigger than the model
+            let chunks = xmins_out_chunks.zip(ymins_out_chunks.zip(xmaxs_out_chunks.zip(ymaxs_out_chunks.zip(iareas_out_chunks)))).take(num_chunks as usize);
+            for (xmins_out_chunk, (ymins_out_chunk, (xmaxs_out_chunk, (ymaxs_out_chunk, iareas_chunk)))) in chunks {
                 let triangles_offset = chunk_start;
                 scope.execute(move || unsafe {
                     avx2_calculate_bounds_chunk(
@@ -250,16 +423,25 @@ pub fn calculate_all_bounds(
 #[allow(dead_code)]
 #[target_feature(enable = "avx,avx2,fma")]
 pub unsafe fn avx2_fill_triangle(
-        colour: &mut Buffer<RGBQUAD>, depth: &mut Buffer<f32>,
+        colour: &mut ColourBuffer, depth: &mut Buffer<f32>,
         xmin: f32, ymin: f32, xmax: f32, ymax: f32,
         x0: f32, y0: f32, z0: f32, iw0: f32,
         x1: f32, y1: f32, z1: f32, iw1: f32,
         x2: f32, y2: f32, z2: f32, iw2: f32,
         iarea: f32,
-        fill_colour: RGBQUAD) {
-    debug_assert!(colour.buffer.as_ptr().align_offset(32) == 0);
-    debug_assert!(colour.stride % 8 == 0);
-    debug_assert!(colour.left % 8 == 0);
+        varyings: &Varyings, material: &PreparedMaterial, lighting: &Lighting) {
+    let (c_top, c_left, c_stride) = colour.layout();
+    let spans = ColourSpans::new(colour);
+    let g_buffer = match colour {
+        ColourBuffer::Deferred(g_buffer) => Some((
+            g_buffer.normals.each_mut().map(|b| b.buffer.as_mut_ptr()),
+            g_buffer.uvs.each_mut().map(|b| b.buffer.as_mut_ptr()),
+            g_buffer.colours.each_mut().map(|b| b.buffer.as_mut_ptr()),
+            g_buffer.materials.buffer.as_mut_ptr() as *mut i32)),
+        _ => None
+    };
+    debug_assert!(c_stride % 8 == 0);
+    debug_assert!(c_left % 8 == 0);
     debug_assert!(depth.buffer.as_ptr().align_offset(32) == 0);
     debug_assert!(depth.stride % 8 == 0);
     debug_assert!(depth.left % 8 == 0);
@@ -274,6 +456,8 @@ pub unsafe fn avx2_fill_triangle(
     let tl1 = is_top_or_left(x2, y2, x0, y0);
     let tl2 = is_top_or_left(x0, y0, x1, y1);
 
+    let gradients = varyings.uvs.map(|uvs| uv_gradients(uvs, [iw0, iw1, iw2], [x0, x1, x2], [y0, y1, y2], iarea));
+
     // draw 8 aligned pixels at once
     let xmin = (xmin / 8.0).floor() * 8.0;
     let xmax = (xmax / 8.0).ceil() * 8.0;
@@ -308,8 +492,21 @@ pub unsafe fn avx2_fill_triangle(
     let ystep2 = _mm256_mul_ps(_mm256_set1_ps(x1-x0), iarea);
 
     let zero = _mm256_setzero_ps();
-    let filled_span = _mm256_set1_epi32(*(((&fill_colour) as *const RGBQUAD) as *const i32));
-    let c_buffer = colour.buffer.as_mut_ptr() as *mut i32;
+    // world space positions and normals at each vertex, by component
+    let positions = [0, 1, 2].map(|c| varyings.positions.map(|v| _mm256_set1_ps(v[c])));
+    let normals = [0, 1, 2].map(|c| varyings.normals.map(|v| _mm256_set1_ps(v[c])));
+    let base_colour = material.base_colour.map(|c| _mm256_set1_ps(c));
+    let vertex_colours = varyings.colours.map(|colours| [0, 1, 2].map(|c| colours.map(|v| _mm256_set1_ps(v[c]))));
+    let white = [_mm256_set1_ps(1.0); 3];
+    let texturing = varyings.uvs.zip(gradients).map(|(uvs, gradients)| (
+        [0, 1].map(|c| uvs.map(|v| _mm256_set1_ps(v[c]))),
+        gradients.map(|(da, db)| (da.map(|d| _mm256_set1_ps(d)), _mm256_set1_ps(db)))));
+    let normal_mapping = match (varyings.tangents, material.normal_map) {
+        (Some(tangents), Some(normal_map)) => Some(([0, 1, 2, 3].map(|c| tangents.map(|v| _mm256_set1_ps(v[c]))), normal_map)),
+        _ => None
+    };
+    let material_id = _mm256_set1_epi32(material.id as i32);
+    let nan = _mm256_set1_ps(f32::NAN);
     let d_buffer = depth.buffer.as_mut_ptr() as *mut f32;
     let iw0 = _mm256_set1_ps(iw0);
     let iw1 = _mm256_set1_ps(iw1);
@@ -321,7 +518,8 @@ pub unsafe fn avx2_fill_triangle(
     macro_rules! fill_with_tl {
         ($cmp0:expr, $cmp1:expr, $cmp2:expr) => {{
             let mut yp = ymin as isize;
-            let mut c_row = c_buffer.offset((((ymin as usize - colour.top) * colour.stride) - colour.left) as isize);
+            // an index rather than a pointer as there can be more than one colour buffer
+            let mut c_row = (((ymin as usize - c_top) * c_stride) - c_left) as isize;
             let mut d_row = d_buffer.offset((((ymin as usize - depth.top) * depth.stride) - depth.left) as isize);
             while yp < ymax as isize {
                 let mut w0 = row_w0;
@@ -359,7 +557,45 @@ pub unsafe fn avx2_fill_triangle(
 
                         let mask = _mm256_and_si256(_mm256_and_si256(inside_mask, near_mask), depth_mask);
 
-                        _mm256_maskstore_epi32(c_row.offset(xp) as *mut i32, mask, filled_span);
+                        let interpolate = |vs: [__m256; 3]| _mm256_fmadd_ps(vs[0], p_w0, _mm256_fmadd_ps(vs[1], p_w1, _mm256_mul_ps(vs[2], p_w2)));
+                        let texture_coordinates = texturing.map(|(uvs, gradients)| {
+                            let uv = uvs.map(interpolate);
+                            let [dx, dy] = gradients.map(|(da, db)| [0, 1].map(|c| _mm256_mul_ps(_mm256_fnmadd_ps(uv[c], db, da[c]), t)));
+                            (uv, dx, dy)
+                        });
+
+                        let mut normal = normals.map(interpolate);
+                        if let (Some((uv, dx, dy)), Some((tangents, normal_map))) = (texture_coordinates, normal_mapping) {
+                            normal = avx2_normal_mapped(normal, tangents.map(interpolate), avx2_sample(normal_map, &material.sampler, uv, dx, dy), material);
+                        }
+
+                        let vertex_colour = vertex_colours.map_or(white, |colours| colours.map(interpolate));
+                        if let Some(spans) = spans {
+                            let mut base_colour = [0, 1, 2].map(|c| _mm256_mul_ps(base_colour[c], vertex_colour[c]));
+                            if let (Some((uv, dx, dy)), Some(texture)) = (texture_coordinates, material.base_colour_texture) {
+                                let texel = avx2_sample(texture, &material.sampler, uv, dx, dy);
+                                base_colour = [0, 1, 2].map(|c| _mm256_mul_ps(base_colour[c], texel[c]));
+                            }
+                            // t is the interpolated w, which is the view space depth
+                            let position = positions.map(interpolate);
+                            let (colour, seen) = avx2_fogged(avx2_shade(position, normal, base_colour, material, lighting), t, position[1], lighting);
+                            let ambient = spans.wants_ambient().then(|| avx2_ambient(base_colour, material, lighting).map(|c| _mm256_mul_ps(c, seen)));
+                            spans.avx2_store(c_row + xp, mask, colour, ambient);
+                        }
+                        // deferred shading only needs the surface
+                        if let Some((normal_buffers, uv_buffers, colour_buffers, material_buffer)) = g_buffer {
+                            for (buffer, n) in normal_buffers.into_iter().zip(normal) {
+                                _mm256_maskstore_ps(buffer.offset(c_row + xp), mask, n);
+                            }
+                            let uv = texture_coordinates.map_or([nan; 2], |(uv, _, _)| uv);
+                            for (buffer, c) in uv_buffers.into_iter().zip(uv) {
+                                _mm256_maskstore_ps(buffer.offset(c_row + xp), mask, c);
+                            }
+                            for (buffer, c) in colour_buffers.into_iter().zip(vertex_colour) {
+                                _mm256_maskstore_ps(buffer.offset(c_row + xp), mask, c);
+                            }
+                            _mm256_maskstore_epi32(material_buffer.offset(c_row + xp), mask, material_id);
+                        }
                         _mm256_maskstore_ps(d_row.offset(xp), mask, z);
                     //}
 
@@ -371,7 +607,7 @@ pub unsafe fn avx2_fill_triangle(
                 }
 
                 yp += 1;
-                c_row = c_row.offset(colour.stride as isize);
+                c_row += c_stride as isize;
                 d_row = d_row.offset(depth.stride as isize);
 
                 row_w0 = _mm256_sub_ps(row_w0, ystep0);
@@ -396,14 +632,14 @@ pub unsafe fn avx2_fill_triangle(
 }
 
 pub fn fill_triangle(
-        colour: &mut Buffer<RGBQUAD>, depth: &mut Buffer<f32>,
+        colour: &mut ColourBuffer, depth: &mut Buffer<f32>,
         xmin: f32, ymin: f32, xmax: f32, ymax: f32,
         x0: f32, y0: f32, z0: f32, iw0: f32,
         x1: f32, y1: f32, z1: f32, iw1: f32,
         x2: f32, y2: f32, z2: f32, iw2: f32,
         iarea: f32,
-        fill_colour: RGBQUAD) {
+        varyings: &Varyings, material: &PreparedMaterial, lighting: &Lighting) {
     unsafe {
-        avx2_fill_triangle(colour, depth, xmin, ymin, xmax, ymax, x0, y0, z0, iw0, x1, y1, z1, iw1, x2, y2, z2, iw2, iarea, fill_colour);
+        avx2_fill_triangle(colour, depth, xmin, ymin, xmax, ymax, x0, y0, z0, iw0, x1, y1, z1, iw1, x2, y2, z2, iw2, iarea, varyings, material, lighting);
     }
 }
\ No newline at end of file
@@ -0,0 +1,246 @@
+use super::model::*;
+use super::transformation::*;
+use super::lighting::*;
+use super::environment::*;
+use super::animation::*;
+
+// everything that can be drawn, loaded from a file; models, materials and textures are referred to by their index
+
+// what a texture's texels mean, which decides how mip levels are averaged and whether sampling decodes sRGB
+#[derive(Clone, Copy, PartialEq)]
+#[allow(dead_code)]
+pub enum TextureKind {
+    Linear,
+    Srgb,
+    // tangent space normals, encoded as 0.5 + 0.5 * n
+    Normal
+}
+
+// RGBA, 8 bits per channel, rows from the top; texels holds every mip level one after the other, starting with the
+// full size one
+#[allow(dead_code)]
+pub struct Texture {
+    pub width: usize,
+    pub height: usize,
+    pub kind: TextureKind,
+    pub texels: Vec<[u8; 4]>,
+    // where each mip level starts in texels and how big it is; i32 so SIMD can gather them
+    pub level_offsets: Vec<i32>,
+    pub level_widths: Vec<i32>,
+    pub level_heights: Vec<i32>
+}
+
+// how light reflects off a material; all of them are evaluated per pixel in linear space
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+pub enum Shading {
+    // diffuse only
+    Lambert,
+    // diffuse plus a normalised Blinn-Phong highlight of the given linear colour
+    BlinnPhong { specular: [f32; 3], shininess: f32 },
+    // Cook-Torrance with a GGX distribution, using the material's metallic and roughness
+    CookTorrance
+}
+
+#[allow(dead_code)]
+pub struct Material {
+    pub name: String,
+    // linear RGBA, multiplied by the texture if there is one
+    pub base_colour: [f32; 4],
+    // index into the scene's textures; sRGB encoded
+    pub base_colour_texture: Option<usize>,
+    // tangent space normals, with green pointing up the texture; not sRGB encoded
+    pub normal_texture: Option<usize>,
+    // how much the normal map's x and y are scaled by, to strengthen or weaken it
+    pub normal_scale: f32,
+    pub metallic: f32,
+    pub roughness: f32,
+    pub shading: Shading
+}
+
+impl Default for Material {
+    fn default() -> Material {
+        Material {
+            name: String::from("default"),
+            base_colour: [1.0, 1.0, 1.0, 1.0],
+            base_colour_texture: None,
+            normal_texture: None,
+            normal_scale: 1.0,
+            metallic: 0.0,
+            roughness: 1.0,
+            // a soft highlight, as models without materials are usually scans or sculpts
+            shading: Shading::BlinnPhong { specular: [0.1, 0.1, 0.1], shininess: 20.0 }
+        }
+    }
+}
+
+#[allow(dead_code)]
+pub struct Node {
+    pub name: String,
+    // relative to the parent node
+    pub transformation: Transformation,
+    pub children: Vec<usize>,
+    pub model: Option<usize>,
+    // index into the scene's skins, if the model is skinned; a skinned model's joints put it in world space, so the
+    // node's own transformation isn't used for it
+    pub skin: Option<usize>,
+    // for each of the model's morph targets, when they aren't animated
+    pub morph_weights: Vec<f32>
+}
+
+// the joints a skinned model's vertices follow, which are nodes so that they're animated like any other
+#[allow(dead_code)]
+pub struct Skin {
+    pub name: String,
+    pub joints: Vec<usize>,
+    // from the model's space to each joint's, as it was when the model was bound to the skeleton
+    pub inverse_bind_transformations: Vec<Transformation>
+}
+
+// how distance fog thickens with view space depth
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+pub enum FogFalloff {
+    // none nearer than start and all of it from end on; the density isn't used
+    Linear { start: f32, end: f32 },
+    // 1 - e^(-density * depth)
+    Exponential,
+    // 1 - e^(-(density * depth)^2), which stays clearer nearby and closes in faster
+    ExponentialSquared
+}
+
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+pub struct Fog {
+    // linear RGB
+    pub colour: [f32; 3],
+    pub falloff: FogFalloff,
+    pub density: f32,
+    // height fog on top of that, of height_density at height and thinning by e for every 1.0 / height_falloff higher
+    // up; 0.0 height_density for none
+    pub height: f32,
+    pub height_density: f32,
+    pub height_falloff: f32
+}
+
+// where the scene is drawn from, looking towards target with up pointing roughly up the screen; the view volume is
+// view_width across at the near plane, with its height following the aspect ratio of whatever's drawn into
+#[derive(Clone, Copy)]
+pub struct Camera {
+    pub eye: CartesianCoordinates,
+    pub target: CartesianCoordinates,
+    pub up: CartesianVector,
+    pub view_width: f32,
+    pub near: f32,
+    pub far: f32,
+    // what HDR colours are multiplied by before tone mapping
+    pub exposure: f32
+}
+
+// the camera that was hard-coded before scenes had one; above the model's head looking down 30 degrees
+impl Default for Camera {
+    fn default() -> Camera {
+        Camera {
+            eye: CartesianCoordinates { x: 0.0, y: 1.0, z: 2.0 },
+            target: CartesianCoordinates { x: 0.0, y: 0.0, z: 0.0 },
+            up: CartesianVector { x: 0.0, y: 1.0, z: -0.5 },
+            // big enough to hold the model and a bit more
+            view_width: 0.4,
+            near: 2.0,
+            far: 2.5,
+            exposure: 1.0
+        }
+    }
+}
+
+#[allow(dead_code)]
+pub struct Scene {
+    pub models: Vec<Model>,
+    pub materials: Vec<Material>,
+    pub textures: Vec<Texture>,
+    pub nodes: Vec<Node>,
+    pub skins: Vec<Skin>,
+    // the nodes without parents
+    pub roots: Vec<usize>,
+    // in world space
+    pub lights: Vec<Light>,
+    // linear RGB light reaching every surface from every direction
+    pub ambient: [f32; 3],
+    pub fog: Option<Fog>,
+    // drawn behind everything and reflected in it
+    pub environment: Option<Environment>,
+    // all played at once, from when the scene is first drawn
+    pub animations: Vec<Animation>,
+    // moved by any camera animations
+    pub camera: Camera
+}
+
+impl Scene {
+    // nothing to draw yet, with the default lights and camera
+    pub fn empty() -> Scene {
+        Scene {
+            models: Vec::new(),
+            materials: Vec::new(),
+            textures: Vec::new(),
+            nodes: Vec::new(),
+            skins: Vec::new(),
+            roots: Vec::new(),
+            lights: default_lights(),
+            ambient: DEFAULT_AMBIENT,
+            fog: None,
+            environment: None,
+            animations: Vec::new(),
+            camera: Camera::default()
+        }
+    }
+
+    // a scene with just one untransformed model, with the default material for every material it uses
+    pub fn from_model(model: Model) -> Scene {
+        let mut scene = Scene::empty();
+        // .obj and .ply files can number their triangles' materials, but .obj material libraries aren't read and .ply
+        // files have nothing to say what they look like
+        let num_materials = model.triangle_materials[..].iter().max().map_or(1, |&m| m as usize + 1);
+        scene.materials.resize_with(num_materials, Material::default);
+        scene.models.push(model);
+        scene.nodes.push(Node { name: String::from("model"), transformation: Transformation::IDENTITY, children: Vec::new(), model: Some(0), skin: None, morph_weights: Vec::new() });
+        scene.roots.push(0);
+        scene
+    }
+
+    // a new root node drawing the model, with every triangle of it and its levels of detail using the one material;
+    // returns the node's index
+    pub fn add_model(&mut self, name: &str, mut model: Model, material: usize, transformation: Transformation) -> usize {
+        let num_triangles = model.num_triangles as usize;
+        model.triangle_materials = std::iter::repeat(material as u32).take(num_triangles).collect();
+        for lod in model.lods.iter_mut() {
+            lod.triangle_materials = std::iter::repeat(material as u32).take(lod.num_triangles as usize).collect();
+        }
+        self.models.push(model);
+        self.nodes.push(Node { name: name.to_string(), transformation, children: Vec::new(), model: Some(self.models.len() - 1), skin: None, morph_weights: Vec::new() });
+        self.roots.push(self.nodes.len() - 1);
+        self.nodes.len() - 1
+    }
+
+    // a new node above all of the roots, to move the whole scene at once
+    pub fn add_root_node(&mut self, name: &str) -> usize {
+        let children = std::mem::take(&mut self.roots);
+        self.nodes.push(Node { name: name.to_string(), transformation: Transformation::IDENTITY, children, model: None, skin: None, morph_weights: Vec::new() });
+        self.roots.push(self.nodes.len() - 1);
+        self.nodes.len() - 1
+    }
+
+    // world transformations for every node from their transformations relative to their parents, which are usually
+    // animated; both are indexed the same way as the nodes
+    pub fn world_transformations(&self, transformations: &[Transformation]) -> Vec<Transformation> {
+        let mut worlds = vec![Transformation::IDENTITY; self.nodes.len()];
+        let mut stack: Vec<(usize, Transformation)> = self.roots.iter().map(|&i| (i, Transformation::IDENTITY)).collect();
+
+        while let Some((i, parent)) = stack.pop() {
+            let node = &self.nodes[i];
+            worlds[i] = transformations[i].then(&parent);
+            stack.extend(node.children.iter().map(|&child| (child, worlds[i])));
+        }
+
+        worlds
+    }
+}
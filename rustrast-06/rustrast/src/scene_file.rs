@@ -0,0 +1,289 @@
+use std::{fs::*, path::*};
+use serde::Deserialize;
+
+use super::scene::*;
+use super::lighting::*;
+use super::transformation::*;
+use super::model::*;
+use super::primitives::*;
+use super::animation::*;
+
+// a declarative description of a scene and how to render it, in TOML, so frames can be rendered without changing
+// the code; not-suitable-for-production, panics on any error
+//
+//   scene = "base.glb"                 # optional glTF scene to add the models to
+//   environment = "sky.hdr"            # optional equirectangular environment
+//   ambient = [0.05, 0.05, 0.05]
+//   turntable_period = 20.0            # optional, seconds for the scene to turn around once
+//   looping = "repeat"                 # or "once" or "ping_pong", for the turntable and the scene's animations
+//
+//   [output]
+//   width = 1280
+//   height = 720
+//   path = "frame.png"
+//   time = 0.0                         # seconds into the scene's animations
+//
+//   [camera]
+//   eye = [0.0, 1.0, 2.0]
+//   target = [0.0, 0.0, 0.0]
+//   up = [0.0, 1.0, -0.5]
+//   view_width = 0.4                   # across the view volume at the near plane
+//   near = 2.0
+//   far = 2.5
+//   exposure = 1.0
+//
+//   [[materials]]
+//   name = "clay"
+//   base_colour = [0.8, 0.5, 0.4, 1.0]  # linear
+//   shading = { type = "cook_torrance" }  # or "lambert", or "blinn_phong" with specular and shininess
+//   metallic = 0.0
+//   roughness = 0.6
+//
+//   [[models]]
+//   path = "head.obj"                   # .obj, .ply or .stl
+//   # or primitive = { type = "uv_sphere", radius = 1.0, segments = 32, rings = 16 }, or any of the others below
+//   material = "clay"
+//   translation = [0.0, 0.0, 0.0]
+//   rotation = [0.0, 90.0, 0.0]         # degrees about x, then y, then z
+//   scale = [1.0, 1.0, 1.0]
+//
+//   [[lights]]
+//   type = "directional"                # or "point" with position and range, or "spot" with both and angles
+//   direction = [-1.0, -1.0, -1.0]
+//   colour = [0.3, 0.3, 0.3]
+//
+// everything but the models' paths or primitives and the materials' names has a default, the camera and materials
+// falling back to the ones used without a scene file; paths are relative to the scene file, and lights replace the
+// scene's default ones if there are any
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SceneFile {
+    pub scene: Option<PathBuf>,
+    pub environment: Option<PathBuf>,
+    pub ambient: Option<[f32; 3]>,
+    pub turntable_period: Option<f32>,
+    pub looping: Option<LoopingDescription>,
+    #[serde(default)]
+    pub output: OutputDescription,
+    #[serde(default)]
+    pub camera: CameraDescription,
+    #[serde(default)]
+    pub materials: Vec<MaterialDescription>,
+    #[serde(default)]
+    pub models: Vec<ModelDescription>,
+    #[serde(default)]
+    pub lights: Vec<LightDescription>
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputDescription {
+    pub width: usize,
+    pub height: usize,
+    pub path: PathBuf,
+    pub time: f32
+}
+
+impl Default for OutputDescription {
+    fn default() -> OutputDescription {
+        OutputDescription { width: 1280, height: 720, path: PathBuf::from("frame.png"), time: 0.0 }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct CameraDescription {
+    pub eye: Option<[f32; 3]>,
+    pub target: Option<[f32; 3]>,
+    pub up: Option<[f32; 3]>,
+    pub view_width: Option<f32>,
+    pub near: Option<f32>,
+    pub far: Option<f32>,
+    pub exposure: Option<f32>
+}
+
+impl CameraDescription {
+    pub fn camera(&self) -> Camera {
+        let default = Camera::default();
+        Camera {
+            eye: self.eye.map(coordinates).unwrap_or(default.eye),
+            target: self.target.map(coordinates).unwrap_or(default.target),
+            up: self.up.map(vector).unwrap_or(default.up),
+            view_width: self.view_width.unwrap_or(default.view_width),
+            near: self.near.unwrap_or(default.near),
+            far: self.far.unwrap_or(default.far),
+            exposure: self.exposure.unwrap_or(default.exposure)
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum LoopingDescription {
+    Once,
+    Repeat,
+    PingPong
+}
+
+impl LoopingDescription {
+    pub fn looping(self) -> Looping {
+        match self {
+            LoopingDescription::Once => Looping::Once,
+            LoopingDescription::Repeat => Looping::Repeat,
+            LoopingDescription::PingPong => Looping::PingPong
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum ShadingDescription {
+    Lambert,
+    BlinnPhong { specular: [f32; 3], shininess: f32 },
+    CookTorrance
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MaterialDescription {
+    pub name: String,
+    pub base_colour: Option<[f32; 4]>,
+    pub shading: Option<ShadingDescription>,
+    pub metallic: Option<f32>,
+    pub roughness: Option<f32>
+}
+
+impl MaterialDescription {
+    // anything not given is the same as the default material
+    pub fn material(&self) -> Material {
+        let default = Material::default();
+        Material {
+            name: self.name.clone(),
+            base_colour: self.base_colour.unwrap_or(default.base_colour),
+            metallic: self.metallic.unwrap_or(default.metallic),
+            roughness: self.roughness.unwrap_or(default.roughness),
+            shading: self.shading.map(|shading| match shading {
+                ShadingDescription::Lambert => Shading::Lambert,
+                ShadingDescription::BlinnPhong { specular, shininess } => Shading::BlinnPhong { specular, shininess },
+                ShadingDescription::CookTorrance => Shading::CookTorrance
+            }).unwrap_or(default.shading),
+            ..default
+        }
+    }
+}
+
+// the generated models, with the same parameters
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum PrimitiveDescription {
+    Plane { width: f32, depth: f32, segments_x: usize, segments_z: usize },
+    Cuboid { width: f32, height: f32, depth: f32 },
+    UvSphere { radius: f32, segments: usize, rings: usize },
+    Icosphere { radius: f32, subdivisions: usize },
+    Cylinder { radius: f32, height: f32, segments: usize },
+    Cone { radius: f32, height: f32, segments: usize },
+    Torus { ring_radius: f32, tube_radius: f32, ring_segments: usize, tube_segments: usize },
+    Heightfield { width: f32, depth: f32, heights: Vec<f32>, columns: usize, rows: usize }
+}
+
+impl PrimitiveDescription {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PrimitiveDescription::Plane { .. } => "plane",
+            PrimitiveDescription::Cuboid { .. } => "cuboid",
+            PrimitiveDescription::UvSphere { .. } => "uv_sphere",
+            PrimitiveDescription::Icosphere { .. } => "icosphere",
+            PrimitiveDescription::Cylinder { .. } => "cylinder",
+            PrimitiveDescription::Cone { .. } => "cone",
+            PrimitiveDescription::Torus { .. } => "torus",
+            PrimitiveDescription::Heightfield { .. } => "heightfield"
+        }
+    }
+
+    pub fn model(&self) -> Model {
+        match *self {
+            PrimitiveDescription::Plane { width, depth, segments_x, segments_z } => plane(width, depth, segments_x, segments_z),
+            PrimitiveDescription::Cuboid { width, height, depth } => cuboid(width, height, depth),
+            PrimitiveDescription::UvSphere { radius, segments, rings } => uv_sphere(radius, segments, rings),
+            PrimitiveDescription::Icosphere { radius, subdivisions } => icosphere(radius, subdivisions),
+            PrimitiveDescription::Cylinder { radius, height, segments } => cylinder(radius, height, segments),
+            PrimitiveDescription::Cone { radius, height, segments } => cone(radius, height, segments),
+            PrimitiveDescription::Torus { ring_radius, tube_radius, ring_segments, tube_segments } => torus(ring_radius, tube_radius, ring_segments, tube_segments),
+            PrimitiveDescription::Heightfield { width, depth, ref heights, columns, rows } => heightfield(width, depth, heights, columns, rows)
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModelDescription {
+    // one or the other
+    pub path: Option<PathBuf>,
+    pub primitive: Option<PrimitiveDescription>,
+    // the name of one of the scene file's materials, or the default material if there isn't one
+    pub material: Option<String>,
+    #[serde(default)]
+    pub translation: [f32; 3],
+    #[serde(default)]
+    pub rotation: [f32; 3],
+    #[serde(default = "unit_scale")]
+    pub scale: [f32; 3]
+}
+
+fn unit_scale() -> [f32; 3] {
+    [1.0, 1.0, 1.0]
+}
+
+impl ModelDescription {
+    // scaled, then rotated, then translated
+    pub fn transformation(&self) -> Transformation {
+        let [rx, ry, rz] = self.rotation.map(f32::to_radians);
+        let [sx, sy, sz] = self.scale;
+        let [dx, dy, dz] = self.translation;
+        Transformation::scale(sx, sy, sz)
+            .then(&Transformation::rotate_x(rx))
+            .then(&Transformation::rotate_y(ry))
+            .then(&Transformation::rotate_z(rz))
+            .then(&Transformation::translate(dx, dy, dz))
+    }
+}
+
+// angles in degrees, from the direction to the edge of the cone; a range of 0.0 is unlimited
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum LightDescription {
+    Directional { direction: [f32; 3], colour: [f32; 3] },
+    Point { position: [f32; 3], colour: [f32; 3], #[serde(default)] range: f32 },
+    Spot { position: [f32; 3], direction: [f32; 3], colour: [f32; 3], #[serde(default)] range: f32, inner_angle: f32, outer_angle: f32 }
+}
+
+impl LightDescription {
+    pub fn light(&self) -> Light {
+        match *self {
+            LightDescription::Directional { direction, colour } => Light::Directional { direction: vector(direction).normalised(), colour },
+            LightDescription::Point { position, colour, range } => Light::Point { position: coordinates(position), colour, range },
+            LightDescription::Spot { position, direction, colour, range, inner_angle, outer_angle } => Light::Spot {
+                position: coordinates(position),
+                direction: vector(direction).normalised(),
+                colour,
+                range,
+                inner_angle: inner_angle.to_radians(),
+                outer_angle: outer_angle.to_radians()
+            }
+        }
+    }
+}
+
+fn coordinates([x, y, z]: [f32; 3]) -> CartesianCoordinates {
+    CartesianCoordinates { x, y, z }
+}
+
+fn vector([x, y, z]: [f32; 3]) -> CartesianVector {
+    CartesianVector { x, y, z }
+}
+
+pub fn read_scene_file(path: &Path) -> SceneFile {
+    let text = read_to_string(path).unwrap();
+    toml::from_str(&text).unwrap_or_else(|e| panic!("{}: {}", path.display(), e))
+}
@@ -0,0 +1,85 @@
+use core::arch::x86_64::*;
+
+// functions AVX2 doesn't have; not-suitable-for-production, these will only work on processors that support AVX2
+
+// polynomial approximations good to about 1e-5; x must be positive
+// http://jrfonseca.blogspot.com/2008/09/fast-sse2-pow-tables-or-polynomials.html
+#[target_feature(enable = "fma,avx,avx2")]
+#[inline]
+pub unsafe fn avx2_log2(x: __m256) -> __m256 {
+    let bits = _mm256_castps_si256(x);
+    let exponent = _mm256_cvtepi32_ps(_mm256_sub_epi32(_mm256_srli_epi32(bits, 23), _mm256_set1_epi32(127)));
+    // the mantissa as a number from 1 to 2
+    let m = _mm256_castsi256_ps(_mm256_or_si256(_mm256_and_si256(bits, _mm256_set1_epi32(0x007fffff)), _mm256_set1_epi32(0x3f800000)));
+
+    let mut p = _mm256_set1_ps(-3.4436006e-2);
+    p = _mm256_fmadd_ps(p, m, _mm256_set1_ps(3.1821337e-1));
+    p = _mm256_fmadd_ps(p, m, _mm256_set1_ps(-1.2315303));
+    p = _mm256_fmadd_ps(p, m, _mm256_set1_ps(2.5988452));
+    p = _mm256_fmadd_ps(p, m, _mm256_set1_ps(-3.324199));
+    p = _mm256_fmadd_ps(p, m, _mm256_set1_ps(3.11579));
+
+    _mm256_fmadd_ps(p, _mm256_sub_ps(m, _mm256_set1_ps(1.0)), exponent)
+}
+
+#[target_feature(enable = "fma,avx,avx2")]
+#[inline]
+pub unsafe fn avx2_exp2(x: __m256) -> __m256 {
+    let x = _mm256_min_ps(_mm256_max_ps(x, _mm256_set1_ps(-126.0)), _mm256_set1_ps(127.0));
+    let whole = _mm256_round_ps(x, _MM_FROUND_TO_NEAREST_INT | _MM_FROUND_NO_EXC);
+    // from -0.5 to 0.5
+    let f = _mm256_sub_ps(x, whole);
+    let exponent = _mm256_castsi256_ps(_mm256_slli_epi32(_mm256_add_epi32(_mm256_cvtps_epi32(whole), _mm256_set1_epi32(127)), 23));
+
+    let mut p = _mm256_set1_ps(1.8775767e-3);
+    p = _mm256_fmadd_ps(p, f, _mm256_set1_ps(8.98934e-3));
+    p = _mm256_fmadd_ps(p, f, _mm256_set1_ps(5.5826318e-2));
+    p = _mm256_fmadd_ps(p, f, _mm256_set1_ps(2.4015361e-1));
+    p = _mm256_fmadd_ps(p, f, _mm256_set1_ps(6.931531e-1));
+    p = _mm256_fmadd_ps(p, f, _mm256_set1_ps(9.9999994e-1));
+
+    _mm256_mul_ps(p, exponent)
+}
+
+#[target_feature(enable = "fma,avx,avx2")]
+#[inline]
+pub unsafe fn avx2_dot(a: [__m256; 3], b: [__m256; 3]) -> __m256 {
+    _mm256_fmadd_ps(a[0], b[0], _mm256_fmadd_ps(a[1], b[1], _mm256_mul_ps(a[2], b[2])))
+}
+
+#[target_feature(enable = "fma,avx,avx2")]
+#[inline]
+pub unsafe fn avx2_cross(a: [__m256; 3], b: [__m256; 3]) -> [__m256; 3] {
+    [
+        _mm256_fmsub_ps(a[1], b[2], _mm256_mul_ps(a[2], b[1])),
+        _mm256_fmsub_ps(a[2], b[0], _mm256_mul_ps(a[0], b[2])),
+        _mm256_fmsub_ps(a[0], b[1], _mm256_mul_ps(a[1], b[0]))
+    ]
+}
+
+// zero length vectors stay zero
+#[target_feature(enable = "fma,avx,avx2")]
+#[inline]
+pub unsafe fn avx2_normalised(v: [__m256; 3]) -> [__m256; 3] {
+    let length_squared = _mm256_max_ps(avx2_dot(v, v), _mm256_set1_ps(f32::MIN_POSITIVE));
+    // rsqrt alone is too rough for tight highlights, so one Newton-Raphson step
+    let estimate = _mm256_rsqrt_ps(length_squared);
+    let half_x = _mm256_mul_ps(length_squared, _mm256_set1_ps(0.5));
+    let scale = _mm256_mul_ps(estimate, _mm256_fnmadd_ps(half_x, _mm256_mul_ps(estimate, estimate), _mm256_set1_ps(1.5)));
+    v.map(|c| _mm256_mul_ps(c, scale))
+}
+
+// the same for one vector at a time, for the scalar code that has to match the SIMD code
+
+pub fn dot(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+pub fn cross(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+pub fn normalised(v: [f32; 3]) -> [f32; 3] {
+    let length = dot(v, v).max(f32::MIN_POSITIVE).sqrt();
+    v.map(|c| c / length)
+}
@@ -1,12 +1,13 @@
 use core::arch::x86_64::*;
 use std::{ops::*, slice::*};
 use aligned_vec::*;
-use safe_transmute::trivial::*;
+use safe_transmute::{trivial::*, to_bytes::*};
 
 // needs to be as high as that required by the widest SIMD tech in use; here it's 128 for caching
 const ALIGNMENT: usize = 128;
 
 // hides the mechanics of alignment  and conversion to from calling code
+#[derive(Clone)]
 pub struct SimdVec<T> where T : TriviallyTransmutable {
     vs: AVec<T, ConstAlign<ALIGNMENT>>
 }
@@ -19,6 +20,11 @@ impl<T> SimdVec<T> where T : TriviallyTransmutable {
     pub fn with_capacity(capacity: usize) -> Self {
         SimdVec {vs: AVec::with_capacity(ALIGNMENT, capacity) }
     }
+
+    // any bit pattern is valid for trivially transmutable types, so all zeroes is too
+    pub fn zeroed(len: usize) -> Self {
+        SimdVec {vs: AVec::from_iter(ALIGNMENT, std::iter::repeat(unsafe { std::mem::zeroed() }).take(len)) }
+    }
  
     pub fn push(&mut self, v: T) {
         self.vs.push(v)
@@ -32,6 +38,15 @@ impl<T> SimdVec<T> where T : TriviallyTransmutable {
         self.vs.as_ptr()
     }
 
+    // for reading and writing directly from and to files
+    pub fn as_bytes(&self) -> &[u8] {
+        transmute_to_bytes(&self.vs)
+    }
+
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        transmute_to_bytes_mut(&mut self.vs)
+    }
+
     // these ignore any trailing values; alignment ensures there are no leading ones
     // can't figure out how to mark SIMD types as TriviallyTransmutable
     pub fn as_m256(&self) -> &[__m256] {
@@ -0,0 +1,351 @@
+use std::{cmp::Reverse, collections::*, mem::take};
+
+use super::model::*;
+
+// level of detail by edge collapse, in the order that moves the surface least by Garland and Heckbert's quadric error
+// metric (https://www.cs.cmu.edu/~garland/Papers/quadrics.pdf), which is the sum of squared distances to the planes of
+// the triangles originally around each vertex
+//
+// each collapse moves a vertex onto one of its neighbours rather than somewhere in between, so the simplified model's
+// vertices are a subset of the original's and keep their normals, texture coordinates, joints and morph deltas as
+// they were; the price is a little more error than placing it optimally
+
+// collapses are rejected if they'd turn any remaining triangle's normal through more than about 75 degrees, which
+// would fold the surface over on itself
+const MIN_NORMAL_COS: f64 = 0.25;
+// or if they'd move the surface further than this on average, relative to the size of the model; without a limit,
+// a model with long seams ends up as little more than triangles strung between them
+const MAX_ERROR: f64 = 0.01;
+
+// a symmetric 4x4 matrix, stored as its upper triangle: a00 a01 a02 a03 a11 a12 a13 a22 a23 a33; plus the total area
+// of the planes in it, so the error can be averaged over them
+#[derive(Clone, Copy, Default)]
+struct Quadric {
+    q: [f64; 10],
+    area: f64
+}
+
+impl Quadric {
+    // squared distance to the plane n.p + d = 0, weighted by area; n must be unit length
+    fn plane(n: [f64; 3], d: f64, area: f64) -> Self {
+        let [a, b, c] = n;
+        Quadric {
+            q: [a * a, a * b, a * c, a * d, b * b, b * c, b * d, c * c, c * d, d * d].map(|q| q * area),
+            area
+        }
+    }
+
+    fn add(&mut self, other: &Quadric) {
+        for (q, o) in self.q.iter_mut().zip(other.q.iter()) {
+            *q += o;
+        }
+        self.area += other.area;
+    }
+
+    // the mean squared distance from p to the planes
+    fn error(&self, p: [f64; 3]) -> f64 {
+        if self.area == 0.0 {
+            return 0.0;
+        }
+        let [a00, a01, a02, a03, a11, a12, a13, a22, a23, a33] = self.q;
+        let [x, y, z] = p;
+        let error = a00 * x * x + a11 * y * y + a22 * z * z + 2.0 * (a01 * x * y + a02 * x * z + a12 * y * z)
+            + 2.0 * (a03 * x + a13 * y + a23 * z) + a33;
+        error.max(0.0) / self.area
+    }
+}
+
+fn sub(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+
+fn cross(a: [f64; 3], b: [f64; 3]) -> [f64; 3] {
+    [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]]
+}
+
+fn dot(a: [f64; 3], b: [f64; 3]) -> f64 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+// not normalised, so the magnitude is twice the triangle's area
+fn triangle_normal(ps: [[f64; 3]; 3]) -> [f64; 3] {
+    cross(sub(ps[1], ps[0]), sub(ps[2], ps[0]))
+}
+
+struct Simplifier {
+    positions: Vec<[f64; 3]>,
+    quadrics: Vec<Quadric>,
+    // vertices on a border, a seam or a non-manifold edge, which never move
+    locked: Vec<bool>,
+    removed: Vec<bool>,
+    // bumped whenever a vertex's surroundings change, so that its stale collapses in the heap can be skipped
+    versions: Vec<u32>,
+    triangles: Vec<[u32; 3]>,
+    alive: Vec<bool>,
+    num_alive: usize,
+    // may include dead triangles, which are skipped
+    vertex_triangles: Vec<Vec<u32>>,
+    max_error_squared: f64,
+    // cost as bits, which order the same as the f64s themselves as they're never negative; then version, from and to
+    heap: BinaryHeap<Reverse<(u64, u32, u32, u32)>>
+}
+
+impl Simplifier {
+    fn new(model: &Model) -> Self {
+        let num_vertices = model.num_vertices as usize;
+        let positions: Vec<[f64; 3]> = (0..model.num_vertices).map(|i| {
+            let (p, _) = model.homogenous_coordinates(i).to_cartesian();
+            [p.x as f64, p.y as f64, p.z as f64]
+        }).collect();
+        let triangles: Vec<[u32; 3]> = (0..model.num_triangles as usize)
+            .map(|it| [model.trianglev0s[it], model.trianglev1s[it], model.trianglev2s[it]])
+            .collect();
+
+        let mut quadrics = vec![Quadric::default(); num_vertices];
+        let mut vertex_triangles = vec![Vec::new(); num_vertices];
+        let mut edges: HashMap<(u32, u32), u32> = HashMap::new();
+        for (it, vs) in triangles.iter().enumerate() {
+            let n = triangle_normal(vs.map(|v| positions[v as usize]));
+            let twice_area = dot(n, n).sqrt();
+            if twice_area > 0.0 {
+                let n = n.map(|c| c / twice_area);
+                let quadric = Quadric::plane(n, -dot(n, positions[vs[0] as usize]), twice_area * 0.5);
+                vs.iter().for_each(|&v| quadrics[v as usize].add(&quadric));
+            }
+            for i in 0..3 {
+                vertex_triangles[vs[i] as usize].push(it as u32);
+                let (a, b) = (vs[i], vs[(i + 1) % 3]);
+                *edges.entry((a.min(b), a.max(b))).or_insert(0) += 1;
+            }
+        }
+
+        // vertices split for their attributes don't share edges, so seams show up as borders here too
+        let mut locked = vec![false; num_vertices];
+        for (&(a, b), &count) in edges.iter() {
+            if count != 2 {
+                locked[a as usize] = true;
+                locked[b as usize] = true;
+            }
+        }
+
+        let mut simplifier = Simplifier {
+            positions,
+            quadrics,
+            locked,
+            removed: vec![false; num_vertices],
+            versions: vec![0; num_vertices],
+            num_alive: triangles.len(),
+            alive: vec![true; triangles.len()],
+            triangles,
+            vertex_triangles,
+            max_error_squared: (MAX_ERROR * model.size() as f64).powi(2),
+            heap: BinaryHeap::new()
+        };
+        for v in 0..num_vertices as u32 {
+            simplifier.push_collapses(v);
+        }
+        simplifier
+    }
+
+    fn neighbours(&self, v: u32) -> Vec<u32> {
+        let mut neighbours: Vec<u32> = self.vertex_triangles[v as usize].iter()
+            .filter(|&&it| self.alive[it as usize])
+            .flat_map(|&it| self.triangles[it as usize])
+            .filter(|&w| w != v)
+            .collect();
+        neighbours.sort_unstable();
+        neighbours.dedup();
+        neighbours
+    }
+
+    // replaces any of the vertex's collapses already in the heap
+    fn push_collapses(&mut self, from: u32) {
+        self.versions[from as usize] += 1;
+        if self.locked[from as usize] || self.removed[from as usize] {
+            return;
+        }
+        let version = self.versions[from as usize];
+        for to in self.neighbours(from) {
+            let mut quadric = self.quadrics[from as usize];
+            quadric.add(&self.quadrics[to as usize]);
+            let cost = quadric.error(self.positions[to as usize]);
+            if cost > self.max_error_squared {
+                continue;
+            }
+            self.heap.push(Reverse((cost.to_bits(), version, from, to)));
+        }
+    }
+
+    fn can_collapse(&self, from: u32, to: u32) -> bool {
+        // the edge's ends mustn't have neighbours in common other than across the triangles between them, or the
+        // collapse would pinch the surface into a non-manifold edge
+        let shared = self.vertex_triangles[from as usize].iter()
+            .filter(|&&it| self.alive[it as usize] && self.triangles[it as usize].contains(&to))
+            .count();
+        let to_neighbours = self.neighbours(to);
+        let common = self.neighbours(from).iter().filter(|w| to_neighbours.binary_search(w).is_ok()).count();
+        if common > shared {
+            return false;
+        }
+
+        let p = self.positions[to as usize];
+        self.vertex_triangles[from as usize].iter()
+            .filter(|&&it| self.alive[it as usize] && !self.triangles[it as usize].contains(&to))
+            .all(|&it| {
+                let vs = self.triangles[it as usize];
+                let before = triangle_normal(vs.map(|v| self.positions[v as usize]));
+                let after = triangle_normal(vs.map(|v| if v == from { p } else { self.positions[v as usize] }));
+                let lengths = (dot(before, before) * dot(after, after)).sqrt();
+                lengths > 0.0 && dot(before, after) >= MIN_NORMAL_COS * lengths
+            })
+    }
+
+    fn collapse(&mut self, from: u32, to: u32) {
+        for it in take(&mut self.vertex_triangles[from as usize]) {
+            let it = it as usize;
+            if !self.alive[it] {
+                continue;
+            }
+            if self.triangles[it].contains(&to) {
+                self.alive[it] = false;
+                self.num_alive -= 1;
+            } else {
+                self.triangles[it] = self.triangles[it].map(|v| if v == from { to } else { v });
+                self.vertex_triangles[to as usize].push(it as u32);
+            }
+        }
+        let alive = &self.alive;
+        self.vertex_triangles[to as usize].retain(|&it| alive[it as usize]);
+
+        let quadric = self.quadrics[from as usize];
+        self.quadrics[to as usize].add(&quadric);
+        self.removed[from as usize] = true;
+        self.versions[from as usize] += 1;
+
+        self.push_collapses(to);
+        for w in self.neighbours(to) {
+            self.push_collapses(w);
+        }
+    }
+
+    fn simplify(&mut self, target_triangles: usize) {
+        while self.num_alive > target_triangles {
+            let Some(Reverse((_, version, from, to))) = self.heap.pop() else {
+                break;
+            };
+            if version != self.versions[from as usize] || self.removed[to as usize] || !self.can_collapse(from, to) {
+                continue;
+            }
+            self.collapse(from, to);
+        }
+    }
+}
+
+impl Model {
+    // a copy with about target_triangles triangles, or as close as it gets without moving borders and seams, folding
+    // the surface over or moving it too far; the copy has no levels of detail of its own
+    pub fn simplified(&self, target_triangles: u32) -> Model {
+        let mut simplifier = Simplifier::new(self);
+        simplifier.simplify(target_triangles as usize);
+
+        let order: Vec<u32> = (0..self.num_triangles).filter(|&it| simplifier.alive[it as usize]).collect();
+        let mut model = self.clone();
+        model.lods = Vec::new();
+        model.reorder_triangles(&order);
+        for (it, &old) in order.iter().enumerate() {
+            let [v0, v1, v2] = simplifier.triangles[old as usize];
+            model.trianglev0s[it] = v0;
+            model.trianglev1s[it] = v1;
+            model.trianglev2s[it] = v2;
+        }
+        model.calculate_surface_normals();
+        model.remove_unused_vertices();
+        model
+    }
+
+    // fills lods with simpler and simpler copies, each with reduction times as many triangles as the one before, until
+    // the next would have fewer than min_triangles or simplification stops getting anywhere
+    pub fn generate_lods(&mut self, reduction: f32, min_triangles: u32) {
+        let mut lods: Vec<Model> = Vec::new();
+        loop {
+            let previous = lods.last().unwrap_or(&*self);
+            let target = (previous.num_triangles as f32 * reduction) as u32;
+            if target < min_triangles {
+                break;
+            }
+            let lod = previous.simplified(target);
+            // not even halfway there means borders and seams are most of what's left
+            if lod.num_triangles as f32 > previous.num_triangles as f32 * (1.0 + reduction) * 0.5 {
+                break;
+            }
+            lods.push(lod);
+        }
+        self.lods = lods;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::primitives::*;
+
+    fn vertex_key(model: &Model, v: usize) -> [u32; 5] {
+        [model.xs[v], model.ys[v], model.zs[v], model.texture_us[v], model.texture_vs[v]].map(f32::to_bits)
+    }
+
+    fn assert_valid_triangles(model: &Model) {
+        for it in 0..model.num_triangles as usize {
+            let vs = [model.trianglev0s[it], model.trianglev1s[it], model.trianglev2s[it]];
+            assert!(vs.iter().all(|&v| v < model.num_vertices), "triangle {} has vertices {:?} of {}", it, vs, model.num_vertices);
+            assert!(vs[0] != vs[1] && vs[1] != vs[2] && vs[2] != vs[0], "triangle {} is degenerate", it);
+        }
+    }
+
+    #[test]
+    fn simplification_gets_close_to_the_target() {
+        let model = icosphere(1.0, 3);
+        let target = model.num_triangles / 4;
+        let simplified = model.simplified(target);
+        assert!(simplified.num_triangles <= target * 5 / 4, "{} triangles for a target of {}", simplified.num_triangles, target);
+        assert!(simplified.num_vertices < model.num_vertices);
+        assert_valid_triangles(&simplified);
+    }
+
+    #[test]
+    fn borders_and_seams_stay_where_they_are() {
+        // the texture seam and the poles split the sphere's vertices, so the edges along them only have one triangle each
+        let model = uv_sphere(1.0, 32, 16);
+        let mut edges: HashMap<(u32, u32), u32> = HashMap::new();
+        for it in 0..model.num_triangles as usize {
+            let vs = [model.trianglev0s[it], model.trianglev1s[it], model.trianglev2s[it]];
+            for i in 0..3 {
+                let (a, b) = (vs[i], vs[(i + 1) % 3]);
+                *edges.entry((a.min(b), a.max(b))).or_insert(0) += 1;
+            }
+        }
+        let seam: HashSet<u32> = edges.iter().filter(|&(_, &count)| count != 2).flat_map(|(&(a, b), _)| [a, b]).collect();
+        assert!(!seam.is_empty());
+
+        let simplified = model.simplified(model.num_triangles / 8);
+        assert!(simplified.num_triangles < model.num_triangles / 2);
+        assert_valid_triangles(&simplified);
+        let remaining: HashSet<[u32; 5]> = (0..simplified.num_vertices as usize).map(|v| vertex_key(&simplified, v)).collect();
+        for &v in &seam {
+            assert!(remaining.contains(&vertex_key(&model, v as usize)), "seam vertex {} moved or went", v);
+        }
+    }
+
+    #[test]
+    fn levels_of_detail_get_smaller() {
+        let mut model = icosphere(1.0, 5);
+        model.generate_lods(0.25, 100);
+        assert!(model.lods.len() >= 2, "{} levels of detail", model.lods.len());
+        let mut previous = model.num_triangles;
+        for lod in &model.lods {
+            assert!(lod.num_triangles < previous, "{} triangles after {}", lod.num_triangles, previous);
+            assert!(lod.lods.is_empty());
+            assert_valid_triangles(lod);
+            previous = lod.num_triangles;
+        }
+    }
+}
@@ -0,0 +1,378 @@
+use core::arch::x86_64::*;
+use std::sync::*;
+use once_cell::sync::Lazy;
+use scoped_threadpool::Pool;
+
+use super::simd_vec::*;
+use super::model::*;
+use super::scene::*;
+use super::transformation::*;
+use super::simd_maths::*;
+
+// skeletal skinning, which moves each vertex by a weighted blend of up to four joints' transformations, straight into
+// world space; it runs before the camera transformation, which then starts from the skinned positions
+//
+// linear blending of matrices is cheap but loses volume where joints twist or bend sharply (the "candy wrapper"),
+// which blending rotations as dual quaternions doesn't; scale and shear can't be held in a dual quaternion, so they're
+// kept as a separate "stretch" matrix applied first, and directions are only rotated, which assumes it's near uniform
+
+#[derive(Clone, Copy, PartialEq)]
+#[allow(dead_code)]
+pub enum Skinning {
+    LinearBlend,
+    DualQuaternion
+}
+
+// each joint's transformation for this frame, a plane per element to suit SIMD gathers, indexed by the joint's position
+// in the skin
+pub enum JointPalette {
+    // the top three rows of each matrix, by column, as the bottom row is always 0, 0, 0, 1
+    LinearBlend([Vec<f32>; 12]),
+    // x, y, z and w of the real and dual parts, and the stretch matrix by column
+    DualQuaternion { real: [Vec<f32>; 4], dual: [Vec<f32>; 4], stretch: [Vec<f32>; 9] }
+}
+
+// worlds are the world transformations of all of the scene's nodes
+pub fn joint_palette(skin: &Skin, worlds: &[Transformation], skinning: Skinning) -> JointPalette {
+    let matrices = skin.joints.iter().zip(skin.inverse_bind_transformations.iter()).map(|(&joint, inverse_bind)| inverse_bind.then(&worlds[joint]));
+    match skinning {
+        Skinning::LinearBlend => {
+            let mut planes: [Vec<f32>; 12] = Default::default();
+            for m in matrices {
+                for c in 0..4 {
+                    for r in 0..3 {
+                        planes[c * 3 + r].push(m.matrix[c][r]);
+                    }
+                }
+            }
+            JointPalette::LinearBlend(planes)
+        }
+        Skinning::DualQuaternion => {
+            let mut real: [Vec<f32>; 4] = Default::default();
+            let mut dual: [Vec<f32>; 4] = Default::default();
+            let mut stretch: [Vec<f32>; 9] = Default::default();
+            for m in matrices {
+                let (t, q, _) = m.decomposed();
+                // what's left of the matrix once the rotation is taken out of it, R^T M
+                let (r, tl) = (q.to_3x3(), m.tl_3x3());
+                for c in 0..3 {
+                    for row in 0..3 {
+                        stretch[c * 3 + row].push(r[row][0] * tl[c][0] + r[row][1] * tl[c][1] + r[row][2] * tl[c][2]);
+                    }
+                }
+                // half the translation times the rotation
+                let d = q.then(Quaternion { x: t[0], y: t[1], z: t[2], w: 0.0 });
+                for (plane, v) in real.iter_mut().zip([q.x, q.y, q.z, q.w]) {
+                    plane.push(v);
+                }
+                for (plane, v) in dual.iter_mut().zip([d.x, d.y, d.z, d.w]) {
+                    plane.push(0.5 * v);
+                }
+            }
+            JointPalette::DualQuaternion { real, dual, stretch }
+        }
+    }
+}
+
+impl JointPalette {
+    fn num_joints(&self) -> usize {
+        match self {
+            JointPalette::LinearBlend(planes) => planes[0].len(),
+            JointPalette::DualQuaternion { real, .. } => real[0].len()
+        }
+    }
+}
+
+// v + 2 q.xyz x (q.xyz x v + q.w v), which is q v q* for a unit quaternion
+#[target_feature(enable = "fma,avx,avx2")]
+#[inline]
+unsafe fn avx2_rotated(q: [__m256; 4], v: [__m256; 3]) -> [__m256; 3] {
+    let axis = [q[0], q[1], q[2]];
+    let inner = avx2_cross(axis, v);
+    let inner = [0, 1, 2].map(|c| _mm256_fmadd_ps(q[3], v[c], inner[c]));
+    let outer = avx2_cross(axis, inner);
+    let two = _mm256_set1_ps(2.0);
+    [0, 1, 2].map(|c| _mm256_fmadd_ps(two, outer[c], v[c]))
+}
+
+// positions come out with a w of 1.0
+#[target_feature(enable = "fma,avx,avx2")]
+unsafe fn avx2_chunk_skinned(
+        positions_out: [&mut [__m256]; 4], normals_out: [&mut [__m256]; 3], mut tangents_out: Option<[&mut [__m256]; 3]>,
+        positions: [&SimdVec<f32>; 4], normals: [&SimdVec<f32>; 3], model: &Model, palette: &JointPalette,
+        source_offset: usize, chunk_size: usize) {
+    let positions = positions.map(|p| p.as_m256());
+    let normals = normals.map(|n| n.as_m256());
+    let tangents = [&model.tangent_xs, &model.tangent_ys, &model.tangent_zs].map(|t| t.as_m256());
+    let joints = model.joints.each_ref().map(|js| js.as_m256i());
+    let weights = model.joint_weights.each_ref().map(|ws| ws.as_m256());
+
+    let zero = _mm256_setzero_ps();
+    let one = _mm256_set1_ps(1.0);
+    let sign_bit = _mm256_set1_ps(-0.0);
+    // so that a bad index can't read outside of the palette
+    let last = _mm256_set1_epi32(palette.num_joints() as i32 - 1);
+
+    for i in 0..chunk_size {
+        let j = source_offset + i;
+        let js = joints.map(|js| _mm256_min_epu32(js[j], last));
+        let ws = weights.map(|ws| ws[j]);
+        let gather = |plane: &Vec<f32>, k: usize| _mm256_i32gather_ps(plane.as_ptr(), js[k], 4);
+        let blend = |plane: &Vec<f32>| (0..4).fold(zero, |sum, k| _mm256_fmadd_ps(ws[k], gather(plane, k), sum));
+
+        let iw = _mm256_div_ps(one, positions[3][j]);
+        let p = [0, 1, 2].map(|c| _mm256_mul_ps(positions[c][j], iw));
+        let n = normals.map(|n| n[j]);
+        let t = tangents_out.is_some().then(|| tangents.map(|t| t[j]));
+
+        let (p, n, t) = match palette {
+            JointPalette::LinearBlend(planes) => {
+                let m = planes.each_ref().map(blend);
+                let transformed = |v: [__m256; 3]| [0, 1, 2].map(|r| _mm256_fmadd_ps(m[r], v[0], _mm256_fmadd_ps(m[3 + r], v[1], _mm256_mul_ps(m[6 + r], v[2]))));
+                let moved = transformed(p);
+                let p = [0, 1, 2].map(|r| _mm256_add_ps(moved[r], m[9 + r]));
+                // the blend is near enough a rotation and uniform scale for normals not to need the inverse transpose
+                (p, avx2_normalised(transformed(n)), t.map(|t| avx2_normalised(transformed(t))))
+            }
+            JointPalette::DualQuaternion { real, dual, stretch } => {
+                // q and -q are the same rotation, so each joint's is blended from whichever is nearer the first's
+                let real0 = real.each_ref().map(|plane| gather(plane, 0));
+                let mut r = [zero; 4];
+                let mut d = [zero; 4];
+                for (k, &weight) in ws.iter().enumerate() {
+                    let rk = if k == 0 { real0 } else { real.each_ref().map(|plane| gather(plane, k)) };
+                    let dk = dual.each_ref().map(|plane| gather(plane, k));
+                    let dot = _mm256_fmadd_ps(rk[0], real0[0], _mm256_fmadd_ps(rk[1], real0[1], _mm256_fmadd_ps(rk[2], real0[2], _mm256_mul_ps(rk[3], real0[3]))));
+                    let w = _mm256_xor_ps(weight, _mm256_and_ps(dot, sign_bit));
+                    r = [0, 1, 2, 3].map(|c| _mm256_fmadd_ps(w, rk[c], r[c]));
+                    d = [0, 1, 2, 3].map(|c| _mm256_fmadd_ps(w, dk[c], d[c]));
+                }
+                let length_squared = _mm256_fmadd_ps(r[0], r[0], _mm256_fmadd_ps(r[1], r[1], _mm256_fmadd_ps(r[2], r[2], _mm256_mul_ps(r[3], r[3]))));
+                let inverse_length = _mm256_div_ps(one, _mm256_sqrt_ps(_mm256_max_ps(length_squared, _mm256_set1_ps(f32::MIN_POSITIVE))));
+                let r = r.map(|c| _mm256_mul_ps(c, inverse_length));
+                let d = d.map(|c| _mm256_mul_ps(c, inverse_length));
+
+                let s = stretch.each_ref().map(blend);
+                let stretched = [0, 1, 2].map(|row| _mm256_fmadd_ps(s[row], p[0], _mm256_fmadd_ps(s[3 + row], p[1], _mm256_mul_ps(s[6 + row], p[2]))));
+                let rotated = avx2_rotated(r, stretched);
+
+                // the translation is 2 d r*, whose vector part is 2 (r.w d.xyz - d.w r.xyz + r.xyz x d.xyz)
+                let cross = avx2_cross([r[0], r[1], r[2]], [d[0], d[1], d[2]]);
+                let two = _mm256_set1_ps(2.0);
+                let p = [0, 1, 2].map(|c| {
+                    let translation = _mm256_add_ps(_mm256_fmsub_ps(r[3], d[c], _mm256_mul_ps(d[3], r[c])), cross[c]);
+                    _mm256_fmadd_ps(two, translation, rotated[c])
+                });
+                (p, avx2_rotated(r, n), t.map(|t| avx2_rotated(r, t)))
+            }
+        };
+
+        positions_out[0][i] = p[0];
+        positions_out[1][i] = p[1];
+        positions_out[2][i] = p[2];
+        positions_out[3][i] = one;
+        normals_out[0][i] = n[0];
+        normals_out[1][i] = n[1];
+        normals_out[2][i] = n[2];
+        if let (Some(tangents_out), Some(t)) = (tangents_out.as_mut(), t) {
+            tangents_out[0][i] = t[0];
+            tangents_out[1][i] = t[1];
+            tangents_out[2][i] = t[2];
+        }
+    }
+}
+
+fn rotated(q: [f32; 4], v: [f32; 3]) -> [f32; 3] {
+    let axis = [q[0], q[1], q[2]];
+    let inner = cross(axis, v);
+    let inner = [0, 1, 2].map(|c| q[3] * v[c] + inner[c]);
+    let outer = cross(axis, inner);
+    [0, 1, 2].map(|c| 2.0 * outer[c] + v[c])
+}
+
+// the same calculation as the SIMD version, for one vertex
+fn skinned(positions: [&SimdVec<f32>; 4], normals: [&SimdVec<f32>; 3], model: &Model, palette: &JointPalette, i: usize) -> ([f32; 3], [f32; 3], Option<[f32; 3]>) {
+    let last = palette.num_joints() - 1;
+    let js = model.joints.each_ref().map(|js| (js[i] as usize).min(last));
+    let ws = model.joint_weights.each_ref().map(|ws| ws[i]);
+    let blend = |plane: &Vec<f32>| (0..4).fold(0.0, |sum, k| ws[k].mul_add(plane[js[k]], sum));
+
+    let iw = 1.0 / positions[3][i];
+    let p = [positions[0][i] * iw, positions[1][i] * iw, positions[2][i] * iw];
+    let n = normals.map(|n| n[i]);
+    let t = (model.tangent_xs.len() > 0).then(|| [model.tangent_xs[i], model.tangent_ys[i], model.tangent_zs[i]]);
+
+    match palette {
+        JointPalette::LinearBlend(planes) => {
+            let m = planes.each_ref().map(blend);
+            let transformed = |v: [f32; 3]| [0, 1, 2].map(|r| m[r] * v[0] + m[3 + r] * v[1] + m[6 + r] * v[2]);
+            let moved = transformed(p);
+            let p = [0, 1, 2].map(|r| moved[r] + m[9 + r]);
+            (p, normalised(transformed(n)), t.map(|t| normalised(transformed(t))))
+        }
+        JointPalette::DualQuaternion { real, dual, stretch } => {
+            let real0 = real.each_ref().map(|plane| plane[js[0]]);
+            let mut r = [0.0; 4];
+            let mut d = [0.0; 4];
+            for k in 0..4 {
+                let rk = real.each_ref().map(|plane| plane[js[k]]);
+                let dk = dual.each_ref().map(|plane| plane[js[k]]);
+                let dot = rk[0] * real0[0] + rk[1] * real0[1] + rk[2] * real0[2] + rk[3] * real0[3];
+                let w = if dot < 0.0 { -ws[k] } else { ws[k] };
+                r = [0, 1, 2, 3].map(|c| w.mul_add(rk[c], r[c]));
+                d = [0, 1, 2, 3].map(|c| w.mul_add(dk[c], d[c]));
+            }
+            let inverse_length = 1.0 / (r[0] * r[0] + r[1] * r[1] + r[2] * r[2] + r[3] * r[3]).max(f32::MIN_POSITIVE).sqrt();
+            let r = r.map(|c| c * inverse_length);
+            let d = d.map(|c| c * inverse_length);
+
+            let s = stretch.each_ref().map(blend);
+            let stretched = [0, 1, 2].map(|row| s[row] * p[0] + s[3 + row] * p[1] + s[6 + row] * p[2]);
+            let rotated_p = rotated(r, stretched);
+
+            let cross = cross([r[0], r[1], r[2]], [d[0], d[1], d[2]]);
+            let p = [0, 1, 2].map(|c| 2.0 * (r[3] * d[c] - d[3] * r[c] + cross[c]) + rotated_p[c]);
+            (p, rotated(r, n), t.map(|t| rotated(r, t)))
+        }
+    }
+}
+
+// my machine stops showing improvement above four threads
+static NUM_SKINNING_THREADS: u32 = 4;
+static SKINNING_WORKERS: Lazy<Mutex<Pool>> = Lazy::new(|| Mutex::new(Pool::new(NUM_SKINNING_THREADS)));
+
+// the model's positions, normals and tangents moved by its joints into world space; positions and normals are the
+// model's own, or after morphing, and tangents_out is only given when the model has tangents
+pub fn avx2_skinned(positions_out: [&mut SimdVec<f32>; 4], normals_out: [&mut SimdVec<f32>; 3], tangents_out: Option<[&mut SimdVec<f32>; 3]>, positions: [&SimdVec<f32>; 4], normals: [&SimdVec<f32>; 3], model: &Model, palette: &JointPalette) {
+    debug_assert!(model.joints.iter().all(|js| js.len() == model.num_vertices as usize));
+    debug_assert!(model.joint_weights.iter().all(|ws| ws.len() == model.num_vertices as usize));
+    debug_assert!(palette.num_joints() > 0);
+
+    let num_vertices = model.num_vertices;
+    let num_chunks = NUM_SKINNING_THREADS;
+    // maintain 128 byte alignment for caching
+    let chunk_size = ((num_vertices / num_chunks) / 32) * 4;
+    let mut chunk_start = 0;
+
+    let [xs_out, ys_out, zs_out, ws_out] = positions_out;
+    let [nxs_out, nys_out, nzs_out] = normals_out;
+    let mut tangents_out = tangents_out;
+
+    if chunk_size > 0 {
+        let mut pool = SKINNING_WORKERS.lock().unwrap();
+        pool.scoped(|scope| {
+            let mut positions_chunks = [&mut *xs_out, &mut *ys_out, &mut *zs_out, &mut *ws_out].map(|p| p.as_m256_mut().chunks_exact_mut(chunk_size as usize));
+            let mut normals_chunks = [&mut *nxs_out, &mut *nys_out, &mut *nzs_out].map(|n| n.as_m256_mut().chunks_exact_mut(chunk_size as usize));
+            let mut tangents_chunks = tangents_out.as_mut().map(|ts| ts.each_mut().map(|t| t.as_m256_mut().chunks_exact_mut(chunk_size as usize)));
+
+            // the output buffers can be bigger than the model
+            for _ in 0..num_chunks {
+                let positions_out_chunk = positions_chunks.each_mut().map(|c| c.next().unwrap());
+                let normals_out_chunk = normals_chunks.each_mut().map(|c| c.next().unwrap());
+                let tangents_out_chunk = tangents_chunks.as_mut().map(|ts| ts.each_mut().map(|c| c.next().unwrap()));
+                let source_offset = chunk_start;
+                scope.execute(move || unsafe {
+                    avx2_chunk_skinned(positions_out_chunk, normals_out_chunk, tangents_out_chunk, positions, normals, model, palette, source_offset, chunk_size as usize);
+                });
+
+                chunk_start += chunk_size as usize;
+            }
+        });
+    }
+
+    // do any leftovers sequentially
+    for i in (chunk_start * 8)..(num_vertices as usize) {
+        let (p, n, t) = skinned(positions, normals, model, palette, i);
+        xs_out[i] = p[0];
+        ys_out[i] = p[1];
+        zs_out[i] = p[2];
+        ws_out[i] = 1.0;
+        nxs_out[i] = n[0];
+        nys_out[i] = n[1];
+        nzs_out[i] = n[2];
+        if let (Some([txs_out, tys_out, tzs_out]), Some(t)) = (tangents_out.as_mut(), t) {
+            txs_out[i] = t[0];
+            tys_out[i] = t[1];
+            tzs_out[i] = t[2];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::primitives::*;
+
+    // a cylinder two units tall, with weights blending from the first joint at the bottom to the second at the top;
+    // enough vertices for the SIMD chunks and some left over
+    fn rigged_cylinder() -> Model {
+        let mut model = cylinder(0.5, 2.0, 64);
+        model.generate_tangents();
+        let n = model.num_vertices as usize;
+        let top: Vec<f32> = (0..n).map(|i| ((model.ys[i] + 1.0) / 2.0).clamp(0.0, 1.0)).collect();
+        model.joints = [0, 1, 0, 0].map(|j| (0..n).map(|_| j).collect());
+        model.joint_weights = [
+            top.iter().map(|t| 1.0 - t).collect(),
+            top.iter().copied().collect(),
+            (0..n).map(|_| 0.0).collect(),
+            (0..n).map(|_| 0.0).collect()];
+        model
+    }
+
+    fn skin() -> Skin {
+        Skin { name: String::from("test"), joints: vec![0, 1], inverse_bind_transformations: vec![Transformation::IDENTITY, Transformation::translate(0.0, -1.0, 0.0)] }
+    }
+
+    type Skinned = ([SimdVec<f32>; 4], [SimdVec<f32>; 3], [SimdVec<f32>; 3]);
+
+    fn avx2_skinned_model(model: &Model, palette: &JointPalette) -> Skinned {
+        let n = model.num_vertices as usize;
+        let (mut positions, mut normals, mut tangents): Skinned = (
+            std::array::from_fn(|_| SimdVec::zeroed(n)), std::array::from_fn(|_| SimdVec::zeroed(n)), std::array::from_fn(|_| SimdVec::zeroed(n)));
+        let model_positions = [&model.xs, &model.ys, &model.zs, &model.ws];
+        let model_normals = [&model.vertex_normal_xs, &model.vertex_normal_ys, &model.vertex_normal_zs];
+        avx2_skinned(positions.each_mut(), normals.each_mut(), Some(tangents.each_mut()), model_positions, model_normals, model, palette);
+        (positions, normals, tangents)
+    }
+
+    fn assert_close(name: &str, i: usize, a: [f32; 3], b: [f32; 3]) {
+        assert!((0..3).all(|c| (a[c] - b[c]).abs() < 1e-4), "{} {} {:?} and {:?}", name, i, a, b);
+    }
+
+    #[test]
+    fn simd_skinning_matches_scalar_skinning() {
+        let model = rigged_cylinder();
+        // the top joint bends over and grows a little, which dual quaternions keep as a separate stretch
+        let worlds = [
+            Transformation::translate(0.2, 0.0, 0.0),
+            Transformation::scale(1.1, 1.1, 1.1).then(&Transformation::rotate_z(0.8)).then(&Transformation::translate(0.2, 1.0, 0.3))];
+        for skinning in [Skinning::LinearBlend, Skinning::DualQuaternion] {
+            let palette = joint_palette(&skin(), &worlds, skinning);
+            let (positions, normals, tangents) = avx2_skinned_model(&model, &palette);
+            let model_positions = [&model.xs, &model.ys, &model.zs, &model.ws];
+            let model_normals = [&model.vertex_normal_xs, &model.vertex_normal_ys, &model.vertex_normal_zs];
+            for i in 0..model.num_vertices as usize {
+                let (p, n, t) = skinned(model_positions, model_normals, &model, &palette, i);
+                assert_close("position", i, [0, 1, 2].map(|c| positions[c][i]), p);
+                assert_eq!(positions[3][i], 1.0);
+                assert_close("normal", i, normals.each_ref().map(|n| n[i]), n);
+                assert_close("tangent", i, tangents.each_ref().map(|t| t[i]), t.unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn identity_joints_leave_vertices_in_place() {
+        let model = rigged_cylinder();
+        let skin = Skin { name: String::from("test"), joints: vec![0, 1], inverse_bind_transformations: vec![Transformation::IDENTITY; 2] };
+        let worlds = [Transformation::IDENTITY; 2];
+        for skinning in [Skinning::LinearBlend, Skinning::DualQuaternion] {
+            let (positions, normals, tangents) = avx2_skinned_model(&model, &joint_palette(&skin, &worlds, skinning));
+            for i in 0..model.num_vertices as usize {
+                assert_close("position", i, [0, 1, 2].map(|c| positions[c][i]), [model.xs[i], model.ys[i], model.zs[i]]);
+                assert_close("normal", i, normals.each_ref().map(|n| n[i]), [model.vertex_normal_xs[i], model.vertex_normal_ys[i], model.vertex_normal_zs[i]]);
+                assert_close("tangent", i, tangents.each_ref().map(|t| t[i]), [model.tangent_xs[i], model.tangent_ys[i], model.tangent_zs[i]]);
+            }
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use std::io::*;
+
+use super::simd_vec::*;
+use super::model::*;
+
+// not-suitable-for-production STL parsing; panics on any error
+// https://en.wikipedia.org/wiki/STL_(file_format)
+//
+// STL facets don't share vertices, so every triangle gets three of its own; the facet normals are ignored as they're
+// often missing or wrong and can be calculated from the vertices anyway
+
+const BINARY_HEADER_LENGTH: usize = 80;
+const BINARY_FACET_LENGTH: usize = 50;
+
+// binary files can start with "solid" too, but their length is always determined by the facet count
+pub fn is_binary_stl(bytes: &[u8]) -> bool {
+    if bytes.len() < BINARY_HEADER_LENGTH + 4 {
+        return false;
+    }
+    let num_facets = u32::from_le_bytes(bytes[BINARY_HEADER_LENGTH..BINARY_HEADER_LENGTH + 4].try_into().unwrap()) as usize;
+    bytes.len() == BINARY_HEADER_LENGTH + 4 + num_facets * BINARY_FACET_LENGTH
+}
+
+fn read_binary_vertices(bytes: &[u8], xs: &mut SimdVec<f32>, ys: &mut SimdVec<f32>, zs: &mut SimdVec<f32>) {
+    let f32_at = |offset: usize| f32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+
+    let mut facet = BINARY_HEADER_LENGTH + 4;
+    while facet + BINARY_FACET_LENGTH <= bytes.len() {
+        // skip the normal; each facet ends with a two byte attribute count that's almost never used
+        for iv in 0..3 {
+            let vertex = facet + 12 + iv * 12;
+            xs.push(f32_at(vertex));
+            ys.push(f32_at(vertex + 4));
+            zs.push(f32_at(vertex + 8));
+        }
+        facet += BINARY_FACET_LENGTH;
+    }
+}
+
+fn read_ascii_vertices(bytes: &[u8], xs: &mut SimdVec<f32>, ys: &mut SimdVec<f32>, zs: &mut SimdVec<f32>) {
+    let text = std::str::from_utf8(bytes).unwrap();
+    for line in text.lines() {
+        let mut tokens = line.split_ascii_whitespace();
+        // everything else is structure that doesn't tell us anything
+        if tokens.next() == Some("vertex") {
+            xs.push(tokens.next().unwrap().parse().unwrap());
+            ys.push(tokens.next().unwrap().parse().unwrap());
+            zs.push(tokens.next().unwrap().parse().unwrap());
+        }
+    }
+}
+
+pub fn read_stl<R: Read>(mut file: R) -> Model {
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes).unwrap();
+
+    let mut xs = SimdVec::new();
+    let mut ys = SimdVec::new();
+    let mut zs = SimdVec::new();
+
+    if is_binary_stl(&bytes) {
+        read_binary_vertices(&bytes, &mut xs, &mut ys, &mut zs);
+    }
+    else {
+        read_ascii_vertices(&bytes, &mut xs, &mut ys, &mut zs);
+    }
+
+    let num_triangles = (xs.len() / 3) as u32;
+    let ws = std::iter::repeat(1.0).take(xs.len()).collect();
+    let trianglev0s = (0..num_triangles).map(|it| it * 3).collect();
+    let trianglev1s = (0..num_triangles).map(|it| it * 3 + 1).collect();
+    let trianglev2s = (0..num_triangles).map(|it| it * 3 + 2).collect();
+
+    Model::new(xs, ys, zs, ws, trianglev0s, trianglev1s, trianglev2s)
+}
@@ -0,0 +1,378 @@
+use core::arch::x86_64::*;
+
+use super::scene::*;
+use super::simd_maths::*;
+
+// texture lookups for the fragment stage; coordinates wrap, with 0.0, 0.0 at the top left of the texture, and channels
+// come back as 0.0-1.0, decoded to linear for sRGB textures
+//
+// the mip level comes from how far the texture coordinates move per pixel, which the caller works out
+
+#[derive(Clone, Copy, PartialEq)]
+#[allow(dead_code)]
+pub enum Filter {
+    // the nearest texel of the nearest mip level
+    Nearest,
+    // the four nearest texels of the nearest mip level, blended
+    Bilinear,
+    // bilinear from the two nearest mip levels, blended
+    Trilinear
+}
+
+// anisotropic filtering takes up to max_anisotropy samples along the direction the texture is squashed in, so
+// surfaces seen at a glancing angle don't blur; 1 turns it off
+#[derive(Clone, Copy)]
+pub struct Sampler {
+    pub filter: Filter,
+    pub max_anisotropy: u32
+}
+
+impl Texture {
+    // just the full size level, until generate_mips is called
+    pub fn new(width: usize, height: usize, texels: Vec<[u8; 4]>) -> Texture {
+        Texture {
+            width,
+            height,
+            kind: TextureKind::Linear,
+            texels,
+            level_offsets: vec![0],
+            level_widths: vec![width as i32],
+            level_heights: vec![height as i32]
+        }
+    }
+
+    pub fn num_levels(&self) -> usize {
+        self.level_offsets.len()
+    }
+
+    // halves each level down to 1x1, averaging 2x2 blocks of the one above the way the kind of texture needs; an odd
+    // row or column at the edge is dropped, which is near enough
+    pub fn generate_mips(&mut self, kind: TextureKind) {
+        self.kind = kind;
+        self.texels.truncate(self.width * self.height);
+        self.level_offsets.truncate(1);
+        self.level_widths.truncate(1);
+        self.level_heights.truncate(1);
+
+        let mut offset = 0;
+        let mut width = self.width;
+        let mut height = self.height;
+        while width > 1 || height > 1 {
+            let next_offset = self.texels.len();
+            let next_width = (width / 2).max(1);
+            let next_height = (height / 2).max(1);
+            for y in 0..next_height {
+                for x in 0..next_width {
+                    // a level that's one texel wide or high only gets halved the other way
+                    let xs = [x * 2, (x * 2 + 1).min(width - 1)];
+                    let ys = [y * 2, (y * 2 + 1).min(height - 1)];
+                    let block = [(0, 0), (1, 0), (0, 1), (1, 1)].map(|(i, j)| self.texels[offset + ys[j] * width + xs[i]]);
+                    self.texels.push(average(block, kind));
+                }
+            }
+
+            offset = next_offset;
+            width = next_width;
+            height = next_height;
+            self.level_offsets.push(offset as i32);
+            self.level_widths.push(width as i32);
+            self.level_heights.push(height as i32);
+        }
+    }
+}
+
+pub fn srgb_to_linear(c: u8) -> f32 {
+    srgb_fraction_to_linear(c as f32 / 255.0)
+}
+
+// for colours that were already 0.0-1.0 in the file
+// in double precision so that 0.0 and 1.0 survive a round trip exactly
+pub fn srgb_fraction_to_linear(c: f32) -> f32 {
+    let c = c as f64;
+    (if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }) as f32
+}
+
+pub fn linear_to_srgb(c: f32) -> u8 {
+    (linear_to_srgb_fraction(c) * 255.0).round() as u8
+}
+
+pub fn linear_to_srgb_fraction(c: f32) -> f32 {
+    let c = c.clamp(0.0, 1.0) as f64;
+    (if c <= 0.0031308 { c * 12.92 } else { 1.055 * c.powf(1.0 / 2.4) - 0.055 }) as f32
+}
+
+fn average(block: [[u8; 4]; 4], kind: TextureKind) -> [u8; 4] {
+    let mean = |c: usize| ((block.iter().map(|t| t[c] as u32).sum::<u32>() + 2) / 4) as u8;
+    match kind {
+        TextureKind::Linear => [0, 1, 2, 3].map(mean),
+        // averaging the encoded values would darken the smaller levels
+        TextureKind::Srgb => {
+            let [r, g, b] = [0, 1, 2].map(|c| linear_to_srgb(block.iter().map(|t| srgb_to_linear(t[c])).sum::<f32>() / 4.0));
+            [r, g, b, mean(3)]
+        }
+        // renormalised, otherwise bumps flatten out in the distance
+        TextureKind::Normal => {
+            let n = [0, 1, 2].map(|c| block.iter().map(|t| t[c] as f32 / 255.0 * 2.0 - 1.0).sum::<f32>());
+            let length = (n[0] * n[0] + n[1] * n[1] + n[2] * n[2]).sqrt();
+            let n = if length > 0.0 { n.map(|c| c / length) } else { [0.0, 0.0, 1.0] };
+            let [x, y, z] = n.map(|c| ((c * 0.5 + 0.5) * 255.0).round() as u8);
+            [x, y, z, mean(3)]
+        }
+    }
+}
+
+// Ian Taylor's polynomial fit, which is close enough for 8-bit texels
+// https://chilliant.com/rgb2hsv.html
+fn srgb_decode(c: f32) -> f32 {
+    c * (c * (c * 0.305_306 + 0.682_171_1) + 0.012_522_878)
+}
+
+// the coordinates are only ever one texel outside the level, apart from garbage, which is clamped; the same
+// calculation as the SIMD version
+fn wrap(i: i32, size: i32) -> i32 {
+    let i = if i < 0 { i + size } else if i >= size { i - size } else { i };
+    i.clamp(0, size - 1)
+}
+
+fn fetch(texture: &Texture, level: usize, x: i32, y: i32) -> [f32; 4] {
+    let i = texture.level_offsets[level] + y * texture.level_widths[level] + x;
+    let [r, g, b, a] = texture.texels[i as usize].map(|c| c as f32 / 255.0);
+    if texture.kind == TextureKind::Srgb {
+        [srgb_decode(r), srgb_decode(g), srgb_decode(b), a]
+    }
+    else {
+        [r, g, b, a]
+    }
+}
+
+fn nearest(texture: &Texture, level: usize, u: f32, v: f32) -> [f32; 4] {
+    let (width, height) = (texture.level_widths[level], texture.level_heights[level]);
+    let x = wrap(((u - u.floor()) * width as f32).floor() as i32, width);
+    let y = wrap(((v - v.floor()) * height as f32).floor() as i32, height);
+    fetch(texture, level, x, y)
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    (b - a) * t + a
+}
+
+fn bilinear(texture: &Texture, level: usize, u: f32, v: f32) -> [f32; 4] {
+    let (width, height) = (texture.level_widths[level], texture.level_heights[level]);
+    // texel centres are at half coordinates
+    let x = (u - u.floor()) * width as f32 - 0.5;
+    let y = (v - v.floor()) * height as f32 - 0.5;
+    let (fx, fy) = (x - x.floor(), y - y.floor());
+    let x0 = wrap(x.floor() as i32, width);
+    let y0 = wrap(y.floor() as i32, height);
+    let x1 = wrap(x0 + 1, width);
+    let y1 = wrap(y0 + 1, height);
+
+    let t00 = fetch(texture, level, x0, y0);
+    let t10 = fetch(texture, level, x1, y0);
+    let t01 = fetch(texture, level, x0, y1);
+    let t11 = fetch(texture, level, x1, y1);
+    [0, 1, 2, 3].map(|c| lerp(lerp(t00[c], t10[c], fx), lerp(t01[c], t11[c], fx), fy))
+}
+
+fn sample_level(texture: &Texture, filter: Filter, lod: f32, u: f32, v: f32) -> [f32; 4] {
+    let max_level = (texture.num_levels() - 1) as f32;
+    let lod = lod.min(max_level).max(0.0);
+    match filter {
+        Filter::Nearest => nearest(texture, lod.round_ties_even() as usize, u, v),
+        Filter::Bilinear => bilinear(texture, lod.round_ties_even() as usize, u, v),
+        Filter::Trilinear => {
+            let level = lod.floor();
+            let near = bilinear(texture, level as usize, u, v);
+            let far = bilinear(texture, (level + 1.0).min(max_level) as usize, u, v);
+            [0, 1, 2, 3].map(|c| lerp(near[c], far[c], lod - level))
+        }
+    }
+}
+
+// a filtered texel at u, v, given how far u, v move one pixel right and one pixel down; the same calculation as the
+// SIMD version
+#[allow(dead_code)]
+pub fn sample(texture: &Texture, sampler: &Sampler, uv: [f32; 2], dx: [f32; 2], dy: [f32; 2]) -> [f32; 4] {
+    let size = [texture.width as f32, texture.height as f32];
+    let length = |d: [f32; 2]| ((d[0] * size[0]).powi(2) + (d[1] * size[1]).powi(2)).sqrt();
+    let (x_length, y_length) = (length(dx), length(dy));
+
+    if sampler.max_anisotropy <= 1 {
+        return sample_level(texture, sampler.filter, x_length.max(y_length).log2(), uv[0], uv[1]);
+    }
+
+    // a level for the shorter direction, with samples spread along the longer one
+    let major = x_length.max(y_length);
+    let minor = x_length.min(y_length).max(f32::MIN_POSITIVE);
+    let num_samples = (major / minor).ceil().min(sampler.max_anisotropy as f32).max(1.0);
+    let lod = (major / num_samples).log2();
+    let axis = if x_length >= y_length { dx } else { dy };
+
+    let mut total = [0.0; 4];
+    for i in 0..num_samples as u32 {
+        let offset = (i as f32 + 0.5) / num_samples - 0.5;
+        let texel = sample_level(texture, sampler.filter, lod, uv[0] + axis[0] * offset, uv[1] + axis[1] * offset);
+        for c in 0..4 {
+            total[c] += texel[c] / num_samples;
+        }
+    }
+    total
+}
+
+#[target_feature(enable = "avx,avx2,fma")]
+#[inline]
+unsafe fn avx2_srgb_decode(c: __m256) -> __m256 {
+    let k = _mm256_fmadd_ps(c, _mm256_set1_ps(0.305_306), _mm256_set1_ps(0.682_171_1));
+    _mm256_mul_ps(c, _mm256_fmadd_ps(c, k, _mm256_set1_ps(0.012_522_878)))
+}
+
+#[target_feature(enable = "avx,avx2,fma")]
+#[inline]
+unsafe fn avx2_wrap(i: __m256i, size: __m256i) -> __m256i {
+    let zero = _mm256_setzero_si256();
+    let i = _mm256_add_epi32(i, _mm256_and_si256(_mm256_cmpgt_epi32(zero, i), size));
+    let i = _mm256_sub_epi32(i, _mm256_andnot_si256(_mm256_cmpgt_epi32(size, i), size));
+    _mm256_min_epi32(_mm256_max_epi32(i, zero), _mm256_sub_epi32(size, _mm256_set1_epi32(1)))
+}
+
+// each lane's mip level's offset, width and height
+#[target_feature(enable = "avx,avx2,fma")]
+#[inline]
+unsafe fn avx2_level(texture: &Texture, level: __m256i) -> [__m256i; 3] {
+    [&texture.level_offsets, &texture.level_widths, &texture.level_heights].map(|v| _mm256_i32gather_epi32(v.as_ptr(), level, 4))
+}
+
+#[target_feature(enable = "avx,avx2,fma")]
+#[inline]
+unsafe fn avx2_fetch(texture: &Texture, [offset, width, _]: [__m256i; 3], x: __m256i, y: __m256i) -> [__m256; 4] {
+    let i = _mm256_add_epi32(offset, _mm256_add_epi32(_mm256_mullo_epi32(y, width), x));
+    let texels = _mm256_i32gather_epi32(texture.texels.as_ptr() as *const i32, i, 4);
+
+    // RGBA in memory, so red is the low byte
+    let scale = _mm256_set1_ps(1.0 / 255.0);
+    let byte = _mm256_set1_epi32(0xff);
+    let [r, g, b, a] = [0, 8, 16, 24].map(|shift| {
+        let channel = _mm256_and_si256(_mm256_srlv_epi32(texels, _mm256_set1_epi32(shift)), byte);
+        _mm256_mul_ps(_mm256_cvtepi32_ps(channel), scale)
+    });
+    if texture.kind == TextureKind::Srgb {
+        [avx2_srgb_decode(r), avx2_srgb_decode(g), avx2_srgb_decode(b), a]
+    }
+    else {
+        [r, g, b, a]
+    }
+}
+
+#[target_feature(enable = "avx,avx2,fma")]
+#[inline]
+unsafe fn avx2_nearest(texture: &Texture, level: __m256i, u: __m256, v: __m256) -> [__m256; 4] {
+    let level = avx2_level(texture, level);
+    let [_, width, height] = level;
+    let coordinate = |c: __m256, size: __m256i| {
+        let scaled = _mm256_mul_ps(_mm256_sub_ps(c, _mm256_floor_ps(c)), _mm256_cvtepi32_ps(size));
+        avx2_wrap(_mm256_cvttps_epi32(_mm256_floor_ps(scaled)), size)
+    };
+    avx2_fetch(texture, level, coordinate(u, width), coordinate(v, height))
+}
+
+#[target_feature(enable = "avx,avx2,fma")]
+#[inline]
+unsafe fn avx2_lerp(a: __m256, b: __m256, t: __m256) -> __m256 {
+    _mm256_fmadd_ps(_mm256_sub_ps(b, a), t, a)
+}
+
+#[target_feature(enable = "avx,avx2,fma")]
+#[inline]
+unsafe fn avx2_bilinear(texture: &Texture, level: __m256i, u: __m256, v: __m256) -> [__m256; 4] {
+    let level = avx2_level(texture, level);
+    let [_, width, height] = level;
+    let one = _mm256_set1_epi32(1);
+    // the first texel of each pair, the second and the weight of the second
+    let coordinates = |c: __m256, size: __m256i| {
+        // texel centres are at half coordinates
+        let scaled = _mm256_fmsub_ps(_mm256_sub_ps(c, _mm256_floor_ps(c)), _mm256_cvtepi32_ps(size), _mm256_set1_ps(0.5));
+        let floor = _mm256_floor_ps(scaled);
+        let first = avx2_wrap(_mm256_cvttps_epi32(floor), size);
+        (first, avx2_wrap(_mm256_add_epi32(first, one), size), _mm256_sub_ps(scaled, floor))
+    };
+    let (x0, x1, fx) = coordinates(u, width);
+    let (y0, y1, fy) = coordinates(v, height);
+
+    let t00 = avx2_fetch(texture, level, x0, y0);
+    let t10 = avx2_fetch(texture, level, x1, y0);
+    let t01 = avx2_fetch(texture, level, x0, y1);
+    let t11 = avx2_fetch(texture, level, x1, y1);
+    [0, 1, 2, 3].map(|c| avx2_lerp(avx2_lerp(t00[c], t10[c], fx), avx2_lerp(t01[c], t11[c], fx), fy))
+}
+
+// clamping the level also takes care of garbage, as min returns the second argument for NaN
+#[target_feature(enable = "avx,avx2,fma")]
+#[inline]
+unsafe fn avx2_sample_level(texture: &Texture, filter: Filter, lod: __m256, u: __m256, v: __m256) -> [__m256; 4] {
+    let max_level = texture.num_levels() as i32 - 1;
+    let lod = _mm256_max_ps(_mm256_min_ps(lod, _mm256_set1_ps(max_level as f32)), _mm256_setzero_ps());
+    match filter {
+        Filter::Nearest => avx2_nearest(texture, _mm256_cvtps_epi32(lod), u, v),
+        Filter::Bilinear => avx2_bilinear(texture, _mm256_cvtps_epi32(lod), u, v),
+        Filter::Trilinear => {
+            let level = _mm256_floor_ps(lod);
+            let fraction = _mm256_sub_ps(lod, level);
+            let level = _mm256_cvttps_epi32(level);
+            let near = avx2_bilinear(texture, level, u, v);
+
+            // magnified, which is common close up, so the second level can be skipped
+            if _mm256_movemask_ps(_mm256_cmp_ps(fraction, _mm256_setzero_ps(), _CMP_GT_OQ)) == 0 {
+                return near;
+            }
+            let far = avx2_bilinear(texture, _mm256_min_epi32(_mm256_add_epi32(level, _mm256_set1_epi32(1)), _mm256_set1_epi32(max_level)), u, v);
+            [0, 1, 2, 3].map(|c| avx2_lerp(near[c], far[c], fraction))
+        }
+    }
+}
+
+// RGBA for 8 pixels, given how far u, v move one pixel right and one pixel down
+#[target_feature(enable = "avx,avx2,fma")]
+#[inline]
+pub unsafe fn avx2_sample(texture: &Texture, sampler: &Sampler, uv: [__m256; 2], dx: [__m256; 2], dy: [__m256; 2]) -> [__m256; 4] {
+    let size = [texture.width as f32, texture.height as f32].map(|s| _mm256_set1_ps(s));
+    let length = |d: [__m256; 2]| {
+        let [u, v] = [0, 1].map(|c| _mm256_mul_ps(d[c], size[c]));
+        _mm256_sqrt_ps(_mm256_fmadd_ps(u, u, _mm256_mul_ps(v, v)))
+    };
+    let x_length = length(dx);
+    let y_length = length(dy);
+    let major = _mm256_max_ps(x_length, y_length);
+
+    if sampler.max_anisotropy <= 1 {
+        return avx2_sample_level(texture, sampler.filter, avx2_log2(major), uv[0], uv[1]);
+    }
+
+    // a level for the shorter direction, with samples spread along the longer one
+    let one = _mm256_set1_ps(1.0);
+    let minor = _mm256_max_ps(_mm256_min_ps(x_length, y_length), _mm256_set1_ps(f32::MIN_POSITIVE));
+    let num_samples = _mm256_ceil_ps(_mm256_div_ps(major, minor));
+    let num_samples = _mm256_max_ps(_mm256_min_ps(num_samples, _mm256_set1_ps(sampler.max_anisotropy as f32)), one);
+    let lod = avx2_log2(_mm256_div_ps(major, num_samples));
+    let x_major = _mm256_cmp_ps(x_length, y_length, _CMP_GE_OQ);
+    let axis = [0, 1].map(|c| _mm256_blendv_ps(dy[c], dx[c], x_major));
+
+    // lanes that need fewer samples than the others weight the rest by zero
+    let weight = _mm256_div_ps(one, num_samples);
+    let mut total = [_mm256_setzero_ps(); 4];
+    for i in 0..sampler.max_anisotropy {
+        let i = _mm256_set1_ps(i as f32);
+        let active = _mm256_cmp_ps(i, num_samples, _CMP_LT_OQ);
+        if _mm256_movemask_ps(active) == 0 {
+            break;
+        }
+
+        let offset = _mm256_fmsub_ps(_mm256_add_ps(i, _mm256_set1_ps(0.5)), weight, _mm256_set1_ps(0.5));
+        let [u, v] = [0, 1].map(|c| _mm256_fmadd_ps(axis[c], offset, uv[c]));
+        let texel = avx2_sample_level(texture, sampler.filter, lod, u, v);
+        let weight = _mm256_and_ps(active, weight);
+        for c in 0..4 {
+            total[c] = _mm256_fmadd_ps(texel[c], weight, total[c]);
+        }
+    }
+    total
+}
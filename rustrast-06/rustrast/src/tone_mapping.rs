@@ -0,0 +1,108 @@
+use windows::Win32::Graphics::Gdi::*;
+use core::arch::x86_64::*;
+use once_cell::sync::Lazy;
+
+use super::rasterisation::*;
+
+// turning linear light into 8-bit sRGB for the window; with an HDR target this happens once per pixel after everything
+// is drawn, so brightness above 1.0 from several lights can be compressed rather than clipped
+
+#[derive(Clone, Copy, PartialEq)]
+#[allow(dead_code)]
+pub enum ToneMapping {
+    // anything above 1.0 is clipped
+    Clamp,
+    // x / (1 + x) on each channel, which never quite reaches white
+    Reinhard,
+    // Krzysztof Narkowicz's fit of the ACES filmic curve
+    // https://knarkowicz.wordpress.com/2016/01/06/aces-filmic-tone-mapping-curve/
+    Aces
+}
+
+// linear 0.0-1.0 to sRGB 0.0-255.0, using a table as powf is too slow; the table is big enough that neighbouring
+// entries are less than one step apart except in the darkest shades
+pub const SRGB_TABLE_SIZE: usize = 4096;
+pub static SRGB_ENCODE: Lazy<Vec<f32>> = Lazy::new(|| {
+    (0..SRGB_TABLE_SIZE).map(|i| {
+        let c = i as f32 / (SRGB_TABLE_SIZE - 1) as f32;
+        let encoded = if c <= 0.0031308 { c * 12.92 } else { 1.055 * c.powf(1.0 / 2.4) - 0.055 };
+        encoded * 255.0
+    }).collect()
+});
+
+pub fn srgb_encode(c: f32) -> u8 {
+    SRGB_ENCODE[(c.clamp(0.0, 1.0) * (SRGB_TABLE_SIZE - 1) as f32) as usize].round() as u8
+}
+
+// clamping to 0.0-1.0 also takes care of garbage, as min returns 1.0 for NaN
+#[target_feature(enable = "avx,avx2,fma")]
+#[inline]
+pub unsafe fn avx2_srgb_encode(c: __m256) -> __m256 {
+    let c = _mm256_max_ps(_mm256_min_ps(c, _mm256_set1_ps(1.0)), _mm256_setzero_ps());
+    let i = _mm256_cvttps_epi32(_mm256_mul_ps(c, _mm256_set1_ps((SRGB_TABLE_SIZE - 1) as f32)));
+    _mm256_i32gather_ps(SRGB_ENCODE.as_ptr(), i, 4)
+}
+
+#[target_feature(enable = "avx,avx2,fma")]
+#[inline]
+unsafe fn avx2_tone_map(c: __m256, tone_mapping: ToneMapping) -> __m256 {
+    let one = _mm256_set1_ps(1.0);
+    match tone_mapping {
+        ToneMapping::Clamp => c,
+        ToneMapping::Reinhard => _mm256_div_ps(c, _mm256_add_ps(c, one)),
+        ToneMapping::Aces => {
+            let numerator = _mm256_mul_ps(c, _mm256_fmadd_ps(c, _mm256_set1_ps(2.51), _mm256_set1_ps(0.03)));
+            let denominator = _mm256_fmadd_ps(c, _mm256_fmadd_ps(c, _mm256_set1_ps(2.43), _mm256_set1_ps(0.59)), _mm256_set1_ps(0.14));
+            _mm256_div_ps(numerator, denominator)
+        }
+    }
+}
+
+// 8x8 ordered dither, which spreads the rounding error of smooth gradients so they don't band
+const BAYER: [[u8; 8]; 8] = [
+    [ 0, 32,  8, 40,  2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44,  4, 36, 14, 46,  6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [ 3, 35, 11, 43,  1, 33,  9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47,  7, 39, 13, 45,  5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21]];
+
+// tone maps a tile of the HDR target, one buffer per channel, into the 8-bit output; spans of 8 must be aligned in both
+#[target_feature(enable = "avx,avx2,fma")]
+pub unsafe fn avx2_resolve_tile(
+        hdr: &[Buffer<f32>; 3], colour: &mut Buffer<RGBQUAD>,
+        xmin: usize, ymin: usize, xmax: usize, ymax: usize,
+        tone_mapping: ToneMapping, exposure: f32, dither: bool) {
+    debug_assert!(xmin % 8 == 0 && xmax % 8 == 0);
+    debug_assert!(colour.stride % 8 == 0 && colour.left % 8 == 0);
+    debug_assert!(hdr.iter().all(|b| b.stride % 8 == 0 && b.left % 8 == 0));
+
+    let exposure = _mm256_set1_ps(exposure);
+    let zero = _mm256_setzero_ps();
+    let max = _mm256_set1_ps(255.0);
+    // offsets of -0.5 to 0.5 of a step, or none; kept as floats and loaded a row at a time, as building an array of
+    // __m256 straight from the [f32; 8]s that map returns lets LLVM give it their 4 byte alignment, and its aligned
+    // stores then fault
+    let dither_rows = BAYER.map(|row| row.map(|b| if dither { (b as f32 + 0.5) / 64.0 - 0.5 } else { 0.0 }));
+
+    let c_buffer = colour.buffer.as_mut_ptr() as *mut i32;
+    for yp in ymin..ymax {
+        let c_row = c_buffer.add((yp - colour.top) * colour.stride - colour.left);
+        let h_rows = hdr.each_ref().map(|b| b.buffer.as_ptr().add((yp - b.top) * b.stride - b.left));
+        let dither_row = _mm256_loadu_ps(dither_rows[yp % 8].as_ptr());
+
+        for xp in (xmin..xmax).step_by(8) {
+            let [r, g, b] = h_rows.map(|row| {
+                let c = avx2_tone_map(_mm256_mul_ps(_mm256_loadu_ps(row.add(xp)), exposure), tone_mapping);
+                let c = _mm256_add_ps(avx2_srgb_encode(c), dither_row);
+                _mm256_cvtps_epi32(_mm256_min_ps(_mm256_max_ps(c, zero), max))
+            });
+
+            // BGRA in memory
+            let span = _mm256_or_si256(_mm256_slli_epi32(r, 16), _mm256_or_si256(_mm256_slli_epi32(g, 8), b));
+            _mm256_storeu_si256(c_row.add(xp) as *mut __m256i, span);
+        }
+    }
+}
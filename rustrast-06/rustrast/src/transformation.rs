@@ -4,7 +4,6 @@ use once_cell::sync::Lazy;
 use scoped_threadpool::Pool;
 
 use super::simd_vec::*;
-use super::obj::*;
 
 #[derive(Clone, Copy)]
 pub struct CartesianVector {
@@ -135,6 +134,104 @@ impl HomogenousCoordinates {
     }
 }
 
+// a rotation, as a unit quaternion; x, y and z are the axis times sin(angle / 2) and w is cos(angle / 2), the same
+// order as glTF
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Quaternion {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+    pub w: f32
+}
+
+impl Quaternion {
+    pub const IDENTITY: Self = Quaternion { x: 0.0, y: 0.0, z: 0.0, w: 1.0 };
+
+    pub fn from_axis_angle(axis: CartesianVector, radians: f32) -> Self {
+        let axis = axis.normalised();
+        let (sin, cos) = (radians / 2.0).sin_cos();
+        Quaternion { x: axis.x * sin, y: axis.y * sin, z: axis.z * sin, w: cos }
+    }
+
+    pub fn dot(self, other: Quaternion) -> f32 {
+        self.x * other.x + self.y * other.y + self.z * other.z + self.w * other.w
+    }
+
+    pub fn normalised(self) -> Self {
+        let im = 1.0 / self.dot(self).sqrt();
+        Quaternion { x: self.x * im, y: self.y * im, z: self.z * im, w: self.w * im }
+    }
+
+    pub fn negated(self) -> Self {
+        Quaternion { x: -self.x, y: -self.y, z: -self.z, w: -self.w }
+    }
+
+    // the Hamilton product other * self, which rotates by self and then by other like Transformation::then
+    #[allow(dead_code)]
+    pub fn then(self, other: Quaternion) -> Self {
+        let (a, b) = (other, self);
+        Quaternion {
+            x: a.w * b.x + a.x * b.w + a.y * b.z - a.z * b.y,
+            y: a.w * b.y - a.x * b.z + a.y * b.w + a.z * b.x,
+            z: a.w * b.z + a.x * b.y - a.y * b.x + a.z * b.w,
+            w: a.w * b.w - a.x * b.x - a.y * b.y - a.z * b.z
+        }
+    }
+
+    // spherical linear interpolation the short way round, falling back to normalised linear interpolation when the
+    // two are so close that the angle between them is lost in rounding
+    pub fn slerp(self, other: Quaternion, t: f32) -> Self {
+        let mut cos = self.dot(other);
+        let other = if cos < 0.0 { cos = -cos; other.negated() } else { other };
+        let (s0, s1) = if cos > 0.9995 {
+            (1.0 - t, t)
+        }
+        else {
+            let angle = cos.acos();
+            let isin = 1.0 / angle.sin();
+            (((1.0 - t) * angle).sin() * isin, (t * angle).sin() * isin)
+        };
+        Quaternion {
+            x: self.x * s0 + other.x * s1,
+            y: self.y * s0 + other.y * s1,
+            z: self.z * s0 + other.z * s1,
+            w: self.w * s0 + other.w * s1
+        }.normalised()
+    }
+
+    // columns of the rotation matrix
+    pub fn to_3x3(self) -> [[f32; 3]; 3] {
+        let Quaternion { x, y, z, w } = self;
+        [
+            [1.0 - 2.0 * (y * y + z * z), 2.0 * (x * y + z * w), 2.0 * (x * z - y * w)],
+            [2.0 * (x * y - z * w), 1.0 - 2.0 * (x * x + z * z), 2.0 * (y * z + x * w)],
+            [2.0 * (x * z + y * w), 2.0 * (y * z - x * w), 1.0 - 2.0 * (x * x + y * y)]
+        ]
+    }
+
+    // from the columns of a rotation matrix, by Shepperd's method of working from the largest component
+    pub fn from_3x3(m: [[f32; 3]; 3]) -> Self {
+        let trace = m[0][0] + m[1][1] + m[2][2];
+        let q = if trace > 0.0 {
+            let s = (trace + 1.0).sqrt() * 2.0;
+            Quaternion { x: (m[1][2] - m[2][1]) / s, y: (m[2][0] - m[0][2]) / s, z: (m[0][1] - m[1][0]) / s, w: 0.25 * s }
+        }
+        else if m[0][0] > m[1][1] && m[0][0] > m[2][2] {
+            let s = (1.0 + m[0][0] - m[1][1] - m[2][2]).sqrt() * 2.0;
+            Quaternion { x: 0.25 * s, y: (m[1][0] + m[0][1]) / s, z: (m[2][0] + m[0][2]) / s, w: (m[1][2] - m[2][1]) / s }
+        }
+        else if m[1][1] > m[2][2] {
+            let s = (1.0 + m[1][1] - m[0][0] - m[2][2]).sqrt() * 2.0;
+            Quaternion { x: (m[1][0] + m[0][1]) / s, y: 0.25 * s, z: (m[2][1] + m[1][2]) / s, w: (m[2][0] - m[0][2]) / s }
+        }
+        else {
+            let s = (1.0 + m[2][2] - m[0][0] - m[1][1]).sqrt() * 2.0;
+            Quaternion { x: (m[2][0] + m[0][2]) / s, y: (m[2][1] + m[1][2]) / s, z: 0.25 * s, w: (m[0][1] - m[1][0]) / s }
+        };
+        q.normalised()
+    }
+}
+
 #[derive(Clone, Copy)]
 #[repr(C, align(32))]
 pub struct Transformation {
@@ -155,9 +252,14 @@ impl Transformation {
         _private: ()
     };
 
+    // for matrices from elsewhere, in the same column-major layout
+    pub fn from_columns(matrix: [[f32; 4]; 4]) -> Self {
+        Transformation { matrix, _private: () }
+    }
+
     pub fn translate(dx: f32, dy: f32, dz: f32) -> Self {
         Transformation { matrix: [
-            [1.0, 0.0, 0.0,  1.0], 
+            [1.0, 0.0, 0.0,  0.0], 
             [0.0, 1.0, 0.0,  0.0],
             [0.0, 0.0, 1.0,  0.0],
             [ dx,  dy,  dz,  1.0]],
@@ -188,6 +290,7 @@ impl Transformation {
         }
     }
 
+    #[allow(dead_code)]
     pub fn rotate_y(radians: f32) -> Self {
         let (sin, cos) = radians.sin_cos();
         Transformation { matrix: [
@@ -211,17 +314,56 @@ impl Transformation {
         }
     }
 
+    // scale, then rotate, then translate, as glTF nodes and animations are
+    pub fn from_trs(translation: [f32; 3], rotation: Quaternion, scale: [f32; 3]) -> Self {
+        let r = rotation.to_3x3();
+        let mut matrix = [[0.0; 4]; 4];
+        for c in 0..3 {
+            matrix[c] = [r[c][0] * scale[c], r[c][1] * scale[c], r[c][2] * scale[c], 0.0];
+        }
+        matrix[3] = [translation[0], translation[1], translation[2], 1.0];
+        Transformation { matrix, _private: () }
+    }
+
+    // back into translation, rotation and scale; any shear or projection is lost, and a mirroring is put in the
+    // scale along x
+    pub fn decomposed(&self) -> ([f32; 3], Quaternion, [f32; 3]) {
+        let m = &self.matrix;
+        let translation = [m[3][0], m[3][1], m[3][2]];
+        let mut scale = [0, 1, 2].map(|c| (m[c][0] * m[c][0] + m[c][1] * m[c][1] + m[c][2] * m[c][2]).sqrt());
+        if self.tl_3x3_determinant() < 0.0 {
+            scale[0] = -scale[0];
+        }
+        let rotation = if scale.contains(&0.0) {
+            Quaternion::IDENTITY
+        }
+        else {
+            Quaternion::from_3x3([0, 1, 2].map(|c| [m[c][0] / scale[c], m[c][1] / scale[c], m[c][2] / scale[c]]))
+        };
+        (translation, rotation, scale)
+    }
+
     fn det_2x2(&self, r0: usize, r1: usize, c0: usize, c1: usize) -> f32 {
         let m = &self.matrix;
         m[c0][r0] * m[c1][r1] - m[c1][r0] * m[c0][r1]
     }
 
-    pub fn inverted_transposed_tl_3x3(&self) -> Option<[[f32; 3]; 3]> {
+    // negative if the transformation mirrors
+    pub fn tl_3x3_determinant(&self) -> f32 {
         let m = &self.matrix;
-
-        let det = m[0][0] * self.det_2x2(1, 2, 1, 2)
+        m[0][0] * self.det_2x2(1, 2, 1, 2)
             - m[1][0] * self.det_2x2(1, 2, 0, 2)
-            + m[2][0] * self.det_2x2(1, 2, 0, 1);
+            + m[2][0] * self.det_2x2(1, 2, 0, 1)
+    }
+
+    // for directions along surfaces, which unlike normals transform like positions without the translation
+    pub fn tl_3x3(&self) -> [[f32; 3]; 3] {
+        let m = &self.matrix;
+        [0, 1, 2].map(|c| [m[c][0], m[c][1], m[c][2]])
+    }
+
+    pub fn inverted_transposed_tl_3x3(&self) -> Option<[[f32; 3]; 3]> {
+        let det = self.tl_3x3_determinant();
         if det == 0.0 {
             return None;
         }
@@ -246,6 +388,29 @@ impl Transformation {
         ])
     }
 
+    // the determinant of what's left without one row and one column
+    fn minor_4x4(&self, row: usize, col: usize) -> f32 {
+        let others = |i: usize| match i { 0 => [1, 2, 3], 1 => [0, 2, 3], 2 => [0, 1, 3], _ => [0, 1, 2] };
+        let (rs, cs) = (others(row), others(col));
+        let m = |r: usize, c: usize| self.matrix[cs[c]][rs[r]];
+        m(0, 0) * (m(1, 1) * m(2, 2) - m(1, 2) * m(2, 1))
+            - m(0, 1) * (m(1, 0) * m(2, 2) - m(1, 2) * m(2, 0))
+            + m(0, 2) * (m(1, 0) * m(2, 1) - m(1, 1) * m(2, 0))
+    }
+
+    // for going back from the screen to world space
+    pub fn inverted(&self) -> Option<Self> {
+        let cofactor = |row: usize, col: usize| if (row + col) % 2 == 0 { self.minor_4x4(row, col) } else { -self.minor_4x4(row, col) };
+        let det: f32 = (0..4).map(|col| self.matrix[col][0] * cofactor(0, col)).sum();
+        if det == 0.0 {
+            return None;
+        }
+
+        // the adjugate is the transposed cofactors
+        let matrix = [0, 1, 2, 3].map(|col| [0, 1, 2, 3].map(|row| cofactor(col, row) / det));
+        Some(Transformation { matrix, _private: () })
+    }
+
     // assumes premultiplication so returns t*self
     pub fn then(&self, t: &Transformation) -> Self {
         let mut matrix: [[f32; 4]; 4] = [[0.0; 4]; 4];
@@ -369,17 +534,15 @@ unsafe fn avx2_chunk_transformed_to_cartesian(
 static NUM_PROJECTION_THREADS: u32 = 4;
 static PROJECTION_WORKERS: Lazy<Mutex<Pool>> = Lazy::new(|| Mutex::new(Pool::new(NUM_PROJECTION_THREADS)));
 
-pub fn avx2_transformed_to_cartesian(xs_out: &mut SimdVec<f32>, ys_out: &mut SimdVec<f32>, zs_out: &mut SimdVec<f32>, iws_out: &mut SimdVec<f32>, model: &Model, t: &Transformation) {
+// positions are passed separately from the model as they can be skinned or morphed ones
+pub fn avx2_positions_transformed_to_cartesian(xs_out: &mut SimdVec<f32>, ys_out: &mut SimdVec<f32>, zs_out: &mut SimdVec<f32>, iws_out: &mut SimdVec<f32>, positions: [&SimdVec<f32>; 4], num_vertices: u32, t: &Transformation) {
     let num_chunks = NUM_PROJECTION_THREADS;
     // maintain 128 byte alignment for caching
-    let chunk_size = ((model.num_vertices / num_chunks) / 32) * 4;
+    let chunk_size = ((num_vertices / num_chunks) / 32) * 4;
     let mut chunk_start = 0;
 
     if chunk_size > 0 {
-        let xs = model.xs.as_m256();
-        let ys = model.ys.as_m256();
-        let zs = model.zs.as_m256();
-        let ws = model.ws.as_m256();
+        let [xs, ys, zs, ws] = positions.map(|p| p.as_m256());
 
         let mut pool = PROJECTION_WORKERS.lock().unwrap();
         pool.scoped(|scope| {
@@ -388,7 +551,9 @@ pub fn avx2_transformed_to_cartesian(xs_out: &mut SimdVec<f32>, ys_out: &mut Sim
             let zs_out_chunks = zs_out.as_m256_mut().chunks_exact_mut(chunk_size as usize);
             let iws_out_chunks = iws_out.as_m256_mut().chunks_exact_mut(chunk_size as usize);
 
-            for (xs_out_chunk, (ys_out_chunk, (zs_out_chunk, iws_out_chunk))) in xs_out_chunks.zip(ys_out_chunks.zip(zs_out_chunks.zip(iws_out_chunks))) {
+            // the output buffers can be bigger than the model
+            let chunks = xs_out_chunks.zip(ys_out_chunks.zip(zs_out_chunks.zip(iws_out_chunks))).take(num_chunks as usize);
+            for (xs_out_chunk, (ys_out_chunk, (zs_out_chunk, iws_out_chunk))) in chunks {
                 let vs_out_chunk = [xs_out_chunk, ys_out_chunk, zs_out_chunk, iws_out_chunk];
                 let source_offset = chunk_start;
                 scope.execute(move || unsafe {
@@ -401,15 +566,83 @@ pub fn avx2_transformed_to_cartesian(xs_out: &mut SimdVec<f32>, ys_out: &mut Sim
     }
 
     // do any leftovers sequentially
-    for i in (chunk_start * 8 as usize)..(model.num_vertices as usize) {
+    for i in (chunk_start * 8 as usize)..(num_vertices as usize) {
         let (r, iw) = HomogenousCoordinates {
-            x: model.xs[i], 
-            y: model.ys[i], 
-            z: model.zs[i], 
-            w: model.ws[i]}.transformed(t).to_cartesian();
+            x: positions[0][i], 
+            y: positions[1][i], 
+            z: positions[2][i], 
+            w: positions[3][i]}.transformed(t).to_cartesian();
         xs_out[i] = r.x;
         ys_out[i] = r.y;
         zs_out[i] = r.z;
         iws_out[i] = iw;
     }
-}
\ No newline at end of file
+}
+// directions transformed by a 3x3 matrix and renormalised, for normals with the inverse transpose so they stay
+// perpendicular to surfaces under non-uniform scaling; zero length directions stay zero
+#[target_feature(enable = "fma,avx,avx2")]
+unsafe fn avx2_chunk_transformed_directions(
+        ds_out: [&mut [__m256]; 3],
+        ds: [&[__m256]; 3], m: &[[f32; 3]; 3],
+        source_offset: usize, chunk_size: usize) {
+    let m = m.map(|column| column.map(|v| _mm256_set1_ps(v)));
+    let tiny = _mm256_set1_ps(f32::MIN_POSITIVE);
+
+    for i in 0..chunk_size {
+        let [x, y, z] = ds.map(|d| d[source_offset + i]);
+
+        let mut d = [_mm256_setzero_ps(); 3];
+        for (row, d) in d.iter_mut().enumerate() {
+            *d = _mm256_mul_ps(x, m[0][row]);
+            *d = _mm256_fmadd_ps(y, m[1][row], *d);
+            *d = _mm256_fmadd_ps(z, m[2][row], *d);
+        }
+
+        let length_squared = _mm256_fmadd_ps(d[0], d[0], _mm256_fmadd_ps(d[1], d[1], _mm256_mul_ps(d[2], d[2])));
+        let scale = _mm256_rsqrt_ps(_mm256_max_ps(length_squared, tiny));
+        ds_out[0][i] = _mm256_mul_ps(d[0], scale);
+        ds_out[1][i] = _mm256_mul_ps(d[1], scale);
+        ds_out[2][i] = _mm256_mul_ps(d[2], scale);
+    }
+}
+
+pub fn avx2_transformed_directions(xs_out: &mut SimdVec<f32>, ys_out: &mut SimdVec<f32>, zs_out: &mut SimdVec<f32>, directions: [&SimdVec<f32>; 3], num_vertices: u32, m: &[[f32; 3]; 3]) {
+    debug_assert!(directions.iter().all(|d| d.len() == num_vertices as usize));
+
+    let num_chunks = NUM_PROJECTION_THREADS;
+    // maintain 128 byte alignment for caching
+    let chunk_size = ((num_vertices / num_chunks) / 32) * 4;
+    let mut chunk_start = 0;
+
+    if chunk_size > 0 {
+        let ds = directions.map(|d| d.as_m256());
+
+        let mut pool = PROJECTION_WORKERS.lock().unwrap();
+        pool.scoped(|scope| {
+            let xs_out_chunks = xs_out.as_m256_mut().chunks_exact_mut(chunk_size as usize);
+            let ys_out_chunks = ys_out.as_m256_mut().chunks_exact_mut(chunk_size as usize);
+            let zs_out_chunks = zs_out.as_m256_mut().chunks_exact_mut(chunk_size as usize);
+
+            // the output buffers can be bigger than the model
+            let chunks = xs_out_chunks.zip(ys_out_chunks.zip(zs_out_chunks)).take(num_chunks as usize);
+            for (xs_out_chunk, (ys_out_chunk, zs_out_chunk)) in chunks {
+                let ds_out_chunk = [xs_out_chunk, ys_out_chunk, zs_out_chunk];
+                let source_offset = chunk_start;
+                scope.execute(move || unsafe {
+                    avx2_chunk_transformed_directions(ds_out_chunk, ds, m, source_offset, chunk_size as usize);
+                });
+
+                chunk_start += chunk_size as usize;
+            }
+        });
+    }
+
+    // do any leftovers sequentially
+    for i in (chunk_start * 8)..(num_vertices as usize) {
+        let d = CartesianVector { x: directions[0][i], y: directions[1][i], z: directions[2][i] }.transformed(m);
+        let d = if d.magnitude() > 0.0 { d.normalised() } else { d };
+        xs_out[i] = d.x;
+        ys_out[i] = d.y;
+        zs_out[i] = d.z;
+    }
+}
@@ -0,0 +1,143 @@
+use super::transformation::*;
+
+// splits polygons from files into triangles; convex ones are fanned from the first vertex, anything else is projected
+// onto its best-fit plane and ear clipped
+// https://www.geometrictools.com/Documentation/TriangulationByEarClipping.pdf
+
+// how far from straight an edge has to turn to count as convex, relative to the polygon's size
+const EPSILON: f32 = 1e-6;
+
+// Newell's method, which gives a sensible normal even when the polygon isn't planar
+fn best_fit_normal(points: &[CartesianCoordinates]) -> CartesianVector {
+    let mut normal = CartesianVector { x: 0.0, y: 0.0, z: 0.0 };
+    for i in 0..points.len() {
+        let p = points[i];
+        let q = points[(i + 1) % points.len()];
+        normal.x += (p.y - q.y) * (p.z + q.z);
+        normal.y += (p.z - q.z) * (p.x + q.x);
+        normal.z += (p.x - q.x) * (p.y + q.y);
+    }
+    normal
+}
+
+// 2d coordinates in the plane, oriented so the polygon winds counterclockwise
+fn project(points: &[CartesianCoordinates], normal: &CartesianVector) -> Vec<(f32, f32)> {
+    // any vector not parallel to the normal will do to start the basis
+    let n = normal.normalised();
+    let a = if n.x.abs() < 0.9 { CartesianVector { x: 1.0, y: 0.0, z: 0.0 } } else { CartesianVector { x: 0.0, y: 1.0, z: 0.0 } };
+    let u = a.cross_product(&n).normalised();
+    let v = n.cross_product(&u);
+
+    let origin = points[0];
+    points.iter().map(|&p| {
+        let d = p - origin;
+        (d.dot_product(&u), d.dot_product(&v))
+    }).collect()
+}
+
+fn cross(o: (f32, f32), a: (f32, f32), b: (f32, f32)) -> f32 {
+    (a.0 - o.0) * (b.1 - o.1) - (a.1 - o.1) * (b.0 - o.0)
+}
+
+fn fan(indices: &[usize], triangles: &mut Vec<[usize; 3]>) {
+    for i in 1..(indices.len() - 1) {
+        triangles.push([indices[0], indices[i], indices[i + 1]]);
+    }
+}
+
+fn inside_triangle(p: (f32, f32), a: (f32, f32), b: (f32, f32), c: (f32, f32)) -> bool {
+    // points on an edge count as inside so that ears never touch another vertex
+    cross(a, b, p) >= 0.0 && cross(b, c, p) >= 0.0 && cross(c, a, p) >= 0.0
+}
+
+fn ear_clip(points: &[(f32, f32)], epsilon: f32, triangles: &mut Vec<[usize; 3]>) {
+    let mut remaining: Vec<usize> = (0..points.len()).collect();
+
+    while remaining.len() > 3 {
+        let n = remaining.len();
+        let ear = (0..n).find(|&i| {
+            let prev = remaining[(i + n - 1) % n];
+            let curr = remaining[i];
+            let next = remaining[(i + 1) % n];
+            let (a, b, c) = (points[prev], points[curr], points[next]);
+            if cross(a, b, c) <= epsilon {
+                // reflex or straight, so can't be an ear
+                return false;
+            }
+            // only reflex vertices can be inside an ear, but checking them all is simpler
+            !remaining.iter().any(|&j| j != prev && j != curr && j != next && inside_triangle(points[j], a, b, c))
+        });
+
+        match ear {
+            Some(i) => {
+                triangles.push([remaining[(i + n - 1) % n], remaining[i], remaining[(i + 1) % n]]);
+                remaining.remove(i);
+            }
+            None => {
+                // self-intersecting or degenerate; give up and fan what's left rather than lose triangles
+                break;
+            }
+        }
+    }
+
+    fan(&remaining, triangles);
+}
+
+// appends triangles indexing into `points`, always `points.len() - 2` of them, and returns whether the polygon was
+// convex; triangles keep the polygon's winding
+pub fn triangulate(points: &[CartesianCoordinates], triangles: &mut Vec<[usize; 3]>) -> bool {
+    let n = points.len();
+    if n < 3 {
+        return true;
+    }
+    if n == 3 {
+        triangles.push([0, 1, 2]);
+        return true;
+    }
+
+    let normal = best_fit_normal(points);
+    let all: Vec<usize> = (0..n).collect();
+    if normal.magnitude() == 0.0 {
+        // no area at all, so any triangulation will do
+        fan(&all, triangles);
+        return true;
+    }
+
+    let projected = project(points, &normal);
+    // the normal's magnitude is twice the area, so this scales with the polygon
+    let epsilon = EPSILON * normal.magnitude();
+    let convex = (0..n).all(|i| cross(projected[(i + n - 1) % n], projected[i], projected[(i + 1) % n]) >= -epsilon);
+
+    if convex {
+        fan(&all, triangles);
+    }
+    else {
+        ear_clip(&projected, epsilon, triangles);
+    }
+
+    convex
+}
+
+// triangulates a face given as indices into a model's vertices, appending triangles of those same indices
+pub fn triangulate_face(face: &[u32], xs: &[f32], ys: &[f32], zs: &[f32], ws: &[f32], triangles: &mut Vec<[u32; 3]>) -> bool {
+    let mut corners = Vec::with_capacity(face.len().saturating_sub(2));
+    let convex = triangulate_corners(face, xs, ys, zs, ws, &mut corners);
+    triangles.extend(corners.iter().map(|t| t.map(|i| face[i])));
+    convex
+}
+
+// the same, but as positions in the face rather than vertex indices, for faces whose vertices have other attributes
+pub fn triangulate_corners(face: &[u32], xs: &[f32], ys: &[f32], zs: &[f32], ws: &[f32], triangles: &mut Vec<[usize; 3]>) -> bool {
+    let points: Vec<CartesianCoordinates> = face.iter().map(|&i| {
+        let i = i as usize;
+        HomogenousCoordinates { x: xs[i], y: ys[i], z: zs[i], w: ws[i] }.to_cartesian().0
+    }).collect();
+
+    triangulate(&points, triangles)
+}
+
+pub fn report_non_convex(num_non_convex: usize, num_polygons: usize) {
+    if num_non_convex > 0 {
+        println!("{} of {} polygons weren't convex and were ear clipped", num_non_convex, num_polygons);
+    }
+}
@@ -0,0 +1,278 @@
+use std::cmp::Ordering;
+
+use super::model::*;
+use super::transformation::*;
+
+// reorders triangles so that vertices are reused while they're still in cache, using Tom Forsyth's
+// "Linear-Speed Vertex Cache Optimisation" (https://tomforsyth1000.github.io/papers/fast_vert_cache_opt.html)
+// then groups triangles into clusters and sorts them front to back as in Sander, Nehab and Barczak's
+// "Fast Triangle Reordering for Vertex Locality and Reduced Overdraw" (Tipsify)
+
+// the algorithm is fairly insensitive to the real cache size, which for us is the CPU's rather than a GPU's
+const CACHE_SIZE: usize = 32;
+const CACHE_DECAY_POWER: f32 = 1.5;
+const LAST_TRIANGLE_SCORE: f32 = 0.75;
+const VALENCE_BOOST_SCALE: f32 = 2.0;
+const VALENCE_BOOST_POWER: f32 = 0.5;
+
+// small enough that sorting them reduces overdraw, big enough that cache misses at cluster boundaries don't matter
+const CLUSTER_SIZE: usize = 256;
+
+fn vertex_score(cache_position: Option<usize>, remaining_triangles: u32) -> f32 {
+    if remaining_triangles == 0 {
+        // no triangles left to use this vertex
+        return -1.0;
+    }
+
+    let cache_score = match cache_position {
+        None => 0.0,
+        // the most recent triangle's vertices score the same so the order within it doesn't matter
+        Some(p) if p < 3 => LAST_TRIANGLE_SCORE,
+        Some(p) => (1.0 - (p - 3) as f32 / (CACHE_SIZE - 3) as f32).powf(CACHE_DECAY_POWER)
+    };
+
+    // boost vertices with few triangles left so they're finished off rather than left stranded
+    let valence_boost = VALENCE_BOOST_SCALE * (remaining_triangles as f32).powf(-VALENCE_BOOST_POWER);
+
+    cache_score + valence_boost
+}
+
+// returns the order triangles should be drawn in
+fn forsyth_order(model: &Model) -> Vec<u32> {
+    let num_vertices = model.num_vertices as usize;
+    let num_triangles = model.num_triangles as usize;
+    let triangle_vertices = |it: usize| [model.trianglev0s[it] as usize, model.trianglev1s[it] as usize, model.trianglev2s[it] as usize];
+
+    // the triangles using each vertex, flattened into one list
+    let mut remaining = vec![0u32; num_vertices];
+    for it in 0..num_triangles {
+        for v in triangle_vertices(it) {
+            remaining[v] += 1;
+        }
+    }
+    let mut adjacency_starts = Vec::with_capacity(num_vertices + 1);
+    let mut start = 0;
+    for v in 0..num_vertices {
+        adjacency_starts.push(start);
+        start += remaining[v] as usize;
+    }
+    adjacency_starts.push(start);
+    let mut adjacency = vec![0u32; start];
+    let mut fill = adjacency_starts.clone();
+    for it in 0..num_triangles {
+        for v in triangle_vertices(it) {
+            adjacency[fill[v]] = it as u32;
+            fill[v] += 1;
+        }
+    }
+
+    let mut vertex_scores: Vec<f32> = (0..num_vertices).map(|v| vertex_score(None, remaining[v])).collect();
+    let mut added = vec![false; num_triangles];
+
+    let mut order = Vec::with_capacity(num_triangles);
+    let mut cache: Vec<usize> = Vec::with_capacity(CACHE_SIZE + 3);
+    let mut best = None;
+    // fallback when nothing in the cache has any triangles left
+    let mut next_unadded = 0;
+
+    while order.len() < num_triangles {
+        let it = match best {
+            Some(it) => it,
+            None => {
+                // scanning everything is too slow, so take the next one in file order
+                while added[next_unadded] {
+                    next_unadded += 1;
+                }
+                next_unadded
+            }
+        };
+
+        added[it] = true;
+        order.push(it as u32);
+
+        // move this triangle's vertices to the front of the cache
+        let vs = triangle_vertices(it);
+        for &v in &vs {
+            remaining[v] -= 1;
+            let start = adjacency_starts[v];
+            let end = start + remaining[v] as usize;
+            // keep the triangles not yet added at the start of the vertex's list
+            let position = adjacency[start..=end].iter().position(|&t| t as usize == it).unwrap();
+            adjacency.swap(start + position, end);
+
+            if let Some(p) = cache.iter().position(|&c| c == v) {
+                cache.remove(p);
+            }
+        }
+        for &v in vs.iter().rev() {
+            cache.insert(0, v);
+        }
+
+        // vertices that fall out of the cache need their scores updating too
+        for (p, &v) in cache.iter().enumerate() {
+            let cache_position = if p < CACHE_SIZE { Some(p) } else { None };
+            vertex_scores[v] = vertex_score(cache_position, remaining[v]);
+        }
+
+        // the best next triangle is almost certainly one that uses a vertex in the cache
+        best = None;
+        let mut best_score = -1.0;
+        for &v in &cache {
+            let start = adjacency_starts[v];
+            for &t in &adjacency[start..(start + remaining[v] as usize)] {
+                let t = t as usize;
+                let score = triangle_vertices(t).iter().map(|&tv| vertex_scores[tv]).sum();
+                if score > best_score {
+                    best_score = score;
+                    best = Some(t);
+                }
+            }
+        }
+
+        cache.truncate(CACHE_SIZE);
+    }
+
+    order
+}
+
+// clusters are drawn so that the ones facing out furthest from the centre of the model come first, which makes
+// it more likely that triangles behind them fail the depth test rather than being overwritten
+fn sort_clusters_for_overdraw(model: &Model, order: &mut Vec<u32>) {
+    let position = |v: u32| model.homogenous_coordinates(v).to_cartesian().0;
+    let triangle_centroid = |it: usize| {
+        let p0 = position(model.trianglev0s[it]);
+        let p1 = position(model.trianglev1s[it]);
+        let p2 = position(model.trianglev2s[it]);
+        CartesianVector { x: (p0.x + p1.x + p2.x) / 3.0, y: (p0.y + p1.y + p2.y) / 3.0, z: (p0.z + p1.z + p2.z) / 3.0 }
+    };
+
+    let mut model_centroid = CartesianVector { x: 0.0, y: 0.0, z: 0.0 };
+    for it in 0..model.num_triangles as usize {
+        model_centroid = model_centroid + triangle_centroid(it);
+    }
+    let n = model.num_triangles.max(1) as f32;
+    model_centroid = CartesianVector { x: model_centroid.x / n, y: model_centroid.y / n, z: model_centroid.z / n };
+
+    let mut clusters: Vec<(f32, &[u32])> = order.chunks(CLUSTER_SIZE).map(|cluster| {
+        // surface normals aren't normalised, so this weights them by area
+        let mut normal = CartesianVector { x: 0.0, y: 0.0, z: 0.0 };
+        let mut centroid = CartesianVector { x: 0.0, y: 0.0, z: 0.0 };
+        for &it in cluster {
+            normal = normal + model.surface_normal(it);
+            centroid = centroid + triangle_centroid(it as usize);
+        }
+        let n = cluster.len() as f32;
+        centroid = CartesianVector { x: centroid.x / n, y: centroid.y / n, z: centroid.z / n };
+
+        let magnitude = normal.magnitude();
+        let outwardness = if magnitude > 0.0 { (centroid - model_centroid).dot_product(&normal) / magnitude } else { 0.0 };
+
+        (outwardness, cluster)
+    }).collect();
+
+    clusters.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(Ordering::Equal));
+
+    let sorted = clusters.iter().flat_map(|(_, cluster)| cluster.iter().copied()).collect();
+    *order = sorted;
+}
+
+// vertices are renumbered in the order they're first used so that reading them is roughly sequential;
+// unused vertices go at the end
+fn first_use_order(model: &Model) -> Vec<u32> {
+    let mut used = vec![false; model.num_vertices as usize];
+    let mut order = Vec::with_capacity(model.num_vertices as usize);
+
+    for it in 0..model.num_triangles as usize {
+        for v in [model.trianglev0s[it], model.trianglev1s[it], model.trianglev2s[it]] {
+            if !used[v as usize] {
+                used[v as usize] = true;
+                order.push(v);
+            }
+        }
+    }
+
+    order.extend((0..model.num_vertices).filter(|&v| !used[v as usize]));
+    order
+}
+
+impl Model {
+    // average cache miss ratio: the mean number of vertices each triangle needs that aren't in a FIFO cache of the
+    // given size; 3.0 is the worst and 0.5 is about the best possible for a regular mesh
+    pub fn acmr(&self, cache_size: usize) -> f32 {
+        let mut cache = vec![u32::MAX; cache_size];
+        let mut next = 0;
+        let mut misses = 0;
+
+        for it in 0..self.num_triangles as usize {
+            for v in [self.trianglev0s[it], self.trianglev1s[it], self.trianglev2s[it]] {
+                if !cache.contains(&v) {
+                    misses += 1;
+                    cache[next] = v;
+                    next = (next + 1) % cache_size;
+                }
+            }
+        }
+
+        misses as f32 / self.num_triangles.max(1) as f32
+    }
+
+    pub fn optimise_vertex_cache(&mut self) {
+        let mut order = forsyth_order(self);
+        sort_clusters_for_overdraw(self, &mut order);
+        self.reorder_triangles(&order);
+
+        let order = first_use_order(self);
+        self.reorder_vertices(&order);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::primitives::*;
+
+    // each triangle as the positions and texture coordinates of its corners, starting from the smallest so that the
+    // same triangle compares equal however its corners were rotated, but not if its winding changed
+    fn triangles(model: &Model) -> Vec<[[u32; 5]; 3]> {
+        let corner = |v: u32| {
+            let v = v as usize;
+            [model.xs[v], model.ys[v], model.zs[v], model.texture_us[v], model.texture_vs[v]].map(f32::to_bits)
+        };
+        let mut triangles: Vec<_> = (0..model.num_triangles as usize).map(|it| {
+            let mut corners = [model.trianglev0s[it], model.trianglev1s[it], model.trianglev2s[it]].map(corner);
+            let smallest = (0..3).min_by_key(|&i| corners[i]).unwrap();
+            corners.rotate_left(smallest);
+            corners
+        }).collect();
+        triangles.sort();
+        triangles
+    }
+
+    // the primitives are generated in a fairly cache friendly order already, so they're shuffled first
+    fn shuffled(mut model: Model) -> Model {
+        let n = model.num_triangles;
+        let order: Vec<u32> = (0..n).map(|it| (it as u64 * 7919 % n as u64) as u32).collect();
+        model.reorder_triangles(&order);
+        model
+    }
+
+    #[test]
+    fn optimisation_keeps_the_triangles_and_reduces_misses() {
+        let mut model = shuffled(uv_sphere(1.0, 32, 16));
+        let before = triangles(&model);
+        let acmr_before = model.acmr(32);
+
+        model.optimise_vertex_cache();
+        assert_eq!(triangles(&model), before);
+        assert!(model.acmr(32) < acmr_before * 0.75, "ACMR {} before, {} after", acmr_before, model.acmr(32));
+    }
+
+    #[test]
+    fn overdraw_sorting_keeps_every_triangle_once() {
+        let model = icosphere(1.0, 3);
+        let mut order = forsyth_order(&model);
+        sort_clusters_for_overdraw(&model, &mut order);
+        order.sort();
+        assert_eq!(order, (0..model.num_triangles).collect::<Vec<_>>());
+    }
+}